@@ -29,18 +29,10 @@ impl DeviceIdManager {
         }
 
         // Try to migrate from ccnet.conf
-        let ccnet_conf = self.conf_dir.join("ccnet.conf");
-        if ccnet_conf.exists() {
-            debug!("Found ccnet.conf, attempting to migrate device ID");
-            let content = fs::read_to_string(&ccnet_conf)?;
-            for line in content.lines() {
-                if let Some(id) = line.strip_prefix("ID = ") {
-                    let device_id = id.trim().to_string();
-                    info!("Migrating device ID from ccnet.conf");
-                    write_secure_file(&id_file, &device_id)?;
-                    return Ok(device_id);
-                }
-            }
+        if let Some(device_id) = read_ccnet_id(&self.conf_dir)? {
+            info!("Migrating device ID from ccnet.conf");
+            write_secure_file(&id_file, &device_id)?;
+            return Ok(device_id);
         }
 
         // Create new device ID
@@ -51,12 +43,37 @@ impl DeviceIdManager {
     }
 }
 
+/// Read the peer ID from a legacy `ccnet.conf` in `conf_dir`, if any
+///
+/// Older ccnet releases wrote `ID = <hex>` under `[General]`, but hand-edited
+/// files use `ID=<hex>` or put it in other sections, so any section is
+/// accepted and whitespace around `=` is ignored. Only 40-char hex IDs count.
+pub fn read_ccnet_id(conf_dir: &Path) -> Result<Option<String>> {
+    let ccnet_conf = conf_dir.join("ccnet.conf");
+    if !ccnet_conf.exists() {
+        return Ok(None);
+    }
+
+    debug!("Found ccnet.conf, looking for device ID");
+    let content = fs::read_to_string(&ccnet_conf)
+        .with_context(|| format!("Failed to read {}", ccnet_conf.display()))?;
+
+    let id = content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "ID")
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|id| id.len() == 40 && id.chars().all(|c| c.is_ascii_hexdigit()));
+
+    Ok(id)
+}
+
 /// Write file with secure permissions (0o600)
 ///
 /// This ensures only the owner can read/write sensitive files.
 /// On Unix: Creates file with 0o600 permissions
 /// On non-Unix: Falls back to standard file write
-fn write_secure_file(path: &Path, content: &str) -> Result<()> {
+pub fn write_secure_file(path: &Path, content: &str) -> Result<()> {
     use std::fs::OpenOptions;
     use std::io::Write;
 
@@ -108,15 +125,20 @@ pub struct UserConfig {
 }
 
 impl UserConfig {
-    /// Load user config from file
-    pub fn load(config_file: Option<&Path>) -> Result<Self> {
-        let path = match config_file {
-            Some(p) => p.to_path_buf(),
+    /// Path of the user config file, `~/.seafile.conf` unless overridden
+    fn path(config_file: Option<&Path>) -> Result<PathBuf> {
+        match config_file {
+            Some(p) => Ok(p.to_path_buf()),
             None => {
                 let home = std::env::var("HOME")?;
-                PathBuf::from(home).join(".seafile.conf")
+                Ok(PathBuf::from(home).join(".seafile.conf"))
             }
-        };
+        }
+    }
+
+    /// Load user config from file
+    pub fn load(config_file: Option<&Path>) -> Result<Self> {
+        let path = Self::path(config_file)?;
 
         if !path.exists() {
             return Ok(Self {
@@ -162,6 +184,43 @@ impl UserConfig {
             token,
         })
     }
+
+    /// Drop the cached token from the `[account]` section, keeping everything else
+    ///
+    /// Returns whether a token was removed.
+    pub fn remove_token(config_file: Option<&Path>) -> Result<bool> {
+        let path = Self::path(config_file)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        let content = fs::read_to_string(&path)?;
+        let mut removed = false;
+        let mut in_account_section = false;
+        let mut kept = Vec::new();
+        for line in content.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with('[') {
+                in_account_section = trimmed == "[account]";
+            } else if in_account_section
+                && trimmed
+                    .split_once('=')
+                    .is_some_and(|(key, _)| key.trim() == "token")
+            {
+                removed = true;
+                continue;
+            }
+            kept.push(line);
+        }
+
+        if removed {
+            let mut content = kept.join("\n");
+            content.push('\n');
+            write_secure_file(&path, &content)?;
+            info!(path = %path.display(), "Removed cached token");
+        }
+        Ok(removed)
+    }
 }
 
 /// Initialize seafile configuration
@@ -231,3 +290,38 @@ pub fn check_daemon_running(datadir: &Path) -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_token() {
+        let path = std::env::temp_dir().join(format!("seaf-cli-conf-{}", std::process::id()));
+        fs::write(
+            &path,
+            "[account]\nserver = https://seafile.example.com\nuser = me@example.com\ntoken = abc123\n\n[proxy]\ntoken = not-ours\n",
+        )
+        .unwrap();
+
+        assert!(UserConfig::remove_token(Some(&path)).unwrap());
+        let config = UserConfig::load(Some(&path)).unwrap();
+        assert_eq!(config.token, None);
+        assert_eq!(
+            config.server.as_deref(),
+            Some("https://seafile.example.com")
+        );
+        assert_eq!(config.user.as_deref(), Some("me@example.com"));
+        // Other sections are left alone
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(
+            content.contains("[proxy]\ntoken = not-ours\n"),
+            "{}",
+            content
+        );
+
+        assert!(!UserConfig::remove_token(Some(&path)).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(!UserConfig::remove_token(Some(&path)).unwrap());
+    }
+}
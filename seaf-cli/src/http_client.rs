@@ -1,12 +1,52 @@
 use anyhow::{Context, Result};
-use reqwest::blocking::Client;
+use reqwest::blocking::{multipart, Client, ClientBuilder, Response};
+use reqwest::{Certificate, StatusCode, Url};
+use searpc::error::exit_code;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Timeout and retry settings for HTTP requests
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    /// Time allowed to establish a connection
+    pub connect_timeout: Duration,
+    /// Time allowed for a whole request, including reading the body
+    pub timeout: Duration,
+    /// Extra attempts for idempotent GETs after a transient failure
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further retry
+    pub backoff: Duration,
+    /// Extra CA certificate (PEM or DER) trusted for the server, e.g. a private CA
+    pub ca_cert: Option<PathBuf>,
+    /// Skip TLS certificate and hostname verification entirely
+    pub insecure: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(60),
+            retries: 3,
+            backoff: Duration::from_millis(500),
+            ca_cert: None,
+            insecure: false,
+        }
+    }
+}
 
 /// Seafile HTTP API client
 pub struct SeafileHttpClient {
     client: Client,
+    /// Like `client` but without a total request timeout, for file bodies
+    transfer_client: Client,
     server_url: String,
+    config: HttpConfig,
 }
 
 #[derive(Debug, Serialize)]
@@ -48,16 +88,254 @@ pub struct RepoDownloadInfo {
     pub permission: Option<String>,
 }
 
+/// Storage usage and quota of the authenticated account
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AccountInfo {
+    pub email: String,
+    #[serde(default)]
+    pub name: String,
+    /// Bytes used
+    pub usage: i64,
+    /// Quota in bytes; negative means unlimited
+    pub total: i64,
+}
+
+impl AccountInfo {
+    /// Quota in bytes, `None` when unlimited
+    pub fn quota(&self) -> Option<i64> {
+        (self.total >= 0).then_some(self.total)
+    }
+}
+
+/// Server version and enabled features
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ServerInfo {
+    pub version: String,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub encrypted_library_version: Option<i32>,
+}
+
 #[derive(Debug, Deserialize)]
 struct CreateRepoResponse {
     repo_id: String,
 }
 
+/// Load a CA certificate from a PEM or DER file
+fn load_certificate(path: &Path) -> Result<Certificate> {
+    let data = fs::read(path)
+        .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+    Certificate::from_pem(&data)
+        .or_else(|_| Certificate::from_der(&data))
+        .with_context(|| format!("Invalid CA certificate {}", path.display()))
+}
+
+/// What went wrong in a failed API request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    /// Wrong username or password
+    InvalidCredentials,
+    /// Two-factor authentication code missing or wrong
+    OtpRequired,
+    /// Token missing, expired or revoked
+    Unauthorized,
+    /// Authenticated, but not allowed to do this
+    PermissionDenied,
+    NotFound,
+    /// Storage quota exceeded (Seafile answers with HTTP 443)
+    QuotaExceeded,
+    RateLimited,
+    ServerError,
+    Other,
+}
+
+impl ApiErrorKind {
+    /// Process exit code, in line with `searpc::error::exit_code`
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ApiErrorKind::InvalidCredentials
+            | ApiErrorKind::OtpRequired
+            | ApiErrorKind::Unauthorized
+            | ApiErrorKind::PermissionDenied => exit_code::NO_PERM,
+            ApiErrorKind::NotFound => exit_code::NO_INPUT,
+            ApiErrorKind::QuotaExceeded => exit_code::CANT_CREATE,
+            ApiErrorKind::RateLimited => exit_code::TEMP_FAIL,
+            ApiErrorKind::ServerError => exit_code::UNAVAILABLE,
+            ApiErrorKind::Other => exit_code::FAILURE,
+        }
+    }
+}
+
+/// Error response from the Seafile web API
+///
+/// Carries the HTTP status and the message from Seafile's JSON error body
+/// (`error_msg`, `detail` or `non_field_errors`), falling back to the raw
+/// body text. Reach it through `anyhow::Error::downcast_ref`.
+#[derive(Debug, thiserror::Error)]
+#[error("{action}: {status} - {message}")]
+pub struct SeafileApiError {
+    pub action: String,
+    pub status: StatusCode,
+    pub message: String,
+    kind: ApiErrorKind,
+}
+
+/// Body shapes Seafile uses for errors
+#[derive(Debug, Default, Deserialize)]
+struct ApiErrorBody {
+    error_msg: Option<String>,
+    detail: Option<String>,
+    #[serde(default)]
+    non_field_errors: Vec<String>,
+}
+
+impl SeafileApiError {
+    /// Build the error for a non-success response, consuming its body
+    fn from_response(resp: Response, action: &str) -> Self {
+        let status = resp.status();
+        let otp_required = resp
+            .headers()
+            .get("X-Seafile-OTP")
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"required"));
+        let text = resp.text().unwrap_or_default();
+        let body: ApiErrorBody = serde_json::from_str(&text).unwrap_or_default();
+
+        let message = body
+            .error_msg
+            .or(body.detail)
+            .or_else(|| {
+                (!body.non_field_errors.is_empty()).then(|| body.non_field_errors.join("; "))
+            })
+            .unwrap_or(text);
+
+        let kind = match status.as_u16() {
+            _ if otp_required => ApiErrorKind::OtpRequired,
+            // Bad credentials come back as a form validation error
+            400 if !body.non_field_errors.is_empty() => ApiErrorKind::InvalidCredentials,
+            401 => ApiErrorKind::Unauthorized,
+            403 => ApiErrorKind::PermissionDenied,
+            404 => ApiErrorKind::NotFound,
+            429 => ApiErrorKind::RateLimited,
+            443 => ApiErrorKind::QuotaExceeded,
+            500..=599 => ApiErrorKind::ServerError,
+            _ => ApiErrorKind::Other,
+        };
+
+        SeafileApiError {
+            action: action.to_string(),
+            status,
+            message,
+            kind,
+        }
+    }
+
+    pub fn kind(&self) -> ApiErrorKind {
+        self.kind
+    }
+}
+
+/// Pass successful responses through, turn the rest into [`SeafileApiError`]
+fn check_response(resp: Response, action: &str) -> std::result::Result<Response, SeafileApiError> {
+    if resp.status().is_success() {
+        Ok(resp)
+    } else {
+        Err(SeafileApiError::from_response(resp, action))
+    }
+}
+
+/// Client builder with the connection and TLS settings from `config`
+fn client_builder(config: &HttpConfig) -> Result<ClientBuilder> {
+    let mut builder = Client::builder().connect_timeout(config.connect_timeout);
+
+    if let Some(path) = &config.ca_cert {
+        builder = builder.add_root_certificate(load_certificate(path)?);
+    }
+    if config.insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+/// Reader reporting the number of bytes read so far to a callback
+struct ProgressReader<R, F> {
+    inner: R,
+    done: u64,
+    total: Option<u64>,
+    progress: F,
+}
+
+impl<R: Read, F: FnMut(u64, Option<u64>)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        (self.progress)(self.done, self.total);
+        Ok(n)
+    }
+}
+
+/// Whether a failed GET is worth retrying
+fn is_transient(result: &reqwest::Result<Response>) -> bool {
+    match result {
+        Ok(resp) => {
+            resp.status().is_server_error() || resp.status() == StatusCode::TOO_MANY_REQUESTS
+        }
+        Err(e) => e.is_connect() || e.is_timeout(),
+    }
+}
+
 impl SeafileHttpClient {
-    pub fn new(server_url: &str) -> Self {
-        Self {
-            client: Client::new(),
+    pub fn new(server_url: &str, config: &HttpConfig) -> Result<Self> {
+        if config.insecure {
+            warn!("TLS certificate verification is disabled");
+        }
+
+        let client = client_builder(config)?
+            .timeout(config.timeout)
+            .build()
+            .context("Failed to build HTTP client")?;
+        let transfer_client = client_builder(config)?
+            .timeout(None)
+            .build()
+            .context("Failed to build HTTP client")?;
+
+        Ok(Self {
+            client,
+            transfer_client,
             server_url: server_url.trim_end_matches('/').to_string(),
+            config: config.clone(),
+        })
+    }
+
+    /// GET (authenticated if `token` is given), retried with exponential backoff on transient failures
+    ///
+    /// Only for idempotent requests: connection errors, timeouts, 5xx and 429
+    /// responses are retried up to `config.retries` times.
+    fn get_with_retry(&self, url: &str, token: Option<&str>) -> reqwest::Result<Response> {
+        let mut backoff = self.config.backoff;
+        let mut attempt = 0;
+        loop {
+            let mut req = self.client.get(url);
+            if let Some(token) = token {
+                req = req.header("Authorization", format!("Token {}", token));
+            }
+            let result = req.send();
+
+            if attempt >= self.config.retries || !is_transient(&result) {
+                return result;
+            }
+
+            attempt += 1;
+            match &result {
+                Ok(resp) => {
+                    warn!(url, status = %resp.status(), attempt, "Transient HTTP error, retrying")
+                }
+                Err(e) => warn!(url, error = %e, attempt, "HTTP request failed, retrying"),
+            }
+            debug!(?backoff, "Backing off");
+            std::thread::sleep(backoff);
+            backoff *= 2;
         }
     }
 
@@ -94,31 +372,43 @@ impl SeafileHttpClient {
 
         let resp = req.send().context("Failed to send auth request")?;
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let text = resp.text().unwrap_or_default();
-            anyhow::bail!("Authentication failed: {} - {}", status, text);
-        }
+        let resp = check_response(resp, "Authentication failed")?;
 
         let auth_resp: AuthResponse = resp.json().context("Failed to parse auth response")?;
         Ok(auth_resp.token)
     }
 
-    /// List remote repositories
-    pub fn list_repos(&self, token: &str) -> Result<Vec<RepoInfo>> {
-        let url = format!("{}/api2/repos/", self.server_url);
+    /// Revoke `token` on the server
+    ///
+    /// Uses the device logout endpoint, which deletes the token the request is
+    /// authenticated with. A token the server no longer knows counts as revoked.
+    pub fn delete_token(&self, token: &str) -> Result<()> {
+        let url = format!("{}/api2/logout-device/", self.server_url);
         let resp = self
             .client
-            .get(&url)
+            .post(&url)
             .header("Authorization", format!("Token {}", token))
             .send()
-            .context("Failed to list repos")?;
+            .context("Failed to revoke token")?;
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let text = resp.text().unwrap_or_default();
-            anyhow::bail!("Failed to list repos: {} - {}", status, text);
+        match check_response(resp, "Failed to revoke token") {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ApiErrorKind::Unauthorized => {
+                debug!("Token was already invalid");
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
         }
+    }
+
+    /// List remote repositories
+    pub fn list_repos(&self, token: &str) -> Result<Vec<RepoInfo>> {
+        let url = format!("{}/api2/repos/", self.server_url);
+        let resp = self
+            .get_with_retry(&url, Some(token))
+            .context("Failed to list repos")?;
+
+        let resp = check_response(resp, "Failed to list repos")?;
 
         let repos: Vec<RepoInfo> = resp.json().context("Failed to parse repo list")?;
         Ok(repos)
@@ -128,22 +418,41 @@ impl SeafileHttpClient {
     pub fn get_repo_download_info(&self, token: &str, repo_id: &str) -> Result<RepoDownloadInfo> {
         let url = format!("{}/api2/repos/{}/download-info/", self.server_url, repo_id);
         let resp = self
-            .client
-            .get(&url)
-            .header("Authorization", format!("Token {}", token))
-            .send()
+            .get_with_retry(&url, Some(token))
             .context("Failed to get download info")?;
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let text = resp.text().unwrap_or_default();
-            anyhow::bail!("Failed to get download info: {} - {}", status, text);
-        }
+        let resp = check_response(resp, "Failed to get download info")?;
 
         let info: RepoDownloadInfo = resp.json().context("Failed to parse download info")?;
         Ok(info)
     }
 
+    /// Get usage and quota of the account owning `token`
+    pub fn get_account_info(&self, token: &str) -> Result<AccountInfo> {
+        let url = format!("{}/api2/account/info/", self.server_url);
+        let resp = self
+            .get_with_retry(&url, Some(token))
+            .context("Failed to get account info")?;
+
+        let resp = check_response(resp, "Failed to get account info")?;
+
+        let info: AccountInfo = resp.json().context("Failed to parse account info")?;
+        Ok(info)
+    }
+
+    /// Get server version and features (no authentication needed)
+    pub fn get_server_info(&self) -> Result<ServerInfo> {
+        let url = format!("{}/api2/server-info/", self.server_url);
+        let resp = self
+            .get_with_retry(&url, None)
+            .context("Failed to get server info")?;
+
+        let resp = check_response(resp, "Failed to get server info")?;
+
+        let info: ServerInfo = resp.json().context("Failed to parse server info")?;
+        Ok(info)
+    }
+
     /// Create a new repository
     pub fn create_repo(
         &self,
@@ -169,18 +478,216 @@ impl SeafileHttpClient {
             .send()
             .context("Failed to create repo")?;
 
-        if !resp.status().is_success() {
-            let status = resp.status();
-            let text = resp.text().unwrap_or_default();
-            anyhow::bail!("Failed to create repo: {} - {}", status, text);
-        }
+        let resp = check_response(resp, "Failed to create repo")?;
 
         let resp: CreateRepoResponse = resp.json().context("Failed to parse create response")?;
         Ok(resp.repo_id)
     }
 
+    /// Rename a repository
+    pub fn rename_repo(&self, token: &str, repo_id: &str, name: &str) -> Result<()> {
+        let url = format!("{}/api2/repos/{}/?op=rename", self.server_url, repo_id);
+        let resp = self
+            .client
+            .post(&url)
+            .header("Authorization", format!("Token {}", token))
+            .form(&[("repo_name", name)])
+            .send()
+            .context("Failed to rename repo")?;
+
+        check_response(resp, "Failed to rename repo")?;
+
+        Ok(())
+    }
+
+    /// Delete a repository on the server
+    pub fn delete_repo(&self, token: &str, repo_id: &str) -> Result<()> {
+        let url = format!("{}/api2/repos/{}/", self.server_url, repo_id);
+        let resp = self
+            .client
+            .delete(&url)
+            .header("Authorization", format!("Token {}", token))
+            .send()
+            .context("Failed to delete repo")?;
+
+        check_response(resp, "Failed to delete repo")?;
+
+        Ok(())
+    }
+
+    /// GET a repo API endpoint taking a path, returning the link it answers with
+    fn get_link(&self, token: &str, repo_id: &str, endpoint: &str, path: &str) -> Result<String> {
+        let url = Url::parse_with_params(
+            &format!("{}/api2/repos/{}/{}/", self.server_url, repo_id, endpoint),
+            &[("p", path)],
+        )
+        .context("Invalid server URL")?;
+        let resp = self
+            .get_with_retry(url.as_str(), Some(token))
+            .context("Failed to get link")?;
+
+        let resp = check_response(resp, &format!("Failed to get link for {}", path))?;
+
+        // The link comes back as a JSON string
+        let link: String = resp.json().context("Failed to parse link")?;
+        Ok(link)
+    }
+
+    /// Get a download link for the file at `path` in a repository
+    pub fn get_download_link(&self, token: &str, repo_id: &str, path: &str) -> Result<String> {
+        self.get_link(token, repo_id, "file", path)
+    }
+
+    /// Get an upload link for the directory `parent_dir` in a repository
+    pub fn get_upload_link(&self, token: &str, repo_id: &str, parent_dir: &str) -> Result<String> {
+        self.get_link(token, repo_id, "upload-link", parent_dir)
+    }
+
+    /// Stream the file behind a download link into `dest`
+    ///
+    /// `progress` is called with the bytes received so far and the total
+    /// size, if the server sent one. Returns the number of bytes written.
+    pub fn download_file<W, F>(&self, link: &str, dest: &mut W, mut progress: F) -> Result<u64>
+    where
+        W: Write,
+        F: FnMut(u64, Option<u64>),
+    {
+        let resp = self
+            .transfer_client
+            .get(link)
+            .send()
+            .context("Failed to download file")?;
+
+        let resp = check_response(resp, "Failed to download file")?;
+
+        let total = resp.content_length();
+        let mut reader = ProgressReader {
+            inner: resp,
+            done: 0,
+            total,
+            progress: &mut progress,
+        };
+        let written = std::io::copy(&mut reader, dest).context("Failed to download file")?;
+        Ok(written)
+    }
+
+    /// Upload the local file `src` through an upload link into `parent_dir`
+    ///
+    /// With `replace`, an existing file of the same name is overwritten,
+    /// otherwise the server picks a new name. `progress` is called with the
+    /// bytes sent so far and the file size.
+    pub fn upload_file<F>(
+        &self,
+        link: &str,
+        parent_dir: &str,
+        src: &Path,
+        replace: bool,
+        progress: F,
+    ) -> Result<()>
+    where
+        F: FnMut(u64, Option<u64>) + Send + 'static,
+    {
+        let file = File::open(src).with_context(|| format!("Failed to open {}", src.display()))?;
+        let size = file.metadata()?.len();
+        let file_name = src
+            .file_name()
+            .context("Upload source has no file name")?
+            .to_string_lossy()
+            .into_owned();
+
+        let reader = ProgressReader {
+            inner: file,
+            done: 0,
+            total: Some(size),
+            progress,
+        };
+        let mut form = multipart::Form::new()
+            .text("parent_dir", parent_dir.to_string())
+            .part(
+                "file",
+                multipart::Part::reader_with_length(reader, size).file_name(file_name),
+            );
+        if replace {
+            form = form.text("replace", "1");
+        }
+
+        let resp = self
+            .transfer_client
+            .post(link)
+            .query(&[("ret-json", "1")])
+            .multipart(form)
+            .send()
+            .context("Failed to upload file")?;
+
+        check_response(resp, "Failed to upload file")?;
+
+        Ok(())
+    }
+
     /// Get base URL from server URL
     pub fn get_base_url(&self) -> &str {
         &self.server_url
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(status: u16, otp: Option<&str>, body: &str) -> SeafileApiError {
+        let mut resp = http::Response::builder().status(status);
+        if let Some(otp) = otp {
+            resp = resp.header("X-Seafile-OTP", otp);
+        }
+        let resp = Response::from(resp.body(body.to_string()).unwrap());
+        check_response(resp, "Test").unwrap_err()
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        use ApiErrorKind::*;
+
+        let login_failed = r#"{"non_field_errors":["Unable to login"]}"#;
+        let cases = [
+            (400, login_failed, InvalidCredentials),
+            (400, "bad request", Other),
+            (401, r#"{"detail":"Invalid token"}"#, Unauthorized),
+            (403, r#"{"error_msg":"Denied"}"#, PermissionDenied),
+            (404, r#"{"error_msg":"Not found"}"#, NotFound),
+            (429, "", RateLimited),
+            (443, "", QuotaExceeded),
+            (500, "", ServerError),
+            (502, "Bad Gateway", ServerError),
+            (503, "", ServerError),
+            (418, "", Other),
+        ];
+        for (status, body, kind) in cases {
+            assert_eq!(
+                api_error(status, None, body).kind(),
+                kind,
+                "HTTP {}",
+                status
+            );
+        }
+        // The OTP header wins over the status
+        let err = api_error(400, Some("required"), login_failed);
+        assert_eq!(err.kind(), OtpRequired);
+    }
+
+    #[test]
+    fn test_error_message() {
+        let err = api_error(404, None, r#"{"error_msg":"Library not found."}"#);
+        assert_eq!(err.to_string(), "Test: 404 Not Found - Library not found.");
+        let err = api_error(400, None, r#"{"non_field_errors":["a","b"]}"#);
+        assert_eq!(err.message, "a; b");
+        let err = api_error(502, None, "Bad Gateway");
+        assert_eq!(err.message, "Bad Gateway");
+        assert_eq!(err.kind().exit_code(), exit_code::UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_success_passes() {
+        let resp = Response::from(http::Response::new("{}".to_string()));
+        assert!(check_response(resp, "Test").is_ok());
+    }
+}
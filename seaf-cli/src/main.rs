@@ -1,18 +1,27 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use searpc::error::exit_code;
+use searpc::paths::{self, SEAFILE_SOCKET_NAME};
 use searpc::{SearpcClient, UnixSocketTransport};
+use std::collections::HashSet;
 use std::fs;
+use std::io::Write as _;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitCode};
+use std::time::Duration;
 use tracing::{debug, error, info, trace, warn};
 
 mod config;
 mod http_client;
+mod migrate;
+mod output;
 mod rpc_client;
+mod status;
 
 use config::{check_daemon_running, init_config, DeviceIdManager, UserConfig};
-use http_client::SeafileHttpClient;
-use rpc_client::SeafileRpc as _;
+use http_client::{ApiErrorKind, HttpConfig, SeafileApiError, SeafileHttpClient};
+use output::OutputFormat;
+use rpc_client::{Repo, SeafileRpc as _};
 
 /// Seafile command-line client
 #[derive(Parser)]
@@ -23,10 +32,54 @@ struct Cli {
     #[arg(short = 'c', long = "confdir", global = true)]
     confdir: Option<PathBuf>,
 
+    #[command(flatten)]
+    http: HttpArgs,
+
+    /// Daemon RPC socket (default: seafile.sock in the data dir from seafile.ini)
+    #[arg(long, global = true, env = "SEAFILE_SOCKET")]
+    socket: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// HTTP options shared by all commands talking to the server
+#[derive(Args)]
+struct HttpArgs {
+    /// HTTP connect timeout in seconds
+    #[arg(long, global = true, default_value_t = 10)]
+    connect_timeout: u64,
+
+    /// HTTP request timeout in seconds
+    #[arg(long, global = true, default_value_t = 60)]
+    timeout: u64,
+
+    /// Retries for idempotent HTTP requests on transient failures
+    #[arg(long, global = true, default_value_t = 3)]
+    retries: u32,
+
+    /// Extra CA certificate (PEM or DER) to trust, e.g. for a private CA
+    #[arg(long, global = true, value_name = "PATH")]
+    ca_cert: Option<PathBuf>,
+
+    /// Do not verify the server's TLS certificate (dangerous)
+    #[arg(long, global = true)]
+    insecure: bool,
+}
+
+impl HttpArgs {
+    fn to_config(&self) -> HttpConfig {
+        HttpConfig {
+            connect_timeout: Duration::from_secs(self.connect_timeout),
+            timeout: Duration::from_secs(self.timeout),
+            retries: self.retries,
+            ca_cert: self.ca_cert.clone(),
+            insecure: self.insecure,
+            ..HttpConfig::default()
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize config directory
@@ -36,6 +89,13 @@ enum Commands {
         dir: PathBuf,
     },
 
+    /// Migrate an existing python seaf-cli / ccnet setup
+    Migrate {
+        /// Config directory of the old setup (default: ~/.ccnet)
+        #[arg(long)]
+        from: Option<PathBuf>,
+    },
+
     /// Start seafile daemon
     Start,
 
@@ -47,6 +107,10 @@ enum Commands {
         /// Output in JSON format
         #[arg(long)]
         json: bool,
+
+        /// Machine-readable output format
+        #[arg(long, value_enum, conflicts_with = "json")]
+        format: Option<OutputFormat>,
     },
 
     /// List remote libraries
@@ -80,8 +144,72 @@ enum Commands {
         user_config: Option<PathBuf>,
     },
 
+    /// Show server version and account quota
+    Info {
+        /// Output in JSON format
+        #[arg(long)]
+        json: bool,
+
+        /// Seafile server URL
+        #[arg(short = 's', long)]
+        server: Option<String>,
+
+        /// Username
+        #[arg(short = 'u', long)]
+        username: Option<String>,
+
+        /// Password
+        #[arg(short = 'p', long)]
+        password: Option<String>,
+
+        /// Token
+        #[arg(short = 'T', long)]
+        token: Option<String>,
+
+        /// Two-factor authentication code
+        #[arg(short = 'a', long)]
+        tfa: Option<String>,
+
+        /// User config file
+        #[arg(short = 'C')]
+        user_config: Option<PathBuf>,
+    },
+
+    /// Revoke the cached token on the server and remove it from the user config
+    Logout {
+        /// Seafile server URL
+        #[arg(short = 's', long)]
+        server: Option<String>,
+
+        /// Token to revoke (default: the one in the user config)
+        #[arg(short = 'T', long)]
+        token: Option<String>,
+
+        /// User config file
+        #[arg(short = 'C')]
+        user_config: Option<PathBuf>,
+    },
+
     /// Show syncing status
-    Status,
+    Status {
+        /// Machine-readable output format
+        #[arg(long, value_enum)]
+        format: Option<OutputFormat>,
+
+        /// Keep polling and print the status every interval
+        #[arg(long)]
+        watch: bool,
+
+        /// Polling interval in seconds (with --watch)
+        #[arg(long, default_value_t = 5, requires = "watch")]
+        interval: u64,
+
+        /// Command to run when a library enters the error state (with --watch);
+        /// called as `CMD <repo name> <error>`, with SEAF_REPO_ID, SEAF_REPO_NAME
+        /// and SEAF_ERROR in its environment
+        #[arg(long, requires = "watch")]
+        notify_cmd: Option<String>,
+    },
 
     /// Download a library from seafile server
     Download {
@@ -246,6 +374,215 @@ enum Commands {
         user_config: Option<PathBuf>,
     },
 
+    /// Enable or disable auto sync for a library (or all libraries)
+    #[command(group(ArgGroup::new("mode").required(true).args(["enable", "disable"])))]
+    #[command(group(ArgGroup::new("target").required(true).args(["folder", "all"])))]
+    Autosync {
+        /// Resume automatic syncing
+        #[arg(long)]
+        enable: bool,
+
+        /// Pause automatic syncing
+        #[arg(long)]
+        disable: bool,
+
+        /// Local folder of the library
+        #[arg(short = 'd', long)]
+        folder: Option<PathBuf>,
+
+        /// Apply to all libraries (daemon-wide switch)
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Rename a library on the server
+    Rename {
+        /// Library ID
+        #[arg(short = 'l', long)]
+        library: String,
+
+        /// New library name
+        #[arg(short = 'n', long)]
+        name: String,
+
+        /// Seafile server URL
+        #[arg(short = 's', long)]
+        server: Option<String>,
+
+        /// Username
+        #[arg(short = 'u', long)]
+        username: Option<String>,
+
+        /// Password
+        #[arg(short = 'p', long)]
+        password: Option<String>,
+
+        /// Token
+        #[arg(short = 'T', long)]
+        token: Option<String>,
+
+        /// Two-factor authentication code
+        #[arg(short = 'a', long)]
+        tfa: Option<String>,
+
+        /// User config file
+        #[arg(short = 'C')]
+        user_config: Option<PathBuf>,
+    },
+
+    /// Delete a library on the server (and stop syncing it locally)
+    Delete {
+        /// Library ID
+        #[arg(short = 'l', long)]
+        library: String,
+
+        /// Do not ask for confirmation
+        #[arg(short = 'y', long)]
+        yes: bool,
+
+        /// Seafile server URL
+        #[arg(short = 's', long)]
+        server: Option<String>,
+
+        /// Username
+        #[arg(short = 'u', long)]
+        username: Option<String>,
+
+        /// Password
+        #[arg(short = 'p', long)]
+        password: Option<String>,
+
+        /// Token
+        #[arg(short = 'T', long)]
+        token: Option<String>,
+
+        /// Two-factor authentication code
+        #[arg(short = 'a', long)]
+        tfa: Option<String>,
+
+        /// User config file
+        #[arg(short = 'C')]
+        user_config: Option<PathBuf>,
+    },
+
+    /// Download a single file from a library without syncing it
+    Get {
+        /// Library ID
+        #[arg(short = 'l', long)]
+        library: String,
+
+        /// Path of the file in the library, e.g. /docs/report.pdf
+        remote: String,
+
+        /// Local destination file or directory (default: current directory)
+        local: Option<PathBuf>,
+
+        /// Seafile server URL
+        #[arg(short = 's', long)]
+        server: Option<String>,
+
+        /// Username
+        #[arg(short = 'u', long)]
+        username: Option<String>,
+
+        /// Password
+        #[arg(short = 'p', long)]
+        password: Option<String>,
+
+        /// Token
+        #[arg(short = 'T', long)]
+        token: Option<String>,
+
+        /// Two-factor authentication code
+        #[arg(short = 'a', long)]
+        tfa: Option<String>,
+
+        /// User config file
+        #[arg(short = 'C')]
+        user_config: Option<PathBuf>,
+    },
+
+    /// Upload a single file into a library without syncing it
+    Put {
+        /// Library ID
+        #[arg(short = 'l', long)]
+        library: String,
+
+        /// Local file to upload
+        local: PathBuf,
+
+        /// Directory in the library to upload into
+        #[arg(default_value = "/")]
+        remote_dir: String,
+
+        /// Overwrite a file of the same name instead of keeping both
+        #[arg(long)]
+        replace: bool,
+
+        /// Seafile server URL
+        #[arg(short = 's', long)]
+        server: Option<String>,
+
+        /// Username
+        #[arg(short = 'u', long)]
+        username: Option<String>,
+
+        /// Password
+        #[arg(short = 'p', long)]
+        password: Option<String>,
+
+        /// Token
+        #[arg(short = 'T', long)]
+        token: Option<String>,
+
+        /// Two-factor authentication code
+        #[arg(short = 'a', long)]
+        tfa: Option<String>,
+
+        /// User config file
+        #[arg(short = 'C')]
+        user_config: Option<PathBuf>,
+    },
+
+    /// Move a library's local folder to another path
+    Move {
+        /// Current local folder of the library
+        #[arg(short = 'd', long)]
+        folder: PathBuf,
+
+        /// New location (must not exist yet)
+        #[arg(long)]
+        to: PathBuf,
+
+        /// Seafile server URL (only needed if the library has to be re-synced)
+        #[arg(short = 's', long)]
+        server: Option<String>,
+
+        /// Username
+        #[arg(short = 'u', long)]
+        username: Option<String>,
+
+        /// Password
+        #[arg(short = 'p', long)]
+        password: Option<String>,
+
+        /// Token
+        #[arg(short = 'T', long)]
+        token: Option<String>,
+
+        /// Two-factor authentication code
+        #[arg(short = 'a', long)]
+        tfa: Option<String>,
+
+        /// Library password (for encrypted repos)
+        #[arg(short = 'e', long)]
+        libpasswd: Option<String>,
+
+        /// User config file
+        #[arg(short = 'C')]
+        user_config: Option<PathBuf>,
+    },
+
     /// Configure seafile client
     Config {
         /// Configuration key
@@ -258,7 +595,43 @@ enum Commands {
     },
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit_code_for(&e))
+        }
+    }
+}
+
+/// Exit code for a failed command, following `searpc::error::exit_code`
+fn exit_code_for(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<searpc::SearpcError>() {
+            return e.exit_code();
+        }
+        if let Some(e) = cause.downcast_ref::<SeafileApiError>() {
+            return e.kind().exit_code();
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if e.is_connect() || e.is_timeout() {
+                return exit_code::UNAVAILABLE;
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset
+            ) {
+                return exit_code::UNAVAILABLE;
+            }
+        }
+    }
+    exit_code::FAILURE
+}
+
+fn run() -> Result<()> {
     // Initialize tracing with env filter
     // Set RUST_LOG=debug to see debug logs
     tracing_subscriber::fmt()
@@ -266,6 +639,7 @@ fn main() -> Result<()> {
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
         )
+        .with_writer(std::io::stderr) // keep stdout clean for piped output
         .with_target(false)
         .with_file(true)
         .with_line_number(true)
@@ -276,13 +650,9 @@ fn main() -> Result<()> {
     debug!("Parsed command line arguments");
 
     if let Commands::Init { dir } = &cli.command {
-        let conf_dir = if let Some(dir) = cli.confdir.clone() {
-            dir
-        } else {
-            let home = std::env::var("HOME")
-                .or_else(|_| std::env::var("USERPROFILE")) // Windows fallback
-                .context("HOME or USERPROFILE environment variable not set")?;
-            PathBuf::from(home).join(".ccnet")
+        let conf_dir = match cli.confdir.clone() {
+            Some(dir) => dir,
+            None => default_conf_dir()?,
         };
         init_config(&conf_dir, dir)?;
         return Ok(());
@@ -291,44 +661,84 @@ fn main() -> Result<()> {
     // Determine config directory
     let conf_dir = match cli.confdir {
         Some(dir) => dir,
-        None => {
-            let home = std::env::var("HOME")?;
-            PathBuf::from(home).join(".ccnet")
-        }
+        None => default_conf_dir()?,
     };
 
+    if let Commands::Migrate { from } = &cli.command {
+        let old_conf = match from {
+            Some(dir) => dir.clone(),
+            None => default_conf_dir()?,
+        };
+        debug!(from = %old_conf.display(), to = %conf_dir.display(), "Executing migrate command");
+        let datadir = migrate::migrate_config(&old_conf, &conf_dir)?;
+
+        let socket_path = cli
+            .socket
+            .unwrap_or_else(|| datadir.join(SEAFILE_SOCKET_NAME));
+        match connect_rpc(&socket_path) {
+            Ok(mut client) => migrate::report_libraries(&mut client)?,
+            Err(e) => {
+                debug!(error = %e, "Daemon not reachable");
+                println!("Daemon not running; synced libraries resume after `seaf-cli start`");
+            }
+        }
+        return Ok(());
+    }
+
     // For start command, we don't need to read config yet
     if matches!(cli.command, Commands::Start) {
         return handle_start(&conf_dir);
     }
 
-    // Read seafile.ini to get socket path
-    let seafile_ini = conf_dir.join("seafile.ini");
-    let seafile_datadir = fs::read_to_string(&seafile_ini)
-        .context("Failed to read seafile.ini")?
-        .trim()
-        .to_string();
-
-    let datadir_path = PathBuf::from(&seafile_datadir);
+    let http_config = cli.http.to_config();
+
+    // An explicit socket only replaces the socket path: commands that
+    // touch the data dir still read it from seafile.ini.
+    let socket_override = cli.socket.is_some();
+    let datadir = || -> Result<PathBuf> {
+        let datadir = paths::read_datadir(&conf_dir);
+        if socket_override {
+            datadir.context(
+                "--socket sets only the daemon socket; this command also needs the data dir",
+            )
+        } else {
+            Ok(datadir?)
+        }
+    };
+    let socket_path = match cli.socket {
+        Some(socket) => {
+            debug!(socket = %socket.display(), "Using socket override");
+            socket
+        }
+        None => datadir()?.join(SEAFILE_SOCKET_NAME),
+    };
 
     // Execute command
     match cli.command {
         Commands::Init { .. } => unreachable!(),
+        Commands::Migrate { .. } => unreachable!(),
         Commands::Start => unreachable!(),
 
-        Commands::List { json } => {
+        Commands::List { json, format } => {
             debug!("Executing list command");
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
+            let mut client = connect_rpc(&socket_path)?;
 
             debug!("Fetching repository list");
             let repos = client.get_repo_list(-1, -1)?;
-            info!(count = repos.len(), "Retrieved {} repositories", repos.len());
+            info!(
+                count = repos.len(),
+                "Retrieved {} repositories",
+                repos.len()
+            );
 
             if json {
                 println!("{}", serde_json::to_string_pretty(&repos)?);
+            } else if let Some(format) = format {
+                let records: Vec<Vec<&str>> = repos
+                    .iter()
+                    .map(|r| vec![r.name.as_str(), r.id.as_str(), r.worktree.as_str()])
+                    .collect();
+                output::print_records(format, &["Name", "ID", "Path"], &records);
             } else {
                 println!("Name\tID\tPath");
                 for repo in repos {
@@ -337,8 +747,362 @@ fn main() -> Result<()> {
             }
         }
 
-        Commands::ListRemote {
-            json,
+        Commands::ListRemote {
+            json,
+            server,
+            username,
+            password,
+            token,
+            tfa,
+            user_config,
+        } => {
+            debug!("Executing list-remote command");
+            let user_cfg = UserConfig::load(user_config.as_deref())?;
+            let server_url = server.or(user_cfg.server).context("Server URL required")?;
+            let username = username.or(user_cfg.user).context("Username required")?;
+            debug!(server = %server_url, user = %username, "Resolved server and user");
+
+            let token = if let Some(t) = token.or(user_cfg.token) {
+                debug!("Using provided token");
+                t
+            } else {
+                debug!("No token provided, authenticating");
+                let password = if let Some(p) = password {
+                    p
+                } else {
+                    rpassword::prompt_password(format!("Enter password for user {}: ", username))?
+                };
+
+                let device_mgr = DeviceIdManager::new(&conf_dir, &datadir()?);
+                let device_id = device_mgr.get_device_id()?;
+                let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+                authenticate(
+                    &http_client,
+                    &username,
+                    &password,
+                    &device_id,
+                    tfa.as_deref(),
+                )?
+            };
+
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+            debug!("Fetching remote repository list");
+            let repos = http_client.list_repos(&token)?;
+            info!(
+                count = repos.len(),
+                "Retrieved {} remote repositories",
+                repos.len()
+            );
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&repos)?);
+            } else {
+                println!("Name\tID");
+                for repo in repos {
+                    println!("{}\t{}", repo.name, repo.id);
+                }
+            }
+        }
+
+        Commands::Status {
+            format,
+            watch,
+            interval,
+            notify_cmd,
+        } => {
+            debug!(watch, "Executing status command");
+            let connect = || connect_rpc(&socket_path);
+            if !watch {
+                let rows = status::collect_status(connect)?;
+                status::print_status(&rows, format);
+                return Ok(());
+            }
+
+            // Reconnect on every poll so a daemon restart doesn't end the watch
+            let mut in_error = HashSet::new();
+            loop {
+                match status::collect_status(connect) {
+                    Ok(rows) => {
+                        status::print_status(&rows, format);
+                        println!();
+                        in_error =
+                            status::notify_new_errors(&in_error, &rows, notify_cmd.as_deref());
+                    }
+                    Err(e) => warn!(error = %e, "Failed to query daemon status"),
+                }
+                std::thread::sleep(std::time::Duration::from_secs(interval));
+            }
+        }
+
+        Commands::Download {
+            library,
+            server,
+            dir,
+            username,
+            password,
+            token,
+            tfa,
+            libpasswd,
+            user_config,
+        } => {
+            debug!(library = %library, "Executing download command");
+            let mut client = connect_rpc(&socket_path)?;
+
+            handle_download(
+                &mut client,
+                &http_config,
+                &conf_dir,
+                &datadir()?,
+                &library,
+                server.as_deref(),
+                dir.as_deref(),
+                username.as_deref(),
+                password.as_deref(),
+                token.as_deref(),
+                tfa.as_deref(),
+                libpasswd.as_deref(),
+                user_config.as_deref(),
+            )?;
+        }
+
+        Commands::DownloadByName {
+            libraryname,
+            server,
+            dir,
+            username,
+            password,
+            token,
+            tfa,
+            libpasswd,
+            user_config,
+        } => {
+            debug!(library_name = %libraryname, "Executing download-by-name command");
+            let user_cfg = UserConfig::load(user_config.as_deref())?;
+            let server_url = server.or(user_cfg.server).context("Server URL required")?;
+            let username = username.or(user_cfg.user).context("Username required")?;
+            debug!(server = %server_url, user = %username, "Resolved server and user");
+
+            let token = get_or_create_token(
+                &http_config,
+                &server_url,
+                &username,
+                password.as_deref(),
+                token.as_deref(),
+                tfa.as_deref(),
+                user_cfg.token.as_deref(),
+                &conf_dir,
+                &datadir()?,
+            )?;
+
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+            let repos = http_client.list_repos(&token)?;
+
+            debug!("Searching for library by name: {}", libraryname);
+            let library_id = repos
+                .iter()
+                .find(|r| r.name == libraryname)
+                .map(|r| r.id.clone())
+                .context("Library not found")?;
+            info!(library_id = %library_id, library_name = %libraryname, "Found library");
+
+            let mut client = connect_rpc(&socket_path)?;
+
+            handle_download(
+                &mut client,
+                &http_config,
+                &conf_dir,
+                &datadir()?,
+                &library_id,
+                Some(&server_url),
+                dir.as_deref(),
+                Some(&username),
+                password.as_deref(),
+                Some(&token),
+                tfa.as_deref(),
+                libpasswd.as_deref(),
+                user_config.as_deref(),
+            )?;
+        }
+
+        Commands::Sync {
+            library,
+            server,
+            folder,
+            username,
+            password,
+            token,
+            tfa,
+            libpasswd,
+            user_config,
+        } => {
+            debug!(library = %library, folder = %folder.display(), "Executing sync command");
+            if !folder.exists() {
+                error!(folder = %folder.display(), "Local directory does not exist");
+                anyhow::bail!("Local directory does not exist");
+            }
+
+            let mut client = connect_rpc(&socket_path)?;
+
+            handle_sync(
+                &mut client,
+                &http_config,
+                &conf_dir,
+                &datadir()?,
+                &library,
+                server.as_deref(),
+                &folder,
+                username.as_deref(),
+                password.as_deref(),
+                token.as_deref(),
+                tfa.as_deref(),
+                libpasswd.as_deref(),
+                user_config.as_deref(),
+            )?;
+        }
+
+        Commands::Desync { folder } => {
+            debug!(folder = %folder.display(), "Executing desync command");
+            let mut client = connect_rpc(&socket_path)?;
+
+            let repo = find_repo_by_folder(&mut client, &folder)?;
+
+            info!(repo_id = %repo.id, repo_name = %repo.name, "Desynchronizing library");
+            println!("Desynchronize {}", repo.name);
+            client.remove_repo(&repo.id)?;
+            debug!("Library desynchronized successfully");
+        }
+
+        Commands::Create {
+            name,
+            desc,
+            libpasswd,
+            server,
+            username,
+            password,
+            token,
+            tfa,
+            user_config,
+        } => {
+            debug!(name = %name, encrypted = libpasswd.is_some(), "Executing create command");
+            let user_cfg = UserConfig::load(user_config.as_deref())?;
+            let server_url = server.or(user_cfg.server).context("Server URL required")?;
+            let username = username.or(user_cfg.user).context("Username required")?;
+            debug!(server = %server_url, user = %username, "Resolved server and user");
+
+            let token = get_or_create_token(
+                &http_config,
+                &server_url,
+                &username,
+                password.as_deref(),
+                token.as_deref(),
+                tfa.as_deref(),
+                user_cfg.token.as_deref(),
+                &conf_dir,
+                &datadir()?,
+            )?;
+
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+            let repo_id = http_client.create_repo(&token, &name, &desc, libpasswd.as_deref())?;
+            info!(repo_id = %repo_id, name = %name, "Repository created");
+            println!("{}", repo_id);
+        }
+
+        Commands::Autosync {
+            enable,
+            disable: _,
+            folder,
+            all,
+        } => {
+            debug!(enable, all, "Executing autosync command");
+            let mut client = connect_rpc(&socket_path)?;
+
+            let state = if enable { "enabled" } else { "disabled" };
+            if all {
+                if enable {
+                    client.enable_auto_sync()?;
+                } else {
+                    client.disable_auto_sync()?;
+                }
+                info!("Auto sync {} for all libraries", state);
+                println!("Auto sync {} for all libraries", state);
+            } else {
+                let folder = folder.context("Library folder required")?;
+                let repo = find_repo_by_folder(&mut client, &folder)?;
+                let value = if enable { "true" } else { "false" };
+                client.set_repo_property(&repo.id, "auto-sync", value)?;
+                info!(repo_id = %repo.id, repo_name = %repo.name, "Auto sync {}", state);
+                println!("Auto sync {} for {}", state, repo.name);
+            }
+        }
+
+        Commands::Info {
+            json,
+            server,
+            username,
+            password,
+            token,
+            tfa,
+            user_config,
+        } => {
+            debug!("Executing info command");
+            let user_cfg = UserConfig::load(user_config.as_deref())?;
+            let server_url = server.or(user_cfg.server).context("Server URL required")?;
+            let username = username.or(user_cfg.user).context("Username required")?;
+            debug!(server = %server_url, user = %username, "Resolved server and user");
+
+            let token = get_or_create_token(
+                &http_config,
+                &server_url,
+                &username,
+                password.as_deref(),
+                token.as_deref(),
+                tfa.as_deref(),
+                user_cfg.token.as_deref(),
+                &conf_dir,
+                &datadir()?,
+            )?;
+
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+            let server_info = http_client.get_server_info()?;
+            let account = http_client.get_account_info(&token)?;
+
+            if json {
+                let info = serde_json::json!({
+                    "server": server_url,
+                    "server_info": server_info,
+                    "account": account,
+                });
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("Server:\t{} (version {})", server_url, server_info.version);
+                if !server_info.features.is_empty() {
+                    println!("Features:\t{}", server_info.features.join(", "));
+                }
+                if account.name.is_empty() {
+                    println!("Account:\t{}", account.email);
+                } else {
+                    println!("Account:\t{} ({})", account.email, account.name);
+                }
+                match account.quota() {
+                    Some(total) if total > 0 => println!(
+                        "Usage:\t{} of {} ({:.1}%)",
+                        format_size(account.usage),
+                        format_size(total),
+                        account.usage as f64 / total as f64 * 100.0
+                    ),
+                    Some(total) => println!(
+                        "Usage:\t{} of {}",
+                        format_size(account.usage),
+                        format_size(total)
+                    ),
+                    None => println!("Usage:\t{} (unlimited)", format_size(account.usage)),
+                }
+            }
+        }
+
+        Commands::Rename {
+            library,
+            name,
             server,
             username,
             password,
@@ -346,188 +1110,94 @@ fn main() -> Result<()> {
             tfa,
             user_config,
         } => {
-            debug!("Executing list-remote command");
+            debug!(library = %library, name = %name, "Executing rename command");
             let user_cfg = UserConfig::load(user_config.as_deref())?;
             let server_url = server.or(user_cfg.server).context("Server URL required")?;
             let username = username.or(user_cfg.user).context("Username required")?;
             debug!(server = %server_url, user = %username, "Resolved server and user");
 
-            let token = if let Some(t) = token.or(user_cfg.token) {
-                debug!("Using provided token");
-                t
-            } else {
-                debug!("No token provided, authenticating");
-                let password = if let Some(p) = password {
-                    p
-                } else {
-                    rpassword::prompt_password(format!("Enter password for user {}: ", username))?
-                };
-
-                let device_mgr = DeviceIdManager::new(&conf_dir, &datadir_path);
-                let device_id = device_mgr.get_device_id()?;
-                let http_client = SeafileHttpClient::new(&server_url);
-                http_client.get_token(&username, &password, &device_id, tfa.as_deref())?
-            };
-
-            let http_client = SeafileHttpClient::new(&server_url);
-            debug!("Fetching remote repository list");
-            let repos = http_client.list_repos(&token)?;
-            info!(count = repos.len(), "Retrieved {} remote repositories", repos.len());
-
-            if json {
-                println!("{}", serde_json::to_string_pretty(&repos)?);
-            } else {
-                println!("Name\tID");
-                for repo in repos {
-                    println!("{}\t{}", repo.name, repo.id);
-                }
-            }
-        }
-
-        Commands::Status => {
-            debug!("Executing status command");
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
+            let token = get_or_create_token(
+                &http_config,
+                &server_url,
+                &username,
+                password.as_deref(),
+                token.as_deref(),
+                tfa.as_deref(),
+                user_cfg.token.as_deref(),
+                &conf_dir,
+                &datadir()?,
+            )?;
 
-            // Get clone tasks
-            debug!("Fetching clone tasks");
-            let tasks = client.get_clone_tasks()?;
-            trace!(count = tasks.len(), "Found {} clone tasks", tasks.len());
-            println!("# {:<50}\t{:<20}\t{:<20}", "Name", "Status", "Progress");
-
-            for task in tasks {
-                trace!(repo = %task.repo_name, state = %task.state, "Processing clone task");
-                match task.state.as_str() {
-                    "fetch" => {
-                        if let Ok(tx_task) = client.find_transfer_task(&task.repo_id) {
-                            let progress = if tx_task.block_total > 0 {
-                                (tx_task.block_done as f64 / tx_task.block_total as f64) * 100.0
-                            } else {
-                                0.0
-                            };
-                            let rate = tx_task.rate as f64 / 1024.0;
-                            debug!(repo = %task.repo_name, progress = %format!("{:.1}%", progress), rate = %format!("{:.1}KB/s", rate), "Download in progress");
-                            println!(
-                                "{:<50}\t{:<20}\t{:.1}%, {:.1}KB/s",
-                                task.repo_name, "downloading", progress, rate
-                            );
-                        }
-                    }
-                    "error" => {
-                        let err = client.sync_error_id_to_str(task.error)?;
-                        error!(repo = %task.repo_name, error = %err, "Clone task error");
-                        println!("{:<50}\t{:<20}\t{:<20}", task.repo_name, "error", err);
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+            http_client.rename_repo(&token, &library, &name)?;
+            info!(repo_id = %library, name = %name, "Repository renamed on server");
+
+            // The daemon learns the new name from the server's head commit,
+            // so kick off a sync if the library is synced locally.
+            match connect_rpc(&socket_path) {
+                Ok(mut client) => {
+                    let repos = client.get_repo_list(-1, -1)?;
+                    if repos.iter().any(|r| r.id == library) {
+                        debug!(repo_id = %library, "Triggering sync to pick up new name");
+                        client.sync(&library, None)?;
                     }
-                    "done" => {
-                        trace!(repo = %task.repo_name, "Clone task completed");
-                    }
-                    _ => {
-                        println!("{:<50}\t{:<20}", task.repo_name, task.state);
-                    }
-                }
-            }
-
-            // Get repo sync status
-            debug!("Fetching repository sync status");
-            let repos = client.get_repo_list(-1, -1)?;
-            trace!(count = repos.len(), "Found {} repositories", repos.len());
-
-            for repo in repos {
-                let auto_sync = client.is_auto_sync_enabled()?;
-                if !auto_sync || !repo.auto_sync {
-                    trace!(repo = %repo.name, "Auto sync disabled");
-                    println!("{:<50}\t{:<20}", repo.name, "auto sync disabled");
-                    continue;
                 }
-
-                match client.get_repo_sync_task(&repo.id) {
-                    Ok(Some(task)) => match task.state.as_str() {
-                        "uploading" | "downloading" => {
-                            if let Ok(tx_task) = client.find_transfer_task(&repo.id) {
-                                let progress = if tx_task.block_total > 0 {
-                                    (tx_task.block_done as f64 / tx_task.block_total as f64) * 100.0
-                                } else {
-                                    0.0
-                                };
-                                let rate = tx_task.rate as f64 / 1024.0;
-                                debug!(repo = %repo.name, state = %task.state, progress = %format!("{:.1}%", progress), "Transfer in progress");
-                                println!(
-                                    "{:<50}\t{:<20}\t{:.1}%, {:.1}KB/s",
-                                    repo.name, task.state, progress, rate
-                                );
-                            }
-                        }
-                        "error" => {
-                            let err = client.sync_error_id_to_str(task.error)?;
-                            error!(repo = %repo.name, error = %err, "Sync error");
-                            println!("{:<50}\t{:<20}\t{:<20}", repo.name, "error", err);
-                        }
-                        _ => {
-                            trace!(repo = %repo.name, state = %task.state, "Sync state");
-                            println!("{:<50}\t{:<20}", repo.name, task.state);
-                        }
-                    },
-                    Ok(None) | Err(_) => {
-                        trace!(repo = %repo.name, "Waiting for sync");
-                        println!("{:<50}\t{:<20}", repo.name, "waiting for sync");
-                    }
+                Err(e) => {
+                    warn!(error = %e, "Daemon not reachable, local name updates on next sync");
                 }
             }
+            println!("Renamed {} to {}", library, name);
         }
 
-        Commands::Download {
-            library,
+        Commands::Logout {
             server,
-            dir,
-            username,
-            password,
             token,
-            tfa,
-            libpasswd,
             user_config,
         } => {
-            debug!(library = %library, "Executing download command");
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
-
-            handle_download(
-                &mut client,
-                &conf_dir,
-                &datadir_path,
-                &library,
-                server.as_deref(),
-                dir.as_deref(),
-                username.as_deref(),
-                password.as_deref(),
-                token.as_deref(),
-                tfa.as_deref(),
-                libpasswd.as_deref(),
-                user_config.as_deref(),
-            )?;
+            debug!("Executing logout command");
+            let user_cfg = UserConfig::load(user_config.as_deref())?;
+            let server_url = server.or(user_cfg.server).context("Server URL required")?;
+            let cached_token = user_cfg.token;
+            let token = token
+                .or_else(|| cached_token.clone())
+                .context("No token given and none cached in the user config")?;
+
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+            http_client.delete_token(&token)?;
+            info!(server = %server_url, "Token revoked");
+            println!("Logged out from {}", server_url);
+
+            // An explicitly passed token may not be the cached one
+            if cached_token.as_deref() == Some(token.as_str())
+                && UserConfig::remove_token(user_config.as_deref())?
+            {
+                println!("Removed cached token");
+            }
         }
 
-        Commands::DownloadByName {
-            libraryname,
+        Commands::Delete {
+            library,
+            yes,
             server,
-            dir,
             username,
             password,
             token,
             tfa,
-            libpasswd,
             user_config,
         } => {
-            debug!(library_name = %libraryname, "Executing download-by-name command");
+            debug!(library = %library, "Executing delete command");
             let user_cfg = UserConfig::load(user_config.as_deref())?;
             let server_url = server.or(user_cfg.server).context("Server URL required")?;
             let username = username.or(user_cfg.user).context("Username required")?;
             debug!(server = %server_url, user = %username, "Resolved server and user");
 
+            if !yes && !confirm(&format!("Delete library {} on {}?", library, server_url))? {
+                println!("Aborted");
+                return Ok(());
+            }
+
             let token = get_or_create_token(
+                &http_config,
                 &server_url,
                 &username,
                 password.as_deref(),
@@ -535,105 +1205,92 @@ fn main() -> Result<()> {
                 tfa.as_deref(),
                 user_cfg.token.as_deref(),
                 &conf_dir,
-                &datadir_path,
+                &datadir()?,
             )?;
 
-            let http_client = SeafileHttpClient::new(&server_url);
-            let repos = http_client.list_repos(&token)?;
-
-            debug!("Searching for library by name: {}", libraryname);
-            let library_id = repos
-                .iter()
-                .find(|r| r.name == libraryname)
-                .map(|r| r.id.clone())
-                .context("Library not found")?;
-            info!(library_id = %library_id, library_name = %libraryname, "Found library");
-
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
-
-            handle_download(
-                &mut client,
-                &conf_dir,
-                &datadir_path,
-                &library_id,
-                Some(&server_url),
-                dir.as_deref(),
-                Some(&username),
-                password.as_deref(),
-                Some(&token),
-                tfa.as_deref(),
-                libpasswd.as_deref(),
-                user_config.as_deref(),
-            )?;
+            // On the server first: if that fails, the library stays synced
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+            http_client.delete_repo(&token, &library)?;
+            info!(repo_id = %library, "Repository deleted on server");
+
+            match connect_rpc(&socket_path) {
+                Ok(mut client) => {
+                    let repos = client.get_repo_list(-1, -1)?;
+                    if let Some(repo) = repos.iter().find(|r| r.id == library) {
+                        info!(repo_id = %repo.id, worktree = %repo.worktree, "Desynchronizing library");
+                        println!("Desynchronize {} (local files are kept)", repo.name);
+                        client.remove_repo(&repo.id)?;
+                    }
+                }
+                Err(e) => {
+                    warn!(error = %e, "Daemon not reachable, skipping local desync");
+                }
+            }
+            println!("Deleted library {}", library);
         }
 
-        Commands::Sync {
+        Commands::Get {
             library,
+            remote,
+            local,
             server,
-            folder,
             username,
             password,
             token,
             tfa,
-            libpasswd,
             user_config,
         } => {
-            debug!(library = %library, folder = %folder.display(), "Executing sync command");
-            if !folder.exists() {
-                error!(folder = %folder.display(), "Local directory does not exist");
-                anyhow::bail!("Local directory does not exist");
-            }
-
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
+            debug!(library = %library, remote = %remote, "Executing get command");
+            let user_cfg = UserConfig::load(user_config.as_deref())?;
+            let server_url = server.or(user_cfg.server).context("Server URL required")?;
+            let username = username.or(user_cfg.user).context("Username required")?;
+            debug!(server = %server_url, user = %username, "Resolved server and user");
 
-            handle_sync(
-                &mut client,
-                &conf_dir,
-                &datadir_path,
-                &library,
-                server.as_deref(),
-                &folder,
-                username.as_deref(),
+            let token = get_or_create_token(
+                &http_config,
+                &server_url,
+                &username,
                 password.as_deref(),
                 token.as_deref(),
                 tfa.as_deref(),
-                libpasswd.as_deref(),
-                user_config.as_deref(),
+                user_cfg.token.as_deref(),
+                &conf_dir,
+                &datadir()?,
             )?;
-        }
-
-        Commands::Desync { folder } => {
-            debug!(folder = %folder.display(), "Executing desync command");
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
-
-            let repo_path = folder.canonicalize()?;
-            debug!(canonical_path = %repo_path.display(), "Resolved folder path");
-            let repos = client.get_repo_list(-1, -1)?;
-
-            let repo = repos
-                .iter()
-                .find(|r| PathBuf::from(&r.worktree) == repo_path)
-                .context("Not a library")?;
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+
+            let file_name = remote
+                .rsplit('/')
+                .next()
+                .filter(|name| !name.is_empty())
+                .context("Remote path must name a file")?;
+            let dest = match local {
+                Some(path) if path.is_dir() => path.join(file_name),
+                Some(path) => path,
+                None => PathBuf::from(file_name),
+            };
 
-            info!(repo_id = %repo.id, repo_name = %repo.name, "Desynchronizing library");
-            println!("Desynchronize {}", repo.name);
-            client.remove_repo(&repo.id)?;
-            debug!("Library desynchronized successfully");
+            let link = http_client.get_download_link(&token, &library, &remote)?;
+            let mut file = fs::File::create(&dest)
+                .with_context(|| format!("Failed to create {}", dest.display()))?;
+            let result = http_client.download_file(&link, &mut file, |done, total| {
+                print_transfer_progress(file_name, done, total)
+            });
+            eprintln!();
+            if let Err(e) = result {
+                drop(file);
+                let _ = fs::remove_file(&dest);
+                return Err(e);
+            }
+            info!(remote = %remote, dest = %dest.display(), "File downloaded");
+            println!("Downloaded {} to {}", remote, dest.display());
         }
 
-        Commands::Create {
-            name,
-            desc,
-            libpasswd,
+        Commands::Put {
+            library,
+            local,
+            remote_dir,
+            replace,
             server,
             username,
             password,
@@ -641,13 +1298,14 @@ fn main() -> Result<()> {
             tfa,
             user_config,
         } => {
-            debug!(name = %name, encrypted = libpasswd.is_some(), "Executing create command");
+            debug!(library = %library, local = %local.display(), "Executing put command");
             let user_cfg = UserConfig::load(user_config.as_deref())?;
             let server_url = server.or(user_cfg.server).context("Server URL required")?;
             let username = username.or(user_cfg.user).context("Username required")?;
             debug!(server = %server_url, user = %username, "Resolved server and user");
 
             let token = get_or_create_token(
+                &http_config,
                 &server_url,
                 &username,
                 password.as_deref(),
@@ -655,21 +1313,62 @@ fn main() -> Result<()> {
                 tfa.as_deref(),
                 user_cfg.token.as_deref(),
                 &conf_dir,
-                &datadir_path,
+                &datadir()?,
             )?;
+            let http_client = SeafileHttpClient::new(&server_url, &http_config)?;
+
+            let file_name = local
+                .file_name()
+                .context("Local path must name a file")?
+                .to_string_lossy()
+                .into_owned();
+
+            let link = http_client.get_upload_link(&token, &library, &remote_dir)?;
+            let progress_name = file_name.clone();
+            let result =
+                http_client.upload_file(&link, &remote_dir, &local, replace, move |done, total| {
+                    print_transfer_progress(&progress_name, done, total)
+                });
+            eprintln!();
+            result?;
+            info!(local = %local.display(), remote_dir = %remote_dir, "File uploaded");
+            println!("Uploaded {} to {}", file_name, remote_dir);
+        }
 
-            let http_client = SeafileHttpClient::new(&server_url);
-            let repo_id = http_client.create_repo(&token, &name, &desc, libpasswd.as_deref())?;
-            info!(repo_id = %repo_id, name = %name, "Repository created");
-            println!("{}", repo_id);
+        Commands::Move {
+            folder,
+            to,
+            server,
+            username,
+            password,
+            token,
+            tfa,
+            libpasswd,
+            user_config,
+        } => {
+            debug!(folder = %folder.display(), to = %to.display(), "Executing move command");
+            let mut client = connect_rpc(&socket_path)?;
+
+            handle_move(
+                &mut client,
+                &http_config,
+                &conf_dir,
+                &datadir()?,
+                &folder,
+                &to,
+                server.as_deref(),
+                username.as_deref(),
+                password.as_deref(),
+                token.as_deref(),
+                tfa.as_deref(),
+                libpasswd.as_deref(),
+                user_config.as_deref(),
+            )?;
         }
 
         Commands::Config { key, value } => {
             debug!(key = %key, has_value = value.is_some(), "Executing config command");
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
+            let mut client = connect_rpc(&socket_path)?;
 
             if let Some(val) = value {
                 debug!(key = %key, value = %val, "Setting config value");
@@ -686,10 +1385,7 @@ fn main() -> Result<()> {
 
         Commands::Stop => {
             debug!("Executing stop command");
-            let socket_path = datadir_path.join("seafile.sock");
-            trace!(socket = %socket_path.display(), "Connecting to RPC server");
-            let transport = UnixSocketTransport::connect(&socket_path, "seafile-rpcserver")?;
-            let mut client = SearpcClient::new(transport);
+            let mut client = connect_rpc(&socket_path)?;
 
             info!("Sending shutdown request to daemon");
             match client.shutdown() {
@@ -708,18 +1404,16 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Config directory used without `-c`, `~/.ccnet`
+fn default_conf_dir() -> Result<PathBuf> {
+    paths::default_conf_dir().context("HOME or USERPROFILE environment variable not set")
+}
+
 /// Handle start command
 fn handle_start(conf_dir: &Path) -> Result<()> {
     debug!("Starting daemon with conf_dir: {}", conf_dir.display());
 
-    // Read seafile.ini
-    let seafile_ini = conf_dir.join("seafile.ini");
-    let seafile_datadir = fs::read_to_string(&seafile_ini)
-        .context("Failed to read seafile.ini")?
-        .trim()
-        .to_string();
-
-    let datadir_path = PathBuf::from(&seafile_datadir);
+    let datadir_path = paths::read_datadir(conf_dir)?;
     let seafile_worktree = datadir_path
         .parent()
         .ok_or_else(|| anyhow!("Invalid data dir path: {}", datadir_path.display()))?
@@ -754,7 +1448,7 @@ fn handle_start(conf_dir: &Path) -> Result<()> {
     debug!("seaf-daemon process started");
 
     // Wait for daemon to start and set delete_confirm_threshold
-    let socket_path = datadir_path.join("seafile.sock");
+    let socket_path = datadir_path.join(SEAFILE_SOCKET_NAME);
     debug!("Waiting for socket: {}", socket_path.display());
 
     for i in 0..4 {
@@ -781,9 +1475,166 @@ fn handle_start(conf_dir: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Connect to the daemon's RPC socket
+fn connect_rpc(socket_path: &Path) -> Result<SearpcClient<UnixSocketTransport>> {
+    trace!(socket = %socket_path.display(), "Connecting to RPC server");
+    // A transport error, so a missing daemon exits with UNAVAILABLE
+    let transport =
+        UnixSocketTransport::connect(socket_path, "seafile-rpcserver").map_err(|e| {
+            searpc::SearpcError::TransportError {
+                message: format!("Failed to connect to {}", socket_path.display()),
+                source: Some(e),
+            }
+        })?;
+    Ok(SearpcClient::new(transport))
+}
+
+/// Find the local library whose worktree is `folder`
+fn find_repo_by_folder<T: searpc::Transport>(
+    client: &mut SearpcClient<T>,
+    folder: &Path,
+) -> Result<Repo> {
+    let repo_path = folder.canonicalize()?;
+    debug!(canonical_path = %repo_path.display(), "Resolved folder path");
+
+    client
+        .get_repo_list(-1, -1)?
+        .into_iter()
+        .find(|r| Path::new(&r.worktree) == repo_path)
+        .context("Not a library")
+}
+
+/// Whether the daemon rejected a call because it doesn't implement the function
+fn is_unknown_function(err: &searpc::SearpcError) -> bool {
+    err.kind() == Some(searpc::KnownErrorCode::FunctionNotFound)
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Redraw a one-line transfer progress indicator on stderr
+fn print_transfer_progress(name: &str, done: u64, total: Option<u64>) {
+    match total {
+        Some(total) if total > 0 => eprint!(
+            "\r{}: {} / {} ({:.0}%)",
+            name,
+            format_size(done as i64),
+            format_size(total as i64),
+            done as f64 / total as f64 * 100.0
+        ),
+        _ => eprint!("\r{}: {}", name, format_size(done as i64)),
+    }
+}
+
+/// Human-readable byte count, e.g. `1.5 GB`
+fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Handle move command
+///
+/// Auto sync is paused while the folder is moved. If the daemon cannot
+/// relocate the worktree itself, the library is unsynced and synced again
+/// at the new location.
+#[allow(clippy::too_many_arguments)]
+fn handle_move<T: searpc::Transport>(
+    client: &mut SearpcClient<T>,
+    http_config: &HttpConfig,
+    conf_dir: &Path,
+    datadir_path: &Path,
+    folder: &Path,
+    to: &Path,
+    server: Option<&str>,
+    username: Option<&str>,
+    password: Option<&str>,
+    token: Option<&str>,
+    tfa: Option<&str>,
+    libpasswd: Option<&str>,
+    user_config: Option<&Path>,
+) -> Result<()> {
+    let repo = find_repo_by_folder(client, folder)?;
+    if to.exists() {
+        anyhow::bail!("{} already exists", to.display());
+    }
+
+    info!(repo_id = %repo.id, repo_name = %repo.name, "Moving library to {}", to.display());
+    client.set_repo_property(&repo.id, "auto-sync", "false")?;
+    if let Err(e) = fs::rename(&repo.worktree, to) {
+        if repo.auto_sync {
+            client.set_repo_property(&repo.id, "auto-sync", "true")?;
+        }
+        return Err(e)
+            .with_context(|| format!("Failed to move {} to {}", repo.worktree, to.display()));
+    }
+
+    let to = to.canonicalize()?;
+    let to_str = to
+        .to_str()
+        .ok_or_else(|| anyhow!("Path contains invalid UTF-8: {}", to.display()))?;
+
+    match client.set_repo_worktree(&repo.id, to_str) {
+        Ok(_) => {
+            debug!("Daemon relocated worktree");
+            if repo.auto_sync {
+                client.set_repo_property(&repo.id, "auto-sync", "true")?;
+            }
+        }
+        Err(e) if is_unknown_function(&e) => {
+            info!("Daemon cannot relocate worktrees, re-syncing library at new location");
+            client.remove_repo(&repo.id)?;
+            handle_sync(
+                client,
+                http_config,
+                conf_dir,
+                datadir_path,
+                &repo.id,
+                server,
+                &to,
+                username,
+                password,
+                token,
+                tfa,
+                libpasswd,
+                user_config,
+            )
+            // The old registration is gone: say how to finish by hand
+            .with_context(|| {
+                format!(
+                    "Unsynced {} but could not sync it again; run `seaf-cli sync -l {} -d {}`",
+                    repo.name,
+                    repo.id,
+                    to.display()
+                )
+            })?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    println!("Moved {} to {}", repo.name, to.display());
+    Ok(())
+}
+
 /// Get or create authentication token
 #[allow(clippy::too_many_arguments)]
 fn get_or_create_token(
+    http_config: &HttpConfig,
     server_url: &str,
     username: &str,
     password: Option<&str>,
@@ -813,16 +1664,39 @@ fn get_or_create_token(
         &device_id[..8]
     );
 
-    let http_client = SeafileHttpClient::new(server_url);
-    let token = http_client.get_token(username, &password, &device_id, tfa)?;
+    let http_client = SeafileHttpClient::new(server_url, http_config)?;
+    let token = authenticate(&http_client, username, &password, &device_id, tfa)?;
     debug!("Authentication successful");
     Ok(token)
 }
 
+/// Obtain a token, asking for a two-factor code if the server requires one
+fn authenticate(
+    http_client: &SeafileHttpClient,
+    username: &str,
+    password: &str,
+    device_id: &str,
+    tfa: Option<&str>,
+) -> Result<String> {
+    match http_client.get_token(username, password, device_id, tfa) {
+        Err(e)
+            if tfa.is_none()
+                && e.downcast_ref::<SeafileApiError>()
+                    .is_some_and(|e| e.kind() == ApiErrorKind::OtpRequired) =>
+        {
+            debug!("Server requires a two-factor code");
+            let otp = rpassword::prompt_password("Enter two-factor authentication code: ")?;
+            http_client.get_token(username, password, device_id, Some(otp.trim()))
+        }
+        result => result,
+    }
+}
+
 /// Handle download command
 #[allow(clippy::too_many_arguments)]
 fn handle_download<T: searpc::Transport>(
     client: &mut SearpcClient<T>,
+    http_config: &HttpConfig,
     conf_dir: &Path,
     datadir_path: &Path,
     repo_id: &str,
@@ -844,6 +1718,7 @@ fn handle_download<T: searpc::Transport>(
         .context("Username required")?;
 
     let token = get_or_create_token(
+        http_config,
         server_url,
         username,
         password,
@@ -854,7 +1729,7 @@ fn handle_download<T: searpc::Transport>(
         datadir_path,
     )?;
 
-    let http_client = SeafileHttpClient::new(server_url);
+    let http_client = SeafileHttpClient::new(server_url, http_config)?;
     let download_info = http_client.get_repo_download_info(&token, repo_id)?;
     debug!("Received download_info from API:");
     debug!("  repo_name: {}", download_info.repo_name);
@@ -968,6 +1843,7 @@ fn handle_download<T: searpc::Transport>(
 #[allow(clippy::too_many_arguments)]
 fn handle_sync<T: searpc::Transport>(
     client: &mut SearpcClient<T>,
+    http_config: &HttpConfig,
     conf_dir: &Path,
     datadir_path: &Path,
     repo_id: &str,
@@ -989,6 +1865,7 @@ fn handle_sync<T: searpc::Transport>(
         .context("Username required")?;
 
     let token = get_or_create_token(
+        http_config,
         server_url,
         username,
         password,
@@ -999,10 +1876,13 @@ fn handle_sync<T: searpc::Transport>(
         datadir_path,
     )?;
 
-    let http_client = SeafileHttpClient::new(server_url);
+    let http_client = SeafileHttpClient::new(server_url, http_config)?;
     debug!("Getting download info for repo: {}", repo_id);
     let download_info = http_client.get_repo_download_info(&token, repo_id)?;
-    debug!("download_info: {}", serde_json::to_string_pretty(&download_info)?);
+    debug!(
+        "download_info: {}",
+        serde_json::to_string_pretty(&download_info)?
+    );
 
     let is_encrypted = !download_info.encrypted.is_empty() && download_info.encrypted != "0";
 
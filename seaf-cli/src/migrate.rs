@@ -0,0 +1,108 @@
+//! Migration from an existing python seaf-cli / ccnet setup
+//!
+//! The python client and this one drive the same `seaf-daemon`, so the
+//! synced library list (kept by the daemon in `seafile-data`) carries over
+//! as long as the new config directory points at the same data dir. What
+//! needs care is the surrounding state: `seafile.ini`, the device ID that
+//! older ccnet releases kept in `ccnet.conf`, and the account file.
+
+use crate::config::{read_ccnet_id, DeviceIdManager, UserConfig};
+use crate::rpc_client::SeafileRpc as _;
+use anyhow::{Context, Result};
+use searpc::{SearpcClient, Transport};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Read the data dir recorded in `<conf_dir>/seafile.ini`
+fn read_datadir(conf_dir: &Path) -> Result<PathBuf> {
+    searpc::paths::read_datadir(conf_dir).with_context(|| {
+        format!(
+            "No seafile.ini in {}: not a seaf-cli config directory",
+            conf_dir.display()
+        )
+    })
+}
+
+/// Bring the config in `old_conf` over to `new_conf`
+///
+/// Returns the seafile data dir shared by both setups.
+pub fn migrate_config(old_conf: &Path, new_conf: &Path) -> Result<PathBuf> {
+    let datadir = read_datadir(old_conf)?;
+    if !datadir.exists() {
+        anyhow::bail!("Seafile data dir {} does not exist", datadir.display());
+    }
+    println!("Seafile data dir: {}", datadir.display());
+
+    // seafile.ini: point the new config dir at the same data dir
+    if new_conf != old_conf {
+        if new_conf.join("seafile.ini").exists() {
+            let existing = read_datadir(new_conf)?;
+            if existing != datadir {
+                anyhow::bail!(
+                    "{} already uses data dir {}",
+                    new_conf.display(),
+                    existing.display()
+                );
+            }
+            debug!("Target config already points at the data dir");
+        } else {
+            fs::create_dir_all(new_conf.join("logs"))?;
+            fs::write(
+                new_conf.join("seafile.ini"),
+                datadir.to_string_lossy().as_bytes(),
+            )?;
+            info!(conf_dir = %new_conf.display(), "Wrote seafile.ini");
+        }
+        println!(
+            "Config dir: {} -> {}",
+            old_conf.display(),
+            new_conf.display()
+        );
+    }
+
+    // Device ID: keep the identity the server already knows this machine by
+    let id_file = datadir.join("id");
+    let ccnet_id = read_ccnet_id(old_conf)?;
+    let source = if id_file.exists() {
+        "seafile-data/id"
+    } else if ccnet_id.is_some() {
+        "ccnet.conf"
+    } else {
+        "newly generated"
+    };
+    let device_id = DeviceIdManager::new(old_conf, &datadir).get_device_id()?;
+    if let Some(ccnet_id) = ccnet_id.filter(|id| *id != device_id) {
+        warn!(
+            "ccnet.conf ID {}... differs from seafile-data/id, keeping the latter",
+            ccnet_id.get(..8).unwrap_or(&ccnet_id)
+        );
+    }
+    // The ID files are user-editable: an ID may be shorter than usual
+    let short_id = device_id.get(..8).unwrap_or(&device_id);
+    println!("Device ID: {}... ({})", short_id, source);
+
+    // Accounts: ~/.seafile.conf uses the same format in both clients
+    let user_cfg = UserConfig::load(None)?;
+    match (&user_cfg.server, &user_cfg.user) {
+        (Some(server), Some(user)) => println!("Account: {} on {}", user, server),
+        _ => println!("Account: none configured in ~/.seafile.conf"),
+    }
+
+    Ok(datadir)
+}
+
+/// Report the libraries the daemon carries over, flagging missing worktrees
+pub fn report_libraries<T: Transport>(client: &mut SearpcClient<T>) -> Result<()> {
+    let repos = client.get_repo_list(-1, -1)?;
+    println!("Synced libraries: {}", repos.len());
+    for repo in &repos {
+        let note = if Path::new(&repo.worktree).exists() {
+            ""
+        } else {
+            "\t(worktree missing)"
+        };
+        println!("  {}\t{}{}", repo.name, repo.worktree, note);
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Machine-readable output formats
+//!
+//! Column order and header names are stable so scripts ported from the
+//! Python seaf-cli can keep piping output into awk/cut.
+
+use clap::ValueEnum;
+use std::borrow::Cow;
+
+/// Output format for `list` and `status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Tab-separated values (tabs, newlines and backslashes escaped as `\t`, `\n`, `\\`)
+    Tsv,
+    /// Comma-separated values (RFC 4180 quoting)
+    Csv,
+}
+
+impl OutputFormat {
+    /// Format one record as a single line (without trailing newline)
+    pub fn format_record<S: AsRef<str>>(self, fields: &[S]) -> String {
+        let sep = match self {
+            OutputFormat::Tsv => "\t",
+            OutputFormat::Csv => ",",
+        };
+
+        fields
+            .iter()
+            .map(|f| self.escape(f.as_ref()))
+            .collect::<Vec<_>>()
+            .join(sep)
+    }
+
+    fn escape(self, field: &str) -> Cow<'_, str> {
+        match self {
+            OutputFormat::Tsv => {
+                if !field.contains(['\t', '\n', '\r', '\\']) {
+                    return Cow::Borrowed(field);
+                }
+                let mut out = String::with_capacity(field.len() + 2);
+                for c in field.chars() {
+                    match c {
+                        '\t' => out.push_str("\\t"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\\' => out.push_str("\\\\"),
+                        c => out.push(c),
+                    }
+                }
+                Cow::Owned(out)
+            }
+            OutputFormat::Csv => {
+                if !field.contains([',', '"', '\n', '\r']) {
+                    return Cow::Borrowed(field);
+                }
+                Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+            }
+        }
+    }
+}
+
+/// Print a header line followed by one line per record
+pub fn print_records<S: AsRef<str>>(format: OutputFormat, header: &[&str], rows: &[Vec<S>]) {
+    println!("{}", format.format_record(header));
+    for row in rows {
+        println!("{}", format.format_record(row));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tsv_escaping() {
+        let record =
+            OutputFormat::Tsv.format_record(&["My\tLib", "line\nbreak\r", r"C:\dir", "plain"]);
+        assert_eq!(record, "My\\tLib\tline\\nbreak\\r\tC:\\\\dir\tplain");
+        // One line, one separator per field boundary
+        assert_eq!(record.matches('\t').count(), 3);
+        assert!(!record.contains('\n'));
+    }
+
+    #[test]
+    fn test_csv_escaping() {
+        let record = OutputFormat::Csv.format_record(&[
+            "a,b",
+            r#"say "hi""#,
+            "two\nlines",
+            "plain",
+            "tab\there",
+        ]);
+        assert_eq!(
+            record,
+            "\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\",plain,tab\there"
+        );
+    }
+}
@@ -92,6 +92,24 @@ pub trait SeafileRpc {
     #[rpc(name = "seafile_is_auto_sync_enabled")]
     fn is_auto_sync_enabled(&mut self) -> Result<bool>;
 
+    /// Enable auto sync globally
+    fn enable_auto_sync(&mut self) -> Result<()>;
+
+    /// Disable auto sync globally
+    fn disable_auto_sync(&mut self) -> Result<()>;
+
+    /// Set a per-repository property (e.g. "auto-sync" = "true"/"false")
+    fn set_repo_property(&mut self, repo_id: &str, key: &str, value: &str) -> Result<()>;
+
+    /// Trigger an immediate sync of a repository
+    fn sync(&mut self, repo_id: &str, peer_id: Option<&str>) -> Result<()>;
+
+    /// Point a repository at a new worktree
+    ///
+    /// Only newer daemons implement this; older ones answer with
+    /// "cannot find function".
+    fn set_repo_worktree(&mut self, repo_id: &str, worktree: &str) -> Result<()>;
+
     /// Convert sync error ID to human-readable string
     fn sync_error_id_to_str(&mut self, error_id: i32) -> Result<String>;
 
@@ -99,14 +117,14 @@ pub trait SeafileRpc {
     fn get_config(&mut self, key: &str) -> Result<String>;
 
     /// Set configuration value
-    fn set_config(&mut self, key: &str, value: &str) -> Result<i32>;
+    fn set_config(&mut self, key: &str, value: &str) -> Result<()>;
 
     /// Set configuration value as integer
-    fn set_config_int(&mut self, key: &str, value: i32) -> Result<i32>;
+    fn set_config_int(&mut self, key: &str, value: i32) -> Result<()>;
 
     /// Remove a repository (destroy it)
     #[rpc(name = "seafile_destroy_repo")]
-    fn remove_repo(&mut self, repo_id: &str) -> Result<i32>;
+    fn remove_repo(&mut self, repo_id: &str) -> Result<()>;
 
     /// Download a repository from server
     ///
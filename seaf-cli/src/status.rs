@@ -0,0 +1,270 @@
+//! Sync status collection and rendering for `seaf-cli status`
+
+use crate::output::{self, OutputFormat};
+use crate::rpc_client::{Repo, SeafileRpc as _, TransferTask};
+use anyhow::Result;
+use searpc::{SearpcClient, Transport};
+use std::collections::HashSet;
+use std::process::Command;
+use tracing::{debug, error, trace, warn};
+
+/// One line of status output
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusRow {
+    pub repo_id: String,
+    pub name: String,
+    pub status: String,
+    /// Transfer progress in percent and rate in KB/s
+    pub transfer: Option<(f64, f64)>,
+    pub error: Option<String>,
+}
+
+impl StatusRow {
+    fn new(repo_id: &str, name: &str, status: &str) -> Self {
+        StatusRow {
+            repo_id: repo_id.to_string(),
+            name: name.to_string(),
+            status: status.to_string(),
+            transfer: None,
+            error: None,
+        }
+    }
+}
+
+/// Progress (percent) and rate (KB/s) of a transfer task
+fn transfer_progress(task: &TransferTask) -> (f64, f64) {
+    let progress = if task.block_total > 0 {
+        (task.block_done as f64 / task.block_total as f64) * 100.0
+    } else {
+        0.0
+    };
+    (progress, task.rate as f64 / 1024.0)
+}
+
+/// Number of daemon connections used to query libraries in parallel
+const STATUS_WORKERS: usize = 8;
+
+/// Query the daemon for clone tasks and per-library sync state
+///
+/// Per-library queries are spread over up to [`STATUS_WORKERS`] connections,
+/// each opened with `connect`, so status stays fast with many libraries.
+/// Rows keep the daemon's library order.
+pub fn collect_status<T, F>(connect: F) -> Result<Vec<StatusRow>>
+where
+    T: Transport,
+    F: Fn() -> Result<SearpcClient<T>> + Sync,
+{
+    let mut client = connect()?;
+    let mut rows = Vec::new();
+
+    // Get clone tasks
+    debug!("Fetching clone tasks");
+    let tasks = client.get_clone_tasks()?;
+    trace!(count = tasks.len(), "Found {} clone tasks", tasks.len());
+
+    for task in tasks {
+        trace!(repo = %task.repo_name, state = %task.state, "Processing clone task");
+        match task.state.as_str() {
+            "fetch" => {
+                if let Ok(tx_task) = client.find_transfer_task(&task.repo_id) {
+                    let (progress, rate) = transfer_progress(&tx_task);
+                    debug!(repo = %task.repo_name, progress = %format!("{:.1}%", progress), rate = %format!("{:.1}KB/s", rate), "Download in progress");
+                    let mut row = StatusRow::new(&task.repo_id, &task.repo_name, "downloading");
+                    row.transfer = Some((progress, rate));
+                    rows.push(row);
+                }
+            }
+            "error" => {
+                let err = client.sync_error_id_to_str(task.error)?;
+                error!(repo = %task.repo_name, error = %err, "Clone task error");
+                let mut row = StatusRow::new(&task.repo_id, &task.repo_name, "error");
+                row.error = Some(err);
+                rows.push(row);
+            }
+            "done" => {
+                trace!(repo = %task.repo_name, "Clone task completed");
+            }
+            _ => {
+                rows.push(StatusRow::new(&task.repo_id, &task.repo_name, &task.state));
+            }
+        }
+    }
+
+    // Get repo sync status
+    debug!("Fetching repository sync status");
+    let repos = client.get_repo_list(-1, -1)?;
+    trace!(count = repos.len(), "Found {} repositories", repos.len());
+    if repos.is_empty() {
+        return Ok(rows);
+    }
+
+    // Auto sync is a daemon-wide switch, one query covers every library
+    let auto_sync = client.is_auto_sync_enabled()?;
+
+    // usize::div_ceil needs Rust 1.73, past the 1.70 MSRV
+    #[allow(clippy::manual_div_ceil)]
+    let chunk_size = (repos.len() + STATUS_WORKERS - 1) / STATUS_WORKERS;
+    let chunks: Vec<&[Repo]> = repos.chunks(chunk_size).collect();
+    debug!(workers = chunks.len(), "Querying libraries in parallel");
+
+    // The first chunk reuses the existing connection
+    let (first, rest) = chunks.split_first().expect("repos is not empty");
+    let repo_rows = std::thread::scope(|scope| -> Result<Vec<StatusRow>> {
+        let handles: Vec<_> = rest
+            .iter()
+            .map(|chunk| {
+                let connect = &connect;
+                scope.spawn(move || -> Result<Vec<StatusRow>> {
+                    let mut client = connect()?;
+                    chunk_status(&mut client, chunk, auto_sync)
+                })
+            })
+            .collect();
+
+        let mut repo_rows = chunk_status(&mut client, first, auto_sync)?;
+        for handle in handles {
+            let chunk_rows = handle.join().expect("status worker panicked")?;
+            repo_rows.extend(chunk_rows);
+        }
+        Ok(repo_rows)
+    })?;
+
+    rows.extend(repo_rows);
+    Ok(rows)
+}
+
+/// Sync state of a run of libraries over one connection
+fn chunk_status<T: Transport>(
+    client: &mut SearpcClient<T>,
+    repos: &[Repo],
+    auto_sync: bool,
+) -> Result<Vec<StatusRow>> {
+    let mut rows = Vec::with_capacity(repos.len());
+
+    for repo in repos {
+        if !auto_sync || !repo.auto_sync {
+            trace!(repo = %repo.name, "Auto sync disabled");
+            rows.push(StatusRow::new(&repo.id, &repo.name, "auto sync disabled"));
+            continue;
+        }
+
+        match client.get_repo_sync_task(&repo.id) {
+            Ok(Some(task)) => match task.state.as_str() {
+                "uploading" | "downloading" => {
+                    if let Ok(tx_task) = client.find_transfer_task(&repo.id) {
+                        let (progress, rate) = transfer_progress(&tx_task);
+                        debug!(repo = %repo.name, state = %task.state, progress = %format!("{:.1}%", progress), "Transfer in progress");
+                        let mut row = StatusRow::new(&repo.id, &repo.name, &task.state);
+                        row.transfer = Some((progress, rate));
+                        rows.push(row);
+                    }
+                }
+                "error" => {
+                    let err = client.sync_error_id_to_str(task.error)?;
+                    error!(repo = %repo.name, error = %err, "Sync error");
+                    let mut row = StatusRow::new(&repo.id, &repo.name, "error");
+                    row.error = Some(err);
+                    rows.push(row);
+                }
+                _ => {
+                    trace!(repo = %repo.name, state = %task.state, "Sync state");
+                    rows.push(StatusRow::new(&repo.id, &repo.name, &task.state));
+                }
+            },
+            Ok(None) | Err(_) => {
+                trace!(repo = %repo.name, "Waiting for sync");
+                rows.push(StatusRow::new(&repo.id, &repo.name, "waiting for sync"));
+            }
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Print status rows as a table or in a machine-readable format
+///
+/// Machine-readable columns: Name, Status, Progress (percent), Rate (KB/s), Error.
+pub fn print_status(rows: &[StatusRow], format: Option<OutputFormat>) {
+    let Some(format) = format else {
+        println!("# {:<50}\t{:<20}\t{:<20}", "Name", "Status", "Progress");
+        for row in rows {
+            match (&row.transfer, &row.error) {
+                (Some((progress, rate)), _) => println!(
+                    "{:<50}\t{:<20}\t{:.1}%, {:.1}KB/s",
+                    row.name, row.status, progress, rate
+                ),
+                (None, Some(err)) => println!("{:<50}\t{:<20}\t{:<20}", row.name, row.status, err),
+                (None, None) => println!("{:<50}\t{:<20}", row.name, row.status),
+            }
+        }
+        return;
+    };
+
+    let records: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            let (progress, rate) = match row.transfer {
+                Some((progress, rate)) => (format!("{:.1}", progress), format!("{:.1}", rate)),
+                None => (String::new(), String::new()),
+            };
+            vec![
+                row.name.clone(),
+                row.status.clone(),
+                progress,
+                rate,
+                row.error.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    output::print_records(
+        format,
+        &["Name", "Status", "Progress", "Rate", "Error"],
+        &records,
+    );
+}
+
+/// Run `cmd` for every library that entered the error state since `previous`
+///
+/// The command is invoked as `cmd <repo name> <error>` with `SEAF_REPO_ID`,
+/// `SEAF_REPO_NAME` and `SEAF_ERROR` also set in its environment. Returns the
+/// set of libraries currently in error, to be passed back on the next poll.
+pub fn notify_new_errors(
+    previous: &HashSet<String>,
+    rows: &[StatusRow],
+    cmd: Option<&str>,
+) -> HashSet<String> {
+    let current: HashSet<String> = rows
+        .iter()
+        .filter(|row| row.error.is_some())
+        .map(|row| row.repo_id.clone())
+        .collect();
+
+    let Some(cmd) = cmd else {
+        return current;
+    };
+
+    for row in rows {
+        let Some(err) = &row.error else { continue };
+        if previous.contains(&row.repo_id) {
+            continue;
+        }
+
+        debug!(repo = %row.name, cmd = %cmd, "Running notify command");
+        let result = Command::new(cmd)
+            .arg(&row.name)
+            .arg(err)
+            .env("SEAF_REPO_ID", &row.repo_id)
+            .env("SEAF_REPO_NAME", &row.name)
+            .env("SEAF_ERROR", err)
+            .status();
+        match result {
+            Ok(status) if !status.success() => {
+                warn!(repo = %row.name, %status, "Notify command failed")
+            }
+            Ok(_) => {}
+            Err(e) => warn!(repo = %row.name, error = %e, "Failed to run notify command"),
+        }
+    }
+
+    current
+}
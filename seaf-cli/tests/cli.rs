@@ -0,0 +1,205 @@
+//! End-to-end tests of daemon commands against a fake daemon
+#![cfg(unix)]
+
+mod fake_daemon;
+
+use fake_daemon::{repo, FakeDaemon, State};
+use serde_json::json;
+use std::process::{Command, Output};
+
+const REPO_A: &str = "2c0c3a1e-0d1f-4e57-9c5b-1f0f8d1d7a6e";
+const REPO_B: &str = "7f4e8a90-1b2c-4d3e-8f5a-6b7c8d9e0f1a";
+
+/// Run seaf-cli against `daemon` with an isolated config directory
+fn seaf_cli(daemon: &FakeDaemon, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_seaf-cli"))
+        .arg("--socket")
+        .arg(daemon.socket())
+        .arg("-c")
+        .arg(daemon.dir().join("conf"))
+        .args(args)
+        .env("HOME", daemon.dir())
+        .env("RUST_LOG", "error")
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "seaf-cli failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+/// Daemon with two synced libraries whose worktrees exist on disk
+fn two_libraries() -> FakeDaemon {
+    let daemon = FakeDaemon::start(State::default());
+    let docs = daemon.dir().join("Docs");
+    let photos = daemon.dir().join("Photos");
+    std::fs::create_dir_all(&docs).unwrap();
+    std::fs::create_dir_all(&photos).unwrap();
+    {
+        let mut state = daemon.state();
+        state.repos = vec![
+            repo(REPO_A, "Docs", &docs.canonicalize().unwrap()),
+            repo(REPO_B, "Photos", &photos.canonicalize().unwrap()),
+        ];
+        state.auto_sync = true;
+    }
+    daemon
+}
+
+#[test]
+fn test_list() {
+    let daemon = two_libraries();
+
+    let out = stdout(&seaf_cli(&daemon, &["list"]));
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "Name\tID\tPath");
+    assert!(lines[1].starts_with(&format!("Docs\t{}\t", REPO_A)));
+    assert!(lines[2].starts_with(&format!("Photos\t{}\t", REPO_B)));
+
+    let out = stdout(&seaf_cli(&daemon, &["list", "--json"]));
+    let repos: serde_json::Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(repos[1]["id"], REPO_B);
+}
+
+#[test]
+fn test_status() {
+    let daemon = two_libraries();
+    {
+        let mut state = daemon.state();
+        state.clone_tasks = vec![json!({
+            "repo_id": "11111111-2222-4333-8444-555555555555",
+            "repo_name": "Music",
+            "state": "fetch",
+            "error": 0,
+        })];
+        state.transfer_tasks.insert(
+            "11111111-2222-4333-8444-555555555555".to_string(),
+            json!({
+                "repo_id": "11111111-2222-4333-8444-555555555555",
+                "block_done": 5,
+                "block_total": 10,
+                "rate": 2048,
+            }),
+        );
+        state.sync_tasks.insert(
+            REPO_A.to_string(),
+            json!({"repo_id": REPO_A, "state": "synchronized", "error": 0}),
+        );
+    }
+
+    let out = stdout(&seaf_cli(&daemon, &["status", "--format", "tsv"]));
+    assert!(out.contains("Docs\tsynchronized"), "{}", out);
+    assert!(out.contains("Photos\twaiting for sync"), "{}", out);
+    assert!(out.contains("Music\tdownloading\t50.0\t2.0"), "{}", out);
+}
+
+#[test]
+fn test_config_get_set() {
+    let daemon = FakeDaemon::start(State::default());
+
+    let out = stdout(&seaf_cli(
+        &daemon,
+        &["config", "-k", "upload_limit", "-v", "1000"],
+    ));
+    assert_eq!(out.trim(), "Set upload_limit = 1000");
+    assert_eq!(daemon.state().config["upload_limit"], "1000");
+
+    let out = stdout(&seaf_cli(&daemon, &["config", "-k", "upload_limit"]));
+    assert_eq!(out.trim(), "upload_limit = 1000");
+}
+
+#[test]
+fn test_desync() {
+    let daemon = two_libraries();
+    let docs = daemon.dir().join("Docs");
+
+    let out = stdout(&seaf_cli(
+        &daemon,
+        &["desync", "-d", docs.to_str().unwrap()],
+    ));
+    assert_eq!(out.trim(), "Desynchronize Docs");
+    let state = daemon.state();
+    assert!(state.calls.contains(&"seafile_destroy_repo".to_string()));
+    assert_eq!(state.repos.len(), 1);
+    assert_eq!(state.repos[0]["id"], REPO_B);
+}
+
+#[test]
+fn test_desync_not_a_library() {
+    let daemon = two_libraries();
+
+    let output = seaf_cli(&daemon, &["desync", "-d", daemon.dir().to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Not a library"));
+    assert_eq!(daemon.state().repos.len(), 2);
+}
+
+#[test]
+fn test_autosync() {
+    let daemon = two_libraries();
+    let photos = daemon.dir().join("Photos");
+
+    let out = stdout(&seaf_cli(&daemon, &["autosync", "--disable", "--all"]));
+    assert_eq!(out.trim(), "Auto sync disabled for all libraries");
+    assert!(!daemon.state().auto_sync);
+
+    let out = stdout(&seaf_cli(
+        &daemon,
+        &["autosync", "--disable", "-d", photos.to_str().unwrap()],
+    ));
+    assert_eq!(out.trim(), "Auto sync disabled for Photos");
+    assert_eq!(daemon.state().repos[1]["auto_sync"], false);
+}
+
+#[test]
+fn test_stop() {
+    let daemon = FakeDaemon::start(State::default());
+
+    let out = stdout(&seaf_cli(&daemon, &["stop"]));
+    assert_eq!(out.trim(), "Seafile daemon stopped");
+    assert!(daemon.state().shut_down);
+}
+
+#[test]
+fn test_socket_override_needs_datadir() {
+    let daemon = FakeDaemon::start(State::default());
+
+    // The socket's directory is not taken for the data dir
+    let output = seaf_cli(
+        &daemon,
+        &[
+            "download",
+            "-l",
+            REPO_A,
+            "-s",
+            "http://127.0.0.1:9",
+            "-u",
+            "user",
+            "-p",
+            "secret",
+        ],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--socket sets only the daemon socket"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("seafile.ini"), "{}", stderr);
+}
+
+#[test]
+fn test_daemon_not_running() {
+    let daemon = FakeDaemon::start(State::default());
+    std::fs::remove_file(daemon.socket()).unwrap();
+
+    let output = seaf_cli(&daemon, &["list"]);
+    // EX_UNAVAILABLE
+    assert_eq!(output.status.code(), Some(69));
+}
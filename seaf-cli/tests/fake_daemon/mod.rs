@@ -0,0 +1,216 @@
+//! In-process stand-in for the Seafile daemon
+//!
+//! Serves the `seafile-rpcserver` functions seaf-cli uses over a Unix socket,
+//! with the daemon's framing (32-bit native-endian length, service envelope).
+//! State lives in memory and every call is recorded for assertions.
+
+use searpc::{Result, RpcResponse, SearpcError};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Daemon state, seeded by the test and inspected afterwards
+#[derive(Debug, Default)]
+pub struct State {
+    /// Libraries as returned by `seafile_get_repo_list`
+    pub repos: Vec<Value>,
+    pub clone_tasks: Vec<Value>,
+    /// Sync tasks by repo ID (`seafile_get_repo_sync_task`)
+    pub sync_tasks: HashMap<String, Value>,
+    /// Transfer tasks by repo ID (`seafile_find_transfer_task`)
+    pub transfer_tasks: HashMap<String, Value>,
+    pub config: HashMap<String, String>,
+    pub auto_sync: bool,
+    /// Function names in call order
+    pub calls: Vec<String>,
+    pub shut_down: bool,
+}
+
+pub struct FakeDaemon {
+    dir: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeDaemon {
+    /// Start serving on `seafile.sock` in a fresh temporary directory
+    pub fn start(state: State) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "seaf-cli-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let listener = UnixListener::bind(dir.join("seafile.sock")).unwrap();
+        let state = Arc::new(Mutex::new(state));
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return };
+                let state = shared.clone();
+                thread::spawn(move || {
+                    let _ = serve(stream, &state);
+                });
+            }
+        });
+
+        FakeDaemon { dir, state }
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        self.dir.join("seafile.sock")
+    }
+
+    /// Scratch directory next to the socket, e.g. for worktrees
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for FakeDaemon {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Answer framed requests until the client hangs up
+fn serve(mut stream: UnixStream, state: &Mutex<State>) -> io::Result<()> {
+    loop {
+        let mut len = [0u8; 4];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut packet = vec![0u8; u32::from_ne_bytes(len) as usize];
+        stream.read_exact(&mut packet)?;
+
+        let response = RpcResponse::from_result(handle_packet(&packet, state));
+        let body = serde_json::to_vec(&response)?;
+        let mut frame = (body.len() as u32).to_ne_bytes().to_vec();
+        frame.extend_from_slice(&body);
+        stream.write_all(&frame)?;
+    }
+}
+
+/// Unwrap `{"service", "request": "[...]"}` and run the call
+fn handle_packet(packet: &[u8], state: &Mutex<State>) -> Result<Value> {
+    let envelope: Value = serde_json::from_slice(packet)?;
+    if envelope["service"] != "seafile-rpcserver" {
+        return Err(SearpcError::RpcError {
+            code: 501,
+            message: format!("cannot find service {}.", envelope["service"]),
+        });
+    }
+    let request = envelope["request"]
+        .as_str()
+        .ok_or_else(|| SearpcError::InvalidResponse("request is not a string".into()))?;
+    let call: Vec<Value> = serde_json::from_str(request)?;
+    let (function, args) = match call.split_first() {
+        Some((Value::String(name), args)) => (name.clone(), args),
+        _ => return Err(SearpcError::InvalidResponse("bad request".into())),
+    };
+
+    let mut state = state.lock().unwrap();
+    state.calls.push(function.clone());
+    dispatch(&mut state, &function, args)
+}
+
+fn arg_str(args: &[Value], index: usize) -> String {
+    args.get(index)
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn dispatch(state: &mut State, function: &str, args: &[Value]) -> Result<Value> {
+    match function {
+        "seafile_get_repo_list" => Ok(json!(state.repos)),
+        "seafile_get_clone_tasks" => Ok(json!(state.clone_tasks)),
+        "seafile_get_repo_sync_task" => Ok(state
+            .sync_tasks
+            .get(&arg_str(args, 0))
+            .cloned()
+            .unwrap_or(Value::Null)),
+        "seafile_find_transfer_task" => Ok(state
+            .transfer_tasks
+            .get(&arg_str(args, 0))
+            .cloned()
+            .unwrap_or(Value::Null)),
+        "seafile_sync_error_id_to_str" => Ok(json!("Unknown error")),
+        "seafile_is_auto_sync_enabled" => Ok(json!(state.auto_sync as i32)),
+        "seafile_enable_auto_sync" => {
+            state.auto_sync = true;
+            Ok(json!(0))
+        }
+        "seafile_disable_auto_sync" => {
+            state.auto_sync = false;
+            Ok(json!(0))
+        }
+        "seafile_set_repo_property" => {
+            let (id, key, value) = (arg_str(args, 0), arg_str(args, 1), arg_str(args, 2));
+            let repo = state
+                .repos
+                .iter_mut()
+                .find(|r| r["id"] == id.as_str())
+                .ok_or_else(|| repo_not_found(&id))?;
+            if key == "auto-sync" {
+                repo["auto_sync"] = json!(value == "true");
+            }
+            Ok(json!(0))
+        }
+        "seafile_get_config" => Ok(json!(state
+            .config
+            .get(&arg_str(args, 0))
+            .cloned()
+            .unwrap_or_default())),
+        "seafile_set_config" => {
+            state.config.insert(arg_str(args, 0), arg_str(args, 1));
+            Ok(json!(0))
+        }
+        "seafile_destroy_repo" => {
+            let id = arg_str(args, 0);
+            let before = state.repos.len();
+            state.repos.retain(|r| r["id"] != id.as_str());
+            if state.repos.len() == before {
+                return Err(repo_not_found(&id));
+            }
+            Ok(json!(0))
+        }
+        "seafile_shutdown" => {
+            state.shut_down = true;
+            Ok(json!(0))
+        }
+        _ => Err(SearpcError::RpcError {
+            code: 501,
+            message: format!("cannot find function {}.", function),
+        }),
+    }
+}
+
+fn repo_not_found(id: &str) -> SearpcError {
+    SearpcError::RpcError {
+        code: 501,
+        message: format!("Repo {} not found", id),
+    }
+}
+
+/// A library entry as `seafile_get_repo_list` returns it
+pub fn repo(id: &str, name: &str, worktree: &Path) -> Value {
+    json!({
+        "id": id,
+        "name": name,
+        "worktree": worktree.to_str().unwrap(),
+        "auto_sync": true,
+    })
+}
@@ -31,6 +31,8 @@
 //! }
 //! ```
 
+mod service;
+
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::parse::Parser;
@@ -109,6 +111,50 @@ pub fn rpc(attr: TokenStream, item: TokenStream) -> TokenStream {
     }
 }
 
+/// Generate server-side dispatch for the RPC functions of an `impl` block
+///
+/// Implements `searpc::server::RpcService` for the type, so its functions
+/// can be served with `SearpcServer::register_service`. Every method taking
+/// `&self` is an RPC function; its arguments are deserialized from the
+/// request array in order and its `Result<T>` is serialized as `ret`.
+/// Methods may also return `Result<T, E>` for an error type implementing
+/// `searpc::server::ToRpcError`, which sets the response's `err_code`.
+/// Names follow the same rules as [`macro@rpc`]: `prefix` plus the method
+/// name, unless overridden with `#[rpc(name = "...")]`.
+///
+/// ```rust,ignore
+/// trait SeafileRpc {
+///     fn get_config(&self, key: &str) -> Result<String>;
+///     fn is_auto_sync_enabled(&self) -> Result<bool>;
+/// }
+///
+/// #[searpc_service(prefix = "seafile")]
+/// impl SeafileRpc for Daemon {
+///     fn get_config(&self, key: &str) -> Result<String> { ... }
+///     // Serves: seafile_get_config
+///
+///     #[rpc(name = "seafile_is_auto_sync_enabled")]
+///     fn is_auto_sync_enabled(&self) -> Result<bool> { ... }
+///     // bool is sent as 0 or 1, like libsearpc
+/// }
+///
+/// let mut server = SearpcServer::new();
+/// server.register_service(Arc::new(Daemon::new()));
+/// ```
+///
+/// Borrowed parameters (`&str`, `&[T]`, `&T`) are deserialized into their
+/// owned counterparts and passed by reference. Missing trailing arguments
+/// read as `null`, so `Option<T>` parameters may be left out by clients.
+#[proc_macro_attribute]
+pub fn searpc_service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as syn::ItemImpl);
+
+    match service::generate_service_impl(input, attr.into()) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
 /// Configuration from trait-level #[rpc(...)] attribute
 struct RpcConfig {
     service: Option<String>,
@@ -395,6 +441,12 @@ fn generate_call_expression(
         let result = self.#call_method(#rpc_name, args)?;
     };
 
+    // Name the function in deserialization errors too
+    let deserialize = quote! {
+        let value: ::searpc::Result<_> = { #deserialize };
+        value.map_err(|e| ::searpc::SearpcError::in_call(#rpc_name, None, e))
+    };
+
     Ok((call_expr, deserialize))
 }
 
@@ -431,6 +483,10 @@ fn match_return_type(
     if is_type(ty, "bool") {
         return Ok((quote!(call_int), quote!(Ok(result != 0))));
     }
+    // () - the result is not wanted, whatever its type
+    if matches!(ty, Type::Tuple(tuple) if tuple.elems.is_empty()) {
+        return Ok((quote!(call_void), quote!(Ok(result))));
+    }
 
     // Check for Option<T> and Vec<T>
     if let Type::Path(type_path) = ty {
@@ -462,16 +518,11 @@ fn match_return_type(
             if segment.ident == "Vec" {
                 if let syn::PathArguments::AngleBracketed(args) = &segment.arguments {
                     if let Some(syn::GenericArgument::Type(inner)) = args.args.first() {
-                        // Vec<T> - use call_objlist and deserialize
+                        // Vec<T> - decode elements straight into T
                         return Ok((
-                            quote!(call_objlist),
+                            quote!(call_objlist_typed::<#inner>),
                             quote! {
-                                result.into_iter()
-                                    .map(|v| ::serde_json::from_value(v)
-                                        .map_err(|e| ::searpc::SearpcError::TypeError(
-                                            format!("Failed to deserialize Vec element: {}", e)
-                                        )))
-                                    .collect::<::searpc::Result<Vec<#inner>>>()
+                                result.collect::<::searpc::Result<Vec<#inner>>>()
                             },
                         ));
                     }
@@ -480,16 +531,8 @@ fn match_return_type(
         }
     }
 
-    // Default: single object deserialization
-    Ok((
-        quote!(call_object),
-        quote! {
-            ::serde_json::from_value(result)
-                .map_err(|e| ::searpc::SearpcError::TypeError(
-                    format!("Failed to deserialize: {}", e)
-                ))
-        },
-    ))
+    // Default: deserialize the result into the type
+    Ok((quote!(call_as::<#ty>), quote!(Ok(result))))
 }
 
 /// Check if a type matches a specific name
@@ -0,0 +1,165 @@
+//! `#[searpc_service]`: server-side dispatch generated from an `impl` block
+
+use crate::{extract_result_type, is_type, try_extract_method_config};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::parse::Parser;
+use syn::{FnArg, ImplItem, ImplItemFn, ItemImpl, PatType, ReturnType, Type};
+
+/// Configuration from `#[searpc_service(...)]`
+struct ServiceConfig {
+    prefix: Option<String>,
+}
+
+fn parse_service_config(attrs: TokenStream) -> syn::Result<ServiceConfig> {
+    let mut config = ServiceConfig { prefix: None };
+
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("prefix") {
+            config.prefix = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+            Ok(())
+        } else {
+            Err(meta.error("unsupported attribute, expected `prefix`"))
+        }
+    });
+
+    parser.parse2(attrs)?;
+    Ok(config)
+}
+
+/// Generate the `RpcService` implementation for an `impl` block
+pub(crate) fn generate_service_impl(
+    item: ItemImpl,
+    attrs: TokenStream,
+) -> syn::Result<TokenStream> {
+    let config = parse_service_config(attrs)?;
+
+    let mut item = item;
+    let mut names = Vec::new();
+    let mut arms = Vec::new();
+    for impl_item in &mut item.items {
+        let ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+        // Only methods taking &self are RPC functions; others are helpers
+        let Some(FnArg::Receiver(receiver)) = method.sig.inputs.first() else {
+            continue;
+        };
+        if receiver.reference.is_none() || receiver.mutability.is_some() {
+            return Err(syn::Error::new_spanned(
+                receiver,
+                "RPC functions must take &self: handlers may run concurrently",
+            ));
+        }
+
+        let method_config = try_extract_method_config(&method.attrs)?;
+        if method_config.expand {
+            return Err(syn::Error::new_spanned(
+                &method.sig,
+                "`expand` is not supported by #[searpc_service]",
+            ));
+        }
+        let rpc_name = match (method_config.name, &config.prefix) {
+            (Some(name), _) => name,
+            (None, Some(prefix)) => format!("{}_{}", prefix, method.sig.ident),
+            (None, None) => method.sig.ident.to_string(),
+        };
+        method.attrs.retain(|attr| !attr.path().is_ident("rpc"));
+
+        arms.push(generate_arm(method, &item.trait_, &rpc_name)?);
+        names.push(rpc_name);
+    }
+
+    let self_ty = &item.self_ty;
+    let (impl_generics, _, where_clause) = item.generics.split_for_impl();
+    Ok(quote! {
+        #item
+
+        impl #impl_generics ::searpc::server::RpcService for #self_ty #where_clause {
+            fn functions() -> &'static [&'static str] {
+                &[#(#names),*]
+            }
+
+            fn call(
+                &self,
+                function_name: &str,
+                args: &[::serde_json::Value],
+            ) -> ::std::option::Option<::searpc::Result<::serde_json::Value>> {
+                let result: ::searpc::Result<::serde_json::Value> = match function_name {
+                    #(#arms)*
+                    _ => return ::std::option::Option::None,
+                };
+                ::std::option::Option::Some(result)
+            }
+        }
+    })
+}
+
+/// Match arm calling `method` for `rpc_name`
+fn generate_arm(
+    method: &ImplItemFn,
+    trait_: &Option<(Option<syn::token::Not>, syn::Path, syn::token::For)>,
+    rpc_name: &str,
+) -> syn::Result<TokenStream> {
+    let mut bindings = Vec::new();
+    let mut call_args = Vec::new();
+    for (index, input) in method.sig.inputs.iter().skip(1).enumerate() {
+        let FnArg::Typed(PatType { ty, .. }) = input else {
+            continue;
+        };
+        let var = format_ident!("arg{}", index);
+        // Borrowed parameters are deserialized into an owned value first
+        let (owned, pass) = match ty.as_ref() {
+            Type::Reference(reference) => (owned_type(&reference.elem), quote!(&#var)),
+            ty => (quote!(#ty), quote!(#var)),
+        };
+        bindings.push(quote! {
+            let #var: #owned = ::searpc::server::arg(args, #index)?;
+        });
+        call_args.push(pass);
+    }
+
+    let return_type = match &method.sig.output {
+        ReturnType::Type(_, ty) => ty.as_ref(),
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &method.sig,
+                "RPC functions must return Result<T> or Result<T, E>",
+            ))
+        }
+    };
+    // libsearpc has no bool type: send it as an int, like #[rpc] reads it
+    let to_value = if is_type(extract_result_type(return_type)?, "bool") {
+        quote!(::std::result::Result::Ok(::serde_json::Value::from(
+            ret as i32
+        )))
+    } else {
+        quote!(::std::result::Result::Ok(::serde_json::to_value(ret)?))
+    };
+
+    let method_name = &method.sig.ident;
+    let call = match trait_ {
+        Some((_, path, _)) => quote!(<Self as #path>::#method_name),
+        None => quote!(Self::#method_name),
+    };
+    Ok(quote! {
+        #rpc_name => (|| -> ::searpc::Result<::serde_json::Value> {
+            #(#bindings)*
+            let ret = #call(self, #(#call_args),*)
+                .map_err(::searpc::server::ToRpcError::into_rpc_error)?;
+            #to_value
+        })(),
+    })
+}
+
+/// Owned type to deserialize a `&T` parameter into
+fn owned_type(ty: &Type) -> TokenStream {
+    match ty {
+        Type::Path(path) if path.path.is_ident("str") => quote!(::std::string::String),
+        Type::Slice(slice) => {
+            let elem = &slice.elem;
+            quote!(::std::vec::Vec<#elem>)
+        }
+        ty => quote!(#ty),
+    }
+}
@@ -0,0 +1,150 @@
+//! Serialization and parsing costs, without any I/O
+//!
+//! ```bash
+//! cargo bench -p searpc --bench protocol
+//! ```
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use searpc::{Arg, RpcRequest, RpcResponse, SearpcClient};
+use serde_json::json;
+
+/// One element of [`objlist_response`]
+#[derive(serde::Deserialize)]
+#[allow(dead_code)]
+struct Repo {
+    id: String,
+    name: String,
+    worktree: String,
+    size: u64,
+    encrypted: bool,
+}
+
+/// `{"ret": [...]}` with `count` repo-like objects
+fn objlist_response(count: usize) -> Vec<u8> {
+    let repos: Vec<_> = (0..count)
+        .map(|i| {
+            json!({
+                "id": format!("{:08x}-0000-4000-8000-000000000000", i),
+                "name": format!("Library {}", i),
+                "worktree": format!("/home/user/Seafile/Library {}", i),
+                "size": i * 1024,
+                "encrypted": i % 2 == 0,
+            })
+        })
+        .collect();
+    serde_json::to_vec(&json!({ "ret": repos })).unwrap()
+}
+
+fn bench_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_to_json");
+
+    let small = RpcRequest::with_args("searpc_strlen", vec![Arg::string("hello")]);
+    group.bench_function("small", |b| b.iter(|| black_box(&small).to_json().unwrap()));
+
+    let many = RpcRequest::with_args(
+        "seafile_clone",
+        vec![
+            Arg::string("2c0c3a1e-0d1f-4e57-9c5b-1f0f8d1d7a6e"),
+            Arg::int(1),
+            Arg::string("Library"),
+            Arg::string("/home/user/Seafile/Library"),
+            Arg::string("0123456789abcdef0123456789abcdef01234567"),
+            Arg::Null,
+            Arg::string("user@example.com"),
+            Arg::string("https://seafile.example.com"),
+            Arg::int64(1 << 40),
+            Arg::json(json!({"more_info": {"is_readonly": false}})),
+        ],
+    );
+    group.bench_function("ten_args", |b| {
+        b.iter(|| black_box(&many).to_json().unwrap())
+    });
+
+    let mut buf = Vec::new();
+    group.bench_function("ten_args_reused_buffer", |b| {
+        b.iter(|| RpcRequest::encode("seafile_clone", black_box(&many.args), &mut buf).unwrap())
+    });
+
+    for size in [1 << 10, 64 << 10, 1 << 20] {
+        let request = RpcRequest::with_args("echo", vec![Arg::string("x".repeat(size))]);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("string_arg", size), &request, |b, r| {
+            b.iter(|| r.to_json().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_response(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response_parse");
+
+    group.bench_function("int", |b| {
+        b.iter(|| {
+            RpcResponse::from_json(black_box(r#"{"ret": 42}"#))
+                .unwrap()
+                .into_result()
+                .unwrap()
+        })
+    });
+
+    for count in [10, 1_000, 10_000] {
+        let body = String::from_utf8(objlist_response(count)).unwrap();
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_with_input(BenchmarkId::new("objlist", count), &body, |b, body| {
+            b.iter(|| RpcResponse::from_json(body).unwrap().into_result().unwrap())
+        });
+        // Goes through simd-json for large bodies with `--features simd-json`
+        group.bench_with_input(
+            BenchmarkId::new("objlist_from_bytes", count),
+            &body,
+            |b, body| {
+                b.iter_batched(
+                    || body.clone().into_bytes(),
+                    |mut bytes| RpcResponse::from_bytes(&mut bytes).unwrap(),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Client overhead on top of parsing: request building, result conversion
+fn bench_client(c: &mut Criterion) {
+    let mut group = c.benchmark_group("client_call");
+
+    let mut client = SearpcClient::new(|_: &[u8]| Ok(br#"{"ret":5}"#.to_vec()));
+    group.bench_function("call_int", |b| {
+        b.iter(|| {
+            client
+                .call_int("searpc_strlen", vec![Arg::string("hello")])
+                .unwrap()
+        })
+    });
+
+    for count in [10, 1_000, 10_000] {
+        let body = objlist_response(count);
+        let mut client = SearpcClient::new(move |_: &[u8]| Ok(body.clone()));
+        group.bench_function(BenchmarkId::new("call_objlist", count), |b| {
+            b.iter(|| {
+                client
+                    .call_objlist("seafile_get_repo_list", vec![])
+                    .unwrap()
+            })
+        });
+        group.bench_function(BenchmarkId::new("call_objlist_typed", count), |b| {
+            b.iter(|| {
+                client
+                    .call_objlist_typed::<Repo>("seafile_get_repo_list", vec![])
+                    .unwrap()
+                    .collect::<searpc::Result<Vec<_>>>()
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_request, bench_response, bench_client);
+criterion_main!(benches);
@@ -0,0 +1,116 @@
+//! End-to-end calls over loopback connections
+//!
+//! Each transport talks to an in-process server thread that answers every
+//! request with the same canned response, so the numbers cover framing,
+//! envelope wrapping and the socket round trip.
+//!
+//! ```bash
+//! cargo bench -p searpc --bench transport
+//! ```
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use searpc::{Arg, SearpcClient, TcpTransport, Transport};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+
+const RESPONSE: &[u8] = br#"{"ret":5}"#;
+
+/// Serve the 16-bit TCP framing, answering every request with `RESPONSE`
+fn spawn_tcp_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut len = [0u8; 2];
+                while stream.read_exact(&mut len).is_ok() {
+                    let mut request = vec![0u8; u16::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut request).unwrap();
+                    let mut response = (RESPONSE.len() as u16).to_be_bytes().to_vec();
+                    response.extend_from_slice(RESPONSE);
+                    stream.write_all(&response).unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+fn bench_tcp(c: &mut Criterion) {
+    let addr = spawn_tcp_server();
+    let mut group = c.benchmark_group("tcp");
+
+    let mut client = SearpcClient::new(TcpTransport::connect(addr).unwrap());
+    group.bench_function("call_int", |b| {
+        b.iter(|| {
+            client
+                .call_int("searpc_strlen", vec![Arg::string("hello")])
+                .unwrap()
+        })
+    });
+
+    let mut transport = TcpTransport::connect(addr).unwrap();
+    for size in [1 << 10, 60 << 10] {
+        let request = vec![b'x'; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("send", size), &request, |b, r| {
+            b.iter(|| transport.send(r).unwrap())
+        });
+    }
+    group.finish();
+}
+
+#[cfg(unix)]
+fn bench_unix(c: &mut Criterion) {
+    use searpc::UnixSocketTransport;
+    use std::os::unix::net::UnixStream;
+
+    let (client_end, mut server_end) = UnixStream::pair().unwrap();
+    thread::spawn(move || {
+        let mut len = [0u8; 4];
+        while server_end.read_exact(&mut len).is_ok() {
+            let mut packet = vec![0u8; u32::from_ne_bytes(len) as usize];
+            server_end.read_exact(&mut packet).unwrap();
+            server_end
+                .write_all(&(RESPONSE.len() as u32).to_ne_bytes())
+                .unwrap();
+            server_end.write_all(RESPONSE).unwrap();
+        }
+    });
+
+    let mut group = c.benchmark_group("unix");
+    let mut transport = UnixSocketTransport::new(client_end, "seafile-rpcserver");
+    // Requests are wrapped in the service envelope before framing
+    for size in [1 << 10, 64 << 10, 1 << 20] {
+        let request = format!(r#"["echo","{}"]"#, "x".repeat(size)).into_bytes();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("send", size), &request, |b, r| {
+            b.iter(|| transport.send(r).unwrap())
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(unix))]
+fn bench_unix(_: &mut Criterion) {}
+
+fn bench_async_tcp(c: &mut Criterion) {
+    use searpc::{AsyncSearpcClient, AsyncTcpTransport};
+
+    let addr = spawn_tcp_server();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let transport = runtime.block_on(AsyncTcpTransport::connect(addr)).unwrap();
+    let mut client = AsyncSearpcClient::new(transport);
+
+    c.bench_function("async_tcp/call_int", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(client.call_int("searpc_strlen", vec![Arg::string("hello")]))
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, bench_tcp, bench_unix, bench_async_tcp);
+criterion_main!(benches);
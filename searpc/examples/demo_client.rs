@@ -1,10 +1,8 @@
 //! Example client to connect to libsearpc demo server
 //!
-//! First, compile and run the C demo server from libsearpc/demo:
+//! First, run the demo server (or the C demo server from libsearpc/demo):
 //! ```bash
-//! cd libsearpc/demo
-//! make
-//! ./searpc-demo-server
+//! cargo run --example demo_server
 //! ```
 //!
 //! Then run this client:
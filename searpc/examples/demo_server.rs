@@ -0,0 +1,88 @@
+//! Pure-Rust stand-in for the libsearpc C demo server
+//!
+//! Speaks the same protocol (16-bit big-endian length header over TCP) and
+//! implements the same functions, so the demo clients run without building
+//! libsearpc:
+//! ```bash
+//! cargo run --example demo_server
+//! cargo run --example demo_client   # in another terminal
+//! ```
+//!
+//! Listens on 127.0.0.1:12345 unless an address is given as argument.
+//!
+//! Functions:
+//! - `searpc_strlen(str) -> int`
+//! - `searpc_objlisttest(count, len, str) -> objlist` of `count` objects
+//!   `{"count": count, "len": len, "str": str}`
+use searpc::server::arg;
+use searpc::{RpcResponse, SearpcError, SearpcServer};
+use serde_json::{json, Value};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:12345".to_string());
+    let listener = TcpListener::bind(&addr)?;
+    println!("searpc demo server listening on {}", listener.local_addr()?);
+
+    let server = Arc::new(demo_functions());
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(e) = serve(&server, stream) {
+                eprintln!("connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn demo_functions() -> SearpcServer {
+    // Same signatures as the C demo's searpc_server_register_function calls
+    let mut server = SearpcServer::new();
+    server
+        .register_signed("searpc_strlen", "int__string", |args| {
+            let s: String = arg(args, 0)?;
+            Ok(json!(s.len()))
+        })
+        .expect("valid signature")
+        .register_signed("searpc_objlisttest", "objlist__int_int_string", |args| {
+            let count: i64 = arg(args, 0)?;
+            let len: i64 = arg(args, 1)?;
+            let s: String = arg(args, 2)?;
+            let objects = (0..count)
+                .map(|_| json!({"count": count, "len": len, "str": s}))
+                .collect();
+            Ok(Value::Array(objects))
+        })
+        .expect("valid signature");
+    server
+}
+
+/// Answer requests on one connection until the client hangs up
+fn serve(server: &SearpcServer, mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        let mut len_bytes = [0u8; 2];
+        match stream.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        let mut request = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+        stream.read_exact(&mut request)?;
+
+        let mut body = server.handle_request(&request);
+        if body.len() > u16::MAX as usize {
+            let err = SearpcError::transport("Response too large for 16-bit header");
+            body = serde_json::to_vec(&RpcResponse::from(err))?;
+        }
+        let mut packet = (body.len() as u16).to_be_bytes().to_vec();
+        packet.extend_from_slice(&body);
+        stream.write_all(&packet)?;
+    }
+}
@@ -2,11 +2,9 @@
 //!
 //! This example demonstrates the macro-based API with full type safety.
 //!
-//! First, run the C demo server from libsearpc/demo:
+//! First, run the demo server (or the C demo server from libsearpc/demo):
 //! ```bash
-//! cd libsearpc/demo
-//! make
-//! ./searpc-demo-server
+//! cargo run --example demo_server
 //! ```
 //!
 //! Then run this client:
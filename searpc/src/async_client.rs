@@ -2,10 +2,24 @@
 //!
 //! Provides async versions of all RPC call methods.
 
-#[cfg(feature = "async")]
-use crate::{async_transport::AsyncTransport, protocol::*, types::Arg, Result};
-#[cfg(feature = "async")]
+#[cfg(feature = "async-core")]
+use crate::{
+    async_transport::{AsyncPipelinedTransport, AsyncTransport},
+    protocol::*,
+    types::Arg,
+    Result, SearpcError,
+};
+#[cfg(feature = "async-core")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "async-core")]
 use serde_json::Value;
+#[cfg(feature = "async-core")]
+use std::collections::VecDeque;
+#[cfg(feature = "rt-tokio")]
+use {crate::retry::RetryPolicy, std::time::Duration};
+
+#[cfg(feature = "async-core")]
+pub use crate::client::PIPELINE_DEPTH;
 
 /// Async Searpc RPC client
 ///
@@ -27,132 +41,284 @@ use serde_json::Value;
 /// # Ok(())
 /// # }
 /// ```
-#[cfg(feature = "async")]
+#[cfg(feature = "async-core")]
 pub struct AsyncSearpcClient<T: AsyncTransport> {
     transport: T,
+    /// Request buffer, reused across calls
+    buf: Vec<u8>,
+    /// Bound on each call's exchange
+    #[cfg(feature = "rt-tokio")]
+    timeout: Option<Duration>,
+    #[cfg(feature = "rt-tokio")]
+    retry: Option<RetryPolicy>,
 }
 
-#[cfg(feature = "async")]
+#[cfg(feature = "async-core")]
 impl<T: AsyncTransport> AsyncSearpcClient<T> {
     /// Create a new async RPC client with the given transport
     pub fn new(transport: T) -> Self {
-        AsyncSearpcClient { transport }
+        AsyncSearpcClient {
+            transport,
+            buf: Vec::new(),
+            #[cfg(feature = "rt-tokio")]
+            timeout: None,
+            #[cfg(feature = "rt-tokio")]
+            retry: None,
+        }
     }
 
-    /// Make an RPC call expecting an integer result
-    pub async fn call_int(&mut self, fname: &str, args: Vec<Arg>) -> Result<i32> {
-        let request = RpcRequest {
-            function_name: fname.to_string(),
-            args,
-        };
+    /// Fail calls not done within `timeout` with
+    /// [`SearpcError::Timeout`]; `None` (the default) waits forever
+    ///
+    /// Bounds the whole exchange, on any transport, with tokio's timer.
+    /// Discard the transport after a timed-out call, as it may be left in
+    /// the middle of one.
+    #[cfg(feature = "rt-tokio")]
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
 
-        let request_json = request.to_json()?;
-        let response_data = self.transport.send(request_json.as_bytes()).await?;
+    /// Retry calls that fail as `policy` says; `None` (the default) fails
+    /// at once
+    ///
+    /// See [`SearpcClient::set_retry_policy`](crate::SearpcClient::set_retry_policy).
+    /// The timeout applies to each attempt.
+    #[cfg(feature = "rt-tokio")]
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry = policy;
+    }
 
-        let response_str = std::str::from_utf8(&response_data)
-            .map_err(|e| crate::SearpcError::InvalidResponse(e.to_string()))?;
-        let response = RpcResponse::from_json(response_str)?;
+    /// [`call_json`](Self::call_json) with a timeout for this call only,
+    /// see [`set_timeout`](Self::set_timeout)
+    #[cfg(feature = "rt-tokio")]
+    pub async fn call_with_timeout(
+        &mut self,
+        timeout: Duration,
+        fname: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<Value> {
+        let previous = self.timeout.replace(timeout);
+        let result = self.call_json(fname, args).await;
+        self.timeout = previous;
+        result
+    }
 
-        let value = response.into_result()?;
-        value
-            .as_i64()
-            .map(|v| v as i32)
-            .ok_or_else(|| crate::SearpcError::TypeError("Expected int".to_string()))
+    /// Make an RPC call and convert its result
+    ///
+    /// Any error is tagged with the function name and an argument summary.
+    async fn call_map<R>(
+        &mut self,
+        fname: &str,
+        args: &[Arg],
+        convert: impl FnOnce(Value) -> Result<R>,
+    ) -> Result<R> {
+        self.call_bytes(fname, args, |mut bytes| {
+            RpcResponse::from_bytes(&mut bytes)?
+                .into_result()
+                .and_then(convert)
+        })
+        .await
     }
 
-    /// Make an RPC call expecting a 64-bit integer result
-    pub async fn call_int64(&mut self, fname: &str, args: Vec<Arg>) -> Result<i64> {
-        let request = RpcRequest {
-            function_name: fname.to_string(),
-            args,
+    /// Make an RPC call and decode the raw response with `decode`
+    async fn call_bytes<R>(
+        &mut self,
+        fname: &str,
+        args: &[Arg],
+        decode: impl FnOnce(Vec<u8>) -> Result<R>,
+    ) -> Result<R> {
+        let result = match RpcRequest::encode(fname, args, &mut self.buf) {
+            Ok(()) => self.send_request().await,
+            Err(e) => Err(e),
         };
+        result
+            .and_then(decode)
+            .map_err(|e| SearpcError::in_call(fname, Some(summarize_args(args)), e))
+    }
 
-        let request_json = request.to_json()?;
-        let response_data = self.transport.send(request_json.as_bytes()).await?;
-
-        let response_str = std::str::from_utf8(&response_data)
-            .map_err(|e| crate::SearpcError::InvalidResponse(e.to_string()))?;
-        let response = RpcResponse::from_json(response_str)?;
-
-        let value = response.into_result()?;
-        value
-            .as_i64()
-            .ok_or_else(|| crate::SearpcError::TypeError("Expected int64".to_string()))
+    /// Send the request in `self.buf`, retried and within the timeout as
+    /// set
+    async fn send_request(&mut self) -> Result<Vec<u8>> {
+        #[cfg(feature = "rt-tokio")]
+        {
+            let mut attempt = 1;
+            loop {
+                match self.send_once().await {
+                    Err(e) => match self.retry.and_then(|p| p.retry_delay(attempt, &e)) {
+                        Some(delay) => tokio::time::sleep(delay).await,
+                        None => return Err(e),
+                    },
+                    result => return result,
+                }
+                attempt += 1;
+            }
+        }
+        #[cfg(not(feature = "rt-tokio"))]
+        self.transport.send(&self.buf).await
     }
 
-    /// Make an RPC call expecting a string result
-    pub async fn call_string(&mut self, fname: &str, args: Vec<Arg>) -> Result<String> {
-        let request = RpcRequest {
-            function_name: fname.to_string(),
-            args,
-        };
+    /// Send the request in `self.buf` once, within the timeout if there is
+    /// one
+    #[cfg(feature = "rt-tokio")]
+    async fn send_once(&mut self) -> Result<Vec<u8>> {
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.transport.send(&self.buf))
+                .await
+                .unwrap_or(Err(SearpcError::Timeout { timeout })),
+            None => self.transport.send(&self.buf).await,
+        }
+    }
 
-        let request_json = request.to_json()?;
-        let response_data = self.transport.send(request_json.as_bytes()).await?;
+    /// Make an RPC call expecting an integer result
+    pub async fn call_int(&mut self, fname: &str, args: impl AsRef<[Arg]>) -> Result<i32> {
+        self.call_map(fname, args.as_ref(), |value| {
+            value
+                .as_i64()
+                .map(|v| v as i32)
+                .ok_or_else(|| crate::SearpcError::TypeError("Expected int".to_string()))
+        })
+        .await
+    }
 
-        let response_str = std::str::from_utf8(&response_data)
-            .map_err(|e| crate::SearpcError::InvalidResponse(e.to_string()))?;
-        let response = RpcResponse::from_json(response_str)?;
+    /// Make an RPC call expecting a 64-bit integer result
+    pub async fn call_int64(&mut self, fname: &str, args: impl AsRef<[Arg]>) -> Result<i64> {
+        self.call_map(fname, args.as_ref(), |value| {
+            value
+                .as_i64()
+                .ok_or_else(|| crate::SearpcError::TypeError("Expected int64".to_string()))
+        })
+        .await
+    }
 
-        let value = response.into_result()?;
-        value
-            .as_str()
-            .map(|s| s.to_string())
-            .ok_or_else(|| crate::SearpcError::TypeError("Expected string".to_string()))
+    /// Make an RPC call expecting a string result
+    pub async fn call_string(&mut self, fname: &str, args: impl AsRef<[Arg]>) -> Result<String> {
+        self.call_map(fname, args.as_ref(), |value| {
+            value
+                .as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| crate::SearpcError::TypeError("Expected string".to_string()))
+        })
+        .await
     }
 
     /// Make an RPC call expecting a JSON object result
-    pub async fn call_object(&mut self, fname: &str, args: Vec<Arg>) -> Result<Value> {
-        let request = RpcRequest {
-            function_name: fname.to_string(),
-            args,
-        };
-
-        let request_json = request.to_json()?;
-        let response_data = self.transport.send(request_json.as_bytes()).await?;
-
-        let response_str = std::str::from_utf8(&response_data)
-            .map_err(|e| crate::SearpcError::InvalidResponse(e.to_string()))?;
-        let response = RpcResponse::from_json(response_str)?;
-
-        response.into_result()
+    pub async fn call_object(&mut self, fname: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.call_map(fname, args.as_ref(), Ok).await
     }
 
     /// Make an RPC call expecting a list of JSON objects
-    pub async fn call_objlist(&mut self, fname: &str, args: Vec<Arg>) -> Result<Vec<Value>> {
-        let request = RpcRequest {
-            function_name: fname.to_string(),
-            args,
-        };
+    pub async fn call_objlist(
+        &mut self,
+        fname: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<Vec<Value>> {
+        self.call_map(fname, args.as_ref(), |value| match value {
+            Value::Array(items) => Ok(items),
+            _ => Err(crate::SearpcError::TypeError("Expected array".to_string())),
+        })
+        .await
+    }
 
-        let request_json = request.to_json()?;
-        let response_data = self.transport.send(request_json.as_bytes()).await?;
+    /// Make an RPC call expecting an objlist result, deserializing each
+    /// element into `R`
+    ///
+    /// See [`SearpcClient::call_objlist_typed`](crate::SearpcClient::call_objlist_typed).
+    pub async fn call_objlist_typed<R: DeserializeOwned>(
+        &mut self,
+        fname: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<ObjlistIter<R>> {
+        self.call_bytes(fname, args.as_ref(), ObjlistIter::from_bytes)
+            .await
+    }
 
-        let response_str = std::str::from_utf8(&response_data)
-            .map_err(|e| crate::SearpcError::InvalidResponse(e.to_string()))?;
-        let response = RpcResponse::from_json(response_str)?;
+    /// Make an RPC call deserializing its result into `R`
+    ///
+    /// See [`SearpcClient::call_as`](crate::SearpcClient::call_as).
+    pub async fn call_as<R: DeserializeOwned>(
+        &mut self,
+        fname: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<R> {
+        self.call_map(fname, args.as_ref(), from_value).await
+    }
 
-        let value = response.into_result()?;
-        value
-            .as_array()
-            .cloned()
-            .ok_or_else(|| crate::SearpcError::TypeError("Expected array".to_string()))
+    /// Make an RPC call, discarding its result
+    ///
+    /// See [`SearpcClient::call_void`](crate::SearpcClient::call_void).
+    pub async fn call_void(&mut self, fname: &str, args: impl AsRef<[Arg]>) -> Result<()> {
+        self.call_map(fname, args.as_ref(), |_| Ok(())).await
     }
 
     /// Make an RPC call expecting a JSON value result
-    pub async fn call_json(&mut self, fname: &str, args: Vec<Arg>) -> Result<Value> {
-        let request = RpcRequest {
-            function_name: fname.to_string(),
-            args,
-        };
-
-        let request_json = request.to_json()?;
-        let response_data = self.transport.send(request_json.as_bytes()).await?;
+    pub async fn call_json(&mut self, fname: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.call_map(fname, args.as_ref(), Ok).await
+    }
+}
 
-        let response_str = std::str::from_utf8(&response_data)
-            .map_err(|e| crate::SearpcError::InvalidResponse(e.to_string()))?;
-        let response = RpcResponse::from_json(response_str)?;
+/// Clones share the connection if the transport's clones do, as with
+/// `AsyncMultiplexedTransport`, so each of several joined calls can take
+/// its own clone
+#[cfg(feature = "async-core")]
+impl<T: AsyncTransport + Clone> Clone for AsyncSearpcClient<T> {
+    fn clone(&self) -> Self {
+        AsyncSearpcClient {
+            transport: self.transport.clone(),
+            buf: Vec::new(),
+            #[cfg(feature = "rt-tokio")]
+            timeout: self.timeout,
+            #[cfg(feature = "rt-tokio")]
+            retry: self.retry,
+        }
+    }
+}
 
-        response.into_result()
+#[cfg(feature = "async-core")]
+impl<T: AsyncPipelinedTransport> AsyncSearpcClient<T> {
+    /// Make several RPC calls on one connection, sending requests without
+    /// waiting for the responses to earlier ones
+    ///
+    /// Saves a round trip per call over a slow link. Results come in the
+    /// order of `calls`, each as [`call_json`](Self::call_json) would
+    /// return it. A transport error fails the whole sequence instead: the
+    /// connection is unusable after it.
+    pub async fn call_pipelined<'a, A: AsRef<[Arg]>>(
+        &mut self,
+        calls: impl IntoIterator<Item = (&'a str, A)>,
+    ) -> Result<Vec<Result<Value>>> {
+        let calls: Vec<_> = calls.into_iter().collect();
+        let in_call = |i: usize, e| {
+            let (fname, args): &(&str, A) = &calls[i];
+            SearpcError::in_call(fname, Some(summarize_args(args.as_ref())), e)
+        };
+        let mut results: Vec<Option<Result<Value>>> = calls.iter().map(|_| None).collect();
+        let mut in_flight = VecDeque::new();
+        let mut next = 0;
+        while next < calls.len() || !in_flight.is_empty() {
+            if next < calls.len() && in_flight.len() < PIPELINE_DEPTH {
+                let (fname, args) = &calls[next];
+                match RpcRequest::encode(fname, args.as_ref(), &mut self.buf) {
+                    Ok(()) => {
+                        let sent = self.transport.send_request(&self.buf).await;
+                        sent.map_err(|e| in_call(next, e))?;
+                        in_flight.push_back(next);
+                    }
+                    Err(e) => results[next] = Some(Err(in_call(next, e))),
+                }
+                next += 1;
+                continue;
+            }
+            let Some(i) = in_flight.pop_front() else {
+                break;
+            };
+            let mut bytes = self
+                .transport
+                .recv_response()
+                .await
+                .map_err(|e| in_call(i, e))?;
+            let result = RpcResponse::from_bytes(&mut bytes).and_then(RpcResponse::into_result);
+            results[i] = Some(result.map_err(|e| in_call(i, e)));
+        }
+        Ok(results.into_iter().flatten().collect())
     }
 }
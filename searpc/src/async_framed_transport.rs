@@ -0,0 +1,168 @@
+//! Async transport over any tokio byte stream
+//!
+//! [`FramedAsyncTransport`] does the framing of the socket transports for
+//! streams they do not know about, such as TLS streams, SSH channels or
+//! pipes. The framing is either transport's, with or without the Seafile
+//! service envelope:
+//!
+//! ```rust,no_run
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use searpc::transport::{Endianness, Framing};
+//! use searpc::{AsyncSearpcClient, FramedAsyncTransport};
+//!
+//! let stream = tokio::net::UnixStream::connect("/path/to/seafile.sock").await?;
+//! let transport = FramedAsyncTransport::new(stream, Framing::U32(Endianness::Native))
+//!     .with_service("seafile-rpcserver");
+//! let mut client = AsyncSearpcClient::new(transport);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    async_transport::{self, AsyncPipelinedTransport, AsyncTransport},
+    transport::Framing,
+    Result,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Async transport framing packets on a byte stream, see the
+/// [module docs](self)
+pub struct FramedAsyncTransport<S> {
+    stream: S,
+    framing: Framing,
+    /// Service envelope around requests, if any
+    service: Option<String>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> FramedAsyncTransport<S> {
+    /// Send bare requests over `stream` with `framing`
+    pub fn new(stream: S, framing: Framing) -> Self {
+        FramedAsyncTransport {
+            stream,
+            framing,
+            service: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Wrap requests in the Seafile service envelope for `service`
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The underlying stream; reading or writing it breaks the framing
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Send a packet, header and body in a single write
+    async fn send_packet(&mut self, data: &[u8]) -> Result<()> {
+        let mut packet = std::mem::take(&mut self.buf);
+        let mut result = self
+            .framing
+            .encode(self.service.as_deref(), data, &mut packet);
+        if result.is_ok() {
+            result = async_transport::write_request(&mut self.stream, &packet).await;
+        }
+        if result.is_ok() {
+            // For buffering streams, like TLS
+            result = async_transport::flush_request(&mut self.stream).await;
+        }
+        self.buf = packet;
+        result
+    }
+
+    /// Receive a packet
+    async fn recv_packet(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0u8; 4];
+        let header = &mut header[..self.framing.header_len()];
+        async_transport::read_response(&mut self.stream, header, true).await?;
+        let len = self.framing.body_len(header)?;
+        let mut data = vec![0u8; len];
+        let (start, rest) = data.split_at_mut(self.framing.peek_len(header, len));
+        async_transport::read_response(&mut self.stream, start, false).await?;
+        self.framing.check_start(header, start)?;
+        async_transport::read_response(&mut self.stream, rest, false).await?;
+        Ok(data)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncTransport for FramedAsyncTransport<S> {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.send_packet(request).await?;
+        self.recv_packet().await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncPipelinedTransport for FramedAsyncTransport<S> {
+    async fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.send_packet(request).await
+    }
+
+    async fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.recv_packet().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{Arg, AsyncSearpcClient, AsyncSearpcServer};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_demo_framing() {
+        let mut server = AsyncSearpcServer::new();
+        server.register("searpc_strlen", |args| async move {
+            let s: String = arg(&args, 0)?;
+            Ok(json!(s.len()))
+        });
+        let (ours, theirs) = tokio::io::duplex(64);
+        tokio::spawn(async move { server.serve_connection(theirs).await });
+
+        let mut client = AsyncSearpcClient::new(FramedAsyncTransport::new(ours, Framing::U16));
+        for s in ["hello", "searpc"] {
+            let len = client.call_int("searpc_strlen", [Arg::string(s)]).await;
+            assert_eq!(len.unwrap() as usize, s.len());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_seafile_framing() {
+        use crate::transport::Endianness;
+        use crate::{SearpcServer, UnixSocketServer};
+        use std::sync::Arc;
+
+        let mut rpc = SearpcServer::new();
+        rpc.register("ping", |_| Ok(json!("pong")));
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc);
+        server.set_endianness(Endianness::Big);
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::thread::spawn(move || Arc::new(server).serve_connection(theirs));
+
+        ours.set_nonblocking(true).unwrap();
+        let stream = tokio::net::UnixStream::from_std(ours).unwrap();
+        let transport = FramedAsyncTransport::new(stream, Framing::U32(Endianness::Big))
+            .with_service("test-service");
+        let mut client = AsyncSearpcClient::new(transport);
+        assert_eq!(client.call_string("ping", []).await.unwrap(), "pong");
+    }
+}
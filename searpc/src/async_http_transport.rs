@@ -0,0 +1,145 @@
+//! Async HTTP(S) transport, also for the browser
+//!
+//! Async counterpart of `HttpTransport`: each request is posted as is and
+//! the response body is the usual response object. It runs on reqwest,
+//! which uses `fetch` on wasm32, so web dashboards can make typed calls with
+//! [`AsyncSearpcClient`](crate::AsyncSearpcClient):
+//!
+//! ```rust,no_run
+//! # async fn run() -> searpc::Result<()> {
+//! use searpc::{AsyncHttpTransport, AsyncSearpcClient};
+//!
+//! let transport = AsyncHttpTransport::new("https://seafile.example.com/rpc")
+//!     .header("Authorization", "Token 0123456789abcdef");
+//! let mut client = AsyncSearpcClient::new(transport);
+//! let version = client.call_string("seafile_get_version", []).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Outside wasm32, reqwest is built without TLS: enable one of its TLS
+//! features, such as `rustls-tls`, for `https://` URLs.
+
+use crate::async_transport::AsyncTransport;
+use crate::error::{Result, SearpcError};
+use std::time::Duration;
+
+pub use reqwest;
+
+/// Async transport posting requests to `url`
+pub struct AsyncHttpTransport {
+    client: reqwest::Client,
+    url: String,
+    headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+}
+
+impl AsyncHttpTransport {
+    /// Post requests to `url` (`http://` or `https://`)
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_client(reqwest::Client::new(), url)
+    }
+
+    /// Post requests with `client`, for proxies, TLS or connection settings
+    pub fn with_client(client: reqwest::Client, url: impl Into<String>) -> Self {
+        AsyncHttpTransport {
+            client,
+            url: url.into(),
+            headers: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Send `name: value` with every request, e.g. for authentication
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Give up on requests that take longer than `timeout` overall
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// URL requests are posted to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl AsyncTransport for AsyncHttpTransport {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut post = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(request.to_vec());
+        for (name, value) in &self.headers {
+            post = post.header(name, value);
+        }
+        if let Some(timeout) = self.timeout {
+            post = post.timeout(timeout);
+        }
+        let response = post
+            .send()
+            .await
+            .map_err(|e| SearpcError::transport(format!("HTTP: {}", e)))?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(SearpcError::transport(format!(
+                "HTTP {} from {}",
+                status, self.url
+            )));
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| SearpcError::transport(format!("Reading HTTP response: {}", e)))?;
+        Ok(body.to_vec())
+    }
+}
+
+#[cfg(all(test, feature = "http"))]
+mod tests {
+    use super::*;
+    use crate::http_transport::tests::http_server;
+    use crate::{Arg, AsyncSearpcClient};
+
+    #[tokio::test]
+    async fn test_post() {
+        let (url, seen) = http_server(2, "200 OK");
+        let transport = AsyncHttpTransport::new(url).header("Authorization", "Token abc");
+        let mut client = AsyncSearpcClient::new(transport);
+        for s in ["hello", "searpc over fetch"] {
+            let len = client.call_int("searpc_strlen", [Arg::string(s)]).await;
+            assert_eq!(len.unwrap() as usize, s.len());
+        }
+
+        let (request_line, headers) = seen.recv().unwrap();
+        assert_eq!(request_line, "POST /rpc HTTP/1.1");
+        assert!(headers
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case("authorization: Token abc")));
+    }
+
+    #[tokio::test]
+    async fn test_http_errors() {
+        let (url, _seen) = http_server(1, "502 Bad Gateway");
+        let mut transport = AsyncHttpTransport::new(url);
+        let err = transport
+            .send(br#"["searpc_strlen","a"]"#)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("HTTP 502 Bad Gateway"), "{}", err);
+
+        // Nothing listening
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        drop(listener);
+        let mut transport = AsyncHttpTransport::new(url).timeout(Duration::from_secs(5));
+        assert!(transport.send(br#"["searpc_strlen","a"]"#).await.is_err());
+    }
+}
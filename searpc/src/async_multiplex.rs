@@ -0,0 +1,308 @@
+//! Several async calls in flight on one Unix socket connection
+//!
+//! The async counterpart of [`MultiplexedTransport`](crate::MultiplexedTransport),
+//! for servers with the same `"id"` extension. An
+//! [`AsyncSearpcClient`](crate::AsyncSearpcClient) call borrows the client
+//! until it is answered, but clones of a client over this transport share
+//! its connection. Joined calls on clones run side by side:
+//!
+//! ```rust,no_run
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use searpc::{Arg, AsyncMultiplexedTransport, AsyncSearpcClient};
+//!
+//! let transport =
+//!     AsyncMultiplexedTransport::connect("/path/to/seafile.sock", "seafile-rpcserver").await?;
+//! let client = AsyncSearpcClient::new(transport);
+//! let (mut a, mut b) = (client.clone(), client.clone());
+//! let (version, repos) = tokio::join!(
+//!     a.call_string("seafile_get_version", []),
+//!     b.call_objlist("seafile_get_repo_list", [Arg::int(-1), Arg::int(-1)]),
+//! );
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Legacy servers answer in request order, which works as with the blocking
+//! transport. Requests are written by a task of their own, so a call given
+//! up halfway, e.g. by a timeout, cannot leave half a request behind.
+
+use crate::async_transport::{self, AsyncTransport};
+use crate::error::{Result, SearpcError};
+use crate::multiplex::{pending_call_error, Frame};
+use crate::transport::{self, wrap_tagged_request};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Async transport sharing one Unix socket connection between concurrent
+/// calls, see the [module docs](self)
+#[derive(Clone)]
+pub struct AsyncMultiplexedTransport {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    service: String,
+    /// Packets for the writer task, written in the order they were queued
+    requests: mpsc::UnboundedSender<Vec<u8>>,
+    calls: Arc<Mutex<Calls>>,
+    reader: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Calls {
+    next_id: u64,
+    /// Calls waiting for their response, by ID
+    waiting: BTreeMap<u64, oneshot::Sender<Result<Vec<u8>>>>,
+    /// Whether responses carry IDs, once the first one arrived
+    tagged: Option<bool>,
+    /// The connection failed: new calls fail straight away
+    closed: bool,
+}
+
+impl AsyncMultiplexedTransport {
+    /// Send requests for `service` over `stream`
+    ///
+    /// Spawns tasks reading responses and writing requests, so it must be
+    /// called within a tokio runtime. They end when the last clone is
+    /// dropped.
+    pub fn new(stream: UnixStream, service: impl Into<String>) -> Self {
+        let (reader, writer) = stream.into_split();
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        let (requests, queued) = mpsc::unbounded_channel();
+        tokio::spawn(write_requests(writer, queued));
+        let reader = tokio::spawn(read_responses(BufReader::new(reader), Arc::clone(&calls)));
+        AsyncMultiplexedTransport {
+            shared: Arc::new(Shared {
+                service: service.into(),
+                requests,
+                calls,
+                reader,
+            }),
+        }
+    }
+
+    pub async fn connect(path: impl AsRef<Path>, service: impl Into<String>) -> io::Result<Self> {
+        Ok(Self::new(UnixStream::connect(path).await?, service))
+    }
+
+    /// Whether the server answers with IDs, i.e. supports multiplexing;
+    /// `None` until the first response arrived
+    pub fn is_multiplexed(&self) -> Option<bool> {
+        lock(&self.shared.calls).tagged
+    }
+
+    /// Queue `request` under a new call ID, and wait for its response
+    async fn call(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let shared = &*self.shared;
+        let (respond, response) = oneshot::channel();
+        {
+            let mut calls = lock(&shared.calls);
+            if calls.closed {
+                return Err(SearpcError::ConnectionClosed {
+                    request_sent: false,
+                    mid_frame: false,
+                });
+            }
+            calls.next_id += 1;
+            let id = calls.next_id;
+            let mut packet = vec![0u8; 4];
+            wrap_tagged_request(&shared.service, Some(id), request, &mut packet)?;
+            let len = u32::try_from(packet.len() - 4)
+                .map_err(|_| SearpcError::transport("Request too large for 32-bit header"))?;
+            packet[..4].copy_from_slice(&len.to_ne_bytes());
+
+            // Queued under the lock, so requests go out in ID order
+            calls.waiting.insert(id, respond);
+            if shared.requests.send(packet).is_err() {
+                calls.waiting.remove(&id);
+                return Err(SearpcError::ConnectionClosed {
+                    request_sent: false,
+                    mid_frame: false,
+                });
+            }
+        }
+        // The sender is only dropped unanswered if the reader task died
+        response.await.unwrap_or(Err(SearpcError::ConnectionClosed {
+            request_sent: true,
+            mid_frame: false,
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncTransport for AsyncMultiplexedTransport {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.call(request).await
+    }
+}
+
+/// Write the queued packets until the last sender is gone or a write fails
+async fn write_requests(mut writer: OwnedWriteHalf, mut queued: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(packet) = queued.recv().await {
+        if let Err(e) = async_transport::write_request(&mut writer, &packet).await {
+            warn!("searpc multiplexed transport: {}", e);
+            // Possibly half a frame written: the server can only hang up,
+            // and the reader then fails the waiting calls
+            let _ = writer.shutdown().await;
+            return;
+        }
+    }
+}
+
+/// Hand each response to its call until the connection closes
+async fn read_responses(mut stream: BufReader<OwnedReadHalf>, calls: Arc<Mutex<Calls>>) {
+    let error = loop {
+        let mut header = [0u8; 4];
+        if let Err(e) = async_transport::read_response(&mut stream, &mut header, true).await {
+            break e;
+        }
+        let len = u32::from_ne_bytes(header) as usize;
+        if len == 0 {
+            break SearpcError::transport("Received packet with zero length");
+        }
+        if let Err(e) = transport::check_response_len(len, transport::DEFAULT_MAX_RESPONSE_SIZE) {
+            break e;
+        }
+        let mut data = vec![0u8; len];
+        if let Err(e) = async_transport::read_response(&mut stream, &mut data, false).await {
+            break e;
+        }
+
+        let mut calls = lock(&calls);
+        let (call, response) = match Frame::parse(&data) {
+            Frame::Tagged(id, response) => {
+                calls.tagged = Some(true);
+                (calls.waiting.remove(&id), response.into_bytes())
+            }
+            // Nothing subscribes on this transport
+            Frame::Notification(_) => continue,
+            Frame::Plain => {
+                calls.tagged.get_or_insert(false);
+                let oldest = calls.waiting.pop_first().map(|(_, call)| call);
+                (oldest, data)
+            }
+        };
+        match call {
+            // The caller may have given up meanwhile
+            Some(call) => drop(call.send(Ok(response))),
+            None => warn!("searpc multiplexed transport: response to no pending call"),
+        }
+    };
+
+    let mut calls = lock(&calls);
+    calls.closed = true;
+    for (_, call) in std::mem::take(&mut calls.waiting) {
+        let _ = call.send(Err(pending_call_error(&error)));
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // The writer task ends by itself with the sender, closing its half
+        self.reader.abort();
+    }
+}
+
+impl fmt::Debug for AsyncMultiplexedTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let calls = lock(&self.shared.calls);
+        f.debug_struct("AsyncMultiplexedTransport")
+            .field("service", &self.shared.service)
+            .field("in_flight", &calls.waiting.len())
+            .field("multiplexed", &calls.tagged)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{Arg, AsyncSearpcClient, SearpcServer, UnixSocketServer};
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    /// Our end of a connection to a multiplexing server
+    fn connect_test_server() -> UnixStream {
+        let mut rpc = SearpcServer::new();
+        rpc.register("sleep_ms", |args| {
+            let ms: u64 = arg(args, 0)?;
+            std::thread::sleep(Duration::from_millis(ms));
+            Ok(json!(ms))
+        });
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc);
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::thread::spawn(move || Arc::new(server).serve_connection(theirs));
+        ours.set_nonblocking(true).unwrap();
+        UnixStream::from_std(ours).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_calls() {
+        let transport = AsyncMultiplexedTransport::new(connect_test_server(), "test-service");
+        let client = AsyncSearpcClient::new(transport.clone());
+        let (mut slow, mut fast) = (client.clone(), client.clone());
+        let (slow_done, fast_done) = tokio::join!(
+            async {
+                assert_eq!(
+                    slow.call_int("sleep_ms", [Arg::int(300)]).await.unwrap(),
+                    300
+                );
+                Instant::now()
+            },
+            async {
+                assert_eq!(fast.call_int("sleep_ms", [Arg::int(1)]).await.unwrap(), 1);
+                Instant::now()
+            },
+        );
+        assert!(fast_done < slow_done, "fast call waited for the slow one");
+        assert_eq!(transport.is_multiplexed(), Some(true));
+
+        // A timed-out call leaves the connection usable
+        let mut client = client.clone();
+        client.set_timeout(Some(Duration::from_millis(50)));
+        let err = client
+            .call_int("sleep_ms", [Arg::int(300)])
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+        client.set_timeout(None);
+        assert_eq!(client.call_int("sleep_ms", [Arg::int(1)]).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_server_closes() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::thread::spawn(move || {
+            use std::io::Read;
+            let mut theirs = theirs;
+            let mut len = [0u8; 4];
+            theirs.read_exact(&mut len).unwrap();
+        });
+        ours.set_nonblocking(true).unwrap();
+
+        let stream = UnixStream::from_std(ours).unwrap();
+        let mut transport = AsyncMultiplexedTransport::new(stream, "test-service");
+        let err = transport.send(br#"["sleep_ms",1]"#).await.unwrap_err();
+        assert!(err.is_connection_closed());
+        assert!(!err.may_replay());
+        // Later calls fail without writing
+        let err = transport.send(br#"["sleep_ms",1]"#).await.unwrap_err();
+        assert!(err.may_replay());
+    }
+}
@@ -0,0 +1,702 @@
+//! Async RPC server implementation
+//!
+//! Async counterpart of [`SearpcServer`](crate::SearpcServer): handlers
+//! return futures, and connections are served as tasks on the tokio runtime
+//! rather than one thread each.
+
+#[cfg(feature = "rt-tokio")]
+use crate::{
+    async_transport,
+    metrics::MetricsSink,
+    protocol::RpcResponse,
+    server::{
+        busy, encode_response, function_not_found, packet_too_large, parse_request, report_call,
+        trace_bad_request, Limits,
+    },
+    signature::Signature,
+    transport::{self, MAX_FRAME_SIZE},
+    Result, SearpcError,
+};
+#[cfg(feature = "rt-tokio")]
+use serde_json::Value;
+#[cfg(feature = "rt-tokio")]
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Instant,
+};
+#[cfg(feature = "rt-tokio")]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "rt-tokio")]
+use tokio::net::TcpListener;
+#[cfg(feature = "rt-tokio")]
+use tokio::sync::Semaphore;
+#[cfg(feature = "rt-tokio")]
+use tracing::{debug, info_span, warn, Instrument};
+
+/// Future returned by an [`AsyncHandler`]
+#[cfg(feature = "rt-tokio")]
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Value>> + Send>>;
+
+/// Handler for one RPC function: takes the call's arguments, resolves to `ret`
+#[cfg(feature = "rt-tokio")]
+pub type AsyncHandler = Box<dyn Fn(Vec<Value>) -> HandlerFuture + Send + Sync>;
+
+/// Async registry of RPC functions, served over TCP
+///
+/// As with [`SearpcServer`](crate::SearpcServer), functions can be added
+/// and removed while the server is shared, with
+/// [`insert_function`](Self::insert_function) and
+/// [`remove_function`](Self::remove_function).
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use searpc::server::arg;
+/// use searpc::AsyncSearpcServer;
+/// use serde_json::json;
+/// use std::sync::Arc;
+///
+/// let mut server = AsyncSearpcServer::new();
+/// server.register("searpc_strlen", |args| async move {
+///     let s: String = arg(&args, 0)?;
+///     Ok(json!(s.len()))
+/// });
+///
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:12345").await?;
+/// Arc::new(server).serve(listener).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "rt-tokio")]
+#[derive(Default)]
+pub struct AsyncSearpcServer {
+    registry: RwLock<Registry>,
+    limits: Limits,
+    connection_slots: Option<Arc<Semaphore>>,
+    request_slots: Option<Semaphore>,
+    /// Requests waiting for one of `request_slots`
+    queued: AtomicUsize,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    /// Continuation frames for messages over 64KB
+    chunked: bool,
+}
+
+/// The functions of an [`AsyncSearpcServer`]
+#[cfg(feature = "rt-tokio")]
+#[derive(Default)]
+struct Registry {
+    /// Shared so a call can run without holding the lock
+    handlers: HashMap<String, Arc<AsyncHandler>>,
+    signatures: HashMap<String, Signature>,
+}
+
+#[cfg(feature = "rt-tokio")]
+impl Registry {
+    fn insert(
+        &mut self,
+        function_name: String,
+        handler: AsyncHandler,
+        signature: Option<Signature>,
+    ) {
+        match signature {
+            Some(signature) => self.signatures.insert(function_name.clone(), signature),
+            None => self.signatures.remove(&function_name),
+        };
+        self.handlers.insert(function_name, Arc::new(handler));
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+impl AsyncSearpcServer {
+    /// Create a server with no functions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` as `function_name`, replacing any previous one
+    pub fn register<F, Fut>(&mut self, function_name: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: Fn(Vec<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Value>> + Send + 'static,
+    {
+        self.registry_mut()
+            .insert(function_name.into(), boxed(handler), None);
+        self
+    }
+
+    /// Register `handler` as `function_name` on a server that may be serving
+    ///
+    /// Like [`SearpcServer::insert_function`](crate::SearpcServer::insert_function):
+    /// calls already running finish with the handler they started with.
+    pub fn insert_function<F, Fut>(&self, function_name: impl Into<String>, handler: F)
+    where
+        F: Fn(Vec<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Value>> + Send + 'static,
+    {
+        self.registry_write()
+            .insert(function_name.into(), boxed(handler), None);
+    }
+
+    /// Remove `function_name`; `false` if it was not registered
+    ///
+    /// Later calls get `cannot find function`; calls already running finish.
+    pub fn remove_function(&self, function_name: &str) -> bool {
+        let mut registry = self.registry_write();
+        registry.signatures.remove(function_name);
+        registry.handlers.remove(function_name).is_some()
+    }
+
+    /// Register `handler` with a libsearpc signature such as `int__string`
+    ///
+    /// Like [`SearpcServer::register_signed`](crate::SearpcServer::register_signed):
+    /// calls with the wrong number or types of arguments get
+    /// `SEAF_ERR_BAD_ARGS` (503) without reaching the handler, and a return
+    /// value not matching the signature is reported as an error.
+    pub fn register_signed<F, Fut>(
+        &mut self,
+        function_name: impl Into<String>,
+        signature: &str,
+        handler: F,
+    ) -> Result<&mut Self>
+    where
+        F: Fn(Vec<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Value>> + Send + 'static,
+    {
+        let signature: Signature = signature.parse()?;
+        let checked = Arc::new(signature.clone());
+        let handler: AsyncHandler = Box::new(move |args| {
+            let checked = Arc::clone(&checked);
+            match checked.check_args(&args) {
+                Ok(()) => {
+                    let call = handler(args);
+                    Box::pin(async move {
+                        let ret = call.await?;
+                        checked.check_return(&ret)?;
+                        Ok(ret)
+                    })
+                }
+                Err(e) => Box::pin(async move { Err(e) }),
+            }
+        });
+        self.registry_mut()
+            .insert(function_name.into(), handler, Some(signature));
+        Ok(self)
+    }
+
+    /// Signature `function_name` was registered with, if any
+    pub fn signature(&self, function_name: &str) -> Option<Signature> {
+        self.registry().signatures.get(function_name).cloned()
+    }
+
+    /// Register functions that share `state`, such as a database handle
+    ///
+    /// Like [`SearpcServer::with_state`](crate::SearpcServer::with_state);
+    /// handlers get their own `Arc` of the state, so their futures can keep
+    /// it across `.await`s.
+    pub fn with_state<S>(&mut self, state: Arc<S>) -> WithState<'_, S>
+    where
+        S: Send + Sync + 'static,
+    {
+        WithState {
+            server: self,
+            state,
+        }
+    }
+
+    /// Whether `function_name` is registered
+    pub fn has_function(&self, function_name: &str) -> bool {
+        self.registry().handlers.contains_key(function_name)
+    }
+
+    /// Names of the registered functions, sorted
+    pub fn functions(&self) -> Vec<String> {
+        let mut functions: Vec<_> = self.registry().handlers.keys().cloned().collect();
+        functions.sort_unstable();
+        functions
+    }
+
+    /// Bound the connections and requests served at once
+    ///
+    /// Requests over the limit queue, then get a 429 busy error; see
+    /// [`Limits`].
+    pub fn set_limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self.connection_slots = limits.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        self.request_slots = limits.max_concurrent_requests.map(Semaphore::new);
+        self
+    }
+
+    /// The limits set with [`set_limits`](Self::set_limits)
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Report every call to `sink`, like
+    /// [`SearpcServer::set_metrics`](crate::SearpcServer::set_metrics)
+    pub fn set_metrics(&mut self, sink: Arc<dyn MetricsSink>) -> &mut Self {
+        self.metrics = Some(sink);
+        self
+    }
+
+    /// Accept requests and send responses over 64KB as continuation frames
+    ///
+    /// Clients have to opt in too, with
+    /// [`AsyncTcpTransport::with_chunking`](crate::AsyncTcpTransport::with_chunking)
+    /// or [`TcpTransport::with_chunking`](crate::TcpTransport::with_chunking).
+    /// [`Limits::max_packet_size`] applies to the joined request.
+    pub fn set_chunking(&mut self, chunked: bool) -> &mut Self {
+        self.chunked = chunked;
+        self
+    }
+
+    /// Run `function_name` with `args`
+    pub async fn call(&self, function_name: &str, args: Vec<Value>) -> Result<Value> {
+        // Not holding the lock across the await: the handler may register functions
+        let handler = self.registry().handlers.get(function_name).cloned();
+        match handler {
+            Some(handler) => handler(args).await,
+            None => Err(function_not_found(function_name)),
+        }
+    }
+
+    /// Run a serialized request, returning the response to send
+    pub async fn dispatch(&self, request: &[u8]) -> RpcResponse {
+        let (function_name, args) = match parse_request(request) {
+            Ok(call) => call,
+            Err(e) => {
+                trace_bad_request(&e);
+                return RpcResponse::from(e);
+            }
+        };
+        let started = Instant::now();
+        let result = self.call(&function_name, args).await;
+        report_call(self.metrics.as_deref(), &function_name, started, &result);
+        RpcResponse::from_result(result)
+    }
+
+    /// Run a serialized request and return the serialized response
+    pub async fn handle_request(&self, request: &[u8]) -> Vec<u8> {
+        encode_response(&self.dispatch(request).await)
+    }
+
+    /// Accept connections on `listener` and serve each one as its own task
+    ///
+    /// Uses the TCP demo protocol (16-bit big-endian length header), like
+    /// [`AsyncTcpTransport`](crate::AsyncTcpTransport). With
+    /// [`Limits::max_connections`], further clients are accepted as
+    /// connections close. Only returns if accepting fails; errors on single
+    /// connections are logged.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<()> {
+        loop {
+            let slot = match &self.connection_slots {
+                Some(slots) => Some(
+                    Arc::clone(slots)
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore is never closed"),
+                ),
+                None => None,
+            };
+            let (stream, peer) = listener.accept().await?;
+            let server = Arc::clone(&self);
+            let span = info_span!("searpc_connection", %peer);
+            tokio::spawn(
+                async move {
+                    let _slot = slot;
+                    if let Err(e) = server.serve_connection(stream).await {
+                        warn!("searpc connection from {}: {}", peer, e);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    /// Answer requests on one connection until the client hangs up
+    ///
+    /// Requests on a connection are handled one at a time, in order: the
+    /// protocol has no request IDs to match out-of-order responses.
+    pub async fn serve_connection<S>(&self, mut stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        loop {
+            let mut request = Vec::new();
+            let mut frame_start = true;
+            loop {
+                let mut len_bytes = [0u8; 2];
+                match async_transport::read_response(&mut stream, &mut len_bytes, frame_start).await
+                {
+                    Ok(()) => {}
+                    // Clean close between requests
+                    Err(SearpcError::ConnectionClosed {
+                        mid_frame: false, ..
+                    }) => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+                frame_start = false;
+                let len = u16::from_be_bytes(len_bytes) as usize;
+                let total = request.len() + len;
+                if total > self.limits.max_packet_size {
+                    let err = packet_too_large(total, self.limits.max_packet_size);
+                    let body = encode_response(&RpcResponse::from(err));
+                    let mut packet = (body.len() as u16).to_be_bytes().to_vec();
+                    packet.extend_from_slice(&body);
+                    let _ = async_transport::write_request(&mut stream, &packet).await;
+                    return Err(SearpcError::transport(format!(
+                        "Request of {} bytes over the size limit, closing",
+                        total
+                    )));
+                }
+                request.resize(total, 0);
+                let frame = &mut request[total - len..];
+                async_transport::read_response(&mut stream, frame, false).await?;
+                if !self.chunked || len < MAX_FRAME_SIZE {
+                    break;
+                }
+            }
+            debug!("RPC request: {}", String::from_utf8_lossy(&request));
+
+            let mut body = self.handle_limited(&request).await;
+            let mut packet = Vec::with_capacity(2 + body.len());
+            if self.chunked {
+                transport::encode_chunked(&body, &mut packet);
+            } else {
+                if body.len() > MAX_FRAME_SIZE {
+                    let err = SearpcError::transport("Response too large for 16-bit header");
+                    body = encode_response(&RpcResponse::from(err));
+                }
+                packet.extend_from_slice(&(body.len() as u16).to_be_bytes());
+                packet.extend_from_slice(&body);
+            }
+            async_transport::write_request(&mut stream, &packet).await?;
+        }
+    }
+
+    /// [`handle_request`](Self::handle_request) within the request limits
+    async fn handle_limited(&self, request: &[u8]) -> Vec<u8> {
+        let Some(slots) = &self.request_slots else {
+            return self.handle_request(request).await;
+        };
+        let _slot = match slots.try_acquire() {
+            Ok(slot) => slot,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::AcqRel) >= self.limits.max_queued_requests {
+                    self.queued.fetch_sub(1, Ordering::AcqRel);
+                    warn!("searpc server: busy, request turned away");
+                    return encode_response(&RpcResponse::from(busy()));
+                }
+                let slot = slots.acquire().await;
+                self.queued.fetch_sub(1, Ordering::AcqRel);
+                slot.expect("semaphore is never closed")
+            }
+        };
+        self.handle_request(request).await
+    }
+
+    fn registry(&self) -> RwLockReadGuard<'_, Registry> {
+        self.registry.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn registry_write(&self) -> RwLockWriteGuard<'_, Registry> {
+        self.registry
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn registry_mut(&mut self) -> &mut Registry {
+        self.registry
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// `handler` as an [`AsyncHandler`]
+#[cfg(feature = "rt-tokio")]
+fn boxed<F, Fut>(handler: F) -> AsyncHandler
+where
+    F: Fn(Vec<Value>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Value>> + Send + 'static,
+{
+    Box::new(move |args| Box::pin(handler(args)))
+}
+
+/// Registers handlers sharing one state, see [`AsyncSearpcServer::with_state`]
+#[cfg(feature = "rt-tokio")]
+pub struct WithState<'a, S> {
+    server: &'a mut AsyncSearpcServer,
+    state: Arc<S>,
+}
+
+#[cfg(feature = "rt-tokio")]
+impl<S: Send + Sync + 'static> WithState<'_, S> {
+    /// Register `handler` as `function_name`, replacing any previous one
+    pub fn register<F, Fut>(&mut self, function_name: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: Fn(Arc<S>, Vec<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Value>> + Send + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        self.server
+            .register(function_name, move |args| handler(Arc::clone(&state), args));
+        self
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+impl fmt::Debug for AsyncSearpcServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncSearpcServer")
+            .field("functions", &self.functions())
+            .field("limits", &self.limits)
+            .field("metrics", &self.metrics.is_some())
+            .field("chunked", &self.chunked)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "rt-tokio"))]
+mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{Arg, AsyncSearpcClient, AsyncTcpTransport};
+    use serde_json::json;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let mut server = AsyncSearpcServer::new();
+        server.register("searpc_strlen", |args| async move {
+            let s: String = arg(&args, 0)?;
+            Ok(json!(s.len()))
+        });
+
+        let response = server.handle_request(br#"["searpc_strlen","hello"]"#).await;
+        assert_eq!(response, br#"{"ret":5}"#);
+        let response = server.handle_request(br#"["missing"]"#).await;
+        assert_eq!(
+            response,
+            br#"{"err_code":500,"err_msg":"cannot find function missing."}"#
+        );
+        let response = server.dispatch(b"[").await;
+        assert_eq!(response.err_code, Some(511));
+    }
+
+    #[tokio::test]
+    async fn test_runtime_registration() {
+        let server = Arc::new(AsyncSearpcServer::new());
+        let plugin = {
+            let server = Arc::clone(&server);
+            move |args: Vec<Value>| {
+                let server = Arc::clone(&server);
+                async move {
+                    // Handlers may change the registry they run from
+                    let name: String = arg(&args, 0)?;
+                    server.insert_function(name, |_| async { Ok(json!("loaded")) });
+                    Ok(Value::Null)
+                }
+            }
+        };
+        server.insert_function("load_plugin", plugin);
+
+        let response = server.dispatch(br#"["plugin_hello"]"#).await;
+        assert_eq!(response.err_code, Some(500));
+        server.dispatch(br#"["load_plugin","plugin_hello"]"#).await;
+        let response = server.dispatch(br#"["plugin_hello"]"#).await;
+        assert_eq!(response.ret, Some(json!("loaded")));
+        assert_eq!(server.functions(), ["load_plugin", "plugin_hello"]);
+
+        assert!(server.remove_function("plugin_hello"));
+        assert!(!server.remove_function("plugin_hello"));
+        let response = server.dispatch(br#"["plugin_hello"]"#).await;
+        assert_eq!(response.err_code, Some(500));
+        // The server holds a handle on itself through `load_plugin`
+        assert!(server.remove_function("load_plugin"));
+    }
+
+    #[tokio::test]
+    async fn test_with_state() {
+        use tokio::sync::Mutex;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut server = AsyncSearpcServer::new();
+        server
+            .with_state(Arc::clone(&log))
+            .register("log", |log, args| async move {
+                let line: String = arg(&args, 0)?;
+                let mut log = log.lock().await;
+                log.push(line);
+                Ok(json!(log.len()))
+            });
+
+        let response = server.handle_request(br#"["log","a"]"#).await;
+        assert_eq!(response, br#"{"ret":1}"#);
+        let response = server.handle_request(br#"["log","b"]"#).await;
+        assert_eq!(response, br#"{"ret":2}"#);
+        assert_eq!(*log.lock().await, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_register_signed() {
+        let mut server = AsyncSearpcServer::new();
+        server
+            .register_signed("searpc_strlen", "int__string", |args| async move {
+                let s: String = arg(&args, 0)?;
+                Ok(json!(s.len()))
+            })
+            .unwrap()
+            .register_signed("bad_ret", "int__void", |_| async { Ok(json!("0")) })
+            .unwrap();
+        assert!(server
+            .register_signed("x", "bool__int", |_| async { Ok(json!(0)) })
+            .is_err());
+        assert_eq!(
+            server.signature("searpc_strlen").unwrap().to_string(),
+            "int__string"
+        );
+
+        let response = server.dispatch(br#"["searpc_strlen","abc"]"#).await;
+        assert_eq!(response.ret, Some(json!(3)));
+        for request in [&br#"["searpc_strlen",1]"#[..], br#"["searpc_strlen"]"#] {
+            let response = server.dispatch(request).await;
+            assert_eq!(response.err_code, Some(503));
+        }
+        assert!(server.dispatch(br#"["bad_ret"]"#).await.err_code.is_some());
+
+        // Plain registration drops the signature
+        server.register("searpc_strlen", |_| async { Ok(json!(0)) });
+        assert!(server.signature("searpc_strlen").is_none());
+    }
+
+    /// On a single-threaded runtime, a handler waiting for another
+    /// connection's call only finishes if connections are served concurrently
+    #[tokio::test]
+    async fn test_serve_concurrently() {
+        let notify = Arc::new(Notify::new());
+        let mut server = AsyncSearpcServer::new();
+        {
+            let notify = Arc::clone(&notify);
+            server.register("wait", move |_| {
+                let notify = Arc::clone(&notify);
+                async move {
+                    notify.notified().await;
+                    Ok(json!("woken"))
+                }
+            });
+        }
+        {
+            let notify = Arc::clone(&notify);
+            server.register("wake", move |_| {
+                notify.notify_one();
+                async { Ok(Value::Null) }
+            });
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let mut waiter = AsyncSearpcClient::new(AsyncTcpTransport::connect(addr).await.unwrap());
+        let mut waker = AsyncSearpcClient::new(AsyncTcpTransport::connect(addr).await.unwrap());
+        let waiting = tokio::spawn(async move {
+            let result = waiter.call_string("wait", [Arg::Null]).await;
+            (waiter, result)
+        });
+        waker.call_json("wake", []).await.unwrap();
+        let (mut waiter, result) = waiting.await.unwrap();
+        assert_eq!(result.unwrap(), "woken");
+
+        // Connections stay usable for more calls
+        let err = waiter.call_int("missing", []).await.unwrap_err();
+        assert_eq!(err.inner().err_code(), 500);
+    }
+
+    #[tokio::test]
+    async fn test_request_limit() {
+        let notify = Arc::new(Notify::new());
+        let mut server = AsyncSearpcServer::new();
+        server.set_limits(Limits {
+            max_concurrent_requests: Some(1),
+            ..Limits::default()
+        });
+        {
+            let notify = Arc::clone(&notify);
+            server.register("wait", move |_| {
+                let notify = Arc::clone(&notify);
+                async move {
+                    notify.notified().await;
+                    Ok(json!("woken"))
+                }
+            });
+        }
+        server.register("ping", |_| async { Ok(json!(1)) });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let mut waiter = AsyncSearpcClient::new(AsyncTcpTransport::connect(addr).await.unwrap());
+        let mut other = AsyncSearpcClient::new(AsyncTcpTransport::connect(addr).await.unwrap());
+        let waiting = tokio::spawn(async move { waiter.call_string("wait", [Arg::Null]).await });
+        // Let `wait` take the only slot
+        while !waiting.is_finished() {
+            let err = match other.call_int("ping", []).await {
+                Ok(_) => {
+                    tokio::task::yield_now().await;
+                    continue;
+                }
+                Err(err) => err,
+            };
+            assert_eq!(err.kind(), Some(crate::KnownErrorCode::Busy));
+            break;
+        }
+
+        notify.notify_one();
+        assert_eq!(waiting.await.unwrap().unwrap(), "woken");
+        assert_eq!(other.call_int("ping", []).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chunking() {
+        let mut server = AsyncSearpcServer::new();
+        server.set_chunking(true);
+        server.register("echo", |args| async move {
+            let s: String = arg(&args, 0)?;
+            Ok(json!(s))
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let big = "x".repeat(200_000);
+        let transport = AsyncTcpTransport::connect(addr).await.unwrap();
+        let mut client = AsyncSearpcClient::new(transport.with_chunking(true));
+        for s in ["small", &big] {
+            let echoed = client.call_string("echo", [Arg::string(s)]).await;
+            assert_eq!(echoed.unwrap(), s);
+        }
+
+        let sync = tokio::task::spawn_blocking(move || {
+            let transport = crate::TcpTransport::connect(addr).unwrap();
+            let mut client = crate::SearpcClient::new(transport.with_chunking(true));
+            client.call_string("echo", [Arg::string(&big)]).unwrap() == big
+        });
+        assert!(sync.await.unwrap());
+
+        // Without chunking, the client refuses to send it
+        let transport = AsyncTcpTransport::connect(addr).await.unwrap();
+        let mut client = AsyncSearpcClient::new(transport);
+        let big = "x".repeat(70_000);
+        assert!(client
+            .call_string("echo", [Arg::string(big)])
+            .await
+            .is_err());
+    }
+}
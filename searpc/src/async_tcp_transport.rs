@@ -3,17 +3,25 @@
 //! This transport is compatible with the libsearpc C demo server.
 //! Uses tokio for async I/O.
 
-#[cfg(feature = "async")]
-use crate::{async_transport::AsyncTransport, error::SearpcError, Result};
-#[cfg(feature = "async")]
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-#[cfg(feature = "async")]
+#[cfg(feature = "rt-tokio")]
+use crate::{
+    async_transport::{self, AsyncPipelinedTransport, AsyncTransport},
+    error::SearpcError,
+    proxy::Proxy,
+    retry::RetryPolicy,
+    transport::{self, Framing, MAX_FRAME_SIZE},
+    Result,
+};
+#[cfg(feature = "rt-tokio")]
+use tokio::io::BufReader;
+#[cfg(feature = "rt-tokio")]
 use tokio::net::TcpStream;
 
 /// Async TCP transport with 16-bit big-endian length header
 ///
 /// Compatible with libsearpc C demo server protocol.
-/// Maximum packet size: 64KB (u16 limit)
+/// Maximum packet size: 64KB (u16 limit), unless
+/// [chunking](Self::with_chunking) is enabled
 ///
 /// ## Example
 ///
@@ -29,72 +37,156 @@ use tokio::net::TcpStream;
 /// # Ok(())
 /// # }
 /// ```
-#[cfg(feature = "async")]
+#[cfg(feature = "rt-tokio")]
 pub struct AsyncTcpTransport {
-    stream: TcpStream,
+    /// Buffered, so a response's header and body usually take one read
+    stream: BufReader<TcpStream>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+    chunked: bool,
+    max_response_size: usize,
 }
 
-#[cfg(feature = "async")]
+#[cfg(feature = "rt-tokio")]
 impl AsyncTcpTransport {
+    pub fn new(stream: TcpStream) -> Self {
+        Self::with_capacity(stream, transport::DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Use buffers of `capacity` bytes from the start, for high call rates
+    ///
+    /// Responses up to that size are read in one go, and requests up to
+    /// that size are assembled without reallocating.
+    pub fn with_capacity(stream: TcpStream, capacity: usize) -> Self {
+        AsyncTcpTransport {
+            stream: BufReader::with_capacity(capacity, stream),
+            buf: Vec::with_capacity(capacity),
+            chunked: false,
+            max_response_size: transport::DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    /// Split messages over 64KB into continuation frames, see
+    /// [`TcpTransport::with_chunking`](crate::TcpTransport::with_chunking)
+    pub fn with_chunking(mut self, chunked: bool) -> Self {
+        self.chunked = chunked;
+        self
+    }
+
+    /// Longest response to accept, see
+    /// [`TcpTransport::with_max_response_size`](crate::TcpTransport::with_max_response_size)
+    pub fn with_max_response_size(mut self, max: usize) -> Self {
+        self.max_response_size = max;
+        self
+    }
+
     /// Connect to a TCP server
     pub async fn connect(addr: impl tokio::net::ToSocketAddrs) -> Result<Self> {
         let stream = TcpStream::connect(addr)
             .await
-            .map_err(|e| SearpcError::TransportError(e.to_string()))?;
+            .map_err(|e| SearpcError::TransportError {
+                message: e.to_string(),
+                source: Some(e),
+            })?;
+
+        Ok(Self::new(stream))
+    }
+
+    /// Connect, waiting for a server that is not listening yet according
+    /// to `policy`
+    pub async fn connect_with_retry(
+        addr: impl tokio::net::ToSocketAddrs,
+        policy: RetryPolicy,
+    ) -> Result<Self> {
+        let stream = policy
+            .connect_async(|| TcpStream::connect(&addr))
+            .await
+            .map_err(|e| SearpcError::TransportError {
+                message: e.to_string(),
+                source: Some(e),
+            })?;
+        Ok(Self::new(stream))
+    }
+
+    /// Connect to `target` (`host:port`) through `proxy`
+    pub async fn connect_via(proxy: &Proxy, target: &str) -> Result<Self> {
+        Ok(Self::new(proxy.connect_async(target).await?))
+    }
 
-        Ok(AsyncTcpTransport { stream })
+    /// Connect to `target` (`host:port`), through the proxy configured in
+    /// the environment if there is one (see [`Proxy::from_env`])
+    pub async fn connect_from_env(target: &str) -> Result<Self> {
+        match Proxy::from_env(target)? {
+            Some(proxy) => Self::connect_via(&proxy, target).await,
+            None => Self::connect(target).await,
+        }
     }
 
     /// Send a packet with 16-bit big-endian length header
     async fn send_packet(&mut self, data: &[u8]) -> Result<()> {
         let len = data.len();
-        if len > u16::MAX as usize {
-            return Err(SearpcError::TransportError(format!(
+        if len > MAX_FRAME_SIZE && !self.chunked {
+            return Err(SearpcError::transport(format!(
                 "Packet too large: {} > {}",
                 len,
                 u16::MAX
             )));
         }
 
-        // Send 16-bit big-endian length
-        let len_bytes = (len as u16).to_be_bytes();
-        self.stream
-            .write_all(&len_bytes)
-            .await
-            .map_err(|e| SearpcError::TransportError(e.to_string()))?;
-
-        // Send data
-        self.stream
-            .write_all(data)
-            .await
-            .map_err(|e| SearpcError::TransportError(e.to_string()))?;
-
-        Ok(())
+        // 16-bit big-endian length and data in one write, see TcpTransport
+        let mut packet = std::mem::take(&mut self.buf);
+        packet.clear();
+        if self.chunked {
+            transport::encode_chunked(data, &mut packet);
+        } else {
+            packet.extend_from_slice(&(len as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+        }
+        let result = async_transport::write_request(self.stream.get_mut(), &packet).await;
+        self.buf = packet;
+        result
     }
 
-    /// Receive a packet with 16-bit big-endian length header
+    /// Receive a packet with 16-bit big-endian length header, joining
+    /// continuation frames if chunking
     async fn recv_packet(&mut self) -> Result<Vec<u8>> {
-        // Read 16-bit big-endian length
-        let mut len_bytes = [0u8; 2];
-        self.stream
-            .read_exact(&mut len_bytes)
-            .await
-            .map_err(|e| SearpcError::TransportError(e.to_string()))?;
+        let mut data = Vec::new();
+        let mut frame_start = true;
+        loop {
+            // Read 16-bit big-endian length
+            let mut len_bytes = [0u8; 2];
+            async_transport::read_response(&mut self.stream, &mut len_bytes, frame_start).await?;
+            frame_start = false;
+            let len = u16::from_be_bytes(len_bytes) as usize;
 
-        let len = u16::from_be_bytes(len_bytes) as usize;
-
-        // Read data
-        let mut data = vec![0u8; len];
-        self.stream
-            .read_exact(&mut data)
-            .await
-            .map_err(|e| SearpcError::TransportError(e.to_string()))?;
+            // Read data
+            let start = data.len();
+            transport::check_response_len(start + len, self.max_response_size)?;
+            data.resize(start + len, 0);
+            if start == 0 {
+                let (head, rest) = data.split_at_mut(Framing::U16.peek_len(&len_bytes, len));
+                async_transport::read_response(&mut self.stream, head, false).await?;
+                Framing::U16.check_start(&len_bytes, head)?;
+                async_transport::read_response(&mut self.stream, rest, false).await?;
+            } else {
+                // Continuation frames carry the middle of the message
+                async_transport::read_response(&mut self.stream, &mut data[start..], false).await?;
+            }
+            if !self.chunked || len < MAX_FRAME_SIZE {
+                break;
+            }
+        }
 
+        if data.is_empty() {
+            return Err(SearpcError::transport(
+                "Received packet with zero length".to_string(),
+            ));
+        }
         Ok(data)
     }
 }
 
-#[cfg(feature = "async")]
+#[cfg(feature = "rt-tokio")]
 #[async_trait::async_trait]
 impl AsyncTransport for AsyncTcpTransport {
     async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
@@ -103,8 +195,135 @@ impl AsyncTransport for AsyncTcpTransport {
     }
 }
 
-#[cfg(all(test, feature = "async"))]
+#[cfg(feature = "rt-tokio")]
+#[async_trait::async_trait]
+impl AsyncPipelinedTransport for AsyncTcpTransport {
+    async fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.send_packet(request).await
+    }
+
+    async fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.recv_packet().await
+    }
+}
+
+#[cfg(all(test, feature = "rt-tokio"))]
 mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{Arg, AsyncSearpcClient, AsyncSearpcServer};
+    use serde_json::json;
+    use std::io;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_client_timeout() {
+        let mut server = AsyncSearpcServer::new();
+        server.register("ping", |_| async { Ok(json!("pong")) });
+        server.register("hang", |_| async {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            Ok(json!(null))
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let timeout = std::time::Duration::from_millis(50);
+        let mut client = AsyncSearpcClient::new(AsyncTcpTransport::connect(addr).await.unwrap());
+        assert_eq!(
+            client.call_with_timeout(timeout, "ping", []).await.unwrap(),
+            "pong"
+        );
+        client.set_timeout(Some(timeout));
+        let err = client.call_json("hang", []).await.unwrap_err();
+        assert!(
+            matches!(err.inner(), SearpcError::Timeout { timeout: t } if *t == timeout),
+            "{}",
+            err
+        );
+        assert_eq!(err.function(), Some("hang"));
+    }
+
+    /// Refuses its first `failures` calls, as while the daemon restarts
+    struct Flaky {
+        failures: u32,
+        inner: AsyncTcpTransport,
+    }
+
+    #[async_trait::async_trait]
+    impl AsyncTransport for Flaky {
+        async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+            }
+            self.inner.send(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_retry_policy() {
+        let mut server = AsyncSearpcServer::new();
+        server.register("ping", |_| async { Ok(json!("pong")) });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let inner = AsyncTcpTransport::connect(addr).await.unwrap();
+        let mut client = AsyncSearpcClient::new(Flaky { failures: 2, inner });
+        client.set_retry_policy(Some(crate::RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: std::time::Duration::from_millis(1),
+            ..Default::default()
+        }));
+        assert_eq!(client.call_json("ping", []).await.unwrap(), "pong");
+
+        // Out of attempts: the last error
+        let inner = AsyncTcpTransport::connect(addr).await.unwrap();
+        let mut client = AsyncSearpcClient::new(Flaky { failures: 3, inner });
+        client.set_retry_policy(Some(crate::RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: std::time::Duration::from_millis(1),
+            ..Default::default()
+        }));
+        let err = client.call_json("ping", []).await.unwrap_err();
+        assert_eq!(
+            err.io_error().map(io::Error::kind),
+            Some(io::ErrorKind::ConnectionRefused)
+        );
+    }
+
+    /// More calls than fit in the pipeline, one of them failing
+    #[tokio::test]
+    async fn test_call_pipelined() {
+        let mut server = AsyncSearpcServer::new();
+        server.register("double", |args| async move {
+            let n: i64 = arg(&args, 0)?;
+            Ok(json!(n * 2))
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(server).serve(listener));
+
+        let mut client = AsyncSearpcClient::new(AsyncTcpTransport::connect(addr).await.unwrap());
+        let calls = (0..100).map(|n| {
+            let fname = if n == 50 { "missing" } else { "double" };
+            (fname, [Arg::int64(n)])
+        });
+        let results = client.call_pipelined(calls).await.unwrap();
+        assert_eq!(results.len(), 100);
+        for (n, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => assert_eq!(value, json!(n * 2)),
+                Err(e) => {
+                    assert_eq!(n, 50);
+                    assert!(e.to_string().starts_with("missing"), "{}", e);
+                }
+            }
+        }
+        assert_eq!(client.call_int("double", [Arg::int(21)]).await.unwrap(), 42);
+    }
+
     #[test]
     fn test_packet_encoding() {
         // Test that packet length is encoded as big-endian
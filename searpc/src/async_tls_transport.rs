@@ -0,0 +1,191 @@
+//! Async TLS-secured TCP transport (tokio-rustls)
+//!
+//! The async counterpart of [`TlsTcpTransport`](crate::TlsTcpTransport),
+//! with the same framing choices. Configurations come from
+//! [`client_config`](crate::tls_transport::client_config) or are built
+//! with rustls directly, for custom root stores or client certificates.
+
+use crate::{
+    async_transport::{self, AsyncPipelinedTransport, AsyncTransport},
+    error::SearpcError,
+    tls_transport::framing,
+    Result,
+};
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::sync::Arc;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// Async TLS transport using the packet protocol
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use searpc::tls_transport::{client_config, rustls::RootCertStore};
+/// use searpc::{AsyncSearpcClient, AsyncTlsTransport};
+///
+/// let mut roots = RootCertStore::empty();
+/// # let ca_der = rustls::pki_types::CertificateDer::from(Vec::new());
+/// roots.add(ca_der)?;
+/// let config = client_config(roots)?;
+/// let transport = AsyncTlsTransport::connect("rpc.example.com:12345", "rpc.example.com", config)
+///     .await?
+///     .with_service("seafile-rpcserver");
+/// let mut client = AsyncSearpcClient::new(transport);
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncTlsTransport {
+    stream: TlsStream<TcpStream>,
+    /// Seafile framing for this service, or the 16-bit demo framing if `None`
+    service: Option<String>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+}
+
+impl AsyncTlsTransport {
+    pub fn new(stream: TlsStream<TcpStream>) -> Self {
+        AsyncTlsTransport {
+            stream,
+            service: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Connect to `addr` and complete the TLS handshake, verifying the
+    /// certificate against `server_name` (also sent as SNI)
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+            SearpcError::transport(format!("Invalid server name {:?}: {}", server_name, e))
+        })?;
+        let tcp = TcpStream::connect(addr)
+            .await
+            .map_err(|e| SearpcError::transport_io("connect", e))?;
+        let stream = TlsConnector::from(config)
+            .connect(server_name, tcp)
+            .await
+            .map_err(|e| SearpcError::transport_io("TLS handshake", e))?;
+        Ok(Self::new(stream))
+    }
+
+    /// Use the Seafile framing, sending requests to `service`
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    /// The underlying TLS stream
+    pub fn get_ref(&self) -> &TlsStream<TcpStream> {
+        &self.stream
+    }
+
+    /// Send a packet, header and body in a single write
+    async fn send_packet(&mut self, data: &[u8]) -> Result<()> {
+        let mut packet = std::mem::take(&mut self.buf);
+        let service = self.service.as_deref();
+        let mut result = framing(service).encode(service, data, &mut packet);
+        if result.is_ok() {
+            result = async_transport::write_request(&mut self.stream, &packet).await;
+        }
+        if result.is_ok() {
+            // TLS buffers records until flushed
+            result = async_transport::flush_request(&mut self.stream).await;
+        }
+        self.buf = packet;
+        result
+    }
+
+    /// Receive a packet
+    async fn recv_packet(&mut self) -> Result<Vec<u8>> {
+        let framing = framing(self.service.as_deref());
+        let mut header = [0u8; 4];
+        let header = &mut header[..framing.header_len()];
+        async_transport::read_response(&mut self.stream, header, true).await?;
+        let len = framing.body_len(header)?;
+        let mut data = vec![0u8; len];
+        let (start, rest) = data.split_at_mut(framing.peek_len(header, len));
+        async_transport::read_response(&mut self.stream, start, false).await?;
+        framing.check_start(header, start)?;
+        async_transport::read_response(&mut self.stream, rest, false).await?;
+        Ok(data)
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncTransport for AsyncTlsTransport {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.send_packet(request).await?;
+        self.recv_packet().await
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncPipelinedTransport for AsyncTlsTransport {
+    async fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.send_packet(request).await
+    }
+
+    async fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.recv_packet().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls_transport::client_config;
+    use crate::tls_transport::tests::{roots, serve_one, test_certs};
+    use crate::{Arg, AsyncSearpcClient};
+    use rustls::RootCertStore;
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        for seafile in [false, true] {
+            let (cert, server_config) = test_certs();
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = std::thread::spawn(move || serve_one(listener, server_config, seafile));
+
+            let config = client_config(roots(cert)).unwrap();
+            let mut transport = AsyncTlsTransport::connect(addr, "localhost", config)
+                .await
+                .unwrap();
+            if seafile {
+                transport = transport.with_service("test-service");
+            }
+            let mut client = AsyncSearpcClient::new(transport);
+            for s in ["hello", "searpc over tls"] {
+                let len = client
+                    .call_int("searpc_strlen", vec![Arg::string(s)])
+                    .await
+                    .unwrap();
+                assert_eq!(len as usize, s.len());
+            }
+            drop(client);
+            server.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_certificate() {
+        let (_, server_config) = test_certs();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve_one(listener, server_config, false));
+
+        let config = client_config(RootCertStore::empty()).unwrap();
+        let err = AsyncTlsTransport::connect(addr, "localhost", config)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("TLS handshake"), "{}", err);
+    }
+}
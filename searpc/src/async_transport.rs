@@ -1,16 +1,22 @@
 //! Async transport layer for Searpc RPC
 //!
-//! This module provides async versions of transports using tokio.
+//! The traits need no runtime. The helpers here serve the tokio
+//! transports; `FuturesTransport` is the one for other runtimes.
 
-#[cfg(feature = "async")]
 use crate::Result;
+#[cfg(any(feature = "rt-tokio", feature = "rt-futures-io"))]
+use crate::{error::SearpcError, transport::is_disconnect};
+#[cfg(feature = "rt-tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Async transport trait for sending/receiving RPC packets
 ///
 /// Similar to the sync [`Transport`](crate::Transport) trait,
-/// but all methods are async.
-#[cfg(feature = "async")]
-#[async_trait::async_trait]
+/// but all methods are async. On wasm32 the futures need not be `Send`,
+/// as the browser's are not.
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
 pub trait AsyncTransport {
     /// Send a request and receive a response
     ///
@@ -18,3 +24,109 @@ pub trait AsyncTransport {
     /// It sends the request bytes and returns the response bytes.
     async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>>;
 }
+
+/// Boxed transport, to pick TCP or Unix socket at runtime:
+/// `AsyncSearpcClient<Box<dyn AsyncTransport + Send>>`
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl<T: AsyncTransport + Send + ?Sized> AsyncTransport for Box<T> {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        (**self).send(request).await
+    }
+}
+
+/// Async transport whose requests and responses can be handled apart, so
+/// several requests can be in flight on one connection
+///
+/// The server answers in request order, so each response belongs to the
+/// oldest request still unanswered. See
+/// [`AsyncSearpcClient::call_pipelined`](crate::AsyncSearpcClient::call_pipelined).
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+pub trait AsyncPipelinedTransport: AsyncTransport {
+    /// Send a request without waiting for its response
+    async fn send_request(&mut self, request: &[u8]) -> Result<()>;
+
+    /// Receive the response to the oldest request still unanswered
+    async fn recv_response(&mut self) -> Result<Vec<u8>>;
+}
+
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl<T: AsyncPipelinedTransport + Send + ?Sized> AsyncPipelinedTransport for Box<T> {
+    async fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        (**self).send_request(request).await
+    }
+
+    async fn recv_response(&mut self) -> Result<Vec<u8>> {
+        (**self).recv_response().await
+    }
+}
+
+/// Read exactly `buf.len()` bytes of a response
+///
+/// Async counterpart of the sync transports' EOF handling: a close before
+/// the first byte of a frame is clean, anything later is mid-frame.
+#[cfg(feature = "rt-tokio")]
+pub(crate) async fn read_response<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+    frame_start: bool,
+) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await {
+            Ok(0) => {
+                return Err(SearpcError::ConnectionClosed {
+                    request_sent: true,
+                    mid_frame: !frame_start || filled > 0,
+                })
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) if is_disconnect(e.kind()) => {
+                return Err(SearpcError::ConnectionClosed {
+                    request_sent: true,
+                    mid_frame: !frame_start || filled > 0,
+                })
+            }
+            Err(e) => {
+                return Err(SearpcError::TransportError {
+                    message: e.to_string(),
+                    source: Some(e),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write part of a request, reporting a vanished peer as [`SearpcError::ConnectionClosed`]
+#[cfg(feature = "rt-tokio")]
+pub(crate) async fn write_request<W: AsyncWrite + Unpin>(writer: &mut W, buf: &[u8]) -> Result<()> {
+    writer.write_all(buf).await.map_err(write_error)
+}
+
+/// Flush a request written with [`write_request`], for buffering writers
+#[cfg(feature = "rt-tokio")]
+pub(crate) async fn flush_request<W: AsyncWrite + Unpin>(writer: &mut W) -> Result<()> {
+    writer.flush().await.map_err(write_error)
+}
+
+#[cfg(any(feature = "rt-tokio", feature = "rt-futures-io"))]
+pub(crate) fn write_error(e: std::io::Error) -> SearpcError {
+    if is_disconnect(e.kind()) || e.kind() == std::io::ErrorKind::WriteZero {
+        SearpcError::ConnectionClosed {
+            request_sent: false,
+            mid_frame: false,
+        }
+    } else {
+        SearpcError::TransportError {
+            message: e.to_string(),
+            source: Some(e),
+        }
+    }
+}
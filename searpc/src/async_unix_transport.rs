@@ -0,0 +1,373 @@
+//! Async Unix Domain Socket transport (32-bit header, service envelope)
+//!
+//! The async counterpart of [`UnixSocketTransport`](crate::UnixSocketTransport),
+//! speaking the same protocol as Seafile's daemon over tokio.
+
+#[cfg(feature = "rt-tokio")]
+use crate::{
+    async_transport::{self, AsyncPipelinedTransport, AsyncTransport},
+    error::SearpcError,
+    retry::RetryPolicy,
+    transport::{self, wrap_request, Endianness, Framing, DEFAULT_BUFFER_SIZE},
+    unix_connect, Result,
+};
+#[cfg(feature = "rt-tokio")]
+use std::io;
+#[cfg(feature = "rt-tokio")]
+use std::path::Path;
+#[cfg(feature = "rt-tokio")]
+use std::time::Duration;
+#[cfg(feature = "rt-tokio")]
+use tokio::io::BufReader;
+#[cfg(feature = "rt-tokio")]
+use tokio::net::UnixStream;
+
+/// Async Unix Domain Socket transport
+///
+/// Uses the 32-bit native-endian length header and wraps each request in
+/// `{"service": ..., "request": ...}`, like [`UnixSocketTransport`](crate::UnixSocketTransport).
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use searpc::{AsyncSearpcClient, AsyncUnixSocketTransport};
+///
+/// let transport =
+///     AsyncUnixSocketTransport::connect("/path/to/seafile.sock", "seafile-rpcserver").await?;
+/// let mut client = AsyncSearpcClient::new(transport);
+///
+/// let version = client.call_string("seafile_get_version", vec![]).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "rt-tokio")]
+pub struct AsyncUnixSocketTransport {
+    /// Buffered, so a response's header and body usually take one read
+    stream: BufReader<UnixStream>,
+    service: String,
+    endianness: Endianness,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+    max_response_size: usize,
+}
+
+#[cfg(feature = "rt-tokio")]
+impl AsyncUnixSocketTransport {
+    pub fn new(stream: UnixStream, service: impl Into<String>) -> Self {
+        Self::with_capacity(stream, service, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Use buffers of `capacity` bytes from the start, for high call rates
+    ///
+    /// Responses up to that size are read in one go, and requests up to
+    /// that size are assembled without reallocating.
+    pub fn with_capacity(stream: UnixStream, service: impl Into<String>, capacity: usize) -> Self {
+        AsyncUnixSocketTransport {
+            stream: BufReader::with_capacity(capacity, stream),
+            service: service.into(),
+            endianness: Endianness::Native,
+            buf: Vec::with_capacity(capacity),
+            max_response_size: transport::DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    /// Byte order of the length header, native by default
+    pub fn with_endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Longest response to accept, see
+    /// [`UnixSocketTransport::with_max_response_size`](crate::UnixSocketTransport::with_max_response_size)
+    pub fn with_max_response_size(mut self, max: usize) -> Self {
+        self.max_response_size = max;
+        self
+    }
+
+    /// Connect to the socket at `path`, sending requests to `service`
+    pub async fn connect(path: impl AsRef<Path>, service: impl Into<String>) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .map_err(|e| SearpcError::TransportError {
+                message: e.to_string(),
+                source: Some(e),
+            })?;
+        Ok(Self::new(stream, service))
+    }
+
+    /// Connect, waiting for a daemon that is not listening yet according
+    /// to `policy`
+    pub async fn connect_with_retry(
+        path: impl AsRef<Path>,
+        service: impl Into<String>,
+        policy: RetryPolicy,
+    ) -> Result<Self> {
+        let stream = policy
+            .connect_async(|| UnixStream::connect(path.as_ref()))
+            .await
+            .map_err(|e| SearpcError::TransportError {
+                message: e.to_string(),
+                source: Some(e),
+            })?;
+        Ok(Self::new(stream, service))
+    }
+
+    /// Connect, giving up after `timeout`
+    ///
+    /// Like [`UnixSocketTransport::connect_timeout`](crate::UnixSocketTransport::connect_timeout),
+    /// a full listen backlog does not hold the connect up past `timeout`.
+    pub async fn connect_timeout(
+        path: impl AsRef<Path>,
+        service: impl Into<String>,
+        timeout: Duration,
+    ) -> Result<Self> {
+        // Linux refuses a connect to a full backlog with EAGAIN rather than
+        // queueing it, so there is nothing to wait on: retry until the
+        // timeout, as the blocking connect does
+        let connect = async {
+            loop {
+                match UnixStream::connect(path.as_ref()).await {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        tokio::time::sleep(unix_connect::BACKLOG_RETRY).await;
+                    }
+                    result => return result,
+                }
+            }
+        };
+        let stream = tokio::time::timeout(timeout, connect)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Unix socket connect timed out",
+                ))
+            })
+            .map_err(|e| SearpcError::TransportError {
+                message: e.to_string(),
+                source: Some(e),
+            })?;
+        Ok(Self::new(stream, service))
+    }
+
+    /// Send a packet with service wrapper, in a single write
+    async fn send_packet(&mut self, rpc_request: &[u8]) -> Result<()> {
+        let mut packet = std::mem::take(&mut self.buf);
+        packet.clear();
+        packet.extend_from_slice(&[0; 4]);
+        let mut result = wrap_request(&self.service, rpc_request, &mut packet).and_then(|()| {
+            let len = u32::try_from(packet.len() - 4)
+                .map_err(|_| SearpcError::transport("Request too large for 32-bit header"))?;
+            // Native endian by default - matches C code using guint32
+            packet[..4].copy_from_slice(&self.endianness.encode(len));
+            Ok(())
+        });
+        if result.is_ok() {
+            result = async_transport::write_request(self.stream.get_mut(), &packet).await;
+        }
+        self.buf = packet;
+        result
+    }
+
+    /// Receive a packet
+    async fn recv_packet(&mut self) -> Result<Vec<u8>> {
+        let mut len_buf = [0u8; 4];
+        async_transport::read_response(&mut self.stream, &mut len_buf, true).await?;
+        let len = self.endianness.decode(len_buf) as usize;
+
+        if len == 0 {
+            return Err(SearpcError::transport(
+                "Received packet with zero length".to_string(),
+            ));
+        }
+        // A 16-bit response has its `{"` in our header: say so, rather
+        // than only that the length is too large
+        if len > self.max_response_size {
+            Framing::U32(self.endianness).check_start(&len_buf, &[])?;
+        }
+        transport::check_response_len(len, self.max_response_size)?;
+
+        let mut data = vec![0u8; len];
+        async_transport::read_response(&mut self.stream, &mut data, false).await?;
+        Ok(data)
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+#[async_trait::async_trait]
+impl AsyncTransport for AsyncUnixSocketTransport {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.send_packet(request).await?;
+        self.recv_packet().await
+    }
+}
+
+#[cfg(feature = "rt-tokio")]
+#[async_trait::async_trait]
+impl AsyncPipelinedTransport for AsyncUnixSocketTransport {
+    async fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.send_packet(request).await
+    }
+
+    async fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.recv_packet().await
+    }
+}
+
+#[cfg(all(test, feature = "rt-tokio"))]
+mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{AsyncSearpcClient, SearpcServer, UnixSocketServer};
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_send() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let peer = tokio::spawn(async move {
+            let mut len = [0u8; 4];
+            theirs.read_exact(&mut len).await.unwrap();
+            let mut body = vec![0u8; u32::from_ne_bytes(len) as usize];
+            theirs.read_exact(&mut body).await.unwrap();
+            let envelope: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+            let response = br#"{"ret":"9.0.0"}"#;
+            theirs
+                .write_all(&(response.len() as u32).to_ne_bytes())
+                .await
+                .unwrap();
+            theirs.write_all(response).await.unwrap();
+            envelope
+        });
+
+        let mut transport = AsyncUnixSocketTransport::new(ours, "test-service");
+        let response = transport.send(br#"["get_version"]"#).await.unwrap();
+        assert_eq!(response, br#"{"ret":"9.0.0"}"#);
+
+        let envelope = peer.await.unwrap();
+        assert_eq!(envelope["service"], "test-service");
+        assert_eq!(envelope["request"], r#"["get_version"]"#);
+
+        // The peer hung up after answering
+        let err = transport.send(br#"["get_version"]"#).await.unwrap_err();
+        assert!(err.is_connection_closed());
+    }
+
+    #[tokio::test]
+    async fn test_endianness() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let peer = tokio::spawn(async move {
+            let len = theirs.read_u32_le().await.unwrap();
+            let mut body = vec![0u8; len as usize];
+            theirs.read_exact(&mut body).await.unwrap();
+            let response = br#"{"ret":1}"#;
+            theirs.write_u32_le(response.len() as u32).await.unwrap();
+            theirs.write_all(response).await.unwrap();
+        });
+
+        let mut transport =
+            AsyncUnixSocketTransport::new(ours, "test-service").with_endianness(Endianness::Little);
+        let response = transport.send(br#"["ping"]"#).await.unwrap();
+        assert_eq!(response, br#"{"ret":1}"#);
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_boxed_transport() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut rpc = SearpcServer::new();
+        rpc.register("ping", |_| Ok(json!("pong")));
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc);
+        std::thread::spawn(move || Arc::new(server).serve_connection(theirs));
+
+        ours.set_nonblocking(true).unwrap();
+        let transport: Box<dyn AsyncTransport + Send> = Box::new(AsyncUnixSocketTransport::new(
+            UnixStream::from_std(ours).unwrap(),
+            "test-service",
+        ));
+        let mut client = AsyncSearpcClient::new(transport);
+        assert_eq!(client.call_string("ping", vec![]).await.unwrap(), "pong");
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        let dir =
+            std::env::temp_dir().join(format!("searpc-async-unix-timeout-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+        let timeout = Duration::from_millis(30);
+
+        let err = AsyncUnixSocketTransport::connect_timeout(&path, "test-service", timeout)
+            .await
+            .err()
+            .unwrap();
+        assert!(!err.is_timeout(), "{}", err);
+
+        // Connects while there is room in the backlog, then times out
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        #[cfg(target_os = "linux")]
+        {
+            let mut connected = Vec::new();
+            let err = loop {
+                let transport =
+                    AsyncUnixSocketTransport::connect_timeout(&path, "test-service", timeout);
+                match transport.await {
+                    Ok(transport) => connected.push(transport),
+                    Err(e) => break e,
+                }
+                assert!(connected.len() < 10_000, "backlog never filled");
+            };
+            assert!(err.is_timeout(), "{}", err);
+            assert!(!connected.is_empty());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unix_server() {
+        let dir = std::env::temp_dir().join(format!(
+            "searpc-async-unix-transport-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+        let mut rpc = SearpcServer::new();
+        rpc.register("searpc_strlen", |args| {
+            let s: String = arg(args, 0)?;
+            Ok(json!(s.len()))
+        });
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc);
+
+        // Connecting before the daemon listens
+        let bind_path = path.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let listener = std::os::unix::net::UnixListener::bind(bind_path).unwrap();
+            Arc::new(server).serve(listener)
+        });
+        let policy = RetryPolicy {
+            max_attempts: 50,
+            initial_backoff: std::time::Duration::from_millis(5),
+            max_backoff: std::time::Duration::from_millis(20),
+            ..RetryPolicy::default()
+        };
+        let transport = AsyncUnixSocketTransport::connect_with_retry(&path, "test-service", policy)
+            .await
+            .unwrap();
+        let mut client = AsyncSearpcClient::new(transport);
+        for s in ["hello", "searpc"] {
+            let len = client
+                .call_int("searpc_strlen", vec![crate::Arg::string(s)])
+                .await
+                .unwrap();
+            assert_eq!(len as usize, s.len());
+        }
+    }
+}
@@ -1,104 +1,401 @@
 use crate::error::{Result, SearpcError};
-use crate::protocol::{RpcRequest, RpcResponse};
-use crate::transport::Transport;
+use crate::protocol::{self, ObjlistIter, RpcRequest, RpcResponse};
+use crate::reconnect::ReconnectingTransport;
+use crate::retry::RetryPolicy;
+use crate::transport::{PipelinedTransport, Transport};
 use crate::types::Arg;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use tracing::debug;
 
+/// Most requests [`SearpcClient::call_batch`] and
+/// `AsyncSearpcClient::call_pipelined` have in flight
+///
+/// Past it, responses are read before more requests go out, so that a
+/// server blocked writing responses nobody reads cannot stall the client
+/// writing requests nobody reads.
+pub const PIPELINE_DEPTH: usize = 32;
+
+/// Hook run on each call before it is sent, see
+/// [`SearpcClient::intercept_request`]
+pub type RequestHook = Box<dyn FnMut(&mut RpcRequest) -> Result<()> + Send + Sync>;
+
+/// Hook run on each call once it is answered, see
+/// [`SearpcClient::intercept_response`]
+pub type ResponseHook = Box<dyn FnMut(&RpcRequest, &Result<Vec<u8>>, Duration) + Send + Sync>;
+
 /// Searpc RPC Client
 ///
 /// Good taste: simple struct, single responsibility
+///
+/// Calls take their arguments as a `Vec`, array or slice of [`Arg`], so hot
+/// loops can reuse one argument list; the request buffer is reused too.
 pub struct SearpcClient<T: Transport> {
     transport: T,
+    /// Request buffer, reused across calls
+    buf: Vec<u8>,
+    /// Set on the transport, to report its timeouts as such
+    timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
+    request_hooks: Vec<RequestHook>,
+    response_hooks: Vec<ResponseHook>,
 }
 
 impl<T: Transport> SearpcClient<T> {
     pub fn new(transport: T) -> Self {
-        SearpcClient { transport }
+        SearpcClient {
+            transport,
+            buf: Vec::new(),
+            timeout: None,
+            retry: None,
+            request_hooks: Vec::new(),
+            response_hooks: Vec::new(),
+        }
+    }
+
+    /// Client that connects with `connect` on its first call, and connects
+    /// anew once a connection breaks
+    ///
+    /// A call that finds the connection closed before its request went out,
+    /// as after a daemon restart, is sent once more on a new connection.
+    /// Other broken calls still fail, as the daemon may have run them, but
+    /// the next call reconnects. See [`ReconnectingTransport`], and
+    /// [`set_retry_policy`](Self::set_retry_policy) to wait for the daemon
+    /// to come back.
+    pub fn reconnecting<F>(connect: F) -> SearpcClient<ReconnectingTransport<T>>
+    where
+        F: FnMut() -> Result<T> + Send + 'static,
+    {
+        SearpcClient::new(ReconnectingTransport::new(connect))
+    }
+
+    /// The transport, e.g. for its [`peer_addr`](Transport::peer_addr)
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Fail calls the daemon does not answer within `timeout` with
+    /// [`SearpcError::Timeout`]; `None` (the default) waits forever
+    ///
+    /// Set with [`Transport::set_timeout`], which fails for transports that
+    /// cannot bound calls. The socket transports bound the whole exchange,
+    /// so a daemon trickling its response cannot hold a call up; each retry
+    /// gets the full timeout again. Discard the connection after a
+    /// timed-out call, as the late response would be read as the next
+    /// call's.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.transport.set_timeout(timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Retry calls that fail as `policy` says; `None` (the default) fails
+    /// at once
+    ///
+    /// Only the transport is retried: errors the daemon answers with are
+    /// returned as they are. A failed connection stays failed unless the
+    /// transport connects anew, as with [`reconnecting`](Self::reconnecting),
+    /// so this is how to ride out a daemon restart.
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry = policy;
+    }
+
+    /// Run `hook` on every call before it is sent
+    ///
+    /// Hooks run in the order they were added. They may rewrite the
+    /// function name and arguments, e.g. to inject an auth token, or fail
+    /// the call before anything is sent. Errors still name the function as
+    /// the caller did.
+    pub fn intercept_request<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: FnMut(&mut RpcRequest) -> Result<()> + Send + Sync + 'static,
+    {
+        self.request_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Run `hook` on every sent call with the request as sent, the raw
+    /// response or transport error, and how long the exchange took
+    ///
+    /// For logging and latency measurement; errors in the response itself
+    /// are only found when it is decoded, after the hooks.
+    pub fn intercept_response<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: FnMut(&RpcRequest, &Result<Vec<u8>>, Duration) + Send + Sync + 'static,
+    {
+        self.response_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// [`call`](Self::call) with a timeout for this call only, see
+    /// [`set_timeout`](Self::set_timeout)
+    pub fn call_with_timeout(
+        &mut self,
+        timeout: Duration,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<Value> {
+        let previous = self.timeout;
+        self.set_timeout(Some(timeout))?;
+        let result = self.call(function_name, args);
+        // The call's own error comes first
+        let restored = self.set_timeout(previous);
+        let value = result?;
+        restored?;
+        Ok(value)
     }
 
     /// Low-level call: returns raw JSON Value
-    pub fn call(&mut self, function_name: &str, args: Vec<Arg>) -> Result<Value> {
-        // 1. Create request
-        let request = RpcRequest::with_args(function_name, args);
-        let request_json = request.to_json()?;
-        debug!("RPC request: {}", request_json);
+    pub fn call(&mut self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.call_map(function_name, args.as_ref(), Ok)
+    }
 
-        // 2. Send via transport
-        let response_bytes = self.transport.send(request_json.as_bytes())?;
+    /// Make a call and convert its result
+    ///
+    /// Any error, from the transport, the server or `convert`, is tagged with
+    /// the function name and an argument summary.
+    fn call_map<R>(
+        &mut self,
+        function_name: &str,
+        args: &[Arg],
+        convert: impl FnOnce(Value) -> Result<R>,
+    ) -> Result<R> {
+        self.call_bytes(function_name, args, |mut bytes| {
+            // Check for errors and return result
+            RpcResponse::from_bytes(&mut bytes)?
+                .into_result()
+                .and_then(convert)
+        })
+    }
 
-        // 3. Parse response
-        let response_str = std::str::from_utf8(&response_bytes).map_err(|e| {
-            SearpcError::InvalidResponse(format!("Response is not valid UTF-8: {}", e))
-        })?;
-        debug!("RPC response: {}", response_str);
+    /// Make a call and decode the raw response with `decode`
+    fn call_bytes<R>(
+        &mut self,
+        function_name: &str,
+        args: &[Arg],
+        decode: impl FnOnce(Vec<u8>) -> Result<R>,
+    ) -> Result<R> {
+        self.exchange(function_name, args)
+            .and_then(decode)
+            .map_err(|e| {
+                SearpcError::in_call(function_name, Some(protocol::summarize_args(args)), e)
+            })
+    }
 
-        let response = RpcResponse::from_json(response_str)?;
+    /// Encode and send a call, through the hooks if there are any
+    fn exchange(&mut self, function_name: &str, args: &[Arg]) -> Result<Vec<u8>> {
+        if self.request_hooks.is_empty() && self.response_hooks.is_empty() {
+            RpcRequest::encode(function_name, args, &mut self.buf)?;
+            return self.send_request();
+        }
+        let mut request = RpcRequest::with_args(function_name, args.to_vec());
+        for hook in &mut self.request_hooks {
+            hook(&mut request)?;
+        }
+        let start = Instant::now();
+        let result = request
+            .write_json(&mut self.buf)
+            .and_then(|()| self.send_request());
+        let elapsed = start.elapsed();
+        for hook in &mut self.response_hooks {
+            hook(&request, &result, elapsed);
+        }
+        result
+    }
 
-        // 4. Check for errors and return result
-        response.into_result()
+    /// Send the request in `self.buf` and return the raw response
+    fn send_request(&mut self) -> Result<Vec<u8>> {
+        debug!("RPC request: {}", String::from_utf8_lossy(&self.buf));
+        let mut send = || {
+            self.transport
+                .send(&self.buf)
+                .map_err(|e| timed_out(self.timeout, e))
+        };
+        let response_bytes = match self.retry {
+            Some(policy) => policy.call(send)?,
+            None => send()?,
+        };
+        debug!("RPC response: {}", String::from_utf8_lossy(&response_bytes));
+        Ok(response_bytes)
     }
 
     /// Call function expecting int return type
-    pub fn call_int(&mut self, function_name: &str, args: Vec<Arg>) -> Result<i32> {
-        let value = self.call(function_name, args)?;
-        value
-            .as_i64()
-            .and_then(|v| i32::try_from(v).ok())
-            .ok_or_else(|| SearpcError::TypeError(format!("Expected int, got: {:?}", value)))
+    pub fn call_int(&mut self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<i32> {
+        self.call_map(function_name, args.as_ref(), |value| {
+            value
+                .as_i64()
+                .and_then(|v| i32::try_from(v).ok())
+                .ok_or_else(|| SearpcError::TypeError(format!("Expected int, got: {:?}", value)))
+        })
     }
 
     /// Call function expecting int64 return type
-    pub fn call_int64(&mut self, function_name: &str, args: Vec<Arg>) -> Result<i64> {
-        let value = self.call(function_name, args)?;
-        value
-            .as_i64()
-            .ok_or_else(|| SearpcError::TypeError(format!("Expected int64, got: {:?}", value)))
+    pub fn call_int64(&mut self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<i64> {
+        self.call_map(function_name, args.as_ref(), |value| {
+            value
+                .as_i64()
+                .ok_or_else(|| SearpcError::TypeError(format!("Expected int64, got: {:?}", value)))
+        })
     }
 
     /// Call function expecting string return type
-    pub fn call_string(&mut self, function_name: &str, args: Vec<Arg>) -> Result<String> {
-        let value = self.call(function_name, args)?;
-        value
-            .as_str()
-            .map(|s| s.to_string())
-            .ok_or_else(|| SearpcError::TypeError(format!("Expected string, got: {:?}", value)))
+    pub fn call_string(&mut self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<String> {
+        self.call_map(function_name, args.as_ref(), |value| {
+            value
+                .as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| SearpcError::TypeError(format!("Expected string, got: {:?}", value)))
+        })
     }
 
     /// Call function expecting object return type (returns JSON Value)
-    pub fn call_object(&mut self, function_name: &str, args: Vec<Arg>) -> Result<Value> {
-        let value = self.call(function_name, args)?;
-        if value.is_object() || value.is_null() {
-            Ok(value)
-        } else {
-            Err(SearpcError::TypeError(format!(
-                "Expected object, got: {:?}",
-                value
-            )))
-        }
+    pub fn call_object(&mut self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.call_map(function_name, args.as_ref(), |value| {
+            if value.is_object() || value.is_null() {
+                Ok(value)
+            } else {
+                Err(SearpcError::TypeError(format!(
+                    "Expected object, got: {:?}",
+                    value
+                )))
+            }
+        })
     }
 
     /// Call function expecting objlist return type (returns Vec of JSON Values)
-    pub fn call_objlist(&mut self, function_name: &str, args: Vec<Arg>) -> Result<Vec<Value>> {
-        let value = self.call(function_name, args)?;
+    pub fn call_objlist(
+        &mut self,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<Vec<Value>> {
+        self.call_map(function_name, args.as_ref(), |value| match value {
+            // Move the array out: listings can hold tens of thousands of entries
+            Value::Array(items) => Ok(items),
+            // Handle null as empty array (Seafile daemon returns null for empty lists)
+            Value::Null => Ok(Vec::new()),
+            other => Err(SearpcError::TypeError(format!(
+                "Expected array, got: {:?}",
+                other
+            ))),
+        })
+    }
 
-        // Handle null as empty array (Seafile daemon returns null for empty lists)
-        if value.is_null() {
-            return Ok(Vec::new());
-        }
+    /// Call function expecting objlist return type, deserializing each
+    /// element into `T`
+    ///
+    /// Elements are decoded lazily from the response buffer, without an
+    /// intermediate `Vec<Value>`, which roughly halves peak memory for big
+    /// listings. Errors for individual elements come from the iterator and
+    /// are not tagged with the function name.
+    pub fn call_objlist_typed<R: DeserializeOwned>(
+        &mut self,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<ObjlistIter<R>> {
+        self.call_bytes(function_name, args.as_ref(), ObjlistIter::from_bytes)
+    }
 
-        value
-            .as_array()
-            .cloned()
-            .ok_or_else(|| SearpcError::TypeError(format!("Expected array, got: {:?}", value)))
+    /// Call function deserializing its result into `R`
+    ///
+    /// For any serde type, such as a struct for an object result. A result
+    /// that does not fit `R` fails with [`SearpcError::TypeError`].
+    pub fn call_as<R: DeserializeOwned>(
+        &mut self,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<R> {
+        self.call_map(function_name, args.as_ref(), protocol::from_value)
+    }
+
+    /// Call function whose result is of no interest, like the setters
+    /// returning a meaningless int
+    ///
+    /// Errors from the server still fail the call; `ret` is dropped
+    /// whatever its type.
+    pub fn call_void(&mut self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<()> {
+        self.call_map(function_name, args.as_ref(), |_| Ok(()))
     }
 
     /// Call function expecting JSON return type
-    pub fn call_json(&mut self, function_name: &str, args: Vec<Arg>) -> Result<Value> {
+    pub fn call_json(&mut self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
         self.call(function_name, args)
     }
 }
 
+impl<T: PipelinedTransport> SearpcClient<T> {
+    /// Make several calls on one connection, sending requests without
+    /// waiting for the responses to earlier ones
+    ///
+    /// Saves a round trip per call, e.g. when asking the sync status of
+    /// many repos. Results come in the order of `requests`, each as
+    /// [`call`](Self::call) would return it. The hooks run on each call;
+    /// retries do not. A transport error fails the whole batch instead: the
+    /// connection is unusable after it.
+    pub fn call_batch(
+        &mut self,
+        requests: impl IntoIterator<Item = RpcRequest>,
+    ) -> Result<Vec<Result<Value>>> {
+        let requests: Vec<_> = requests.into_iter().collect();
+        let in_call = |i: usize, e| {
+            let request: &RpcRequest = &requests[i];
+            let args = protocol::summarize_args(&request.args);
+            SearpcError::in_call(&request.function_name, Some(args), e)
+        };
+        let mut results: Vec<Option<Result<Value>>> = requests.iter().map(|_| None).collect();
+        let mut in_flight = VecDeque::new();
+        let mut next = 0;
+        while next < requests.len() || !in_flight.is_empty() {
+            if next < requests.len() && in_flight.len() < PIPELINE_DEPTH {
+                let mut request = Cow::Borrowed(&requests[next]);
+                let encoded = self
+                    .request_hooks
+                    .iter_mut()
+                    .try_for_each(|hook| hook(request.to_mut()))
+                    .and_then(|()| request.write_json(&mut self.buf));
+                match encoded {
+                    Ok(()) => {
+                        debug!("RPC request: {}", String::from_utf8_lossy(&self.buf));
+                        let sent = self.transport.send_request(&self.buf);
+                        sent.map_err(|e| in_call(next, timed_out(self.timeout, e)))?;
+                        in_flight.push_back((next, request, Instant::now()));
+                    }
+                    Err(e) => results[next] = Some(Err(in_call(next, e))),
+                }
+                next += 1;
+                continue;
+            }
+            let Some((i, request, start)) = in_flight.pop_front() else {
+                break;
+            };
+            let received = self
+                .transport
+                .recv_response()
+                .map_err(|e| timed_out(self.timeout, e));
+            let elapsed = start.elapsed();
+            for hook in &mut self.response_hooks {
+                hook(&request, &received, elapsed);
+            }
+            let mut bytes = received.map_err(|e| in_call(i, e))?;
+            debug!("RPC response: {}", String::from_utf8_lossy(&bytes));
+            let result = RpcResponse::from_bytes(&mut bytes).and_then(RpcResponse::into_result);
+            results[i] = Some(result.map_err(|e| in_call(i, e)));
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+/// `e`, reported as [`SearpcError::Timeout`] if it is the client's
+/// `timeout` that cut the call short
+fn timed_out(timeout: Option<Duration>, e: SearpcError) -> SearpcError {
+    match timeout {
+        Some(timeout) if e.is_timeout() => SearpcError::Timeout { timeout },
+        _ => e,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -125,6 +422,18 @@ mod tests {
         assert_eq!(result, 5);
     }
 
+    #[test]
+    fn test_reused_args() {
+        let transport = mock_transport(r#"["searpc_strlen","hello"]"#, r#"{"ret": 5}"#);
+
+        let mut client = SearpcClient::new(transport);
+        let args = [Arg::string("hello")];
+        for _ in 0..3 {
+            assert_eq!(client.call_int("searpc_strlen", &args).unwrap(), 5);
+        }
+        assert_eq!(client.call_int("searpc_strlen", &args[..]).unwrap(), 5);
+    }
+
     #[test]
     fn test_call_string() {
         let transport = mock_transport(r#"["get_version"]"#, r#"{"ret": "1.0.0"}"#);
@@ -144,12 +453,334 @@ mod tests {
         let mut client = SearpcClient::new(transport);
         let result = client.call_int("bad_func", vec![]);
 
-        match result {
-            Err(SearpcError::RpcError { code, message }) => {
-                assert_eq!(code, 404);
+        let err = result.unwrap_err();
+        assert_eq!(err.function(), Some("bad_func"));
+        match err.inner() {
+            SearpcError::RpcError { code, message } => {
+                assert_eq!(*code, 404);
                 assert_eq!(message, "Function not found");
             }
             _ => panic!("Expected RpcError"),
         }
     }
+
+    #[test]
+    fn test_call_void() {
+        for response in [
+            r#"{"ret": 0}"#,
+            r#"{"ret": "ok"}"#,
+            r#"{"ret": null}"#,
+            "{}",
+        ] {
+            let transport = mock_transport(r#"["set_config","k","v"]"#, response);
+            let mut client = SearpcClient::new(transport);
+            client
+                .call_void("set_config", ["k".into(), "v".into()])
+                .unwrap();
+        }
+
+        let transport = mock_transport(
+            r#"["set_config"]"#,
+            r#"{"ret": 0, "err_code": 500, "err_msg": "Bad key"}"#,
+        );
+        let err = SearpcClient::new(transport)
+            .call_void("set_config", [])
+            .unwrap_err();
+        assert_eq!(err.inner().err_code(), 500);
+    }
+
+    #[test]
+    fn test_call_as() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Task {
+            state: String,
+            rate: u64,
+        }
+
+        let transport = mock_transport(
+            r#"["get_task","a"]"#,
+            r#"{"ret": {"state": "syncing", "rate": 42}}"#,
+        );
+        let task: Task = SearpcClient::new(transport)
+            .call_as("get_task", ["a".into()])
+            .unwrap();
+        assert_eq!(
+            task,
+            Task {
+                state: "syncing".to_string(),
+                rate: 42
+            }
+        );
+
+        let transport = mock_transport(r#"["get_ratio"]"#, r#"{"ret": [0.5, null]}"#);
+        let mut client = SearpcClient::new(transport);
+        let ratio: (f64, Option<f64>) = client.call_as("get_ratio", []).unwrap();
+        assert_eq!(ratio, (0.5, None));
+
+        let transport = mock_transport(r#"["get_task","a"]"#, r#"{"ret": "idle"}"#);
+        let err = SearpcClient::new(transport)
+            .call_as::<Task>("get_task", ["a".into()])
+            .unwrap_err();
+        assert_eq!(err.function(), Some("get_task"));
+        assert!(matches!(err.inner(), SearpcError::TypeError(_)), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout() {
+        let (ours, _theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let transport = crate::UnixSocketTransport::new(ours, "test-service");
+        let mut client = SearpcClient::new(transport);
+        let timeout = Duration::from_millis(50);
+        let err = client
+            .call_with_timeout(timeout, "get_version", [])
+            .unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+        assert_eq!(err.to_string(), "get_version(): Call timed out after 50ms");
+
+        // Without a timeout of its own, the transport's is reported as is
+        client.transport.set_timeout(Some(timeout)).unwrap();
+        let err = client.call("get_version", []).unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+        assert!(matches!(err.inner(), SearpcError::TransportError { .. }));
+
+        // Closures cannot bound calls
+        let transport = mock_transport(r#"["get_version"]"#, r#"{"ret": "1.0.0"}"#);
+        let err = SearpcClient::new(transport)
+            .set_timeout(Some(timeout))
+            .unwrap_err();
+        assert!(err.to_string().contains("timeouts"), "{}", err);
+    }
+
+    /// The timeout bounds the whole exchange, not each read
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout_trickle() {
+        use std::io::{Read, Write};
+
+        let (ours, mut theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut len = [0u8; 4];
+            theirs.read_exact(&mut len).unwrap();
+            let mut request = vec![0u8; u32::from_ne_bytes(len) as usize];
+            theirs.read_exact(&mut request).unwrap();
+            let response = format!(r#"{{"ret":"{}"}}"#, "x".repeat(100));
+            theirs
+                .write_all(&(response.len() as u32).to_ne_bytes())
+                .unwrap();
+            for byte in response.bytes() {
+                std::thread::sleep(Duration::from_millis(20));
+                if theirs.write_all(&[byte]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let transport = crate::UnixSocketTransport::new(ours, "test-service");
+        let mut client = SearpcClient::new(transport);
+        let start = Instant::now();
+        let err = client
+            .call_with_timeout(Duration::from_millis(150), "get_version", [])
+            .unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            start.elapsed()
+        );
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        // Down for two attempts, as while the daemon restarts
+        let mut attempts = 0;
+        let transport = move |_: &[u8]| -> Result<Vec<u8>> {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()),
+                _ => Ok(br#"{"ret": 5}"#.to_vec()),
+            }
+        };
+        let mut client = SearpcClient::new(transport);
+        client.set_retry_policy(Some(RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            jitter: true,
+            ..RetryPolicy::default()
+        }));
+        assert_eq!(
+            client
+                .call_int("searpc_strlen", [Arg::string("hello")])
+                .unwrap(),
+            5
+        );
+
+        // Errors the daemon answers with are not retried, whatever they say
+        let mut attempts = 0;
+        let transport = move |_: &[u8]| -> Result<Vec<u8>> {
+            attempts += 1;
+            match attempts {
+                1 => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()),
+                _ => Ok(br#"{"err_code": 500, "err_msg": "Transport Error"}"#.to_vec()),
+            }
+        };
+        let mut client = SearpcClient::new(transport);
+        client.set_retry_policy(Some(RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(1),
+            retry_on: crate::RetryOn::Transport,
+            ..RetryPolicy::default()
+        }));
+        let err = client
+            .call_int("searpc_strlen", [Arg::string("hello")])
+            .unwrap_err();
+        assert!(
+            matches!(err.inner(), SearpcError::RpcError { code: 500, .. }),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_reconnecting() {
+        type TestTransport = Box<dyn FnMut(&[u8]) -> Result<Vec<u8>> + Send>;
+
+        // Each connection serves two calls, then is found closed
+        let mut connections = 0;
+        let mut client = SearpcClient::reconnecting(move || -> Result<TestTransport> {
+            connections += 1;
+            let mut calls = 0;
+            Ok(Box::new(move |_: &[u8]| {
+                calls += 1;
+                if calls > 2 {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: false,
+                        mid_frame: false,
+                    });
+                }
+                Ok(format!(r#"{{"ret": {}}}"#, connections).into_bytes())
+            }))
+        });
+        let answers: Vec<_> = (0..5)
+            .map(|_| client.call_int("connection", []).unwrap())
+            .collect();
+        assert_eq!(answers, [1, 1, 2, 2, 3]);
+        assert_eq!(client.transport().dropped_connections(), 2);
+
+        // Broken after the request went out: failed, but the next call
+        // reconnects
+        let mut client = SearpcClient::reconnecting(|| -> Result<TestTransport> {
+            let mut calls = 0;
+            Ok(Box::new(move |_: &[u8]| {
+                calls += 1;
+                if calls > 1 {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: true,
+                        mid_frame: false,
+                    });
+                }
+                Ok(br#"{"ret": 0}"#.to_vec())
+            }))
+        });
+        client.call_int("ping", []).unwrap();
+        assert!(client
+            .call_int("ping", [])
+            .unwrap_err()
+            .is_connection_closed());
+        client.call_int("ping", []).unwrap();
+    }
+
+    #[test]
+    fn test_interceptors() {
+        use std::sync::{Arc, Mutex};
+
+        let transport = mock_transport(r#"["get_repo","token","repo-id"]"#, r#"{"ret": 1}"#);
+        let mut client = SearpcClient::new(transport);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let hook_log = Arc::clone(&log);
+        client
+            .intercept_request(|request| {
+                request.args.insert(0, Arg::string("token"));
+                Ok(())
+            })
+            .intercept_request(|request| {
+                if request.function_name == "remove_repo" {
+                    return Err(SearpcError::RpcError {
+                        code: 403,
+                        message: "read-only client".to_string(),
+                    });
+                }
+                Ok(())
+            })
+            .intercept_response(move |request, result, elapsed| {
+                assert!(elapsed < Duration::from_secs(10));
+                hook_log.lock().unwrap().push(format!(
+                    "{} {}",
+                    request.to_json().unwrap(),
+                    String::from_utf8_lossy(result.as_ref().unwrap())
+                ));
+            });
+
+        assert_eq!(
+            client
+                .call_int("get_repo", [Arg::string("repo-id")])
+                .unwrap(),
+            1
+        );
+        let err = client
+            .call_void("remove_repo", [Arg::string("repo-id")])
+            .unwrap_err();
+        assert_eq!(err.function(), Some("remove_repo"));
+        assert!(matches!(
+            err.inner(),
+            SearpcError::RpcError { code: 403, .. }
+        ));
+        // Refused before it was sent
+        assert_eq!(
+            *log.lock().unwrap(),
+            [r#"["get_repo","token","repo-id"] {"ret": 1}"#]
+        );
+    }
+
+    #[test]
+    fn test_type_error_names_function() {
+        let transport = mock_transport(r#"["get_version",1]"#, r#"{"ret": "1.0.0"}"#);
+
+        let mut client = SearpcClient::new(transport);
+        let err = client.call_int("get_version", vec![1.into()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"get_version(1): Type error: Expected int, got: String("1.0.0")"#
+        );
+    }
+
+    #[test]
+    fn test_call_objlist_typed() {
+        #[derive(serde::Deserialize)]
+        struct Repo {
+            name: String,
+        }
+
+        let transport = mock_transport(
+            r#"["seafile_get_repo_list",-1,-1]"#,
+            r#"{"ret": [{"name": "Docs"}, {"name": "Photos"}]}"#,
+        );
+        let mut client = SearpcClient::new(transport);
+        let names: Vec<String> = client
+            .call_objlist_typed::<Repo>("seafile_get_repo_list", [Arg::int(-1), Arg::int(-1)])
+            .unwrap()
+            .map(|repo| repo.unwrap().name)
+            .collect();
+        assert_eq!(names, ["Docs", "Photos"]);
+
+        let transport = mock_transport(r#"["seafile_get_repo_list"]"#, r#"{"ret": 1}"#);
+        let mut client = SearpcClient::new(transport);
+        let err = client
+            .call_objlist_typed::<Repo>("seafile_get_repo_list", [])
+            .err()
+            .unwrap();
+        assert_eq!(err.function(), Some("seafile_get_repo_list"));
+    }
 }
@@ -0,0 +1,461 @@
+//! Compressed payloads
+//!
+//! Large responses, like an objlist of thousands of files, compress well.
+//! [`Compressed`] wraps a transport and, once the server agreed, sends
+//! each request as a frame announcing the codec it accepts:
+//!
+//! ```json
+//! {"codec": "deflate", "plain": "[\"function_name\", ...]"}
+//! {"codec": "deflate", "data": "<base64 of the compressed request>"}
+//! ```
+//!
+//! The second form is used for requests of at least the threshold. A
+//! server with [`SearpcServer::enable_compression`](crate::SearpcServer::enable_compression)
+//! answers such frames with `{"codec": ..., "data": ...}` when the
+//! response is at least its own threshold, and with a plain response
+//! otherwise. Frames are JSON text, so they fit in any framing, the Seafile
+//! service envelope included.
+//!
+//! The codec is negotiated with an ordinary call to [`NEGOTIATE`] before
+//! the first request. Servers without compression answer it with "cannot
+//! find function", and the wrapper then sends everything unchanged.
+//!
+//! ```rust,no_run
+//! use searpc::compression::Compressed;
+//! use searpc::{SearpcClient, UnixSocketTransport};
+//!
+//! let transport = UnixSocketTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?;
+//! let mut client = SearpcClient::new(Compressed::new(transport).threshold(4096));
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::error::{Result, SearpcError};
+use crate::server::{bad_request, encode_response};
+use crate::transport::{ConnectionAddr, Transport};
+use crate::RpcResponse;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+/// Function the client calls with the codecs it supports, most preferred
+/// first; the server returns the one it picked, or `null`
+pub const NEGOTIATE: &str = "__searpc_compression";
+
+/// Payloads from this size up are compressed by default
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// Largest payload a frame may decompress to, against decompression bombs
+pub const MAX_DECOMPRESSED_SIZE: usize = 256 << 20;
+
+/// Compression algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Deflate,
+    /// Needs the `zstd` feature
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Codec {
+    /// Every codec built in, best first
+    pub fn all() -> Vec<Codec> {
+        vec![
+            #[cfg(feature = "zstd")]
+            Codec::Zstd,
+            Codec::Deflate,
+        ]
+    }
+
+    /// Name on the wire
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Deflate => "deflate",
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::all().into_iter().find(|codec| codec.name() == name)
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self {
+            Codec::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(
+                    Vec::with_capacity(data.len() / 4),
+                    flate2::Compression::default(),
+                );
+                encoder.write_all(data).and_then(|()| encoder.finish())
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::encode_all(data, 0),
+        };
+        compressed.map_err(|e| SearpcError::transport_io("Compressing", e))
+    }
+
+    /// Decompress `data`, failing if it holds more than `limit` bytes
+    pub fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let mut decoder: Box<dyn Read + '_> = match self {
+            Codec::Deflate => Box::new(flate2::read::DeflateDecoder::new(data)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Box::new(
+                zstd::Decoder::new(data)
+                    .map_err(|e| SearpcError::transport_io("Decompressing", e))?,
+            ),
+        };
+        let mut decompressed = Vec::new();
+        decoder
+            .by_ref()
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| SearpcError::InvalidResponse(format!("Decompressing: {}", e)))?;
+        if decompressed.len() > limit {
+            return Err(SearpcError::InvalidResponse(format!(
+                "Compressed payload larger than {} bytes",
+                limit
+            )));
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Request or response in a [`Compressed`] frame
+#[derive(Serialize, Deserialize)]
+struct Frame<'a> {
+    #[serde(borrow)]
+    codec: Cow<'a, str>,
+    /// Base64 of the compressed payload
+    #[serde(default, borrow, skip_serializing_if = "Option::is_none")]
+    data: Option<Cow<'a, str>>,
+    /// Payload under the threshold, as a JSON string
+    #[serde(default, borrow, skip_serializing_if = "Option::is_none")]
+    plain: Option<Cow<'a, str>>,
+}
+
+/// `codec` goes first when serialized, so frames start with this
+const FRAME_START: &[u8] = br#"{"codec":"#;
+
+/// Frame for `payload`, compressed if it is at least `threshold` bytes
+fn encode_frame(codec: Codec, payload: &[u8], threshold: usize) -> Result<Vec<u8>> {
+    let (data, plain) = if payload.len() >= threshold {
+        (
+            Some(Cow::Owned(BASE64.encode(codec.compress(payload)?))),
+            None,
+        )
+    } else {
+        let plain = std::str::from_utf8(payload).map_err(|e| {
+            SearpcError::InvalidResponse(format!("Request is not valid UTF-8: {}", e))
+        })?;
+        (None, Some(Cow::Borrowed(plain)))
+    };
+    let frame = Frame {
+        codec: Cow::Borrowed(codec.name()),
+        data,
+        plain,
+    };
+    Ok(serde_json::to_vec(&frame)?)
+}
+
+/// The codec and payload of a frame
+fn decode_frame(frame: &[u8]) -> Result<(Codec, Vec<u8>)> {
+    let frame: Frame<'_> = serde_json::from_slice(frame)?;
+    let codec = Codec::from_name(&frame.codec).ok_or_else(|| {
+        SearpcError::InvalidResponse(format!("Unsupported codec {:?}", frame.codec))
+    })?;
+    let payload = match (frame.data, frame.plain) {
+        (Some(data), None) => {
+            let compressed = BASE64
+                .decode(data.as_bytes())
+                .map_err(|e| SearpcError::InvalidResponse(format!("Bad base64: {}", e)))?;
+            codec.decompress(&compressed, MAX_DECOMPRESSED_SIZE)?
+        }
+        (None, Some(plain)) => plain.into_owned().into_bytes(),
+        _ => {
+            return Err(SearpcError::InvalidResponse(
+                "Frame needs one of \"data\" and \"plain\"".to_string(),
+            ))
+        }
+    };
+    Ok((codec, payload))
+}
+
+/// Answer `request` if it is a frame from a [`Compressed`] transport, or
+/// return `None` for a plain request
+///
+/// `dispatch` runs the payload; its response is compressed with the
+/// client's codec if it is at least `threshold` bytes.
+pub(crate) fn handle_frame(
+    request: &[u8],
+    threshold: usize,
+    dispatch: impl FnOnce(&[u8]) -> Vec<u8>,
+) -> Option<Vec<u8>> {
+    if !request.starts_with(b"{") {
+        return None;
+    }
+    let (codec, payload) = match decode_frame(request) {
+        Ok(frame) => frame,
+        Err(e) => return Some(encode_response(&RpcResponse::from(bad_request(e)))),
+    };
+    let response = dispatch(&payload);
+    if response.len() < threshold {
+        return Some(response);
+    }
+    // Plain responses are understood as well, should compression fail
+    Some(encode_frame(codec, &response, threshold).unwrap_or(response))
+}
+
+/// Server side of [`NEGOTIATE`]: the first codec offered that is built in
+pub(crate) fn negotiate(args: &[Value]) -> Value {
+    args.iter()
+        .filter_map(Value::as_str)
+        .find_map(Codec::from_name)
+        .map_or(Value::Null, |codec| Value::from(codec.name()))
+}
+
+/// Transport wrapper compressing large payloads, see the [module docs](self)
+///
+/// Works with [`Transport`]s and, with the `async` feature,
+/// [`AsyncTransport`](crate::AsyncTransport)s.
+pub struct Compressed<T> {
+    inner: T,
+    codecs: Vec<Codec>,
+    threshold: usize,
+    /// Outcome of the negotiation, once it happened
+    negotiated: Option<Option<Codec>>,
+}
+
+impl<T> Compressed<T> {
+    /// Offer every codec built in, compressing requests from
+    /// [`DEFAULT_THRESHOLD`] bytes up
+    pub fn new(inner: T) -> Self {
+        Compressed {
+            inner,
+            codecs: Codec::all(),
+            threshold: DEFAULT_THRESHOLD,
+            negotiated: None,
+        }
+    }
+
+    /// Compress requests of at least `bytes`
+    ///
+    /// Which responses are compressed is up to the server.
+    pub fn threshold(mut self, bytes: usize) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    /// Offer `codecs` to the server, most preferred first
+    pub fn codecs(mut self, codecs: impl IntoIterator<Item = Codec>) -> Self {
+        self.codecs = codecs.into_iter().collect();
+        self
+    }
+
+    /// Codec agreed with the server: `None` before the first call or if
+    /// the server does not compress
+    pub fn codec(&self) -> Option<Codec> {
+        self.negotiated.flatten()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn negotiation_request(&self) -> Vec<u8> {
+        let mut request = vec![Value::from(NEGOTIATE)];
+        request.extend(self.codecs.iter().map(|codec| Value::from(codec.name())));
+        serde_json::to_vec(&request).expect("negotiation request serializes")
+    }
+
+    /// Note the server's answer to the negotiation request
+    ///
+    /// Any RPC error, "cannot find function" from a server without
+    /// compression in particular, means sending plain requests.
+    fn negotiated(&mut self, response: &[u8]) {
+        let codec = serde_json::from_slice::<RpcResponse>(response)
+            .ok()
+            .and_then(|response| response.ret)
+            .and_then(|ret| ret.as_str().and_then(Codec::from_name))
+            .filter(|codec| self.codecs.contains(codec));
+        self.negotiated = Some(codec);
+    }
+
+    fn encode_request<'a>(&self, request: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        match self.codec() {
+            Some(codec) => Ok(Cow::Owned(encode_frame(codec, request, self.threshold)?)),
+            None => Ok(Cow::Borrowed(request)),
+        }
+    }
+}
+
+fn decode_response(response: Vec<u8>) -> Result<Vec<u8>> {
+    if response.starts_with(FRAME_START) {
+        Ok(decode_frame(&response)?.1)
+    } else {
+        Ok(response)
+    }
+}
+
+impl<T: Transport> Transport for Compressed<T> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        if self.negotiated.is_none() {
+            let response = self.inner.send(&self.negotiation_request())?;
+            self.negotiated(&response);
+        }
+        let request = self.encode_request(request)?;
+        decode_response(self.inner.send(&request)?)
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.inner.local_addr()
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        self.inner.connection_age()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+}
+
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl<T: crate::AsyncTransport + Send> crate::AsyncTransport for Compressed<T> {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        if self.negotiated.is_none() {
+            let response = self.inner.send(&self.negotiation_request()).await?;
+            self.negotiated(&response);
+        }
+        let request = self.encode_request(request)?;
+        decode_response(self.inner.send(&request).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arg, SearpcClient, SearpcServer};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    fn listing_server() -> SearpcServer {
+        let mut server = SearpcServer::new();
+        server.register("list_files", |_| {
+            let files: Vec<_> = (0..2000)
+                .map(|i| json!({"name": format!("file-{}.txt", i), "size": i * 100}))
+                .collect();
+            Ok(Value::from(files))
+        });
+        server.register("echo", |args| Ok(args[0].clone()));
+        server
+    }
+
+    /// Requests and their responses, in order
+    type Wire = Vec<(Vec<u8>, Vec<u8>)>;
+
+    /// Transport calling `server` directly, keeping what went over the wire
+    fn recording(server: SearpcServer) -> (impl Transport, Arc<Mutex<Wire>>) {
+        let wire = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&wire);
+        let transport = move |request: &[u8]| {
+            let response = server.handle_request(request);
+            seen.lock()
+                .unwrap()
+                .push((request.to_vec(), response.clone()));
+            Ok(response)
+        };
+        (transport, wire)
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        for codec in Codec::all() {
+            let mut server = listing_server();
+            server.enable_compression(DEFAULT_THRESHOLD);
+            let (transport, wire) = recording(server);
+            let mut client = SearpcClient::new(Compressed::new(transport).codecs([codec]));
+
+            let files = client.call_objlist("list_files", []).unwrap();
+            assert_eq!(files.len(), 2000);
+            assert_eq!(files[1999]["name"], "file-1999.txt");
+            let long = "x".repeat(5000);
+            let echoed = client.call_string("echo", [Arg::string(&long)]).unwrap();
+            assert_eq!(echoed, long);
+            assert_eq!(
+                client.call_string("echo", [Arg::string("hi")]).unwrap(),
+                "hi"
+            );
+
+            let wire = wire.lock().unwrap();
+            assert_eq!(wire.len(), 4);
+            let agreed = format!(r#"{{"ret":"{}"}}"#, codec.name());
+            assert_eq!(wire[0].1, agreed.as_bytes());
+            let (listing, response) = &wire[1];
+            assert!(listing.starts_with(FRAME_START));
+            assert!(response.starts_with(FRAME_START));
+            assert!(response.len() < 20_000, "{} bytes", response.len());
+            // Large request compressed, small response left alone
+            assert!(wire[2].0.len() < 1000);
+            assert_eq!(wire[3].1, br#"{"ret":"hi"}"#);
+        }
+    }
+
+    #[test]
+    fn test_server_without_compression() {
+        let (transport, wire) = recording(listing_server());
+        let mut transport = Compressed::new(transport);
+        let response = transport.send(br#"["list_files"]"#).unwrap();
+        assert!(response.starts_with(br#"{"ret":[{"#));
+        assert_eq!(transport.codec(), None);
+
+        let wire = wire.lock().unwrap();
+        assert!(wire[0].1.starts_with(br#"{"err_code":500"#));
+        assert_eq!(wire[1].0, br#"["list_files"]"#);
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        let mut server = listing_server();
+        server.enable_compression(DEFAULT_THRESHOLD);
+        for frame in [
+            &br#"{"codec":"lzma","plain":"[\"echo\",1]"}"#[..],
+            br#"{"codec":"deflate","data":"not base64!"}"#,
+            br#"{"codec":"deflate"}"#,
+        ] {
+            let response: RpcResponse =
+                serde_json::from_slice(&server.handle_request(frame)).unwrap();
+            assert_eq!(response.err_code, Some(511));
+        }
+    }
+
+    #[test]
+    fn test_decompression_limit() {
+        let compressed = Codec::Deflate.compress(&[0u8; 100_000]).unwrap();
+        assert_eq!(
+            Codec::Deflate
+                .decompress(&compressed, 100_000)
+                .unwrap()
+                .len(),
+            100_000
+        );
+        assert!(Codec::Deflate.decompress(&compressed, 99_999).is_err());
+    }
+}
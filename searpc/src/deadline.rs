@@ -0,0 +1,140 @@
+//! One deadline for all reads and writes of a call
+//!
+//! Socket timeouts bound each read and write on its own, so a server
+//! trickling its response a byte at a time would keep a call going for as
+//! long as it likes. The socket transports set them to the time left
+//! before each read and write instead, and fail once none is left.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::time::{Duration, Instant};
+
+/// Sockets with std's read and write timeouts
+pub(crate) trait SocketTimeouts {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+macro_rules! socket_timeouts {
+    ($($socket:ty),*) => {
+        $(impl SocketTimeouts for $socket {
+            fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+                <$socket>::set_read_timeout(self, timeout)
+            }
+
+            fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+                <$socket>::set_write_timeout(self, timeout)
+            }
+        })*
+    };
+}
+
+socket_timeouts!(TcpStream);
+#[cfg(unix)]
+socket_timeouts!(UnixStream, UnixDatagram);
+
+/// The transport's timeout, and when the current call runs out of it
+#[derive(Debug, Default)]
+pub(crate) struct Deadline {
+    timeout: Option<Duration>,
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// Bound the calls started from now on by `timeout`
+    ///
+    /// Also sets it on `socket`, which checks that the socket takes it.
+    pub(crate) fn set_timeout(
+        &mut self,
+        socket: &impl SocketTimeouts,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        socket.set_read_timeout(timeout)?;
+        socket.set_write_timeout(timeout)?;
+        self.timeout = timeout;
+        self.at = None;
+        Ok(())
+    }
+
+    /// Start the clock for a call
+    pub(crate) fn start(&mut self) {
+        self.at = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Time left in the call, or `TimedOut` once there is none
+    fn left(&self) -> io::Result<Option<Duration>> {
+        let Some(at) = self.at else {
+            return Ok(None);
+        };
+        match at.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(Some(left)),
+            _ => Err(io::Error::new(ErrorKind::TimedOut, "call deadline passed")),
+        }
+    }
+
+    pub(crate) fn before_read(&self, socket: &impl SocketTimeouts) -> io::Result<()> {
+        match self.left()? {
+            Some(left) => socket.set_read_timeout(Some(left)),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn before_write(&self, socket: &impl SocketTimeouts) -> io::Result<()> {
+        match self.left()? {
+            Some(left) => socket.set_write_timeout(Some(left)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Socket whose reads and writes share the current call's [`Deadline`]
+#[derive(Debug)]
+pub(crate) struct DeadlineStream<S> {
+    socket: S,
+    deadline: Deadline,
+}
+
+impl<S: SocketTimeouts> DeadlineStream<S> {
+    pub(crate) fn new(socket: S) -> Self {
+        DeadlineStream {
+            socket,
+            deadline: Deadline::default(),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    pub(crate) fn deadline(&self) -> &Deadline {
+        &self.deadline
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.deadline.set_timeout(&self.socket, timeout)
+    }
+
+    pub(crate) fn start(&mut self) {
+        self.deadline.start();
+    }
+}
+
+impl<S: Read + SocketTimeouts> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.deadline.before_read(&self.socket)?;
+        self.socket.read(buf)
+    }
+}
+
+impl<S: Write + SocketTimeouts> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.deadline.before_write(&self.socket)?;
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
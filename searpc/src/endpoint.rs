@@ -0,0 +1,217 @@
+//! Picking the transport from the environment
+//!
+//! Tools talking to a Seafile daemon need not hard-code where its socket
+//! is: [`connect_default`] connects to the [`Endpoint`] the environment
+//! names, see [`Endpoint::from_env`]:
+//!
+//! ```rust,no_run
+//! use searpc::{endpoint, SearpcClient};
+//!
+//! // SEAFILE_SOCKET=~/.seafile-data/seafile.sock, or SEARPC_ADDR=127.0.0.1:12345
+//! let mut client = SearpcClient::new(endpoint::connect_default()?);
+//! let version = client.call_string("seafile_get_version", [])?;
+//! # Ok::<(), searpc::SearpcError>(())
+//! ```
+
+use crate::error::{Result, SearpcError};
+use crate::{TcpTransport, Transport};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Address of the RPC server, [`Endpoint::parse`]d: `unix:PATH`,
+/// `tcp:HOST:PORT`, a path or `HOST:PORT`
+pub const ADDR_ENV: &str = "SEARPC_ADDR";
+/// Path of the Seafile daemon's socket, used if [`ADDR_ENV`] is not set
+pub const SOCKET_ENV: &str = "SEAFILE_SOCKET";
+/// Service for Unix socket endpoints, [`DEFAULT_SERVICE`] if not set
+pub const SERVICE_ENV: &str = "SEARPC_SERVICE";
+/// Service of the Seafile daemon
+pub const DEFAULT_SERVICE: &str = "seafile-rpcserver";
+
+/// Where an RPC server listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Unix socket with the Seafile framing, sending requests to `service`
+    Unix { path: PathBuf, service: String },
+    /// `host:port` with the TCP framing
+    Tcp(String),
+}
+
+impl Endpoint {
+    /// Endpoint for `addr`, with Unix sockets serving `service`
+    ///
+    /// `unix:` and `tcp:` prefixes pick the kind; without one, addresses
+    /// containing a `/` are socket paths.
+    pub fn parse(addr: &str, service: impl Into<String>) -> Result<Self> {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return Ok(Self::unix(path, service));
+        }
+        let host_port = match addr.strip_prefix("tcp:") {
+            Some(host_port) => host_port,
+            None if addr.contains('/') => return Ok(Self::unix(addr, service)),
+            None => addr,
+        };
+        match host_port.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Endpoint::Tcp(host_port.to_string()))
+            }
+            _ => Err(SearpcError::transport(format!(
+                "Invalid RPC address {:?}: expected unix:PATH or HOST:PORT",
+                addr
+            ))),
+        }
+    }
+
+    fn unix(path: impl Into<PathBuf>, service: impl Into<String>) -> Self {
+        Endpoint::Unix {
+            path: path.into(),
+            service: service.into(),
+        }
+    }
+
+    /// Endpoint named by [`ADDR_ENV`] or else [`SOCKET_ENV`], with the
+    /// service in [`SERVICE_ENV`]; `None` if neither is set
+    pub fn from_env() -> Result<Option<Self>> {
+        from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Connect to the endpoint
+    ///
+    /// TCP connections go through the proxy configured in the environment,
+    /// if any, see [`TcpTransport::connect_from_env`].
+    pub fn connect(&self) -> Result<Box<dyn Transport + Send>> {
+        match self {
+            #[cfg(unix)]
+            Endpoint::Unix { path, service } => {
+                let transport = crate::UnixSocketTransport::connect(path, service.as_str())
+                    .map_err(|e| {
+                        SearpcError::transport_io(&format!("Connecting to {}", path.display()), e)
+                    })?;
+                Ok(Box::new(transport))
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix { .. } => Err(SearpcError::transport(
+                "Unix sockets are not supported on this platform",
+            )),
+            Endpoint::Tcp(addr) => Ok(Box::new(TcpTransport::connect_from_env(addr)?)),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Unix { path, service } => {
+                write!(f, "unix:{} ({})", path.display(), service)
+            }
+            Endpoint::Tcp(addr) => write!(f, "tcp:{}", addr),
+        }
+    }
+}
+
+/// Connect to the endpoint in the environment, see [`Endpoint::from_env`]
+///
+/// Fails if the environment names none.
+pub fn connect_default() -> Result<Box<dyn Transport + Send>> {
+    match Endpoint::from_env()? {
+        Some(endpoint) => endpoint.connect(),
+        None => Err(SearpcError::transport(format!(
+            "No RPC server configured: set {} or {}",
+            ADDR_ENV, SOCKET_ENV
+        ))),
+    }
+}
+
+/// [`Endpoint::from_env`] with variables looked up by `var`; empty ones
+/// count as unset
+fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Option<Endpoint>> {
+    let var = |name| var(name).filter(|value| !value.is_empty());
+    let service = var(SERVICE_ENV).unwrap_or_else(|| DEFAULT_SERVICE.to_string());
+    if let Some(addr) = var(ADDR_ENV) {
+        return Endpoint::parse(&addr, service).map(Some);
+    }
+    Ok(var(SOCKET_ENV).map(|path| Endpoint::unix(path, service)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name: &str| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_parse() {
+        let unix = |path: &str| Endpoint::unix(path, "svc");
+        assert_eq!(Endpoint::parse("unix:sock", "svc").unwrap(), unix("sock"));
+        assert_eq!(
+            Endpoint::parse("/run/seafile.sock", "svc").unwrap(),
+            unix("/run/seafile.sock")
+        );
+        let tcp = Endpoint::Tcp("localhost:12345".to_string());
+        assert_eq!(Endpoint::parse("localhost:12345", "svc").unwrap(), tcp);
+        assert_eq!(Endpoint::parse("tcp:localhost:12345", "svc").unwrap(), tcp);
+        assert_eq!(
+            Endpoint::parse("[::1]:1", "svc").unwrap(),
+            Endpoint::Tcp("[::1]:1".to_string())
+        );
+        for bad in ["localhost", "localhost:http", ":1", "tcp:"] {
+            assert!(Endpoint::parse(bad, "svc").is_err(), "{}", bad);
+        }
+        assert_eq!(tcp.to_string(), "tcp:localhost:12345");
+        assert_eq!(unix("/s").to_string(), "unix:/s (svc)");
+    }
+
+    #[test]
+    fn test_from_vars() {
+        assert_eq!(from_vars(vars(&[])).unwrap(), None);
+        assert_eq!(from_vars(vars(&[(ADDR_ENV, "")])).unwrap(), None);
+        let endpoint = from_vars(vars(&[(SOCKET_ENV, "/s")])).unwrap();
+        assert_eq!(endpoint, Some(Endpoint::unix("/s", DEFAULT_SERVICE)));
+
+        // The address wins over the socket
+        let env = vars(&[
+            (ADDR_ENV, "unix:/a"),
+            (SOCKET_ENV, "/s"),
+            (SERVICE_ENV, "ccnet-rpcserver"),
+        ]);
+        let endpoint = from_vars(env).unwrap();
+        assert_eq!(endpoint, Some(Endpoint::unix("/a", "ccnet-rpcserver")));
+        assert!(from_vars(vars(&[(ADDR_ENV, "nowhere")])).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_connect() {
+        use crate::{SearpcClient, SearpcServer, UnixSocketServer};
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join(format!("searpc-endpoint-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+        let mut rpc = SearpcServer::new();
+        rpc.register("ping", |_| Ok(json!("pong")));
+        let mut server = UnixSocketServer::new();
+        server.add_service(DEFAULT_SERVICE, rpc);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || Arc::new(server).serve(listener));
+
+        let endpoint = from_vars(vars(&[(SOCKET_ENV, path.to_str().unwrap())]))
+            .unwrap()
+            .unwrap();
+        let mut client = SearpcClient::new(endpoint.connect().unwrap());
+        assert_eq!(client.call_string("ping", []).unwrap(), "pong");
+        std::fs::remove_file(&path).unwrap();
+
+        let err = endpoint.connect().err().unwrap();
+        assert!(err.to_string().contains("seafile.sock"), "{}", err);
+    }
+}
@@ -16,8 +16,15 @@ pub enum SearpcError {
 
     /// Transport layer error (network, timeout, etc.)
     /// Matches C's TRANSPORT_ERROR (code 500)
-    #[error("Transport error: {0}")]
-    TransportError(String),
+    ///
+    /// `source` holds the underlying I/O error, if there was one, so callers
+    /// can tell e.g. `BrokenPipe` from `ConnectionRefused`.
+    #[error("Transport error: {message}")]
+    TransportError {
+        message: String,
+        #[source]
+        source: Option<std::io::Error>,
+    },
 
     /// JSON serialization/deserialization error
     #[error("JSON error: {0}")]
@@ -38,4 +45,551 @@ pub enum SearpcError {
     /// Environment variable error
     #[error("Environment variable error: {0}")]
     EnvVarError(#[from] std::env::VarError),
+
+    /// The peer closed the connection
+    ///
+    /// `request_sent` tells whether the whole request was written before the
+    /// close; if not, the server cannot have run it and it may be replayed on
+    /// a new connection. `mid_frame` is set when the close cut a response
+    /// short, as opposed to a clean EOF between frames. Either way the
+    /// connection is unusable and must be discarded.
+    #[error("Connection closed by peer{}", if *.mid_frame { " in the middle of a frame" } else { "" })]
+    ConnectionClosed { request_sent: bool, mid_frame: bool },
+
+    /// The stream no longer lines up with frame boundaries, e.g. after a
+    /// corrupted length header: a length over the transport's
+    /// `with_max_response_size` limit, or a body that is not JSON
+    ///
+    /// Nothing more can be read from the connection, which must be
+    /// discarded; [`ReconnectingTransport`](crate::ReconnectingTransport)
+    /// does that and connects anew.
+    #[error("Protocol desync: {reason}")]
+    ProtocolDesync { reason: String },
+
+    /// The call took longer than the client's timeout (see
+    /// [`SearpcClient::set_timeout`](crate::SearpcClient::set_timeout))
+    ///
+    /// The response may still arrive, so the connection should be
+    /// discarded.
+    #[error("Call timed out after {timeout:?}")]
+    Timeout { timeout: std::time::Duration },
+
+    /// Error from an RPC call, tagged with the function that produced it
+    ///
+    /// `args` summarizes the arguments with string values redacted, so it is
+    /// safe to log (see [`RpcRequest::args_summary`](crate::RpcRequest::args_summary)).
+    /// Use [`SearpcError::inner`] to get at the underlying error.
+    #[error("{function}{}: {error}", .args.as_ref().map(|a| format!("({})", a)).unwrap_or_default())]
+    Call {
+        function: String,
+        args: Option<String>,
+        error: Box<SearpcError>,
+    },
+}
+
+impl SearpcError {
+    /// Transport error without an underlying I/O error
+    pub fn transport(message: impl Into<String>) -> Self {
+        SearpcError::TransportError {
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Transport error caused by `source`, described as `"<context>: <source>"`
+    pub fn transport_io(context: &str, source: std::io::Error) -> Self {
+        SearpcError::TransportError {
+            message: format!("{}: {}", context, source),
+            source: Some(source),
+        }
+    }
+
+    /// Whether the peer closed the connection (which must then be discarded)
+    pub fn is_connection_closed(&self) -> bool {
+        matches!(self.inner(), SearpcError::ConnectionClosed { .. })
+    }
+
+    /// Whether the connection lost track of frame boundaries (and must be
+    /// discarded)
+    pub fn is_protocol_desync(&self) -> bool {
+        matches!(self.inner(), SearpcError::ProtocolDesync { .. })
+    }
+
+    /// Whether an I/O timeout (see the transports' `set_read_timeout`) or
+    /// the client's timeout cut the call short
+    ///
+    /// The connection may be left mid-frame and should be discarded.
+    pub fn is_timeout(&self) -> bool {
+        match self.inner() {
+            SearpcError::Timeout { .. } => true,
+            SearpcError::TransportError {
+                source: Some(e), ..
+            } => matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the request can safely be sent again on a new connection
+    ///
+    /// True only when the connection closed before the request was fully
+    /// written, so the server never saw it.
+    pub fn may_replay(&self) -> bool {
+        matches!(
+            self.inner(),
+            SearpcError::ConnectionClosed {
+                request_sent: false,
+                ..
+            }
+        )
+    }
+
+    /// Tag `error` with the RPC function it came from
+    ///
+    /// Errors that already carry call context are returned unchanged.
+    pub fn in_call(function: &str, args: Option<String>, error: SearpcError) -> Self {
+        match error {
+            SearpcError::Call { .. } => error,
+            error => SearpcError::Call {
+                function: function.to_string(),
+                args,
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// The error without call context
+    pub fn inner(&self) -> &SearpcError {
+        match self {
+            SearpcError::Call { error, .. } => error.inner(),
+            other => other,
+        }
+    }
+
+    /// The RPC function this error came from, if known
+    pub fn function(&self) -> Option<&str> {
+        match self {
+            SearpcError::Call { function, .. } => Some(function),
+            _ => None,
+        }
+    }
+
+    /// Underlying I/O error of a transport or IO error
+    pub fn io_error(&self) -> Option<&std::io::Error> {
+        match self.inner() {
+            SearpcError::TransportError { source, .. } => source.as_ref(),
+            SearpcError::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Code to report in a response's `err_code`
+    ///
+    /// RPC errors keep their code; everything else is reported as
+    /// [`TRANSPORT_ERROR_CODE`] (500), like libsearpc does for local failures.
+    pub fn err_code(&self) -> i32 {
+        match self.inner() {
+            SearpcError::RpcError { code, .. } => *code,
+            _ => TRANSPORT_ERROR_CODE,
+        }
+    }
+
+    /// Message to report in a response's `err_msg`
+    ///
+    /// RPC errors keep their original message, other errors use their
+    /// `Display` text. Call context is left out.
+    pub fn err_msg(&self) -> String {
+        match self.inner() {
+            SearpcError::RpcError { message, .. } => message.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    /// Process exit code for a CLI that failed with this error
+    ///
+    /// Codes follow BSD `sysexits.h`, so scripts can tell failure categories
+    /// apart. See [`exit_code`] for the table.
+    pub fn exit_code(&self) -> u8 {
+        match self.inner() {
+            SearpcError::RpcError { .. }
+            | SearpcError::TransportError { .. }
+            | SearpcError::ConnectionClosed { .. }
+            | SearpcError::Timeout { .. }
+            | SearpcError::Call { .. } => self
+                .kind()
+                .map_or(exit_code::SOFTWARE, KnownErrorCode::exit_code),
+            SearpcError::JsonError(_)
+            | SearpcError::InvalidResponse(_)
+            | SearpcError::ProtocolDesync { .. }
+            | SearpcError::TypeError(_) => exit_code::PROTOCOL,
+            SearpcError::IoError(_) => exit_code::UNAVAILABLE,
+            SearpcError::EnvVarError(_) => exit_code::CONFIG,
+        }
+    }
+
+    /// Known meaning of this error, if any
+    ///
+    /// Transport errors, closed connections and timeouts map to
+    /// [`KnownErrorCode::Transport`]; RPC errors are
+    /// classified by code and message. Other variants return `None`.
+    pub fn kind(&self) -> Option<KnownErrorCode> {
+        match self.inner() {
+            SearpcError::RpcError { code, message } => KnownErrorCode::classify(*code, message),
+            SearpcError::TransportError { .. }
+            | SearpcError::ConnectionClosed { .. }
+            | SearpcError::Timeout { .. } => Some(KnownErrorCode::Transport),
+            _ => None,
+        }
+    }
+}
+
+/// Exit codes for CLI tools built on this crate (from BSD `sysexits.h`)
+///
+/// | Code | Name          | Errors                                                        |
+/// |------|---------------|---------------------------------------------------------------|
+/// | 1    | `FAILURE`     | anything not covered below (CLI-side failures)                |
+/// | 64   | `USAGE`       | `BadArgs`                                                     |
+/// | 66   | `NO_INPUT`    | `BadRepo`, `BadCommit`, `BadFile`, `BadDirId`, `NoWorktree`, `DirMissing`, `PathNoExist` |
+/// | 69   | `UNAVAILABLE` | transport and I/O errors, `ServiceNotFound`, `MonitorNotConnected`, `BadRelay`, `BadPeerId` |
+/// | 70   | `SOFTWARE`    | `General`, `Internal`, `ListCommits`, unknown RPC error codes |
+/// | 73   | `CANT_CREATE` | `QuotaFull`, `TooManyFiles`, `FilesWithSameName`              |
+/// | 75   | `TEMP_FAIL`   | `RepoLocked`, `GcConflict`, `GcNotStarted`, `Busy`            |
+/// | 76   | `PROTOCOL`    | malformed responses, protocol desyncs, type mismatches, `FunctionNotFound`, `BadRequest` |
+/// | 77   | `NO_PERM`     | `RepoAuth`                                                    |
+/// | 78   | `CONFIG`      | environment variable errors                                   |
+pub mod exit_code {
+    pub const FAILURE: u8 = 1;
+    pub const USAGE: u8 = 64;
+    pub const NO_INPUT: u8 = 66;
+    pub const UNAVAILABLE: u8 = 69;
+    pub const SOFTWARE: u8 = 70;
+    pub const CANT_CREATE: u8 = 73;
+    pub const TEMP_FAIL: u8 = 75;
+    pub const PROTOCOL: u8 = 76;
+    pub const NO_PERM: u8 = 77;
+    pub const CONFIG: u8 = 78;
+}
+
+/// Serializes as a protocol error object: `{"err_code": ..., "err_msg": ...}`
+impl serde::Serialize for SearpcError {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = serializer.serialize_struct("SearpcError", 2)?;
+        state.serialize_field("err_code", &self.err_code())?;
+        state.serialize_field("err_msg", &self.err_msg())?;
+        state.end()
+    }
+}
+
+/// Error codes with a known meaning in libsearpc and the Seafile daemon
+///
+/// libsearpc reports its own failures with codes that Seafile reuses for
+/// daemon errors (500, 501, 511), so [`SearpcError::kind`] also looks at the
+/// message to tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum KnownErrorCode {
+    /// Transport failure (C `TRANSPORT_ERROR_CODE`, 500)
+    Transport,
+    /// Server has no such function (500, "cannot find function ...")
+    FunctionNotFound,
+    /// Server has no such service (501, "cannot find service ...")
+    ServiceNotFound,
+    /// Server could not parse the request (511, "failed to load RPC call")
+    BadRequest,
+    /// Server is at its request limit, try again later (429, searpc-rs only)
+    Busy,
+
+    // Seafile daemon codes (SEAF_ERR_* in seafile-error.h)
+    /// `SEAF_ERR_GENERAL` (500)
+    General,
+    /// `SEAF_ERR_BAD_REPO` (501)
+    BadRepo,
+    /// `SEAF_ERR_BAD_COMMIT` (502)
+    BadCommit,
+    /// `SEAF_ERR_BAD_ARGS` (503)
+    BadArgs,
+    /// `SEAF_ERR_INTERNAL` (504)
+    Internal,
+    /// `SEAF_ERR_BAD_FILE` (505)
+    BadFile,
+    /// `SEAF_ERR_BAD_RELAY` (506)
+    BadRelay,
+    /// `SEAF_ERR_LIST_COMMITS` (507)
+    ListCommits,
+    /// `SEAF_ERR_REPO_AUTH` (508)
+    RepoAuth,
+    /// `SEAF_ERR_GC_NOT_STARTED` (509)
+    GcNotStarted,
+    /// `SEAF_ERR_MONITOR_NOT_CONNECTED` (510)
+    MonitorNotConnected,
+    /// `SEAF_ERR_BAD_DIR_ID` (511)
+    BadDirId,
+    /// `SEAF_ERR_NO_WORKTREE` (512)
+    NoWorktree,
+    /// `SEAF_ERR_BAD_PEER_ID` (513)
+    BadPeerId,
+    /// `SEAF_ERR_REPO_LOCKED` (514)
+    RepoLocked,
+    /// `SEAF_ERR_DIR_MISSING` (515)
+    DirMissing,
+    /// `SEAF_ERR_PATH_NO_EXIST` (516)
+    PathNoExist,
+    /// `SEAF_ERR_FILES_WITH_SAME_NAME` (517)
+    FilesWithSameName,
+    /// `SEAF_ERR_GC_CONFLICT` (518)
+    GcConflict,
+    /// `SEAF_ERR_QUOTA_FULL` (519)
+    QuotaFull,
+    /// `SEAF_ERR_TOO_MANY_FILES` (520)
+    TooManyFiles,
+}
+
+impl KnownErrorCode {
+    /// Numeric code as sent in `err_code`
+    pub fn code(self) -> i32 {
+        use KnownErrorCode::*;
+        match self {
+            Busy => 429,
+            Transport | FunctionNotFound | General => 500,
+            ServiceNotFound | BadRepo => 501,
+            BadCommit => 502,
+            BadArgs => 503,
+            Internal => 504,
+            BadFile => 505,
+            BadRelay => 506,
+            ListCommits => 507,
+            RepoAuth => 508,
+            GcNotStarted => 509,
+            MonitorNotConnected => 510,
+            BadRequest | BadDirId => 511,
+            NoWorktree => 512,
+            BadPeerId => 513,
+            RepoLocked => 514,
+            DirMissing => 515,
+            PathNoExist => 516,
+            FilesWithSameName => 517,
+            GcConflict => 518,
+            QuotaFull => 519,
+            TooManyFiles => 520,
+        }
+    }
+
+    /// Seafile daemon error for `code`, if it is a documented one
+    pub fn from_seafile_code(code: i32) -> Option<Self> {
+        use KnownErrorCode::*;
+        Some(match code {
+            500 => General,
+            501 => BadRepo,
+            502 => BadCommit,
+            503 => BadArgs,
+            504 => Internal,
+            505 => BadFile,
+            506 => BadRelay,
+            507 => ListCommits,
+            508 => RepoAuth,
+            509 => GcNotStarted,
+            510 => MonitorNotConnected,
+            511 => BadDirId,
+            512 => NoWorktree,
+            513 => BadPeerId,
+            514 => RepoLocked,
+            515 => DirMissing,
+            516 => PathNoExist,
+            517 => FilesWithSameName,
+            518 => GcConflict,
+            519 => QuotaFull,
+            520 => TooManyFiles,
+            _ => return None,
+        })
+    }
+
+    /// Process exit code for this error, see [`exit_code`]
+    pub fn exit_code(self) -> u8 {
+        use KnownErrorCode::*;
+        match self {
+            BadArgs => exit_code::USAGE,
+            BadRepo | BadCommit | BadFile | BadDirId | NoWorktree | DirMissing | PathNoExist => {
+                exit_code::NO_INPUT
+            }
+            Transport | ServiceNotFound | MonitorNotConnected | BadRelay | BadPeerId => {
+                exit_code::UNAVAILABLE
+            }
+            General | Internal | ListCommits => exit_code::SOFTWARE,
+            QuotaFull | TooManyFiles | FilesWithSameName => exit_code::CANT_CREATE,
+            RepoLocked | GcConflict | GcNotStarted | Busy => exit_code::TEMP_FAIL,
+            FunctionNotFound | BadRequest => exit_code::PROTOCOL,
+            RepoAuth => exit_code::NO_PERM,
+        }
+    }
+
+    /// Classify an `err_code`/`err_msg` pair from a response
+    pub fn classify(code: i32, message: &str) -> Option<Self> {
+        match code {
+            TRANSPORT_ERROR_CODE if message == TRANSPORT_ERROR_MSG => Some(Self::Transport),
+            500 if message.starts_with("cannot find function") => Some(Self::FunctionNotFound),
+            501 if message.starts_with("cannot find service") => Some(Self::ServiceNotFound),
+            511 if message.starts_with("failed to load RPC call") => Some(Self::BadRequest),
+            429 => Some(Self::Busy),
+            _ => Self::from_seafile_code(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_error(code: i32, message: &str) -> SearpcError {
+        SearpcError::RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_kind_searpc_codes() {
+        assert_eq!(
+            rpc_error(500, "cannot find function seafile_foo.").kind(),
+            Some(KnownErrorCode::FunctionNotFound)
+        );
+        assert_eq!(
+            rpc_error(501, "cannot find service seafile-rpcserver.").kind(),
+            Some(KnownErrorCode::ServiceNotFound)
+        );
+        assert_eq!(
+            rpc_error(511, "failed to load RPC call: bad json").kind(),
+            Some(KnownErrorCode::BadRequest)
+        );
+        assert_eq!(
+            rpc_error(TRANSPORT_ERROR_CODE, TRANSPORT_ERROR_MSG).kind(),
+            Some(KnownErrorCode::Transport)
+        );
+        assert_eq!(
+            rpc_error(429, "server busy, try again later").kind(),
+            Some(KnownErrorCode::Busy)
+        );
+        assert_eq!(
+            SearpcError::transport("Read failed").kind(),
+            Some(KnownErrorCode::Transport)
+        );
+    }
+
+    #[test]
+    fn test_kind_seafile_codes() {
+        assert_eq!(
+            rpc_error(501, "Repo not exists").kind(),
+            Some(KnownErrorCode::BadRepo)
+        );
+        assert_eq!(
+            rpc_error(511, "Bad dir id").kind(),
+            Some(KnownErrorCode::BadDirId)
+        );
+        assert_eq!(rpc_error(404, "Not found").kind(), None);
+        assert_eq!(SearpcError::TypeError("Expected int".into()).kind(), None);
+    }
+
+    #[test]
+    fn test_transport_io_source() {
+        use std::error::Error as _;
+        use std::io;
+
+        let err = SearpcError::transport_io(
+            "Write failed",
+            io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"),
+        );
+        assert_eq!(
+            err.to_string(),
+            "Transport error: Write failed: broken pipe"
+        );
+        assert_eq!(err.io_error().unwrap().kind(), io::ErrorKind::BrokenPipe);
+        assert!(err.source().is_some());
+
+        let err = SearpcError::transport("Received packet with zero length");
+        assert!(err.io_error().is_none());
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn test_code_roundtrip() {
+        for code in 500..=520 {
+            let kind = KnownErrorCode::from_seafile_code(code).unwrap();
+            assert_eq!(kind.code(), code);
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let err = rpc_error(516, "Path does not exist");
+        assert_eq!(
+            serde_json::to_string(&err).unwrap(),
+            r#"{"err_code":516,"err_msg":"Path does not exist"}"#
+        );
+
+        let err = SearpcError::TypeError("Expected int".into());
+        assert_eq!(
+            serde_json::to_string(&err).unwrap(),
+            r#"{"err_code":500,"err_msg":"Type error: Expected int"}"#
+        );
+    }
+
+    #[test]
+    fn test_call_context() {
+        let err = SearpcError::in_call(
+            "seafile_get_repo",
+            Some("<str:36>".into()),
+            SearpcError::TypeError("Expected int".into()),
+        );
+        assert_eq!(
+            err.to_string(),
+            "seafile_get_repo(<str:36>): Type error: Expected int"
+        );
+        assert_eq!(err.function(), Some("seafile_get_repo"));
+        assert!(matches!(err.inner(), SearpcError::TypeError(_)));
+
+        // Context is attached once, at the innermost call
+        let err = SearpcError::in_call("outer", None, err);
+        assert_eq!(err.function(), Some("seafile_get_repo"));
+
+        let err = SearpcError::in_call(
+            "seafile_foo",
+            None,
+            rpc_error(500, "cannot find function seafile_foo."),
+        );
+        assert_eq!(
+            err.to_string(),
+            "seafile_foo: RPC error 500: cannot find function seafile_foo."
+        );
+        assert_eq!(err.kind(), Some(KnownErrorCode::FunctionNotFound));
+        assert_eq!(err.err_code(), 500);
+        assert_eq!(err.err_msg(), "cannot find function seafile_foo.");
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(
+            rpc_error(503, "Invalid arguments").exit_code(),
+            exit_code::USAGE
+        );
+        assert_eq!(
+            rpc_error(514, "Repo is locked").exit_code(),
+            exit_code::TEMP_FAIL
+        );
+        assert_eq!(rpc_error(404, "Not found").exit_code(), exit_code::SOFTWARE);
+        assert_eq!(
+            SearpcError::transport("Read failed").exit_code(),
+            exit_code::UNAVAILABLE
+        );
+        assert_eq!(
+            SearpcError::in_call("f", None, SearpcError::TypeError("Expected int".into()))
+                .exit_code(),
+            exit_code::PROTOCOL
+        );
+    }
 }
@@ -0,0 +1,260 @@
+//! Transport over any byte stream
+//!
+//! [`FramedTransport`] does the framing of the socket transports for
+//! channels they do not know about, such as serial lines, a child
+//! process's stdio or socketpairs. The framing is either transport's, with
+//! or without the Seafile service envelope:
+//!
+//! ```rust,no_run
+//! use searpc::transport::Framing;
+//! use searpc::{FramedTransport, SearpcClient};
+//! use std::process::{Command, Stdio};
+//!
+//! let mut child = Command::new("rpc-server")
+//!     .stdin(Stdio::piped())
+//!     .stdout(Stdio::piped())
+//!     .spawn()?;
+//! let (stdin, stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+//! let transport = FramedTransport::split(stdout, stdin, Framing::U16);
+//! let mut client = SearpcClient::new(transport);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::error::Result;
+use crate::transport::{self, Framing, PipelinedTransport, Transport};
+use std::io::{self, Read, Write};
+
+/// Transport framing packets on a byte stream, see the [module docs](self)
+pub struct FramedTransport<S> {
+    stream: S,
+    framing: Framing,
+    /// Service envelope around requests, if any
+    service: Option<String>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+}
+
+/// Separate reading and writing halves as one stream, see
+/// [`FramedTransport::split`]
+#[derive(Debug)]
+pub struct Split<R, W> {
+    pub reader: R,
+    pub writer: W,
+}
+
+impl<R: Read, W: Write> Read for Split<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R: Read, W: Write> Write for Split<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<R: Read, W: Write> FramedTransport<Split<R, W>> {
+    /// Send bare requests over a channel with separate halves, such as a
+    /// pipe pair or stdio
+    pub fn split(reader: R, writer: W, framing: Framing) -> Self {
+        Self::new(Split { reader, writer }, framing)
+    }
+}
+
+impl<S: Read + Write> FramedTransport<S> {
+    /// Send bare requests over `stream` with `framing`
+    pub fn new(stream: S, framing: Framing) -> Self {
+        FramedTransport {
+            stream,
+            framing,
+            service: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Wrap requests in the Seafile service envelope for `service`
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The underlying stream; reading or writing it breaks the framing
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Send a packet, header and body in a single write
+    fn send_packet(&mut self, data: &[u8]) -> Result<()> {
+        let mut packet = std::mem::take(&mut self.buf);
+        let result = self
+            .framing
+            .encode(self.service.as_deref(), data, &mut packet)
+            .and_then(|()| transport::write_request(&mut self.stream, &packet))
+            // For buffering writers, like BufWriter
+            .and_then(|()| transport::flush_request(&mut self.stream));
+        self.buf = packet;
+        result
+    }
+
+    /// Receive a packet
+    fn recv_packet(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0u8; 4];
+        let header = &mut header[..self.framing.header_len()];
+        transport::read_response(&mut self.stream, header, true)?;
+        let len = self.framing.body_len(header)?;
+        let mut data = vec![0u8; len];
+        let (start, rest) = data.split_at_mut(self.framing.peek_len(header, len));
+        transport::read_response(&mut self.stream, start, false)?;
+        self.framing.check_start(header, start)?;
+        transport::read_response(&mut self.stream, rest, false)?;
+        Ok(data)
+    }
+}
+
+impl<S: Read + Write> Transport for FramedTransport<S> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.send_packet(request)?;
+        self.recv_packet()
+    }
+}
+
+impl<S: Read + Write> PipelinedTransport for FramedTransport<S> {
+    fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.send_packet(request)
+    }
+
+    fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.recv_packet()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::transport::Endianness;
+    use crate::{SearpcClient, SearpcServer, UnixSocketServer};
+    use serde_json::json;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
+
+    fn rpc_server() -> SearpcServer {
+        let mut server = SearpcServer::new();
+        server.register("ping", |_| Ok(json!("pong")));
+        server
+    }
+
+    /// Serve `stream` with the 16-bit demo framing
+    fn serve_demo(mut stream: UnixStream) {
+        let server = rpc_server();
+        let mut len = [0u8; 2];
+        while stream.read_exact(&mut len).is_ok() {
+            let mut request = vec![0u8; u16::from_be_bytes(len) as usize];
+            if stream.read_exact(&mut request).is_err() {
+                return;
+            }
+            let response = server.handle_request(&request);
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    }
+
+    /// Serve `stream` with the Seafile framing
+    fn serve_seafile(stream: UnixStream) {
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc_server());
+        let _ = Arc::new(server).serve_connection(stream);
+    }
+
+    #[test]
+    fn test_demo_framing() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || serve_demo(theirs));
+
+        // Through a BufWriter, which only sends once flushed
+        let reader = ours.try_clone().unwrap();
+        let transport = FramedTransport::split(reader, io::BufWriter::new(ours), Framing::U16);
+        let mut client = SearpcClient::new(transport);
+        for _ in 0..2 {
+            assert_eq!(client.call_string("ping", []).unwrap(), "pong");
+        }
+    }
+
+    #[test]
+    fn test_seafile_framing() {
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc_server());
+        server.set_endianness(Endianness::Little);
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || Arc::new(server).serve_connection(theirs));
+
+        let transport = FramedTransport::new(ours, Framing::U32(Endianness::Little))
+            .with_service("test-service");
+        let mut client = SearpcClient::new(transport);
+        assert_eq!(client.call_string("ping", []).unwrap(), "pong");
+        let err = client.call_string("missing", []).unwrap_err();
+        assert_eq!(err.inner().err_code(), 500);
+    }
+
+    #[test]
+    fn test_framing_mismatch() {
+        // A demo client on a Seafile socket
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || serve_seafile(theirs));
+        let mut client = SearpcClient::new(FramedTransport::new(ours, Framing::U16));
+        let err = client.call_string("ping", []).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+        assert!(
+            err.to_string().contains("32-bit Seafile framing"),
+            "{}",
+            err
+        );
+
+        // A Seafile client hearing from a demo server
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        theirs.write_all(b"\x00\x0b{\"ret\":\"x\"}").unwrap();
+        let transport = FramedTransport::new(ours, Framing::U32(Endianness::Little))
+            .with_service("test-service");
+        let err = SearpcClient::new(transport)
+            .call_string("ping", [])
+            .unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+        assert!(err.to_string().contains("16-bit demo framing"), "{}", err);
+    }
+
+    #[test]
+    fn test_probe_framing() {
+        fn connect(serve: fn(UnixStream)) -> io::Result<UnixStream> {
+            let (ours, theirs) = UnixStream::pair()?;
+            ours.set_read_timeout(Some(std::time::Duration::from_millis(200)))?;
+            std::thread::spawn(move || serve(theirs));
+            Ok(ours)
+        }
+        let framing = transport::probe_framing("test-service", || connect(serve_seafile));
+        assert_eq!(framing.unwrap(), Framing::U32(Endianness::Native));
+        let framing = transport::probe_framing("test-service", || connect(serve_demo));
+        assert_eq!(framing.unwrap(), Framing::U16);
+
+        let err = transport::probe_framing("test-service", || {
+            let (ours, _) = UnixStream::pair()?;
+            Ok(ours)
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("either framing"), "{}", err);
+    }
+}
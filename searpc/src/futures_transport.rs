@@ -0,0 +1,223 @@
+//! Async transport for runtimes other than tokio
+//!
+//! [`FuturesTransport`] frames packets like `FramedAsyncTransport`, over
+//! streams implementing the `futures-io` traits, such as those of smol and
+//! async-std. With it, [`AsyncSearpcClient`](crate::AsyncSearpcClient) runs
+//! without tokio:
+//!
+//! ```rust,no_run
+//! use searpc::transport::{Endianness, Framing};
+//! use searpc::{AsyncSearpcClient, FuturesTransport};
+//!
+//! smol::block_on(async {
+//!     let stream = smol::net::unix::UnixStream::connect("/path/to/seafile.sock").await?;
+//!     let transport = FuturesTransport::new(stream, Framing::U32(Endianness::Native))
+//!         .with_service("seafile-rpcserver");
+//!     let mut client = AsyncSearpcClient::new(transport);
+//!     let version = client.call_string("seafile_get_version", []).await?;
+//!     println!("{}", version);
+//!     Ok::<(), Box<dyn std::error::Error>>(())
+//! })?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::{
+    async_transport::{self, AsyncPipelinedTransport, AsyncTransport},
+    error::SearpcError,
+    transport::{is_disconnect, Framing},
+    Result,
+};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+
+/// Async transport framing packets on a `futures-io` stream, see the
+/// [module docs](self)
+pub struct FuturesTransport<S> {
+    stream: S,
+    framing: Framing,
+    /// Service envelope around requests, if any
+    service: Option<String>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> FuturesTransport<S> {
+    /// Send bare requests over `stream` with `framing`
+    pub fn new(stream: S, framing: Framing) -> Self {
+        FuturesTransport {
+            stream,
+            framing,
+            service: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Wrap requests in the Seafile service envelope for `service`
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// The underlying stream; reading or writing it breaks the framing
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Send a packet, header and body in a single write
+    async fn send_packet(&mut self, data: &[u8]) -> Result<()> {
+        let mut packet = std::mem::take(&mut self.buf);
+        let mut result = self
+            .framing
+            .encode(self.service.as_deref(), data, &mut packet);
+        if result.is_ok() {
+            result = self.write_all(&packet).await;
+        }
+        self.buf = packet;
+        result
+    }
+
+    /// Receive a packet
+    async fn recv_packet(&mut self) -> Result<Vec<u8>> {
+        let mut header = [0u8; 4];
+        let header = &mut header[..self.framing.header_len()];
+        self.read_exact(header, true).await?;
+        let len = self.framing.body_len(header)?;
+        let mut data = vec![0u8; len];
+        let (start, rest) = data.split_at_mut(self.framing.peek_len(header, len));
+        self.read_exact(start, false).await?;
+        self.framing.check_start(header, start)?;
+        self.read_exact(rest, false).await?;
+        Ok(data)
+    }
+
+    /// Write and flush all of `buf`, see `async_transport::write_request`
+    async fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match poll_fn(|cx| Pin::new(&mut self.stream).poll_write(cx, buf)).await {
+                Ok(0) => {
+                    return Err(async_transport::write_error(
+                        io::ErrorKind::WriteZero.into(),
+                    ))
+                }
+                Ok(n) => buf = &buf[n..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(async_transport::write_error(e)),
+            }
+        }
+        poll_fn(|cx| Pin::new(&mut self.stream).poll_flush(cx))
+            .await
+            .map_err(async_transport::write_error)
+    }
+
+    /// Read exactly `buf.len()` bytes of a response, see
+    /// `async_transport::read_response`
+    async fn read_exact(&mut self, buf: &mut [u8], frame_start: bool) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let unfilled = &mut buf[filled..];
+            match poll_fn(|cx| Pin::new(&mut self.stream).poll_read(cx, unfilled)).await {
+                Ok(0) => {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: true,
+                        mid_frame: !frame_start || filled > 0,
+                    })
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if is_disconnect(e.kind()) => {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: true,
+                        mid_frame: !frame_start || filled > 0,
+                    })
+                }
+                Err(e) => {
+                    return Err(SearpcError::TransportError {
+                        message: e.to_string(),
+                        source: Some(e),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncTransport for FuturesTransport<S> {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.send_packet(request).await?;
+        self.recv_packet().await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncPipelinedTransport for FuturesTransport<S> {
+    async fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.send_packet(request).await
+    }
+
+    async fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.recv_packet().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AsyncSearpcClient, SearpcServer};
+    use serde_json::json;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_smol_unix_socket() {
+        use crate::transport::Endianness;
+        use crate::UnixSocketServer;
+        use std::sync::Arc;
+
+        let mut rpc = SearpcServer::new();
+        rpc.register("ping", |_| Ok(json!("pong")));
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc);
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::thread::spawn(move || Arc::new(server).serve_connection(theirs));
+
+        smol::block_on(async {
+            let stream = smol::Async::new(ours).unwrap();
+            let transport = FuturesTransport::new(stream, Framing::U32(Endianness::Native))
+                .with_service("test-service");
+            let mut client = AsyncSearpcClient::new(transport);
+            assert_eq!(client.call_string("ping", []).await.unwrap(), "pong");
+
+            let results = client
+                .call_pipelined([("ping", []), ("missing", [])])
+                .await
+                .unwrap();
+            assert_eq!(results[0].as_ref().unwrap(), "pong");
+            assert!(results[1].is_err());
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_peer_gone() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        drop(theirs);
+        smol::block_on(async {
+            let mut transport =
+                FuturesTransport::new(smol::Async::new(ours).unwrap(), Framing::U16);
+            let err = transport.send(br#"["ping"]"#).await.unwrap_err();
+            assert!(err.is_connection_closed(), "{}", err);
+        });
+    }
+}
@@ -0,0 +1,266 @@
+//! `Arbitrary` implementations for property tests and fuzzing
+//!
+//! Enabled with the `arbitrary` feature. Covers [`Arg`], [`RpcRequest`] and
+//! [`RpcResponse`], plus [`Frame`] for feeding the packet decoders:
+//!
+//! ```rust
+//! use arbitrary::{Arbitrary, Unstructured};
+//! use searpc::RpcRequest;
+//!
+//! let raw = [7u8; 64];
+//! let request = RpcRequest::arbitrary(&mut Unstructured::new(&raw)).unwrap();
+//! let json: serde_json::Value = serde_json::from_str(&request.to_json().unwrap()).unwrap();
+//! assert_eq!(json[0], request.function_name.as_str());
+//! ```
+
+use crate::protocol::{RpcRequest, RpcResponse};
+use crate::types::Arg;
+use arbitrary::{Arbitrary, Result, Unstructured};
+use serde_json::{Map, Number, Value};
+
+/// Nesting limit for generated JSON values
+const MAX_DEPTH: usize = 3;
+
+/// Generate a JSON value nested at most `depth` levels deep
+///
+/// Floats are short binary fractions: JSON cannot carry NaN or infinities,
+/// and serde_json's default float parsing may be off by one ulp for others.
+fn arbitrary_value(u: &mut Unstructured<'_>, depth: usize) -> Result<Value> {
+    let kinds = if depth == 0 { 5 } else { 7 };
+    Ok(match u.choose_index(kinds)? {
+        0 => Value::Null,
+        1 => Value::Bool(u.arbitrary()?),
+        2 => Value::from(i64::arbitrary(u)?),
+        3 => {
+            let f = f64::from(i32::arbitrary(u)?) / 16.0;
+            Number::from_f64(f).map_or(Value::Null, Value::Number)
+        }
+        4 => Value::String(u.arbitrary()?),
+        5 => {
+            let len = u.int_in_range(0..=4)?;
+            let items = (0..len)
+                .map(|_| arbitrary_value(u, depth - 1))
+                .collect::<Result<_>>()?;
+            Value::Array(items)
+        }
+        _ => {
+            let len = u.int_in_range(0..=4)?;
+            let mut map = Map::new();
+            for _ in 0..len {
+                map.insert(u.arbitrary()?, arbitrary_value(u, depth - 1)?);
+            }
+            Value::Object(map)
+        }
+    })
+}
+
+impl<'a> Arbitrary<'a> for Arg {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.choose_index(5)? {
+            0 => Arg::Null,
+            1 => Arg::Int(u.arbitrary()?),
+            2 => Arg::Int64(u.arbitrary()?),
+            3 => Arg::String(u.arbitrary()?),
+            _ => Arg::Json(arbitrary_value(u, MAX_DEPTH)?),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for RpcRequest {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(RpcRequest::with_args(String::arbitrary(u)?, u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for RpcResponse {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let ret = if u.arbitrary()? {
+            Some(arbitrary_value(u, MAX_DEPTH)?)
+        } else {
+            None
+        };
+        let err_data = if u.arbitrary()? {
+            Some(arbitrary_value(u, MAX_DEPTH)?)
+        } else {
+            None
+        };
+        Ok(RpcResponse {
+            ret,
+            err_code: u.arbitrary()?,
+            err_msg: u.arbitrary()?,
+            err_data,
+        })
+    }
+}
+
+/// Bytes a transport decoder may receive: a length header and a body
+///
+/// The declared length is usually the body length, but may be off by a few
+/// bytes in either direction (truncated or trailing data) or zero. It never
+/// strays far from the body, so fuzzing does not just exercise huge
+/// allocations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub declared_len: u32,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    /// Whether the header matches the body and is non-zero
+    pub fn is_well_formed(&self) -> bool {
+        self.declared_len != 0 && self.declared_len as usize == self.body.len()
+    }
+
+    /// Encoding for [`TcpTransport`](crate::TcpTransport): 16-bit big-endian header
+    ///
+    /// The declared length is truncated to 16 bits.
+    pub fn tcp_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.declared_len as u16).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    /// Encoding for `UnixSocketTransport`: 32-bit native-endian header
+    pub fn unix_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.declared_len.to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+impl<'a> Arbitrary<'a> for Frame {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let body = if u.arbitrary()? {
+            serde_json::to_vec(&RpcResponse::arbitrary(u)?).unwrap_or_default()
+        } else {
+            u.arbitrary()?
+        };
+        let len = body.len() as u32;
+        let declared_len = match u.choose_index(4)? {
+            0 => len.saturating_sub(u.int_in_range(1..=8)?),
+            1 => len.saturating_add(u.int_in_range(1..=8)?),
+            2 => 0,
+            _ => len,
+        };
+        Ok(Frame { declared_len, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SearpcError;
+
+    /// Run `check` on inputs generated from a range of deterministic seeds
+    fn for_each_input<T: for<'a> Arbitrary<'a>>(mut check: impl FnMut(T)) {
+        for seed in 0..256u64 {
+            // xorshift, so each seed gives a different byte stream
+            let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+            let raw: Vec<u8> = (0..512)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            if let Ok(input) = T::arbitrary(&mut Unstructured::new(&raw)) {
+                check(input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        for_each_input(|request: RpcRequest| {
+            let json: Value = serde_json::from_str(&request.to_json().unwrap()).unwrap();
+            let array = json.as_array().unwrap();
+            assert_eq!(array[0], request.function_name.as_str());
+            assert_eq!(array.len(), request.args.len() + 1);
+            for (arg, value) in request.args.iter().zip(&array[1..]) {
+                assert_eq!(&serde_json::to_value(arg).unwrap(), value);
+            }
+        });
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        for_each_input(|response: RpcResponse| {
+            let json = response.to_json().unwrap();
+            let parsed = RpcResponse::from_json(&json).unwrap();
+            match (response.into_result(), parsed.into_result()) {
+                (Ok(a), Ok(b)) => assert_eq!(a, b),
+                (
+                    Err(SearpcError::RpcError { code, message }),
+                    Err(SearpcError::RpcError {
+                        code: code2,
+                        message: message2,
+                    }),
+                ) => {
+                    assert_eq!(code, code2);
+                    assert_eq!(message, message2);
+                }
+                (a, b) => panic!("round trip changed {:?} into {:?}", a, b),
+            }
+        });
+    }
+
+    /// What a decoder must return for `frame`: the declared bytes, or an error
+    fn expected_body(frame: &Frame) -> Option<&[u8]> {
+        let len = frame.declared_len as usize;
+        (len != 0 && len <= frame.body.len()).then(|| &frame.body[..len])
+    }
+
+    fn check_decoded(frame: &Frame, result: crate::Result<Vec<u8>>) {
+        match (expected_body(frame), result) {
+            (Some(body), Ok(decoded)) => assert_eq!(decoded, body),
+            (None, Err(_)) => {}
+            (expected, result) => panic!(
+                "{:?}: expected {:?}, got {:?}",
+                frame,
+                expected.map(<[u8]>::len),
+                result.map(|b| b.len())
+            ),
+        }
+        assert_eq!(
+            frame.is_well_formed(),
+            expected_body(frame) == Some(&frame.body[..])
+        );
+    }
+
+    #[test]
+    fn test_tcp_decoder() {
+        use crate::{TcpTransport, Transport};
+        use std::io::Write;
+        use std::net::{Shutdown, TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        for_each_input(|frame: Frame| {
+            let mut transport = TcpTransport::connect(addr).unwrap();
+            let (mut peer, _) = listener.accept().unwrap();
+            peer.write_all(&frame.tcp_bytes()).unwrap();
+            peer.shutdown(Shutdown::Write).unwrap();
+
+            check_decoded(&frame, transport.send(b"[\"f\"]"));
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_decoder() {
+        use crate::{Transport, UnixSocketTransport};
+        use std::io::Write;
+        use std::net::Shutdown;
+        use std::os::unix::net::UnixStream;
+
+        for_each_input(|frame: Frame| {
+            let (client, mut peer) = UnixStream::pair().unwrap();
+            let mut transport = UnixSocketTransport::new(client, "test");
+            peer.write_all(&frame.unix_bytes()).unwrap();
+            peer.shutdown(Shutdown::Write).unwrap();
+
+            check_decoded(&frame, transport.send(b"[\"f\"]"));
+        });
+    }
+}
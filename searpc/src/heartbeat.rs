@@ -0,0 +1,250 @@
+//! Heartbeats on idle connections
+//!
+//! A connection whose daemon died is normally only noticed by the next
+//! call. [`HeartbeatTransport`] pings the peer whenever the connection has
+//! been idle for an interval, so a dead connection shows up in
+//! [`is_alive`](HeartbeatTransport::is_alive) and fails the next call
+//! straight away, with an error that is safe to replay on a new connection.
+//!
+//! The protocol has no no-op frame, so a heartbeat is an ordinary call.
+//! Any response counts, including "cannot find function", so the ping
+//! function does not have to exist on the server.
+
+use crate::error::{Result, SearpcError};
+use crate::transport::{ConnectionAddr, Transport};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Request sent by default as a heartbeat
+pub const DEFAULT_PING: &[u8] = br#"["__searpc_ping"]"#;
+
+/// Transport wrapper pinging the peer while the connection is idle
+///
+/// ```rust,no_run
+/// use searpc::{HeartbeatTransport, SearpcClient, UnixSocketTransport};
+/// use std::time::Duration;
+///
+/// let transport = UnixSocketTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?;
+/// let transport = HeartbeatTransport::new(transport, Duration::from_secs(30));
+/// let mut client = SearpcClient::new(transport);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct HeartbeatTransport<T> {
+    shared: Arc<Shared<T>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+struct Shared<T> {
+    transport: Mutex<T>,
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+struct State {
+    last_used: Instant,
+    /// Why the connection is considered dead
+    dead: Option<String>,
+    stop: bool,
+}
+
+impl<T: Transport + Send + 'static> HeartbeatTransport<T> {
+    /// Ping with [`DEFAULT_PING`] after every `interval` without a call
+    pub fn new(transport: T, interval: Duration) -> Self {
+        Self::with_ping(transport, interval, DEFAULT_PING.to_vec())
+    }
+
+    /// Ping with the serialized request `ping`, e.g. a cheap function the
+    /// server is known to have
+    pub fn with_ping(transport: T, interval: Duration, ping: Vec<u8>) -> Self {
+        let shared = Arc::new(Shared {
+            transport: Mutex::new(transport),
+            state: Mutex::new(State {
+                last_used: Instant::now(),
+                dead: None,
+                stop: false,
+            }),
+            wake: Condvar::new(),
+        });
+        let worker = {
+            let shared = Arc::clone(&shared);
+            std::thread::Builder::new()
+                .name("searpc-heartbeat".to_string())
+                .spawn(move || shared.run(interval, &ping))
+                .expect("failed to spawn heartbeat thread")
+        };
+        HeartbeatTransport {
+            shared,
+            worker: Some(worker),
+        }
+    }
+}
+
+impl<T> HeartbeatTransport<T> {
+    /// Whether the last call or heartbeat got a response
+    pub fn is_alive(&self) -> bool {
+        self.shared.state().dead.is_none()
+    }
+}
+
+impl<T> Shared<T> {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn transport(&self) -> MutexGuard<'_, T> {
+        self.transport
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T: Transport> Shared<T> {
+    fn run(&self, interval: Duration, ping: &[u8]) {
+        let mut state = self.state();
+        loop {
+            if state.stop || state.dead.is_some() {
+                return;
+            }
+            let idle = state.last_used.elapsed();
+            if idle < interval {
+                state = self
+                    .wake
+                    .wait_timeout(state, interval - idle)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+                continue;
+            }
+            drop(state);
+
+            let result = self.transport().send(ping);
+            state = self.state();
+            state.last_used = Instant::now();
+            if let Err(e) = result {
+                tracing::warn!("searpc heartbeat failed: {}", e);
+                state.dead = Some(e.to_string());
+            }
+        }
+    }
+}
+
+impl<T: Transport> Transport for HeartbeatTransport<T> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        if self.shared.state().dead.is_some() {
+            // The request was never written, so it can go to a new connection
+            return Err(SearpcError::ConnectionClosed {
+                request_sent: false,
+                mid_frame: false,
+            });
+        }
+        let result = self.shared.transport().send(request);
+        let mut state = self.shared.state();
+        state.last_used = Instant::now();
+        if let Err(e) = &result {
+            if e.is_connection_closed() {
+                state.dead = Some(e.to_string());
+            }
+        }
+        result
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.shared.transport().peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.shared.transport().local_addr()
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        self.shared.transport().connection_age()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.shared.transport().set_timeout(timeout)
+    }
+}
+
+impl<T> Drop for HeartbeatTransport<T> {
+    fn drop(&mut self) {
+        self.shared.state().stop = true;
+        self.shared.wake.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[test]
+    fn test_heartbeat() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let alive = Arc::new(AtomicBool::new(true));
+        let transport = {
+            let pings = Arc::clone(&pings);
+            let alive = Arc::clone(&alive);
+            move |request: &[u8]| {
+                if !alive.load(Ordering::SeqCst) {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: true,
+                        mid_frame: false,
+                    });
+                }
+                if request == DEFAULT_PING {
+                    pings.fetch_add(1, Ordering::SeqCst);
+                    return Ok(
+                        br#"{"err_code":500,"err_msg":"cannot find function __searpc_ping."}"#
+                            .to_vec(),
+                    );
+                }
+                Ok(br#"{"ret":1}"#.to_vec())
+            }
+        };
+        let mut transport = HeartbeatTransport::new(transport, Duration::from_millis(20));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pings.load(Ordering::SeqCst) < 2 {
+            assert!(Instant::now() < deadline, "no heartbeats");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(transport.is_alive());
+        assert_eq!(transport.send(b"[\"f\"]").unwrap(), br#"{"ret":1}"#);
+
+        // The daemon goes away: the heartbeat notices before the next call
+        alive.store(false, Ordering::SeqCst);
+        while transport.is_alive() {
+            assert!(Instant::now() < deadline, "dead connection not detected");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let err = transport.send(b"[\"f\"]").unwrap_err();
+        assert!(err.is_connection_closed());
+        assert!(err.may_replay());
+    }
+
+    #[test]
+    fn test_busy_connection_not_pinged() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let transport = {
+            let pings = Arc::clone(&pings);
+            move |request: &[u8]| {
+                if request == DEFAULT_PING {
+                    pings.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(br#"{"ret":1}"#.to_vec())
+            }
+        };
+        let mut transport = HeartbeatTransport::new(transport, Duration::from_secs(60));
+        for _ in 0..10 {
+            transport.send(b"[\"f\"]").unwrap();
+        }
+        // Dropping stops the worker without waiting for the interval
+        let started = Instant::now();
+        drop(transport);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(pings.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! HTTP(S) transport
+//!
+//! Posts each request to an HTTP endpoint, for servers fronted by a
+//! reverse proxy. The body is the bare `["function_name", args...]`
+//! request and the response body is the usual
+//! `{"ret": ...}` / `{"err_code": ..., "err_msg": ...}` object, so no
+//! framing is involved. Any status other than 2xx is a transport error.
+//!
+//! ```rust,no_run
+//! use searpc::{HttpTransport, SearpcClient};
+//! use std::time::Duration;
+//!
+//! let transport = HttpTransport::new("https://seafile.example.com/rpc")
+//!     .header("Authorization", "Token 0123456789abcdef")
+//!     .timeout(Duration::from_secs(30));
+//! let mut client = SearpcClient::new(transport);
+//! ```
+
+use crate::error::{Result, SearpcError};
+use crate::transport::{ConnectionAddr, Transport};
+use std::io::Read;
+use std::time::Duration;
+
+pub use ureq;
+
+/// Transport posting requests to `url`
+pub struct HttpTransport {
+    agent: ureq::Agent,
+    url: String,
+    headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+}
+
+impl HttpTransport {
+    /// Post requests to `url` (`http://` or `https://`)
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_agent(ureq::Agent::new(), url)
+    }
+
+    /// Post requests with `agent`, for proxies, TLS or connection settings
+    pub fn with_agent(agent: ureq::Agent, url: impl Into<String>) -> Self {
+        HttpTransport {
+            agent,
+            url: url.into(),
+            headers: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Send `name: value` with every request, e.g. for authentication
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Give up on requests that take longer than `timeout` overall
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// URL requests are posted to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut post = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        for (name, value) in &self.headers {
+            post = post.set(name, value);
+        }
+        if let Some(timeout) = self.timeout {
+            post = post.timeout(timeout);
+        }
+        let response = match post.send_bytes(request) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                return Err(SearpcError::transport(format!(
+                    "HTTP {} {} from {}",
+                    status,
+                    response.status_text(),
+                    self.url
+                )))
+            }
+            Err(ureq::Error::Transport(e)) => {
+                return Err(SearpcError::transport(format!("HTTP: {}", e)))
+            }
+        };
+
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .read_to_end(&mut body)
+            .map_err(|e| SearpcError::transport_io("Reading HTTP response", e))?;
+        Ok(body)
+    }
+
+    /// The URL: each request may use a different connection
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        Some(ConnectionAddr::Url(self.url.clone()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::server::{arg, SearpcServer};
+    use crate::{Arg, SearpcClient};
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Answer `requests` HTTP requests, reporting each one's path and headers
+    pub(crate) fn http_server(
+        requests: usize,
+        status: &'static str,
+    ) -> (String, mpsc::Receiver<(String, Vec<String>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        let (seen, seen_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut server = SearpcServer::new();
+            server.register("searpc_strlen", |args| {
+                let s: String = arg(args, 0)?;
+                Ok(json!(s.len()))
+            });
+            for _ in 0..requests {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = Vec::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_string();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(len) = line.to_ascii_lowercase().strip_prefix("content-length: ") {
+                        content_length = len.parse().unwrap();
+                    }
+                    headers.push(line);
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                seen.send((request_line.trim_end().to_string(), headers))
+                    .unwrap();
+
+                let response = server.handle_request(&body);
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    response.len()
+                )
+                .unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, seen_rx)
+    }
+
+    #[test]
+    fn test_post() {
+        let (url, seen) = http_server(2, "200 OK");
+        let transport = HttpTransport::new(url).header("Authorization", "Token abc");
+        let mut client = SearpcClient::new(transport);
+        for s in ["hello", "searpc over http"] {
+            let len = client.call_int("searpc_strlen", [Arg::string(s)]).unwrap();
+            assert_eq!(len as usize, s.len());
+        }
+
+        let (request_line, headers) = seen.recv().unwrap();
+        assert_eq!(request_line, "POST /rpc HTTP/1.1");
+        assert!(headers.contains(&"Authorization: Token abc".to_string()));
+        assert!(headers
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case("content-type: application/json")));
+    }
+
+    #[test]
+    fn test_http_errors() {
+        let (url, _seen) = http_server(1, "502 Bad Gateway");
+        let mut transport = HttpTransport::new(url);
+        let err = transport.send(br#"["searpc_strlen","a"]"#).unwrap_err();
+        assert!(err.to_string().contains("HTTP 502 Bad Gateway"), "{}", err);
+
+        // Nothing listening
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        drop(listener);
+        let mut transport = HttpTransport::new(url).timeout(Duration::from_secs(5));
+        assert!(transport.send(br#"["searpc_strlen","a"]"#).is_err());
+    }
+}
@@ -15,7 +15,8 @@
 //!    - Single code path for all operations
 //!    - Use `?` operator for uniform error handling
 //!
-//! 3. **Memory safety by design**: Zero unsafe code
+//! 3. **Memory safety by design**: `unsafe` only wraps libc socket calls
+//!    the standard library lacks
 //!    - Automatic RAII vs manual g_free()
 //!    - Compiler-verified lifetime management
 //!
@@ -128,62 +129,161 @@
 //! - NULL parameter support (via `Arg::Null`)
 //! - Error handling (matches C's TRANSPORT_ERROR_CODE 500)
 //! - Type-safe API with compile-time checking
+//! - Server side: [`SearpcServer`] function registry, served over the Seafile
+//!   Unix socket protocol by `UnixSocketServer`
 //!
 //! ✅ **Async Support** (optional, enabled by default):
 //! - Async API with tokio runtime
 //! - [`AsyncSearpcClient`] for async operations
 //! - [`AsyncTcpTransport`] for async TCP
+//! - [`AsyncSearpcServer`] serving connections as tokio tasks
+//! - `FuturesTransport` for async-std and smol, with `rt-futures-io`
+//!   instead of the default `rt-tokio`
 //! - Disable with `default-features = false`
 //!
 //! ⏳ **Future** (not needed for basic usage):
-//! - Connection pooling
 //! - Procedural macros for convenience
-//! - Server implementation
 //!
 //! ## Code Metrics
 //!
-//! - **745 lines** vs C's ~2000 lines (-63%)
-//! - **Zero unsafe blocks**
+//! - **`unsafe` confined to the Unix socket modules**, around libc calls
+//!   for peer credentials, `SOCK_SEQPACKET`, listen backlogs and
+//!   non-blocking connects
 //! - **Zero compilation warnings**
-//! - **14 unit tests** (all passing)
+//! - **Over 190 unit tests** (all passing)
 //! - **100% C compatibility** (verified with demo server)
 
 pub mod client;
+#[cfg(feature = "compression")]
+pub mod compression;
+mod deadline;
+pub mod endpoint;
 pub mod error;
+pub mod framed_transport;
+pub mod heartbeat;
+#[cfg(feature = "http")]
+pub mod http_transport;
+pub mod metrics;
+#[cfg(unix)]
+pub mod multiplex;
+pub mod paths;
+pub mod pool;
 pub mod protocol;
+pub mod proxy;
+pub mod reconnect;
+pub mod recording;
+pub mod retry;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod scm_credentials;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod seqpacket_transport;
+pub mod server;
+pub mod shared;
+pub mod signature;
 pub mod tcp_transport;
+#[cfg(feature = "tls")]
+pub mod tls_transport;
 pub mod transport;
 pub mod types;
 
+#[cfg(unix)]
+pub mod server_builder;
+#[cfg(unix)]
+mod unix_connect;
+#[cfg(unix)]
+pub mod unix_server;
 #[cfg(unix)]
 pub mod unix_transport;
 
+// Test helpers (optional)
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
+// Arbitrary impls for property tests and fuzzing (optional)
+#[cfg(any(test, feature = "arbitrary"))]
+pub mod fuzz;
+
 // Async support (optional, enabled by default)
-#[cfg(feature = "async")]
+#[cfg(feature = "async-core")]
 pub mod async_client;
-#[cfg(feature = "async")]
+#[cfg(feature = "rt-tokio")]
+pub mod async_framed_transport;
+#[cfg(feature = "async-http")]
+pub mod async_http_transport;
+#[cfg(all(unix, feature = "rt-tokio"))]
+pub mod async_multiplex;
+#[cfg(feature = "rt-tokio")]
+pub mod async_server;
+#[cfg(feature = "rt-tokio")]
 pub mod async_tcp_transport;
-#[cfg(feature = "async")]
+#[cfg(feature = "async-tls")]
+pub mod async_tls_transport;
+#[cfg(feature = "async-core")]
 pub mod async_transport;
+#[cfg(all(unix, feature = "rt-tokio"))]
+pub mod async_unix_transport;
+#[cfg(feature = "rt-futures-io")]
+pub mod futures_transport;
+#[cfg(feature = "websocket")]
+pub mod websocket_transport;
 
 pub use client::SearpcClient;
-pub use error::{Result, SearpcError};
-pub use protocol::{RpcRequest, RpcResponse};
+#[cfg(feature = "compression")]
+pub use compression::Compressed;
+pub use endpoint::{connect_default, Endpoint};
+pub use error::{KnownErrorCode, Result, SearpcError};
+pub use framed_transport::FramedTransport;
+pub use heartbeat::HeartbeatTransport;
+#[cfg(feature = "http")]
+pub use http_transport::HttpTransport;
+#[cfg(unix)]
+pub use multiplex::MultiplexedTransport;
+pub use pool::{PooledSearpcClient, TransportPool};
+pub use protocol::{ObjlistIter, RpcRequest, RpcResponse};
+pub use reconnect::ReconnectingTransport;
+pub use recording::Recording;
+pub use retry::{RetryOn, RetryPolicy};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use seqpacket_transport::SeqpacketTransport;
+pub use server::SearpcServer;
+pub use shared::SharedSearpcClient;
 pub use tcp_transport::TcpTransport;
-pub use transport::Transport;
+#[cfg(feature = "tls")]
+pub use tls_transport::TlsTcpTransport;
+pub use transport::{PipelinedTransport, Transport};
 pub use types::{Arg, ExpandArgs, IntoArg};
 
+#[cfg(unix)]
+pub use server_builder::{systemd_listeners, ServerBuilder};
+#[cfg(unix)]
+pub use unix_server::{PeerCredentials, UnixSocketServer};
 #[cfg(unix)]
 pub use unix_transport::UnixSocketTransport;
 
 // Async exports
-#[cfg(feature = "async")]
+#[cfg(feature = "async-core")]
 pub use async_client::AsyncSearpcClient;
-#[cfg(feature = "async")]
+#[cfg(feature = "rt-tokio")]
+pub use async_framed_transport::FramedAsyncTransport;
+#[cfg(feature = "async-http")]
+pub use async_http_transport::AsyncHttpTransport;
+#[cfg(all(unix, feature = "rt-tokio"))]
+pub use async_multiplex::AsyncMultiplexedTransport;
+#[cfg(feature = "rt-tokio")]
+pub use async_server::AsyncSearpcServer;
+#[cfg(feature = "rt-tokio")]
 pub use async_tcp_transport::AsyncTcpTransport;
-#[cfg(feature = "async")]
-pub use async_transport::AsyncTransport;
+#[cfg(feature = "async-tls")]
+pub use async_tls_transport::AsyncTlsTransport;
+#[cfg(feature = "async-core")]
+pub use async_transport::{AsyncPipelinedTransport, AsyncTransport};
+#[cfg(all(unix, feature = "rt-tokio"))]
+pub use async_unix_transport::AsyncUnixSocketTransport;
+#[cfg(feature = "rt-futures-io")]
+pub use futures_transport::FuturesTransport;
+#[cfg(feature = "websocket")]
+pub use websocket_transport::WebSocketTransport;
 
 // Proc-macro exports
 #[cfg(feature = "macro")]
-pub use searpc_macro::{rpc, ExpandArgs};
+pub use searpc_macro::{rpc, searpc_service, ExpandArgs};
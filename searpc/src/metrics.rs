@@ -0,0 +1,239 @@
+//! Server metrics: per-function call counts and latencies
+//!
+//! A server with a [`MetricsSink`] reports every call to it, with the
+//! function name, how long the call took and its error code if it failed.
+//! Any `Fn(&str, Duration, Option<i32>)` closure is a sink, to forward
+//! calls to an existing metrics system; [`CallMetrics`] keeps counters and
+//! latency histograms itself and renders them for Prometheus.
+//!
+//! ```rust
+//! use searpc::metrics::CallMetrics;
+//! use searpc::SearpcServer;
+//! use serde_json::json;
+//! use std::sync::Arc;
+//!
+//! let metrics = Arc::new(CallMetrics::new());
+//! let mut server = SearpcServer::new();
+//! server
+//!     .register("seafile_get_version", |_| Ok(json!("9.0.0")))
+//!     .set_metrics(metrics.clone());
+//!
+//! server.handle_request(br#"["seafile_get_version"]"#);
+//! assert_eq!(metrics.snapshot()["seafile_get_version"].calls, 1);
+//! println!("{}", metrics.render_prometheus());
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Receives a record of every call a server handles
+pub trait MetricsSink: Send + Sync {
+    /// `function_name` ran for `duration`; `err_code` is set if it failed
+    fn record(&self, function_name: &str, duration: Duration, err_code: Option<i32>);
+}
+
+impl<F> MetricsSink for F
+where
+    F: Fn(&str, Duration, Option<i32>) + Send + Sync,
+{
+    fn record(&self, function_name: &str, duration: Duration, err_code: Option<i32>) {
+        self(function_name, duration, err_code)
+    }
+}
+
+/// Upper bounds of the latency histogram buckets
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// What [`CallMetrics`] knows about one function
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionStats {
+    /// Calls handled, failed or not
+    pub calls: u64,
+    /// Calls that returned an error
+    pub errors: u64,
+    /// Time spent in all calls
+    pub total_duration: Duration,
+    /// Calls per [`LATENCY_BUCKETS`] bucket: `buckets[i]` counts calls
+    /// slower than bucket `i - 1` and no slower than bucket `i`; the
+    /// extra last entry counts calls slower than every bucket
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+}
+
+impl FunctionStats {
+    fn record(&mut self, duration: Duration, failed: bool) {
+        self.calls += 1;
+        self.errors += u64::from(failed);
+        self.total_duration += duration;
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| duration <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+    }
+}
+
+/// In-memory [`MetricsSink`] with counters and latency histograms
+#[derive(Debug, Default)]
+pub struct CallMetrics {
+    functions: Mutex<HashMap<String, FunctionStats>>,
+}
+
+impl CallMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Statistics so far, by function name
+    pub fn snapshot(&self) -> BTreeMap<String, FunctionStats> {
+        self.functions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.clone()))
+            .collect()
+    }
+
+    /// Statistics in the Prometheus text exposition format
+    ///
+    /// Exposes `searpc_calls_total`, `searpc_call_errors_total` and the
+    /// `searpc_call_duration_seconds` histogram, labelled by `function`.
+    pub fn render_prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+
+        out.push_str("# HELP searpc_calls_total RPC calls handled.\n");
+        out.push_str("# TYPE searpc_calls_total counter\n");
+        for (name, stats) in &snapshot {
+            let _ = writeln!(
+                out,
+                "searpc_calls_total{{function=\"{}\"}} {}",
+                escape_label(name),
+                stats.calls
+            );
+        }
+
+        out.push_str("# HELP searpc_call_errors_total RPC calls that returned an error.\n");
+        out.push_str("# TYPE searpc_call_errors_total counter\n");
+        for (name, stats) in &snapshot {
+            let _ = writeln!(
+                out,
+                "searpc_call_errors_total{{function=\"{}\"}} {}",
+                escape_label(name),
+                stats.errors
+            );
+        }
+
+        out.push_str("# HELP searpc_call_duration_seconds Time spent handling RPC calls.\n");
+        out.push_str("# TYPE searpc_call_duration_seconds histogram\n");
+        for (name, stats) in &snapshot {
+            let name = escape_label(name);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&stats.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "searpc_call_duration_seconds_bucket{{function=\"{}\",le=\"{}\"}} {}",
+                    name,
+                    bound.as_secs_f64(),
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "searpc_call_duration_seconds_bucket{{function=\"{}\",le=\"+Inf\"}} {}",
+                name, stats.calls
+            );
+            let _ = writeln!(
+                out,
+                "searpc_call_duration_seconds_sum{{function=\"{}\"}} {}",
+                name,
+                stats.total_duration.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "searpc_call_duration_seconds_count{{function=\"{}\"}} {}",
+                name, stats.calls
+            );
+        }
+        out
+    }
+}
+
+impl MetricsSink for CallMetrics {
+    fn record(&self, function_name: &str, duration: Duration, err_code: Option<i32>) {
+        let mut functions = self
+            .functions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match functions.get_mut(function_name) {
+            Some(stats) => stats.record(duration, err_code.is_some()),
+            None => {
+                let mut stats = FunctionStats::default();
+                stats.record(duration, err_code.is_some());
+                functions.insert(function_name.to_string(), stats);
+            }
+        }
+    }
+}
+
+/// Escape a Prometheus label value
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_metrics() {
+        let metrics = CallMetrics::new();
+        metrics.record("seafile_get_repo", Duration::from_micros(50), None);
+        metrics.record("seafile_get_repo", Duration::from_millis(2), Some(501));
+        metrics.record("seafile_get_repo", Duration::from_secs(10), None);
+        metrics.record("seafile_sync", Duration::from_millis(1), None);
+
+        let snapshot = metrics.snapshot();
+        let repo = &snapshot["seafile_get_repo"];
+        assert_eq!((repo.calls, repo.errors), (3, 1));
+        assert_eq!(
+            repo.total_duration,
+            Duration::from_secs(10) + Duration::from_micros(2050)
+        );
+        assert_eq!(repo.buckets, [1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+        // Bounds are inclusive
+        assert_eq!(snapshot["seafile_sync"].buckets[2], 1);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("searpc_calls_total{function=\"seafile_get_repo\"} 3\n"));
+        assert!(text.contains("searpc_call_errors_total{function=\"seafile_sync\"} 0\n"));
+        assert!(text.contains(
+            "searpc_call_duration_seconds_bucket{function=\"seafile_get_repo\",le=\"0.005\"} 2\n"
+        ));
+        assert!(text.contains(
+            "searpc_call_duration_seconds_bucket{function=\"seafile_get_repo\",le=\"+Inf\"} 3\n"
+        ));
+        assert!(text.contains("searpc_call_duration_seconds_count{function=\"seafile_sync\"} 1\n"));
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
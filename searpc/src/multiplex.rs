@@ -0,0 +1,471 @@
+//! Several calls in flight on one Unix socket connection
+//!
+//! [`UnixSocketTransport`](crate::UnixSocketTransport) waits for each
+//! response before the next request goes out, so threads sharing a
+//! connection take turns. [`MultiplexedTransport`] adds an `"id"` to the
+//! service envelope of each request. A server supporting this extension,
+//! like [`UnixSocketServer`](crate::UnixSocketServer), answers
+//! `{"id": N, "response": "<response JSON as string>"}` as soon as each call
+//! finishes, and the response goes to the call with that ID. Clones share
+//! the connection, so each thread can have its own client:
+//!
+//! ```rust,no_run
+//! use searpc::{MultiplexedTransport, SearpcClient};
+//!
+//! let transport = MultiplexedTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?;
+//! let workers: Vec<_> = (0..8)
+//!     .map(|_| {
+//!         let mut client = SearpcClient::new(transport.clone());
+//!         std::thread::spawn(move || client.call_string("seafile_get_version", vec![]))
+//!     })
+//!     .collect();
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Legacy servers, like the C daemon, ignore the ID and answer with plain
+//! responses in request order. Those go to the oldest call still waiting,
+//! so such servers work too, only without calls overtaking each other.
+//!
+//! Servers can also push notifications, such as sync state changes, to
+//! clients that asked for them with [`MultiplexedTransport::subscribe`]:
+//!
+//! ```rust,no_run
+//! use searpc::MultiplexedTransport;
+//!
+//! let transport = MultiplexedTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?;
+//! for event in transport.subscribe()? {
+//!     println!("{}", event);
+//! }
+//! # Ok::<(), searpc::SearpcError>(())
+//! ```
+
+use crate::error::{Result, SearpcError};
+use crate::protocol::RpcResponse;
+use crate::transport::{
+    self, wrap_subscription, wrap_tagged_request, ConnectionAddr, Notification, TaggedResponse,
+    Transport,
+};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Transport sharing one Unix socket connection between concurrent calls,
+/// see the [module docs](self)
+#[derive(Clone)]
+pub struct MultiplexedTransport {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    service: String,
+    /// Held while picking an ID too, so requests go out in ID order
+    writer: Mutex<UnixStream>,
+    calls: Arc<Mutex<Calls>>,
+    reader: Option<JoinHandle<()>>,
+    connected: Instant,
+}
+
+#[derive(Default)]
+struct Calls {
+    next_id: u64,
+    /// Calls waiting for their response, by ID
+    waiting: BTreeMap<u64, mpsc::Sender<Result<Vec<u8>>>>,
+    /// Whether responses carry IDs, once the first one arrived
+    tagged: Option<bool>,
+    /// The connection failed: new calls fail straight away
+    closed: bool,
+    /// Receivers of notifications, see [`MultiplexedTransport::subscribe`]
+    subscribers: Vec<mpsc::Sender<Value>>,
+    /// See [`MultiplexedTransport::with_max_response_size`]
+    max_response_size: usize,
+}
+
+impl MultiplexedTransport {
+    /// Send requests for `service` over `stream`
+    ///
+    /// Starts a thread reading responses, which ends when the last clone is
+    /// dropped.
+    pub fn new(stream: UnixStream, service: impl Into<String>) -> io::Result<Self> {
+        let reader = stream.try_clone()?;
+        let calls = Arc::new(Mutex::new(Calls {
+            max_response_size: transport::DEFAULT_MAX_RESPONSE_SIZE,
+            ..Calls::default()
+        }));
+        let reader = {
+            let calls = Arc::clone(&calls);
+            std::thread::Builder::new()
+                .name("searpc-multiplex".to_string())
+                .spawn(move || read_responses(reader, &calls))?
+        };
+        Ok(MultiplexedTransport {
+            shared: Arc::new(Shared {
+                service: service.into(),
+                writer: Mutex::new(stream),
+                calls,
+                reader: Some(reader),
+                connected: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn connect(path: impl AsRef<Path>, service: impl Into<String>) -> io::Result<Self> {
+        Self::new(UnixStream::connect(path)?, service)
+    }
+
+    /// Longest response to accept, [`DEFAULT_MAX_RESPONSE_SIZE`](crate::transport::DEFAULT_MAX_RESPONSE_SIZE)
+    /// by default
+    ///
+    /// A longer one closes the connection, failing every waiting call with
+    /// [`SearpcError::ProtocolDesync`]. Applies to all clones.
+    pub fn with_max_response_size(self, max: usize) -> Self {
+        lock(&self.shared.calls).max_response_size = max;
+        self
+    }
+
+    /// Whether the server answers with IDs, i.e. supports multiplexing;
+    /// `None` until the first response arrived
+    pub fn is_multiplexed(&self) -> Option<bool> {
+        lock(&self.shared.calls).tagged
+    }
+
+    /// Receive the notifications the server pushes for the service, in
+    /// order
+    ///
+    /// Asks the server for them first, which fails on servers without
+    /// notification support, like the C daemon. Every receiver gets each
+    /// notification from then on; the channel ends with the connection.
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<Value>> {
+        let (notify, notifications) = mpsc::channel();
+        // Before asking: notifications may beat the answer back. If asking
+        // fails, the receiver is dropped and `notify` pruned with it.
+        lock(&self.shared.calls).subscribers.push(notify);
+        let mut response =
+            self.call(|id, packet| wrap_subscription(&self.shared.service, id, packet))?;
+        RpcResponse::from_bytes(&mut response)?.into_result()?;
+        Ok(notifications)
+    }
+
+    /// Send the envelope `encode` writes for a call ID, and wait for its
+    /// response
+    fn call(&self, encode: impl FnOnce(u64, &mut Vec<u8>) -> Result<()>) -> Result<Vec<u8>> {
+        let shared = &*self.shared;
+        let (respond, response) = mpsc::channel();
+        {
+            let mut writer = lock(&shared.writer);
+            let id = {
+                let mut calls = lock(&shared.calls);
+                if calls.closed {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: false,
+                        mid_frame: false,
+                    });
+                }
+                calls.next_id += 1;
+                calls.next_id
+            };
+            let mut packet = vec![0u8; 4];
+            encode(id, &mut packet)?;
+            let len = u32::try_from(packet.len() - 4)
+                .map_err(|_| SearpcError::transport("Request too large for 32-bit header"))?;
+            packet[..4].copy_from_slice(&len.to_ne_bytes());
+
+            // Registered first: the response may beat `write_request` back
+            lock(&shared.calls).waiting.insert(id, respond);
+            if let Err(e) = transport::write_request(&mut *writer, &packet) {
+                lock(&shared.calls).waiting.remove(&id);
+                // Possibly half a frame written: nothing after it can be read
+                let _ = writer.shutdown(Shutdown::Both);
+                return Err(e);
+            }
+        }
+        // The sender is only dropped unanswered if the reader thread died
+        response
+            .recv()
+            .unwrap_or(Err(SearpcError::ConnectionClosed {
+                request_sent: true,
+                mid_frame: false,
+            }))
+    }
+}
+
+impl Transport for MultiplexedTransport {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.call(|id, packet| wrap_tagged_request(&self.shared.service, Some(id), request, packet))
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        lock(&self.shared.writer)
+            .peer_addr()
+            .ok()
+            .map(ConnectionAddr::from)
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        lock(&self.shared.writer)
+            .local_addr()
+            .ok()
+            .map(ConnectionAddr::from)
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        Some(self.shared.connected.elapsed())
+    }
+}
+
+/// Hand each response on `stream` to its call until the connection closes
+fn read_responses(mut stream: UnixStream, calls: &Mutex<Calls>) {
+    let error = loop {
+        let mut header = [0u8; 4];
+        if let Err(e) = transport::read_response(&mut stream, &mut header, true) {
+            break e;
+        }
+        let len = u32::from_ne_bytes(header) as usize;
+        if len == 0 {
+            break SearpcError::transport("Received packet with zero length");
+        }
+        let max = lock(calls).max_response_size;
+        if let Err(e) = transport::check_response_len(len, max) {
+            break e;
+        }
+        let mut data = vec![0u8; len];
+        if let Err(e) = transport::read_response(&mut stream, &mut data, false) {
+            break e;
+        }
+
+        let mut calls = lock(calls);
+        let (call, response) = match Frame::parse(&data) {
+            Frame::Tagged(id, response) => {
+                calls.tagged = Some(true);
+                (calls.waiting.remove(&id), response.into_bytes())
+            }
+            Frame::Notification(notification) => {
+                match serde_json::from_str::<Value>(&notification) {
+                    Ok(notification) => calls
+                        .subscribers
+                        .retain(|subscriber| subscriber.send(notification.clone()).is_ok()),
+                    Err(e) => warn!("searpc multiplexed transport: bad notification: {}", e),
+                }
+                continue;
+            }
+            Frame::Plain => {
+                calls.tagged.get_or_insert(false);
+                let oldest = calls.waiting.pop_first().map(|(_, call)| call);
+                (oldest, data)
+            }
+        };
+        match call {
+            // The caller may have given up meanwhile
+            Some(call) => drop(call.send(Ok(response))),
+            None => warn!("searpc multiplexed transport: response to no pending call"),
+        }
+    };
+
+    let mut calls = lock(calls);
+    calls.closed = true;
+    // Ends the subscribers' channels
+    calls.subscribers.clear();
+    for (_, call) in std::mem::take(&mut calls.waiting) {
+        let _ = call.send(Err(pending_call_error(&error)));
+    }
+}
+
+/// Error for a call still waiting when reading responses failed with
+/// `error`
+pub(crate) fn pending_call_error(error: &SearpcError) -> SearpcError {
+    match *error {
+        SearpcError::ConnectionClosed { mid_frame, .. } => SearpcError::ConnectionClosed {
+            request_sent: true,
+            mid_frame,
+        },
+        SearpcError::ProtocolDesync { ref reason } => SearpcError::ProtocolDesync {
+            reason: reason.clone(),
+        },
+        ref e => SearpcError::transport(e.to_string()),
+    }
+}
+
+/// What a frame from the server is, by its shape
+pub(crate) enum Frame {
+    /// Response to the call with this ID
+    Tagged(u64, String),
+    /// Pushed by the server, for subscribers
+    Notification(String),
+    /// Response of a legacy server, to the oldest call
+    Plain,
+}
+
+impl Frame {
+    pub(crate) fn parse(data: &[u8]) -> Frame {
+        match serde_json::from_slice::<TaggedResponse<'_>>(data) {
+            Ok(tagged) => Frame::Tagged(tagged.id, tagged.response.into_owned()),
+            Err(_) => match serde_json::from_slice::<Notification<'_>>(data) {
+                Ok(notification) => Frame::Notification(notification.notification.into_owned()),
+                Err(_) => Frame::Plain,
+            },
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // Ends the reader thread's read
+        let _ = lock(&self.writer).shutdown(Shutdown::Both);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+impl fmt::Debug for MultiplexedTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let calls = lock(&self.shared.calls);
+        f.debug_struct("MultiplexedTransport")
+            .field("service", &self.shared.service)
+            .field("in_flight", &calls.waiting.len())
+            .field("multiplexed", &calls.tagged)
+            .field("subscribers", &calls.subscribers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{Arg, SearpcClient, SearpcServer, UnixSocketServer};
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+
+    fn rpc_server() -> SearpcServer {
+        let mut server = SearpcServer::new();
+        server.register("sleep_ms", |args| {
+            let ms: u64 = arg(args, 0)?;
+            std::thread::sleep(Duration::from_millis(ms));
+            Ok(json!(ms))
+        });
+        server
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc_server());
+        let server = Arc::new(server);
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || server.serve_connection(theirs));
+
+        let transport = MultiplexedTransport::new(ours, "test-service").unwrap();
+        assert_eq!(transport.is_multiplexed(), None);
+        let slow = {
+            let mut client = SearpcClient::new(transport.clone());
+            std::thread::spawn(move || {
+                let ms = client.call_int("sleep_ms", [Arg::int(500)]).unwrap();
+                (ms, Instant::now())
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        let mut client = SearpcClient::new(transport.clone());
+        assert_eq!(client.call_int("sleep_ms", [Arg::int(1)]).unwrap(), 1);
+        let fast_done = Instant::now();
+
+        let (ms, slow_done) = slow.join().unwrap();
+        assert_eq!(ms, 500);
+        assert!(fast_done < slow_done, "fast call waited for the slow one");
+        assert_eq!(transport.is_multiplexed(), Some(true));
+    }
+
+    /// Answers in order, without IDs, as libsearpc does
+    fn legacy_server(mut stream: UnixStream) {
+        let server = rpc_server();
+        loop {
+            let mut len = [0u8; 4];
+            if stream.read_exact(&mut len).is_err() {
+                return;
+            }
+            let mut packet = vec![0u8; u32::from_ne_bytes(len) as usize];
+            stream.read_exact(&mut packet).unwrap();
+            let envelope: serde_json::Value = serde_json::from_slice(&packet).unwrap();
+            let response = server.handle_request(envelope["request"].as_str().unwrap().as_bytes());
+            stream
+                .write_all(&(response.len() as u32).to_ne_bytes())
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_legacy_server() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || legacy_server(theirs));
+
+        let transport = MultiplexedTransport::new(ours, "test-service").unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let mut client = SearpcClient::new(transport.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        assert_eq!(client.call_int("sleep_ms", [Arg::int(i)]).unwrap(), i);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(transport.is_multiplexed(), Some(false));
+
+        // Takes the subscription for a call, and fails it
+        assert!(transport.subscribe().is_err());
+        let mut client = SearpcClient::new(transport);
+        assert_eq!(client.call_int("sleep_ms", [Arg::int(1)]).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_server_closes() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || {
+            let mut theirs = theirs;
+            let mut len = [0u8; 4];
+            theirs.read_exact(&mut len).unwrap();
+        });
+
+        let mut transport = MultiplexedTransport::new(ours, "test-service").unwrap();
+        let err = transport.send(br#"["sleep_ms",1]"#).unwrap_err();
+        assert!(err.is_connection_closed());
+        assert!(!err.may_replay());
+        // Later calls fail without writing
+        let err = transport.send(br#"["sleep_ms",1]"#).unwrap_err();
+        assert!(err.may_replay());
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || {
+            let mut theirs = theirs;
+            let mut len = [0u8; 4];
+            theirs.read_exact(&mut len).unwrap();
+            theirs.write_all(&2048u32.to_ne_bytes()).unwrap();
+        });
+
+        let mut transport = MultiplexedTransport::new(ours, "test-service")
+            .unwrap()
+            .with_max_response_size(1024);
+        let err = transport.send(br#"["sleep_ms",1]"#).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+        assert!(err.to_string().contains("limit of 1024 bytes"), "{}", err);
+    }
+}
@@ -0,0 +1,139 @@
+//! Where the Seafile and SeaDrive daemons listen
+//!
+//! seaf-daemon listens on `seafile.sock` in its data dir, which
+//! `seafile.ini` in the config dir (`~/.ccnet` by default) names. SeaDrive
+//! listens on `seadrive.sock` in `~/.seadrive/data`. On Windows, both listen
+//! on a named pipe per user instead. [`Daemon::default_socket`] works this
+//! out, so tools need not:
+//!
+//! ```rust,no_run
+//! use searpc::paths::Daemon;
+//!
+//! let socket = Daemon::Seafile.default_socket()?;
+//! println!("{} listens on {}", Daemon::Seafile.service(), socket.display());
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Config dir of seaf-daemon, in the home directory
+pub const CONF_DIR_NAME: &str = ".ccnet";
+/// File in the config dir holding the path of the data dir
+pub const SEAFILE_INI: &str = "seafile.ini";
+/// Socket of seaf-daemon, in its data dir
+pub const SEAFILE_SOCKET_NAME: &str = "seafile.sock";
+/// Data dir of SeaDrive, in the home directory
+pub const SEADRIVE_DATA_DIR: &str = ".seadrive/data";
+/// Socket of SeaDrive, in its data dir
+pub const SEADRIVE_SOCKET_NAME: &str = "seadrive.sock";
+/// Named pipe of seaf-daemon on Windows, followed by the user name
+pub const SEAFILE_PIPE_PREFIX: &str = r"\\.\pipe\seafile_";
+/// Named pipe of SeaDrive on Windows, followed by the user name
+pub const SEADRIVE_PIPE_PREFIX: &str = r"\\.\pipe\seadrive_";
+
+/// A daemon speaking searpc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Daemon {
+    /// seaf-daemon, of seaf-cli and the desktop client
+    Seafile,
+    /// The SeaDrive virtual drive daemon
+    SeaDrive,
+}
+
+impl Daemon {
+    /// Service the daemon's RPC functions are registered under
+    pub fn service(self) -> &'static str {
+        match self {
+            Daemon::Seafile => "seafile-rpcserver",
+            Daemon::SeaDrive => "seadrive-rpcserver",
+        }
+    }
+
+    /// Socket the daemon listens on for the current user with the default
+    /// config, or its named pipe on Windows
+    ///
+    /// For seaf-daemon, fails if there is no `seafile.ini`.
+    pub fn default_socket(self) -> io::Result<PathBuf> {
+        if cfg!(windows) {
+            let user = var("USERNAME").ok_or_else(|| not_found("USERNAME is not set"))?;
+            let prefix = match self {
+                Daemon::Seafile => SEAFILE_PIPE_PREFIX,
+                Daemon::SeaDrive => SEADRIVE_PIPE_PREFIX,
+            };
+            return Ok(PathBuf::from(format!("{}{}", prefix, user)));
+        }
+        let home = home_dir().ok_or_else(|| not_found("HOME is not set"))?;
+        match self {
+            Daemon::Seafile => seafile_socket(&home.join(CONF_DIR_NAME)),
+            Daemon::SeaDrive => Ok(home.join(SEADRIVE_DATA_DIR).join(SEADRIVE_SOCKET_NAME)),
+        }
+    }
+}
+
+/// Home directory from `HOME`, or `USERPROFILE` on Windows
+pub fn home_dir() -> Option<PathBuf> {
+    var("HOME")
+        .or_else(|| var("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// Default config dir of seaf-daemon, `~/.ccnet`
+pub fn default_conf_dir() -> Option<PathBuf> {
+    Some(home_dir()?.join(CONF_DIR_NAME))
+}
+
+/// Data dir named by `seafile.ini` in `conf_dir`
+pub fn read_datadir(conf_dir: &Path) -> io::Result<PathBuf> {
+    let seafile_ini = conf_dir.join(SEAFILE_INI);
+    let datadir = fs::read_to_string(&seafile_ini).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to read {}: {}", seafile_ini.display(), e),
+        )
+    })?;
+    Ok(PathBuf::from(datadir.trim()))
+}
+
+/// Socket of the seaf-daemon whose config dir is `conf_dir`
+pub fn seafile_socket(conf_dir: &Path) -> io::Result<PathBuf> {
+    Ok(read_datadir(conf_dir)?.join(SEAFILE_SOCKET_NAME))
+}
+
+/// Environment variable `name`, if set and not empty
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn not_found(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seafile_socket() {
+        let conf_dir = std::env::temp_dir().join(format!("searpc-paths-{}", std::process::id()));
+        fs::create_dir_all(&conf_dir).unwrap();
+        let err = seafile_socket(&conf_dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains(SEAFILE_INI), "{}", err);
+
+        fs::write(conf_dir.join(SEAFILE_INI), "/data/seafile-data\n").unwrap();
+        assert_eq!(
+            seafile_socket(&conf_dir).unwrap(),
+            Path::new("/data/seafile-data/seafile.sock")
+        );
+        fs::remove_dir_all(&conf_dir).unwrap();
+    }
+
+    #[test]
+    fn test_daemon() {
+        assert_eq!(Daemon::Seafile.service(), "seafile-rpcserver");
+        assert_eq!(Daemon::SeaDrive.service(), "seadrive-rpcserver");
+        assert_eq!(SEAFILE_PIPE_PREFIX, "\\\\.\\pipe\\seafile_");
+    }
+}
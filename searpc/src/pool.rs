@@ -0,0 +1,536 @@
+//! Pool of connections to one server
+//!
+//! A transport serves one call at a time, so threads sharing a client
+//! queue up behind each other. [`TransportPool`] keeps up to N idle
+//! connections and checks one out for each call; clones share the pool,
+//! so each thread can have its own client:
+//!
+//! ```rust,no_run
+//! use searpc::{SearpcClient, TransportPool, UnixSocketTransport};
+//!
+//! let pool = TransportPool::new(4, || {
+//!     Ok(UnixSocketTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?)
+//! });
+//! let workers: Vec<_> = (0..8)
+//!     .map(|_| {
+//!         let mut client = SearpcClient::new(pool.clone());
+//!         std::thread::spawn(move || client.call_string("seafile_get_version", vec![]))
+//!     })
+//!     .collect();
+//! ```
+//!
+//! Idle connections can go stale, e.g. when the daemon restarts. One that
+//! fails before the request was written (see [`SearpcError::may_replay`])
+//! is dropped and the call retried once on a new connection; connections
+//! idle for longer than [`idle_timeout`](TransportPool::idle_timeout) are
+//! not reused at all.
+//!
+//! [`PooledSearpcClient`] wraps a pool in a client that is `Clone`, `Send`
+//! and `Sync`, with `&self` call methods, so a web service can keep one in
+//! its shared state. Connections are made as concurrent callers need them.
+
+use crate::client::SearpcClient;
+use crate::error::{Result, SearpcError};
+use crate::protocol::ObjlistIter;
+use crate::transport::{ConnectionAddr, Transport};
+use crate::types::Arg;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+type Connect<T> = Box<dyn Fn() -> Result<T> + Send + Sync>;
+
+/// Pool of transports to one server, see the [module docs](self)
+pub struct TransportPool<T> {
+    inner: Arc<Inner<T>>,
+    /// Set on each connection this handle checks out
+    timeout: Option<Duration>,
+}
+
+struct Inner<T> {
+    connect: Connect<T>,
+    /// Idle connections, oldest first
+    idle: Mutex<Vec<Idle<T>>>,
+    max_idle: usize,
+    idle_timeout: Option<Duration>,
+}
+
+struct Idle<T> {
+    transport: T,
+    last_used: Instant,
+    /// As last set on the transport
+    timeout: Option<Duration>,
+}
+
+/// Connection checked out of a [`TransportPool`], returned to it on drop
+pub struct PooledTransport<T> {
+    pool: Arc<Inner<T>>,
+    transport: Option<T>,
+    /// Taken from the idle list, so it may have gone stale
+    reused: bool,
+    /// As last set on the transport
+    timeout: Option<Duration>,
+}
+
+impl<T: Transport> TransportPool<T> {
+    /// Keep up to `max_idle` idle connections made by `connect`
+    pub fn new<F>(max_idle: usize, connect: F) -> Self
+    where
+        F: Fn() -> Result<T> + Send + Sync + 'static,
+    {
+        TransportPool {
+            inner: Arc::new(Inner {
+                connect: Box::new(connect),
+                idle: Mutex::new(Vec::new()),
+                max_idle,
+                idle_timeout: None,
+            }),
+            timeout: None,
+        }
+    }
+
+    /// Drop connections that have been idle for longer than `timeout`
+    /// instead of reusing them
+    ///
+    /// Only takes effect on a pool that has not been cloned yet.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.idle_timeout = Some(timeout);
+        }
+        self
+    }
+
+    /// Check out a connection for several calls in a row
+    ///
+    /// Reuses an idle connection or makes a new one, with this handle's
+    /// timeout.
+    pub fn get(&self) -> Result<PooledTransport<T>> {
+        let reusable = {
+            let mut idle = self.inner.idle();
+            if let Some(timeout) = self.inner.idle_timeout {
+                idle.retain(|idle| idle.last_used.elapsed() < timeout);
+            }
+            idle.pop()
+        };
+        let (mut transport, reused, timeout) = match reusable {
+            Some(idle) => (idle.transport, true, idle.timeout),
+            None => ((self.inner.connect)()?, false, None),
+        };
+        if timeout != self.timeout {
+            transport.set_timeout(self.timeout)?;
+        }
+        Ok(PooledTransport {
+            pool: Arc::clone(&self.inner),
+            transport: Some(transport),
+            reused,
+            timeout: self.timeout,
+        })
+    }
+
+    /// Number of idle connections
+    pub fn idle_count(&self) -> usize {
+        self.inner.idle().len()
+    }
+}
+
+impl<T> Inner<T> {
+    fn idle(&self) -> MutexGuard<'_, Vec<Idle<T>>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Clone for TransportPool<T> {
+    fn clone(&self) -> Self {
+        TransportPool {
+            inner: Arc::clone(&self.inner),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TransportPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransportPool")
+            .field("idle", &self.inner.idle().len())
+            .field("max_idle", &self.inner.max_idle)
+            .field("idle_timeout", &self.inner.idle_timeout)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// Each call checks out a connection and returns it afterwards
+impl<T: Transport> Transport for TransportPool<T> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.get()?.send(request)
+    }
+
+    /// Set on connections as this handle checks them out, so it applies
+    /// to this handle and clones made from it later, not to the rest of
+    /// the pool. Fails on the next call if the connections cannot bound
+    /// calls.
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for PooledTransport<T> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let transport = self
+            .transport
+            .as_mut()
+            .ok_or_else(|| SearpcError::transport("Pooled connection was discarded"))?;
+        match transport.send(request) {
+            Ok(response) => Ok(response),
+            Err(e) if self.reused && e.may_replay() => {
+                // Stale idle connection: the server never saw the request
+                let mut transport = (self.pool.connect)()?;
+                if self.timeout.is_some() {
+                    transport.set_timeout(self.timeout)?;
+                }
+                self.transport = Some(transport);
+                self.reused = false;
+                self.send(request)
+            }
+            Err(e) => {
+                // Unknown state, possibly mid-frame: never reuse it
+                self.transport = None;
+                Err(e)
+            }
+        }
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.transport.as_ref()?.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.transport.as_ref()?.local_addr()
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        self.transport.as_ref()?.connection_age()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if let Some(transport) = &mut self.transport {
+            transport.set_timeout(timeout)?;
+        }
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+impl<T> Drop for PooledTransport<T> {
+    fn drop(&mut self) {
+        if let Some(transport) = self.transport.take() {
+            let mut idle = self.pool.idle();
+            if idle.len() < self.pool.max_idle {
+                idle.push(Idle {
+                    transport,
+                    last_used: Instant::now(),
+                    timeout: self.timeout,
+                });
+            }
+        }
+    }
+}
+
+/// Client over a [`TransportPool`], see the [module docs](self)
+///
+/// Each call checks out a connection of its own, so calls from several
+/// threads run side by side. Clones share the pool.
+pub struct PooledSearpcClient<T> {
+    pool: TransportPool<T>,
+}
+
+impl<T: Transport> PooledSearpcClient<T> {
+    /// Client keeping up to `max_idle` idle connections made by `connect`
+    pub fn new<F>(max_idle: usize, connect: F) -> Self
+    where
+        F: Fn() -> Result<T> + Send + Sync + 'static,
+    {
+        TransportPool::new(max_idle, connect).into()
+    }
+
+    /// The pool, e.g. for [`get`](TransportPool::get) to make several calls
+    /// on one connection
+    pub fn pool(&self) -> &TransportPool<T> {
+        &self.pool
+    }
+
+    /// A [`SearpcClient`] over the pool, for settings such as timeouts and
+    /// hooks; each of its calls checks out a connection too
+    ///
+    /// Settings only apply to the returned client: its timeout is set on
+    /// each connection it checks out.
+    pub fn client(&self) -> SearpcClient<TransportPool<T>> {
+        SearpcClient::new(self.pool.clone())
+    }
+
+    /// See [`SearpcClient::call`]
+    pub fn call(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.client().call(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_int`]
+    pub fn call_int(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<i32> {
+        self.client().call_int(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_int64`]
+    pub fn call_int64(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<i64> {
+        self.client().call_int64(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_string`]
+    pub fn call_string(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<String> {
+        self.client().call_string(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_object`]
+    pub fn call_object(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.client().call_object(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_objlist`]
+    pub fn call_objlist(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Vec<Value>> {
+        self.client().call_objlist(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_objlist_typed`]
+    pub fn call_objlist_typed<R: DeserializeOwned>(
+        &self,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<ObjlistIter<R>> {
+        self.client().call_objlist_typed(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_as`]
+    pub fn call_as<R: DeserializeOwned>(
+        &self,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<R> {
+        self.client().call_as(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_void`]
+    pub fn call_void(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<()> {
+        self.client().call_void(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_json`]
+    pub fn call_json(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.client().call_json(function_name, args)
+    }
+}
+
+impl<T> From<TransportPool<T>> for PooledSearpcClient<T> {
+    fn from(pool: TransportPool<T>) -> Self {
+        PooledSearpcClient { pool }
+    }
+}
+
+impl<T> Clone for PooledSearpcClient<T> {
+    fn clone(&self) -> Self {
+        PooledSearpcClient {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for PooledSearpcClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledSearpcClient")
+            .field("pool", &self.pool)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type TestTransport = Box<dyn FnMut(&[u8]) -> Result<Vec<u8>> + Send>;
+
+    /// Pool of closure transports failing with `fail` once they were used
+    /// `uses_before_failure` times
+    fn counting_pool(
+        max_idle: usize,
+        uses_before_failure: usize,
+        fail: fn() -> SearpcError,
+    ) -> (TransportPool<TestTransport>, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&connects);
+        let pool = TransportPool::new(max_idle, move || {
+            let id = counter.fetch_add(1, Ordering::SeqCst);
+            let mut uses = 0;
+            let transport: TestTransport = Box::new(move |_: &[u8]| {
+                uses += 1;
+                if uses > uses_before_failure {
+                    return Err(fail());
+                }
+                Ok(format!(r#"{{"ret":{}}}"#, id).into_bytes())
+            });
+            Ok(transport)
+        });
+        (pool, connects)
+    }
+
+    fn closed_before_send() -> SearpcError {
+        SearpcError::ConnectionClosed {
+            request_sent: false,
+            mid_frame: false,
+        }
+    }
+
+    fn closed_after_send() -> SearpcError {
+        SearpcError::ConnectionClosed {
+            request_sent: true,
+            mid_frame: false,
+        }
+    }
+
+    #[test]
+    fn test_reuse() {
+        let (mut pool, connects) = counting_pool(2, usize::MAX, closed_before_send);
+        for _ in 0..5 {
+            assert_eq!(pool.send(b"[]").unwrap(), br#"{"ret":0}"#);
+        }
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle_count(), 1);
+
+        // Three at once need three connections; only two stay idle
+        let held: Vec<_> = (0..3).map(|_| pool.get().unwrap()).collect();
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+        drop(held);
+        assert_eq!(pool.idle_count(), 2);
+    }
+
+    #[test]
+    fn test_stale_connection_replaced() {
+        let (mut pool, connects) = counting_pool(1, 1, closed_before_send);
+        assert_eq!(pool.send(b"[]").unwrap(), br#"{"ret":0}"#);
+        // The idle connection fails without sending: retried on a new one
+        assert_eq!(pool.send(b"[]").unwrap(), br#"{"ret":1}"#);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn test_failed_connection_discarded() {
+        let (mut pool, connects) = counting_pool(1, 1, closed_after_send);
+        pool.send(b"[]").unwrap();
+        // The server may have seen the request: no retry, no reuse
+        assert!(pool.send(b"[]").unwrap_err().is_connection_closed());
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(pool.send(b"[]").unwrap(), br#"{"ret":1}"#);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let (pool, connects) = counting_pool(1, usize::MAX, closed_before_send);
+        let mut pool = pool.idle_timeout(Duration::from_millis(10));
+        pool.send(b"[]").unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(pool.send(b"[]").unwrap(), br#"{"ret":1}"#);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_threads() {
+        let (pool, connects) = counting_pool(4, usize::MAX, closed_before_send);
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let mut client = crate::SearpcClient::new(pool.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        client.call_int("f", vec![]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(connects.load(Ordering::SeqCst) <= 8);
+        assert!(pool.idle_count() <= 4);
+    }
+
+    #[test]
+    fn test_pooled_client() {
+        fn assert_shareable<T: Clone + Send + Sync>(_: &T) {}
+
+        let (pool, connects) = counting_pool(4, usize::MAX, closed_before_send);
+        let client = PooledSearpcClient::from(pool);
+        assert_shareable(&client);
+        // No connection until the first call
+        assert_eq!(connects.load(Ordering::SeqCst), 0);
+        assert_eq!(client.call_int("f", []).unwrap(), 0);
+        assert_eq!(client.clone().call_int("f", []).unwrap(), 0);
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let client = client.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        client.call_int("f", []).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // One per thread at most, all kept
+        assert!(connects.load(Ordering::SeqCst) <= 4);
+        assert_eq!(client.pool().idle_count(), connects.load(Ordering::SeqCst));
+
+        // Errors still name the call
+        let (pool, _) = counting_pool(2, 0, closed_after_send);
+        let err = PooledSearpcClient::from(pool)
+            .call_int("f", [])
+            .unwrap_err();
+        assert_eq!(err.function(), Some("f"));
+    }
+
+    /// Records the timeouts set on it
+    struct TimeoutTransport(Arc<Mutex<Vec<Option<Duration>>>>);
+
+    impl Transport for TimeoutTransport {
+        fn send(&mut self, _: &[u8]) -> Result<Vec<u8>> {
+            Ok(br#"{"ret":0}"#.to_vec())
+        }
+
+        fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+            self.0.lock().unwrap().push(timeout);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let set = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&set);
+        let client = PooledSearpcClient::new(1, move || Ok(TimeoutTransport(Arc::clone(&log))));
+
+        let mut with_timeout = client.client();
+        with_timeout
+            .set_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        with_timeout.call_int("f", []).unwrap();
+        // The idle connection is reset for clients without the timeout,
+        // once
+        client.call_int("f", []).unwrap();
+        client.call_int("f", []).unwrap();
+        assert_eq!(*set.lock().unwrap(), [Some(Duration::from_secs(1)), None]);
+    }
+}
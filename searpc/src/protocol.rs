@@ -1,7 +1,12 @@
 use crate::error::{Result, SearpcError};
 use crate::types::Arg;
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::value::RawValue;
 use serde_json::Value;
+use std::marker::PhantomData;
+use std::ops::Range;
 
 /// RPC Request
 ///
@@ -35,36 +40,177 @@ impl RpcRequest {
 
     /// Serialize to JSON string
     pub fn to_json(&self) -> Result<String> {
-        // Build the array: [fname, arg1, arg2, ...]
-        let mut arr = Vec::with_capacity(1 + self.args.len());
-        arr.push(Value::String(self.function_name.clone()));
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Serialize into `buf`, replacing its contents
+    ///
+    /// Reusing one buffer across calls avoids allocating per request.
+    pub fn write_json(&self, buf: &mut Vec<u8>) -> Result<()> {
+        Self::encode(&self.function_name, &self.args, buf)
+    }
+
+    /// Serialize a call from a borrowed function name and arguments into
+    /// `buf`, replacing its contents, without building an `RpcRequest`
+    pub fn encode(function_name: &str, args: &[Arg], buf: &mut Vec<u8>) -> Result<()> {
+        buf.clear();
+        serde_json::to_writer(
+            &mut *buf,
+            &Call {
+                function_name,
+                args,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Loggable summary of the arguments, e.g. `<str:36>, 1, null`
+    ///
+    /// String and JSON values are replaced by their length, since they may
+    /// carry passwords or tokens.
+    pub fn args_summary(&self) -> String {
+        summarize_args(&self.args)
+    }
+
+    /// Tag `error` with this call's function name and argument summary
+    pub fn error_context(&self, error: SearpcError) -> SearpcError {
+        SearpcError::in_call(&self.function_name, Some(self.args_summary()), error)
+    }
+}
+
+/// See [`RpcRequest::args_summary`]
+pub(crate) fn summarize_args(args: &[Arg]) -> String {
+    args.iter()
+        .map(|arg| match arg {
+            Arg::Null => "null".to_string(),
+            Arg::Int(v) => v.to_string(),
+            Arg::Int64(v) => v.to_string(),
+            Arg::String(s) => format!("<str:{}>", s.len()),
+            Arg::Json(v) => format!("<json:{}>", v.to_string().len()),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A call's result as `R`, or [`SearpcError::TypeError`] if it does not fit
+pub(crate) fn from_value<R: DeserializeOwned>(value: Value) -> Result<R> {
+    serde_json::from_value(value).map_err(|e| {
+        SearpcError::TypeError(format!("Expected {}: {}", std::any::type_name::<R>(), e))
+    })
+}
+
+/// Borrowed `["function_name", arg1, ...]`, serialized without copying
+struct Call<'a> {
+    function_name: &'a str,
+    args: &'a [Arg],
+}
 
-        for arg in &self.args {
-            arr.push(serde_json::to_value(arg)?);
+impl Serialize for Call<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(1 + self.args.len()))?;
+        seq.serialize_element(self.function_name)?;
+        for arg in self.args {
+            seq.serialize_element(arg)?;
         }
+        seq.end()
+    }
+}
 
-        Ok(serde_json::to_string(&arr)?)
+impl Serialize for RpcRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Call {
+            function_name: &self.function_name,
+            args: &self.args,
+        }
+        .serialize(serializer)
     }
 }
 
 /// RPC Response
 ///
 /// Deserializes from: {"ret": value, "err_code": code, "err_msg": msg}
-#[derive(Debug, Clone, Deserialize)]
+///
+/// Serializes the way libsearpc servers answer: `{"ret": value}` on success,
+/// `{"err_code": code, "err_msg": msg}` on error. `err_data` is an optional
+/// extension carrying structured error details; libsearpc ignores it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct RpcResponse {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub ret: Option<Value>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub err_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub err_msg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub err_data: Option<Value>,
 }
 
 impl RpcResponse {
+    /// Successful response carrying `ret`
+    pub fn ok(ret: Value) -> Self {
+        RpcResponse {
+            ret: Some(ret),
+            err_code: None,
+            err_msg: None,
+            err_data: None,
+        }
+    }
+
+    /// Error response
+    pub fn error(code: i32, message: impl Into<String>) -> Self {
+        RpcResponse {
+            ret: None,
+            err_code: Some(code),
+            err_msg: Some(message.into()),
+            err_data: None,
+        }
+    }
+
+    /// Attach structured error details
+    pub fn with_err_data(mut self, data: Value) -> Self {
+        self.err_data = Some(data);
+        self
+    }
+
+    /// Response for the outcome of a handler
+    pub fn from_result(result: Result<Value>) -> Self {
+        match result {
+            Ok(ret) => RpcResponse::ok(ret),
+            Err(e) => e.into(),
+        }
+    }
+
     /// Parse from JSON string
     pub fn from_json(json: &str) -> Result<Self> {
         Ok(serde_json::from_str(json)?)
     }
 
+    /// Parse from the raw bytes of a response
+    ///
+    /// With the `simd-json` feature, responses of `SIMD_JSON_MIN_LEN` (16 KiB)
+    /// or more are parsed with simd-json, which works in place and may leave
+    /// `bytes` modified. Smaller ones, where simd-json's setup cost does not
+    /// pay off, go through serde_json.
+    pub fn from_bytes(bytes: &mut [u8]) -> Result<Self> {
+        #[cfg(feature = "simd-json")]
+        if bytes.len() >= SIMD_JSON_MIN_LEN {
+            return simd_json::serde::from_slice(bytes).map_err(|e| {
+                SearpcError::InvalidResponse(format!("Invalid JSON response: {}", e))
+            });
+        }
+
+        let json = std::str::from_utf8(bytes).map_err(|e| {
+            SearpcError::InvalidResponse(format!("Response is not valid UTF-8: {}", e))
+        })?;
+        Self::from_json(json)
+    }
+
+    /// Serialize to JSON string
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
     /// Check if response contains an error
     pub fn into_result(self) -> Result<Value> {
         if let Some(code) = self.err_code {
@@ -83,6 +229,113 @@ impl RpcResponse {
     }
 }
 
+/// Size from which [`RpcResponse::from_bytes`] uses simd-json
+#[cfg(feature = "simd-json")]
+pub const SIMD_JSON_MIN_LEN: usize = 16 * 1024;
+
+/// Typed elements of an objlist response, decoded one at a time
+///
+/// Holds the raw response and the position of each array element in it.
+/// Elements are deserialized straight into `T` as the iterator advances, so
+/// a large listing never exists as a `Vec<Value>` next to the typed result.
+pub struct ObjlistIter<T> {
+    bytes: Vec<u8>,
+    spans: std::vec::IntoIter<Range<usize>>,
+    index: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+/// Envelope of a response whose `ret` is left unparsed
+#[derive(Deserialize)]
+struct RawResponse<'a> {
+    #[serde(borrow, default)]
+    ret: Option<&'a RawValue>,
+    err_code: Option<i32>,
+    err_msg: Option<String>,
+}
+
+impl<T: DeserializeOwned> ObjlistIter<T> {
+    /// Locate the elements of an objlist response
+    ///
+    /// Checks for an error response and validates the JSON, but does not
+    /// build any element. A `null` or missing `ret` gives an empty list, as
+    /// in [`SearpcClient::call_objlist`](crate::SearpcClient::call_objlist).
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let spans = {
+            let response: RawResponse<'_> = serde_json::from_slice(&bytes)?;
+            if let Some(code) = response.err_code {
+                return Err(SearpcError::RpcError {
+                    code,
+                    message: response
+                        .err_msg
+                        .unwrap_or_else(|| "Unknown error".to_string()),
+                });
+            }
+            match response.ret {
+                None => Vec::new(),
+                Some(ret) if ret.get().starts_with('[') => {
+                    let items: Vec<&RawValue> = serde_json::from_str(ret.get())?;
+                    let base = bytes.as_ptr() as usize;
+                    items
+                        .into_iter()
+                        .map(|item| {
+                            let start = item.get().as_ptr() as usize - base;
+                            start..start + item.get().len()
+                        })
+                        .collect()
+                }
+                Some(ret) => {
+                    let value: Value = serde_json::from_str(ret.get())?;
+                    return Err(SearpcError::TypeError(format!(
+                        "Expected array, got: {:?}",
+                        value
+                    )));
+                }
+            }
+        };
+        Ok(ObjlistIter {
+            bytes,
+            spans: spans.into_iter(),
+            index: 0,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for ObjlistIter<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        let span = self.spans.next()?;
+        let index = self.index;
+        self.index += 1;
+        Some(serde_json::from_slice(&self.bytes[span]).map_err(|e| {
+            SearpcError::TypeError(format!(
+                "Failed to deserialize objlist element {}: {}",
+                index, e
+            ))
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.spans.size_hint()
+    }
+}
+
+impl<T: DeserializeOwned> ExactSizeIterator for ObjlistIter<T> {}
+
+impl From<&SearpcError> for RpcResponse {
+    fn from(err: &SearpcError) -> Self {
+        RpcResponse::error(err.err_code(), err.err_msg())
+    }
+}
+
+impl From<SearpcError> for RpcResponse {
+    fn from(err: SearpcError) -> Self {
+        RpcResponse::from(&err)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,6 +348,28 @@ mod tests {
 
         let json = req.to_json().unwrap();
         assert_eq!(json, r#"["get_substring","hello",2]"#);
+
+        let mut buf = b"stale".to_vec();
+        req.write_json(&mut buf).unwrap();
+        assert_eq!(buf, br#"["get_substring","hello",2]"#);
+    }
+
+    #[test]
+    fn test_args_summary() {
+        let req = RpcRequest::with_args(
+            "seafile_clone",
+            vec![
+                Arg::string("secret-token"),
+                Arg::int(1),
+                Arg::int64(-2),
+                Arg::Null,
+            ],
+        );
+        assert_eq!(req.args_summary(), "<str:12>, 1, -2, null");
+        assert!(!req
+            .error_context(SearpcError::transport("x"))
+            .to_string()
+            .contains("secret"));
     }
 
     #[test]
@@ -127,4 +402,144 @@ mod tests {
         let value = resp.into_result().unwrap();
         assert_eq!(value.as_str(), Some("hello world"));
     }
+
+    #[test]
+    fn test_response_from_bytes() {
+        let mut small = br#"{"ret": 42}"#.to_vec();
+        let value = RpcResponse::from_bytes(&mut small).unwrap().into_result();
+        assert_eq!(value.unwrap().as_i64(), Some(42));
+
+        // Large enough for simd-json when the feature is on
+        let repos: Vec<_> = (0..1000)
+            .map(|i| serde_json::json!({"id": format!("repo-{}", i), "name": "Docs \"é\""}))
+            .collect();
+        let mut large = serde_json::to_vec(&serde_json::json!({ "ret": repos })).unwrap();
+        let value = RpcResponse::from_bytes(&mut large)
+            .unwrap()
+            .into_result()
+            .unwrap();
+        assert_eq!(value.as_array().unwrap().len(), 1000);
+        assert_eq!(value[999]["id"], "repo-999");
+        assert_eq!(value[0]["name"], "Docs \"é\"");
+
+        let mut invalid = b"{\"ret\": \"\xff\"}".to_vec();
+        assert!(matches!(
+            RpcResponse::from_bytes(&mut invalid),
+            Err(SearpcError::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_response_serialization() {
+        let resp = RpcResponse::ok(serde_json::json!(42));
+        assert_eq!(resp.to_json().unwrap(), r#"{"ret":42}"#);
+
+        let resp = RpcResponse::error(501, "cannot find service foo.");
+        assert_eq!(
+            resp.to_json().unwrap(),
+            r#"{"err_code":501,"err_msg":"cannot find service foo."}"#
+        );
+
+        let resp = RpcResponse::error(503, "bad args").with_err_data(serde_json::json!({"arg": 1}));
+        assert_eq!(
+            resp.to_json().unwrap(),
+            r#"{"err_code":503,"err_msg":"bad args","err_data":{"arg":1}}"#
+        );
+    }
+
+    #[test]
+    fn test_error_into_response() {
+        let err = SearpcError::RpcError {
+            code: 514,
+            message: "Repo is locked".to_string(),
+        };
+        let resp = RpcResponse::from(err);
+        assert_eq!(resp.err_code, Some(514));
+        assert_eq!(resp.err_msg.as_deref(), Some("Repo is locked"));
+
+        // Round trip: the client sees the original error again
+        let json = resp.to_json().unwrap();
+        match RpcResponse::from_json(&json).unwrap().into_result() {
+            Err(SearpcError::RpcError { code, message }) => {
+                assert_eq!(code, 514);
+                assert_eq!(message, "Repo is locked");
+            }
+            other => panic!("Expected RpcError, got {:?}", other),
+        }
+
+        let resp = RpcResponse::from(SearpcError::transport("Read failed: reset"));
+        assert_eq!(resp.err_code, Some(crate::error::TRANSPORT_ERROR_CODE));
+        assert_eq!(
+            resp.err_msg.as_deref(),
+            Some("Transport error: Read failed: reset")
+        );
+    }
+
+    #[test]
+    fn test_objlist_iter() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Repo {
+            id: String,
+            size: u64,
+        }
+
+        let body = br#"{"ret": [{"id": "a", "size": 1}, {"id": "b\u00e9", "size": 2}]}"#;
+        let repos: ObjlistIter<Repo> = ObjlistIter::from_bytes(body.to_vec()).unwrap();
+        assert_eq!(repos.len(), 2);
+        let repos: Vec<Repo> = repos.collect::<Result<_>>().unwrap();
+        assert_eq!(
+            repos,
+            vec![
+                Repo {
+                    id: "a".to_string(),
+                    size: 1
+                },
+                Repo {
+                    id: "b\u{e9}".to_string(),
+                    size: 2
+                },
+            ]
+        );
+
+        for empty in [&br#"{"ret": null}"#[..], br#"{}"#, br#"{"ret": []}"#] {
+            let mut items = ObjlistIter::<Value>::from_bytes(empty.to_vec()).unwrap();
+            assert!(items.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_objlist_iter_errors() {
+        let error = br#"{"err_code": 500, "err_msg": "Internal error"}"#;
+        match ObjlistIter::<Value>::from_bytes(error.to_vec()) {
+            Err(SearpcError::RpcError { code, message }) => {
+                assert_eq!(code, 500);
+                assert_eq!(message, "Internal error");
+            }
+            _ => panic!("Expected RpcError"),
+        }
+
+        let not_array = br#"{"ret": 5}"#;
+        assert!(matches!(
+            ObjlistIter::<Value>::from_bytes(not_array.to_vec()),
+            Err(SearpcError::TypeError(_))
+        ));
+
+        // Malformed JSON is caught before any element is produced
+        let truncated = br#"{"ret": [1, 2"#;
+        assert!(ObjlistIter::<i32>::from_bytes(truncated.to_vec()).is_err());
+
+        // A bad element fails on its own
+        let mixed = br#"{"ret": [1, "two", 3]}"#;
+        let items: Vec<_> = ObjlistIter::<i32>::from_bytes(mixed.to_vec())
+            .unwrap()
+            .collect();
+        assert_eq!(items[0].as_ref().unwrap(), &1);
+        match &items[1] {
+            Err(SearpcError::TypeError(msg)) => {
+                assert!(msg.starts_with("Failed to deserialize objlist element 1:"))
+            }
+            other => panic!("Expected TypeError, got {:?}", other),
+        }
+        assert_eq!(items[2].as_ref().unwrap(), &3);
+    }
 }
@@ -0,0 +1,248 @@
+//! Recovering from a connection that lost its framing
+//!
+//! Once a length header is corrupted, every later read starts in the middle
+//! of some frame, and the connection only yields garbage. The socket
+//! transports refuse implausible lengths with
+//! [`SearpcError::ProtocolDesync`](crate::SearpcError::ProtocolDesync).
+//! [`ReconnectingTransport`] also refuses responses that are not JSON
+//! objects. On such a desync it drops the connection, and the next call
+//! connects anew:
+//!
+//! ```rust,no_run
+//! use searpc::{ReconnectingTransport, SearpcClient, UnixSocketTransport};
+//!
+//! let transport = ReconnectingTransport::new(|| {
+//!     Ok(UnixSocketTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?)
+//! });
+//! let mut client = SearpcClient::new(transport);
+//! ```
+//!
+//! The call that hit the desync still fails: the server may have run it.
+
+use crate::error::Result;
+use crate::transport::{self, ConnectionAddr, Transport};
+use std::fmt;
+use std::time::Duration;
+use tracing::warn;
+
+type Connect<T> = Box<dyn FnMut() -> Result<T> + Send>;
+
+/// Transport replacing its connection after a desync, see the
+/// [module docs](self)
+pub struct ReconnectingTransport<T> {
+    connect: Connect<T>,
+    /// `None` until the first call, and after a dropped connection
+    inner: Option<T>,
+    dropped: u64,
+    /// Set on each new connection
+    timeout: Option<Duration>,
+}
+
+impl<T: Transport> ReconnectingTransport<T> {
+    /// Connect with `connect` on the first call, and again after each
+    /// dropped connection
+    pub fn new<F>(connect: F) -> Self
+    where
+        F: FnMut() -> Result<T> + Send + 'static,
+    {
+        ReconnectingTransport {
+            connect: Box::new(connect),
+            inner: None,
+            dropped: 0,
+            timeout: None,
+        }
+    }
+
+    /// The current connection, if there is one
+    pub fn get_ref(&self) -> Option<&T> {
+        self.inner.as_ref()
+    }
+
+    /// How many connections were dropped so far
+    pub fn dropped_connections(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl<T: Transport> Transport for ReconnectingTransport<T> {
+    /// Connections are also dropped when the server closes them, or when a
+    /// call times out. If the server closed one before the request went
+    /// out, the request is sent once more on a new connection.
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let reused = self.inner.is_some();
+        let inner = match &mut self.inner {
+            Some(inner) => inner,
+            None => {
+                let mut inner = (self.connect)()?;
+                if self.timeout.is_some() {
+                    inner.set_timeout(self.timeout)?;
+                }
+                self.inner.insert(inner)
+            }
+        };
+        let result = inner.send(request).and_then(|response| {
+            transport::check_response_body(&response)?;
+            Ok(response)
+        });
+        match result {
+            // A timed-out response may still arrive, and would answer the
+            // next call
+            Err(e) if e.is_protocol_desync() || e.is_connection_closed() || e.is_timeout() => {
+                warn!("searpc: dropping connection: {}", e);
+                self.inner = None;
+                self.dropped += 1;
+                if reused && e.may_replay() {
+                    return self.send(request);
+                }
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.inner.as_ref()?.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.inner.as_ref()?.local_addr()
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        self.inner.as_ref()?.connection_age()
+    }
+
+    /// Applies to the current connection and all later ones
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if let Some(inner) = &mut self.inner {
+            inner.set_timeout(timeout)?;
+        }
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ReconnectingTransport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingTransport")
+            .field("inner", &self.inner)
+            .field("dropped", &self.dropped)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SearpcError;
+    use crate::SearpcClient;
+
+    type TestTransport = Box<dyn FnMut(&[u8]) -> Result<Vec<u8>> + Send>;
+
+    #[test]
+    fn test_garbage_response() {
+        // The first connection answers with the tail of some other frame
+        let mut connections = 0;
+        let transport = ReconnectingTransport::new(move || -> Result<TestTransport> {
+            connections += 1;
+            let response = if connections == 1 {
+                &b"\"ret\":1}"[..]
+            } else {
+                br#"{"ret":2}"#
+            };
+            Ok(Box::new(move |_: &[u8]| Ok(response.to_vec())))
+        });
+        let mut client = SearpcClient::new(transport);
+        let err = client.call_int("answer", []).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+        assert_eq!(client.call_int("answer", []).unwrap(), 2);
+        assert_eq!(client.transport().dropped_connections(), 1);
+    }
+
+    #[test]
+    fn test_stale_connection_replayed() {
+        let mut transport = ReconnectingTransport::new(|| -> Result<TestTransport> {
+            let mut calls = 0;
+            Ok(Box::new(move |_: &[u8]| {
+                calls += 1;
+                // Each connection serves one call, then is found closed
+                if calls > 1 {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: false,
+                        mid_frame: false,
+                    });
+                }
+                Ok(br#"{"ret":0}"#.to_vec())
+            }))
+        });
+        for _ in 0..3 {
+            transport.send(b"[]").unwrap();
+        }
+        assert_eq!(transport.dropped_connections(), 2);
+    }
+
+    #[test]
+    fn test_timeout_drops_connection() {
+        let mut connections = 0;
+        let mut transport = ReconnectingTransport::new(move || -> Result<TestTransport> {
+            connections += 1;
+            let first = connections == 1;
+            Ok(Box::new(move |_: &[u8]| {
+                if first {
+                    return Err(SearpcError::Timeout {
+                        timeout: Duration::from_millis(10),
+                    });
+                }
+                Ok(br#"{"ret":0}"#.to_vec())
+            }))
+        });
+        let err = transport.send(b"[]").unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+        assert_eq!(transport.dropped_connections(), 1);
+        assert!(transport.get_ref().is_none());
+        transport.send(b"[]").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_corrupted_length() {
+        use crate::{SearpcServer, UnixSocketServer, UnixSocketTransport};
+        use serde_json::json;
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixListener;
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join(format!("searpc-reconnect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            // The first client gets a header made of JSON text
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut request = vec![0u8; u32::from_ne_bytes(len) as usize];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(br#"{"ret":1}"#).unwrap();
+
+            let mut rpc = SearpcServer::new();
+            rpc.register("answer", |_| Ok(json!(42)));
+            let mut server = UnixSocketServer::new();
+            server.add_service("test-service", rpc);
+            let (stream, _) = listener.accept().unwrap();
+            Arc::new(server).serve_connection(stream)
+        });
+
+        let connect_path = path.clone();
+        let mut client = SearpcClient::new(ReconnectingTransport::new(move || {
+            Ok(UnixSocketTransport::connect(&connect_path, "test-service")?)
+        }));
+        let err = client.call_int("answer", []).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+        assert_eq!(client.call_int("answer", []).unwrap(), 42);
+        drop(client);
+        server.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
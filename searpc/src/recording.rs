@@ -0,0 +1,205 @@
+//! Capture of the traffic on a transport
+//!
+//! [`Recording`] appends each request and its response to a JSON Lines
+//! file, in the fixture format of
+//! [`ReplayTransport`](crate::test_util::ReplayTransport), so a session
+//! captured against a live daemon can be replayed offline to reproduce a
+//! protocol bug:
+//!
+//! ```rust,no_run
+//! use searpc::{Recording, SearpcClient, UnixSocketTransport};
+//!
+//! let transport = UnixSocketTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?;
+//! let transport = Recording::create(transport, "/tmp/session.jsonl")?;
+//! let mut client = SearpcClient::new(transport);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::error::Result;
+use crate::transport::{ConnectionAddr, Transport};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+/// One recorded request/response pair
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub request: String,
+    pub response: String,
+}
+
+/// Transport wrapper writing every exchange to a log, see the
+/// [module docs](self)
+///
+/// Frames are logged as sent by the client and received by it, before any
+/// framing or service envelope. Calls failing in the transport have no
+/// response and are not logged. A log that cannot be written is reported
+/// with a warning, without failing the call.
+pub struct Recording<T> {
+    inner: T,
+    log: Box<dyn Write + Send>,
+}
+
+impl<T> Recording<T> {
+    /// Log the exchanges of `inner` to `log`, one JSON line each
+    pub fn new(inner: T, log: impl Write + Send + 'static) -> Self {
+        Recording {
+            inner,
+            log: Box::new(log),
+        }
+    }
+
+    /// Append the exchanges of `inner` to the file at `path`, creating it
+    pub fn create(inner: T, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(inner, file))
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&mut self, request: &[u8], response: &[u8]) {
+        let exchange = Exchange {
+            request: String::from_utf8_lossy(request).into_owned(),
+            response: String::from_utf8_lossy(response).into_owned(),
+        };
+        let mut line = serde_json::to_vec(&exchange).expect("strings always serialize");
+        line.push(b'\n');
+        // Flushed each time, so a crash leaves a complete log behind
+        if let Err(e) = self.log.write_all(&line).and_then(|()| self.log.flush()) {
+            warn!("searpc recording: writing the log failed: {}", e);
+        }
+    }
+}
+
+impl<T: Transport> Transport for Recording<T> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let response = self.inner.send(request)?;
+        self.record(request, &response);
+        Ok(response)
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.inner.local_addr()
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        self.inner.connection_age()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+}
+
+/// Writes the log on the calling task: best suited to files and other
+/// quick sinks
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl<T: crate::AsyncTransport + Send> crate::AsyncTransport for Recording<T> {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let response = self.inner.send(request).await?;
+        self.record(request, &response);
+        Ok(response)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Recording<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recording")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arg, SearpcClient, SearpcError};
+    use std::sync::{Arc, Mutex};
+
+    /// Log shared with the test
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_records_exchanges() {
+        let log = SharedLog::default();
+        let daemon = |request: &[u8]| -> Result<Vec<u8>> {
+            match request {
+                br#"["fail"]"# => Err(SearpcError::transport("daemon gone")),
+                _ => Ok(br#"{"ret":"9.0.0"}"#.to_vec()),
+            }
+        };
+        let mut client = SearpcClient::new(Recording::new(daemon, log.clone()));
+        client.call_string("get_version", []).unwrap();
+        client
+            .call_string("get_version", [Arg::string("a\"b")])
+            .unwrap();
+        client.call_string("fail", []).unwrap_err();
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let exchanges: Vec<Exchange> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            exchanges,
+            [
+                Exchange {
+                    request: r#"["get_version"]"#.to_string(),
+                    response: r#"{"ret":"9.0.0"}"#.to_string(),
+                },
+                Exchange {
+                    request: r#"["get_version","a\"b"]"#.to_string(),
+                    response: r#"{"ret":"9.0.0"}"#.to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_recording() {
+        use crate::test_util::ReplayTransport;
+
+        let path = std::env::temp_dir().join(format!("searpc-recording-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let daemon = |_: &[u8]| Ok(br#"{"ret":42}"#.to_vec());
+        let mut client = SearpcClient::new(Recording::create(daemon, &path).unwrap());
+        assert_eq!(client.call_int("answer", []).unwrap(), 42);
+
+        let replay = ReplayTransport::load(&path).unwrap();
+        let mut client = SearpcClient::new(replay.clone());
+        assert_eq!(client.call_int("answer", []).unwrap(), 42);
+        replay.verify();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
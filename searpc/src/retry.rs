@@ -0,0 +1,326 @@
+//! Connecting to a daemon that may still be starting
+//!
+//! Right after a daemon is launched, say at boot, its socket may not exist
+//! yet or not accept connections. The `connect_with_retry` constructors of
+//! the socket transports, like
+//! [`UnixSocketTransport::connect_with_retry`](crate::UnixSocketTransport::connect_with_retry),
+//! try again with exponential backoff as long as nothing listens:
+//!
+//! ```rust,no_run
+//! use searpc::{RetryPolicy, UnixSocketTransport};
+//!
+//! let policy = RetryPolicy {
+//!     max_attempts: 20,
+//!     ..RetryPolicy::default()
+//! };
+//! let transport =
+//!     UnixSocketTransport::connect_with_retry("/path/to/seafile.sock", "seafile-rpcserver", policy)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! The same policy retries failed calls, see
+//! [`SearpcClient::set_retry_policy`](crate::SearpcClient::set_retry_policy).
+//! Which failures are retried is up to [`RetryPolicy::retry_on`].
+
+use crate::error::{Result, SearpcError};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::Duration;
+use tracing::debug;
+
+/// How often to try connecting or calling, and how long to wait in between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in all, the first one included
+    pub max_attempts: u32,
+    /// Wait after the first failed attempt, doubled after each further one
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts
+    pub max_backoff: Duration,
+    /// Wait a random time between half and all of the backoff, so clients
+    /// that lost the daemon together do not all come back at once
+    pub jitter: bool,
+    /// Which failed calls are retried; connects are retried as long as
+    /// nothing listens
+    pub retry_on: RetryOn,
+}
+
+impl Default for RetryPolicy {
+    /// 10 attempts over about 11 seconds, without jitter, retrying calls
+    /// the daemon never saw
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            jitter: false,
+            retry_on: RetryOn::Unsent,
+        }
+    }
+}
+
+/// Which failed calls a client retries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetryOn {
+    /// Calls the daemon cannot have run: the connection closed before the
+    /// request was written (see [`SearpcError::may_replay`]), or nothing
+    /// listened to connect to
+    #[default]
+    Unsent,
+    /// Any transport failure, timeouts and desyncs included
+    ///
+    /// The daemon may have run the call already, so this is only for calls
+    /// that are safe to run twice.
+    Transport,
+}
+
+impl RetryOn {
+    /// Whether a call that failed with `e` is worth another attempt
+    pub fn should_retry(self, e: &SearpcError) -> bool {
+        match self {
+            RetryOn::Unsent => e.may_replay() || e.io_error().is_some_and(is_not_listening),
+            RetryOn::Transport => matches!(
+                e.inner(),
+                SearpcError::TransportError { .. }
+                    | SearpcError::ConnectionClosed { .. }
+                    | SearpcError::ProtocolDesync { .. }
+                    | SearpcError::Timeout { .. }
+                    | SearpcError::IoError(_)
+            ),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after failed attempt number `attempt`, counting from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Time to actually wait after failed attempt number `attempt`: the
+    /// [`backoff`](Self::backoff), with [`jitter`](Self::jitter) if set
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff(attempt);
+        if !self.jitter {
+            return backoff;
+        }
+        // Each RandomState is seeded anew, which is random enough here
+        let random = RandomState::new().build_hasher().finish();
+        let half = backoff / 2;
+        half + half.mul_f64(random as f64 / u64::MAX as f64)
+    }
+
+    /// Wait before trying a call again that failed with `e` on attempt
+    /// number `attempt`, or `None` to give up
+    pub fn retry_delay(&self, attempt: u32, e: &SearpcError) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.retry_on.should_retry(e) {
+            return None;
+        }
+        let delay = self.delay(attempt);
+        debug!(attempt, ?delay, "searpc call failed, retrying: {}", e);
+        Some(delay)
+    }
+
+    /// Run `call` until it succeeds, fails in a way
+    /// [`retry_on`](Self::retry_on) does not retry, or the attempts run out
+    pub fn call<T>(&self, mut call: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match call() {
+                Err(e) => match self.retry_delay(attempt, &e) {
+                    Some(delay) => std::thread::sleep(delay),
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Run `connect` until it succeeds, fails other than with nothing
+    /// listening (see [`is_not_listening`]), or the attempts run out
+    pub fn connect<T>(&self, mut connect: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 1;
+        loop {
+            match connect() {
+                Err(e) if attempt < self.max_attempts && is_not_listening(&e) => {
+                    let delay = self.delay(attempt);
+                    debug!(attempt, ?delay, "searpc connect failed, retrying: {}", e);
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// [`connect`](Self::connect) for async connects, waiting on the tokio
+    /// timer
+    #[cfg(feature = "rt-tokio")]
+    pub async fn connect_async<T, F>(&self, mut connect: impl FnMut() -> F) -> io::Result<T>
+    where
+        F: std::future::Future<Output = io::Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match connect().await {
+                Err(e) if attempt < self.max_attempts && is_not_listening(&e) => {
+                    let delay = self.delay(attempt);
+                    debug!(attempt, ?delay, "searpc connect failed, retrying: {}", e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether a connect failed because nothing listens at the address yet:
+/// no socket file, or no server accepting on it
+pub fn is_not_listening(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn quick(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        let delays: Vec<_> = (1..=6).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1600, 2000].map(Duration::from_millis)
+        );
+        assert_eq!(policy.backoff(100), policy.max_backoff);
+        assert_eq!(policy.delay(3), policy.backoff(3));
+
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy
+        };
+        for attempt in 1..=6 {
+            let backoff = policy.backoff(attempt);
+            let delay = policy.delay(attempt);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_retry_on() {
+        let unsent = SearpcError::ConnectionClosed {
+            request_sent: false,
+            mid_frame: false,
+        };
+        let sent = SearpcError::ConnectionClosed {
+            request_sent: true,
+            mid_frame: false,
+        };
+        let refused = SearpcError::from(io::Error::from(io::ErrorKind::ConnectionRefused));
+        let rpc = SearpcError::RpcError {
+            code: 500,
+            message: "Transport Error".to_string(),
+        };
+        for e in [&unsent, &refused] {
+            assert!(RetryOn::Unsent.should_retry(e), "{}", e);
+            assert!(RetryOn::Transport.should_retry(e), "{}", e);
+        }
+        assert!(!RetryOn::Unsent.should_retry(&sent));
+        assert!(RetryOn::Transport.should_retry(&sent));
+        let timeout = SearpcError::Timeout {
+            timeout: Duration::from_secs(1),
+        };
+        assert!(!RetryOn::Unsent.should_retry(&timeout));
+        assert!(RetryOn::Transport.should_retry(&timeout));
+        // Errors from the daemon are answers, not transport failures
+        assert!(!RetryOn::Transport.should_retry(&rpc));
+        let typed = SearpcError::TypeError("Expected int".to_string());
+        assert!(!RetryOn::Transport.should_retry(&typed));
+    }
+
+    #[test]
+    fn test_call() {
+        let attempts = Cell::new(0);
+        let result = quick(5).call(|| {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1 => Err(SearpcError::ConnectionClosed {
+                    request_sent: false,
+                    mid_frame: false,
+                }),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        // The daemon may have run it
+        attempts.set(0);
+        let err = quick(5)
+            .call(|| -> Result<()> {
+                attempts.set(attempts.get() + 1);
+                Err(SearpcError::ConnectionClosed {
+                    request_sent: true,
+                    mid_frame: true,
+                })
+            })
+            .unwrap_err();
+        assert!(err.is_connection_closed());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn test_connect() {
+        // Listening from the third attempt on
+        let attempts = Cell::new(0);
+        let result = quick(5).connect(|| {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1 => Err(io::ErrorKind::NotFound.into()),
+                2 => Err(io::ErrorKind::ConnectionRefused.into()),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // Out of attempts: the last error
+        attempts.set(0);
+        let err = quick(3)
+            .connect(|| -> io::Result<()> {
+                attempts.set(attempts.get() + 1);
+                Err(io::ErrorKind::NotFound.into())
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts.get(), 3);
+
+        // Other errors are not worth waiting for
+        attempts.set(0);
+        let err = quick(3)
+            .connect(|| -> io::Result<()> {
+                attempts.set(attempts.get() + 1);
+                Err(io::ErrorKind::PermissionDenied.into())
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(attempts.get(), 1);
+    }
+}
@@ -0,0 +1,202 @@
+//! `SCM_CREDENTIALS` ancillary data on Unix sockets (Linux)
+//!
+//! The kernel checks credentials sent this way: an unprivileged process
+//! can only send its own PID, user and group IDs.
+
+use crate::deadline::Deadline;
+use crate::unix_server::PeerCredentials;
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+/// Control message buffer, with room and alignment for one `ucred`
+type Control = [u64; 8];
+
+const UCRED_SIZE: u32 = mem::size_of::<libc::ucred>() as u32;
+
+/// Writer attaching this process's credentials to its first write
+pub(crate) struct CredentialsWriter<'a> {
+    stream: &'a UnixStream,
+    deadline: &'a Deadline,
+    sent: bool,
+}
+
+impl<'a> CredentialsWriter<'a> {
+    pub(crate) fn new(stream: &'a UnixStream, deadline: &'a Deadline) -> Self {
+        CredentialsWriter {
+            stream,
+            deadline,
+            sent: false,
+        }
+    }
+}
+
+impl Write for CredentialsWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.deadline.before_write(self.stream)?;
+        if self.sent {
+            return (&mut &*self.stream).write(buf);
+        }
+        let n = send_with_credentials(self.stream, buf)?;
+        self.sent = true;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Send (part of) `data` with this process's credentials
+fn send_with_credentials(stream: &UnixStream, data: &[u8]) -> io::Result<usize> {
+    let cred = libc::ucred {
+        pid: std::process::id() as libc::pid_t,
+        // SAFETY: these have no preconditions and cannot fail
+        uid: unsafe { libc::geteuid() },
+        gid: unsafe { libc::getegid() },
+    };
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let mut control: Control = [0; 8];
+    // SAFETY: all-zero is a valid msghdr
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    // SAFETY: CMSG_SPACE only computes a size
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(UCRED_SIZE) } as _;
+    // SAFETY: `control` has room for one aligned cmsghdr and its ucred, so
+    // the first header is non-null and its data writable
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_CREDENTIALS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(UCRED_SIZE) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::ucred>(), cred);
+        libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Have the kernel report senders' credentials on `stream`
+pub(crate) fn pass_credentials(stream: &UnixStream) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    // SAFETY: `on` is a valid c_int for SO_PASSCRED
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PASSCRED,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wait for data on `stream` and return the credentials sent with it,
+/// leaving the data to be read
+///
+/// Needs [`pass_credentials`] first. Fails with `UnexpectedEof` if the peer
+/// hangs up before sending anything, and with `InvalidData` if it sent no
+/// credentials.
+pub(crate) fn peek_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: (&mut byte as *mut u8).cast(),
+        iov_len: 1,
+    };
+    let mut control: Control = [0; 8];
+    // SAFETY: all-zero is a valid msghdr
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of::<Control>() as _;
+    let received = loop {
+        // SAFETY: `msg` points at buffers that outlive the call
+        let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_PEEK) };
+        if ret >= 0 {
+            break ret;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    // SAFETY: the kernel filled in `msg.msg_controllen` bytes of headers
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS
+            {
+                let cred: libc::ucred = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                // The kernel reports PID 0 for data sent without credentials
+                if cred.pid == 0 {
+                    break;
+                }
+                return Ok(PeerCredentials {
+                    uid: cred.uid,
+                    gid: cred.gid,
+                    pid: Some(cred.pid),
+                });
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no credentials sent with the request",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_round_trip() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        pass_credentials(&theirs).unwrap();
+        CredentialsWriter::new(&ours, &Deadline::default())
+            .write_all(b"ping")
+            .unwrap();
+
+        let peer = peek_credentials(&theirs).unwrap();
+        assert_eq!(peer.pid, Some(std::process::id() as i32));
+        assert!(peer.is_same_user());
+        // Peeking left the data in place
+        let mut data = [0u8; 4];
+        theirs.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"ping");
+    }
+
+    #[test]
+    fn test_no_credentials() {
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ours.write_all(b"ping").unwrap();
+        pass_credentials(&theirs).unwrap();
+        let err = peek_credentials(&theirs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        drop(ours);
+        pass_credentials(&theirs).unwrap();
+        let err = peek_credentials(&theirs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,411 @@
+//! Unix socket transport over `SOCK_SEQPACKET` (Linux)
+//!
+//! A sequenced-packet socket keeps message boundaries like a datagram
+//! socket, on a connection like a stream socket. Each request goes out as
+//! one message and each response comes back as one, so no length header is
+//! needed. For servers that read one anyway,
+//! [`with_framing`](SeqpacketTransport::with_framing) puts it back:
+//!
+//! ```rust,no_run
+//! use searpc::{SearpcClient, SeqpacketTransport};
+//!
+//! let transport = SeqpacketTransport::connect("/run/rpc.sock")?.with_service("rpc-server");
+//! let mut client = SearpcClient::new(transport);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! A message cannot be longer than the socket's send buffer
+//! (`net.core.wmem_default`, usually 208 KiB). Larger requests fail with
+//! `EMSGSIZE`, and servers have to keep their responses below it too.
+
+use crate::deadline::Deadline;
+use crate::error::{Result, SearpcError};
+use crate::transport::{self, wrap_request, ConnectionAddr, Framing, Transport};
+use crate::unix_connect;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+/// Transport sending each request as one message on a `SOCK_SEQPACKET`
+/// socket, see the [module docs](self)
+pub struct SeqpacketTransport {
+    /// std has no sequenced-packet socket type; the calls of a connected
+    /// `UnixDatagram` work the same on one
+    socket: UnixDatagram,
+    /// Service envelope around requests, if any
+    service: Option<String>,
+    /// Length header in front of each message, if any
+    framing: Option<Framing>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+    connected: Instant,
+    max_response_size: usize,
+    deadline: Deadline,
+}
+
+impl SeqpacketTransport {
+    /// Send bare requests over a connected `SOCK_SEQPACKET` socket, such as
+    /// one end of a `socketpair`
+    pub fn new(socket: OwnedFd) -> Self {
+        SeqpacketTransport {
+            socket: UnixDatagram::from(socket),
+            service: None,
+            framing: None,
+            buf: Vec::new(),
+            connected: Instant::now(),
+            max_response_size: transport::DEFAULT_MAX_RESPONSE_SIZE,
+            deadline: Deadline::default(),
+        }
+    }
+
+    /// Connect to the sequenced-packet socket at `path`
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        let (addr, addr_len) = unix_connect::sockaddr_un(path.as_ref())?;
+        // SAFETY: socket has no memory preconditions
+        let fd =
+            unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a fresh socket owned by nothing else
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        loop {
+            // SAFETY: `addr` is a valid sockaddr_un of `addr_len` bytes
+            let ret =
+                unsafe { libc::connect(fd, (&addr as *const libc::sockaddr_un).cast(), addr_len) };
+            if ret == 0 {
+                return Ok(Self::new(socket));
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Wrap requests in the Seafile service envelope for `service`
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    /// Put `framing`'s length header in front of each message, and expect
+    /// it in front of each response
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.framing = Some(framing);
+        self
+    }
+
+    /// Longest response to accept, [`DEFAULT_MAX_RESPONSE_SIZE`](crate::transport::DEFAULT_MAX_RESPONSE_SIZE)
+    /// by default
+    pub fn with_max_response_size(mut self, max: usize) -> Self {
+        self.max_response_size = max;
+        self
+    }
+
+    /// Fail calls whose response takes longer than `timeout` to arrive
+    ///
+    /// `None` (the default) waits forever. Drop the transport after a
+    /// timed-out call, as the late response would be read as the next
+    /// call's.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Fail calls whose request cannot be sent within `timeout`
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+
+    /// Send a request as one message
+    fn send_packet(&mut self, data: &[u8]) -> Result<()> {
+        let mut packet = std::mem::take(&mut self.buf);
+        let result = self.encode(data, &mut packet).and_then(|()| {
+            let n = self
+                .deadline
+                .before_write(&self.socket)
+                .and_then(|()| self.socket.send(&packet))
+                .map_err(transport::write_error)?;
+            if n < packet.len() {
+                return Err(SearpcError::transport(format!(
+                    "Sent {} of a {} byte message",
+                    n,
+                    packet.len()
+                )));
+            }
+            Ok(())
+        });
+        self.buf = packet;
+        result
+    }
+
+    fn encode(&self, data: &[u8], packet: &mut Vec<u8>) -> Result<()> {
+        if let Some(framing) = self.framing {
+            return framing.encode(self.service.as_deref(), data, packet);
+        }
+        packet.clear();
+        match &self.service {
+            Some(service) => wrap_request(service, data, packet),
+            None => {
+                packet.extend_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+
+    /// Receive a response message
+    fn recv_packet(&mut self) -> Result<Vec<u8>> {
+        let len = self.next_len()?;
+        if len == 0 {
+            return Err(SearpcError::ConnectionClosed {
+                request_sent: true,
+                mid_frame: false,
+            });
+        }
+        transport::check_response_len(len, self.max_response_size)?;
+        let mut data = vec![0u8; len];
+        let n = loop {
+            match self.socket.recv(&mut data) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => break result.map_err(read_error)?,
+            }
+        };
+        data.truncate(n);
+
+        let Some(framing) = self.framing else {
+            return Ok(data);
+        };
+        let header_len = framing.header_len();
+        if data.len() <= header_len {
+            return Err(SearpcError::ProtocolDesync {
+                reason: format!("message of {} bytes has no body", data.len()),
+            });
+        }
+        let body_len = framing.body_len(&data[..header_len])?;
+        if body_len != data.len() - header_len {
+            return Err(SearpcError::ProtocolDesync {
+                reason: format!(
+                    "length header says {} bytes, message has {}",
+                    body_len,
+                    data.len() - header_len
+                ),
+            });
+        }
+        data.drain(..header_len);
+        Ok(data)
+    }
+
+    /// Length of the next message, waiting for it; 0 once the peer closed
+    fn next_len(&self) -> Result<usize> {
+        loop {
+            self.deadline
+                .before_read(&self.socket)
+                .map_err(read_error)?;
+            // SAFETY: with a length of 0, nothing is written to the buffer
+            let n = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    ptr::null_mut(),
+                    0,
+                    libc::MSG_PEEK | libc::MSG_TRUNC,
+                )
+            };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(read_error(e));
+            }
+        }
+    }
+}
+
+fn read_error(e: io::Error) -> SearpcError {
+    if transport::is_disconnect(e.kind()) {
+        return SearpcError::ConnectionClosed {
+            request_sent: true,
+            mid_frame: false,
+        };
+    }
+    SearpcError::transport_io("Read failed", e)
+}
+
+impl Transport for SeqpacketTransport {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.deadline.start();
+        self.send_packet(request)?;
+        self.recv_packet()
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.socket.peer_addr().ok().map(ConnectionAddr::from)
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.socket.local_addr().ok().map(ConnectionAddr::from)
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        Some(self.connected.elapsed())
+    }
+
+    /// Bounds each call as a whole: the socket's timeouts are set to the
+    /// time left before sending and before waiting for the response
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.deadline
+            .set_timeout(&self.socket, timeout)
+            .map_err(|e| SearpcError::transport_io("Setting timeout", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Endianness;
+    use crate::{SearpcClient, SearpcServer};
+    use serde_json::json;
+
+    /// Connected pair of `SOCK_SEQPACKET` sockets
+    fn pair() -> (OwnedFd, OwnedFd) {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        // SAFETY: both are fresh descriptors owned by nothing else
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    /// Answer each message on `socket`, passing it through `unwrap` first
+    /// and the response through `wrap`
+    fn serve(
+        socket: OwnedFd,
+        unwrap: fn(&[u8]) -> Vec<u8>,
+        wrap: fn(Vec<u8>) -> Vec<u8>,
+    ) -> std::thread::JoinHandle<usize> {
+        std::thread::spawn(move || {
+            let mut server = SearpcServer::new();
+            server.register("ping", |_| Ok(json!("pong")));
+            let socket = UnixDatagram::from(socket);
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut served = 0;
+            while let Ok(n) = socket.recv(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let response = server.handle_request(&unwrap(&buf[..n]));
+                socket.send(&wrap(response)).unwrap();
+                served += 1;
+            }
+            served
+        })
+    }
+
+    #[test]
+    fn test_bare_messages() {
+        let (ours, theirs) = pair();
+        let server = serve(theirs, <[u8]>::to_vec, |response| response);
+        let mut client = SearpcClient::new(SeqpacketTransport::new(ours));
+        for _ in 0..2 {
+            assert_eq!(client.call_string("ping", []).unwrap(), "pong");
+        }
+        assert!(client.call_string("missing", []).is_err());
+        drop(client);
+        assert_eq!(server.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_framing_and_service() {
+        let (ours, theirs) = pair();
+        serve(
+            theirs,
+            |message| {
+                let len = u32::from_le_bytes(message[..4].try_into().unwrap());
+                assert_eq!(len as usize, message.len() - 4);
+                let envelope: serde_json::Value = serde_json::from_slice(&message[4..]).unwrap();
+                assert_eq!(envelope["service"], "test-service");
+                envelope["request"].as_str().unwrap().as_bytes().to_vec()
+            },
+            |response| [&(response.len() as u32).to_le_bytes()[..], &response].concat(),
+        );
+        let transport = SeqpacketTransport::new(ours)
+            .with_service("test-service")
+            .with_framing(Framing::U32(Endianness::Little));
+        let mut client = SearpcClient::new(transport);
+        assert_eq!(client.call_string("ping", []).unwrap(), "pong");
+
+        // A header disagreeing with the message
+        let (ours, theirs) = pair();
+        serve(theirs, <[u8]>::to_vec, |response| {
+            [&[0xff, 0][..], &response].concat()
+        });
+        let mut transport = SeqpacketTransport::new(ours).with_framing(Framing::U16);
+        let err = transport.send(br#"["ping"]"#).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+    }
+
+    #[test]
+    fn test_limits_and_close() {
+        let (ours, theirs) = pair();
+        serve(theirs, <[u8]>::to_vec, |response| response);
+        let mut transport = SeqpacketTransport::new(ours).with_max_response_size(4);
+        let err = transport.send(br#"["ping"]"#).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+
+        let (ours, theirs) = pair();
+        let mut transport = SeqpacketTransport::new(ours);
+        transport
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let err = transport.send(br#"["ping"]"#).unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+        drop(theirs);
+        let err = transport.send(br#"["ping"]"#).unwrap_err();
+        assert!(err.is_connection_closed(), "{}", err);
+    }
+
+    #[test]
+    fn test_connect() {
+        let dir = std::env::temp_dir().join(format!("searpc-seqpacket-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rpc.sock");
+        let _ = std::fs::remove_file(&path);
+        assert!(SeqpacketTransport::connect(&path).is_err());
+
+        let (addr, addr_len) = unix_connect::sockaddr_un(&path).unwrap();
+        // SAFETY: plain socket calls on a fresh descriptor and a valid address
+        let listener = unsafe {
+            let fd = libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0);
+            assert!(fd >= 0);
+            let listener = OwnedFd::from_raw_fd(fd);
+            assert_eq!(
+                libc::bind(fd, (&addr as *const libc::sockaddr_un).cast(), addr_len),
+                0
+            );
+            assert_eq!(libc::listen(fd, 1), 0);
+            listener
+        };
+        let mut transport = SeqpacketTransport::connect(&path).unwrap();
+        // SAFETY: `listener` is a listening socket; the address is not wanted
+        let fd = unsafe { libc::accept(listener.as_raw_fd(), ptr::null_mut(), ptr::null_mut()) };
+        assert!(fd >= 0);
+        // SAFETY: `fd` is a fresh descriptor owned by nothing else
+        serve(unsafe { OwnedFd::from_raw_fd(fd) }, <[u8]>::to_vec, |r| r);
+        assert_eq!(
+            transport.send(br#"["ping"]"#).unwrap(),
+            br#"{"ret":"pong"}"#
+        );
+        assert!(transport.peer_addr().is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1197 @@
+//! Server side: serve RPC functions to libsearpc/pysearpc clients
+//!
+//! [`SearpcServer`] maps function names to handlers. It takes a raw
+//! `["function_name", arg1, ...]` request and produces the response body
+//! `{"ret": ...}` or `{"err_code": ..., "err_msg": ...}`; framing is left to
+//! whatever carries the bytes.
+//!
+//! ```rust
+//! use searpc::server::{arg, SearpcServer};
+//! use serde_json::json;
+//!
+//! let mut server = SearpcServer::new();
+//! server.register("searpc_strlen", |args| {
+//!     let s: String = arg(args, 0)?;
+//!     Ok(json!(s.len()))
+//! });
+//!
+//! let response = server.handle_request(br#"["searpc_strlen","hello"]"#);
+//! assert_eq!(response, br#"{"ret":5}"#);
+//! ```
+//!
+//! Errors follow libsearpc, so existing clients recognise them:
+//! unknown functions get 500 `cannot find function NAME.`, requests that
+//! cannot be parsed get 511 `failed to load RPC call: ...`. A handler's
+//! [`SearpcError::RpcError`] is sent with its own code and message; domain
+//! error types get the same by implementing [`ToRpcError`].
+//!
+//! Every call is reported to [`tracing`] as an `info` event with the
+//! function name, its duration in microseconds and the outcome (and the
+//! error code of failed calls), for auditing RPC traffic. The socket
+//! servers run each connection in a `searpc_connection` span naming the
+//! caller. Counts and latencies per function can be collected too, see
+//! [`SearpcServer::set_metrics`].
+//!
+//! Middleware added with [`SearpcServer::layer`] wraps every call, for
+//! logging, access checks or rewriting requests:
+//!
+//! ```rust
+//! use searpc::server::SearpcServer;
+//! use searpc::SearpcError;
+//!
+//! let mut server = SearpcServer::new();
+//! server.layer(|request, next| {
+//!     if request.function_name.starts_with("admin_") {
+//!         return Err(SearpcError::RpcError {
+//!             code: 403,
+//!             message: "Permission denied".to_string(),
+//!         });
+//!     }
+//!     next.run(request)
+//! });
+//! ```
+
+#[cfg(feature = "compression")]
+use crate::compression;
+use crate::error::{KnownErrorCode, Result, SearpcError};
+use crate::metrics::MetricsSink;
+use crate::protocol::RpcResponse;
+use crate::signature::Signature;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{
+    Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::Instant;
+use tracing::info;
+
+/// Handler for one RPC function: takes the call's arguments, returns `ret`
+pub type Handler = Box<dyn Fn(&[Value]) -> Result<Value> + Send + Sync>;
+
+/// A parsed call, as seen by middleware
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub function_name: String,
+    pub args: Vec<Value>,
+}
+
+/// Middleware: gets each call and the rest of the chain to pass it on to
+pub type Middleware = Box<dyn Fn(Request, Next<'_>) -> Result<Value> + Send + Sync>;
+
+/// The rest of a middleware chain, ending with the registered function
+pub struct Next<'a> {
+    server: &'a SearpcServer,
+    layers: &'a [Middleware],
+}
+
+impl Next<'_> {
+    /// Pass `request` on to the next middleware, or to the function itself
+    pub fn run(self, request: Request) -> Result<Value> {
+        match self.layers.split_first() {
+            Some((layer, layers)) => layer(
+                request,
+                Next {
+                    server: self.server,
+                    layers,
+                },
+            ),
+            None => self.server.call(&request.function_name, &request.args),
+        }
+    }
+}
+
+/// A set of RPC functions dispatched by name
+///
+/// Usually generated by `#[searpc_service]` from an `impl` block, and added
+/// to a server with [`SearpcServer::register_service`].
+pub trait RpcService: Send + Sync + 'static {
+    /// Names of the functions [`call`](Self::call) handles
+    fn functions() -> &'static [&'static str]
+    where
+        Self: Sized;
+
+    /// Run `function_name`, or return `None` if it is not one of ours
+    fn call(&self, function_name: &str, args: &[Value]) -> Option<Result<Value>>;
+}
+
+/// Conversion of a handler's error into the `err_code`/`err_msg` it is sent as
+///
+/// Lets handlers keep their own error types while clients still see typed
+/// codes, the way the Seafile daemon reports `SEAF_ERR_*` values.
+/// `#[searpc_service]` methods may return `Result<T, E>` for any such `E`;
+/// closures passed to [`SearpcServer::register`] convert with
+/// [`into_rpc_error`](Self::into_rpc_error):
+///
+/// ```rust
+/// use searpc::server::{arg, SearpcServer, ToRpcError};
+/// use searpc::KnownErrorCode;
+///
+/// enum RepoError {
+///     NotFound(String),
+///     Locked,
+/// }
+///
+/// impl ToRpcError for RepoError {
+///     fn err_code(&self) -> i32 {
+///         match self {
+///             RepoError::NotFound(_) => KnownErrorCode::BadRepo.code(),
+///             RepoError::Locked => KnownErrorCode::RepoLocked.code(),
+///         }
+///     }
+///
+///     fn err_msg(&self) -> String {
+///         match self {
+///             RepoError::NotFound(id) => format!("Repo {} not found", id),
+///             RepoError::Locked => "Repo is locked".to_string(),
+///         }
+///     }
+/// }
+///
+/// fn remove_repo(id: &str) -> Result<(), RepoError> {
+///     Err(RepoError::NotFound(id.to_string()))
+/// }
+///
+/// let mut server = SearpcServer::new();
+/// server.register("seafile_destroy_repo", |args| {
+///     let id: String = arg(args, 0)?;
+///     remove_repo(&id).map_err(ToRpcError::into_rpc_error)?;
+///     Ok(0.into())
+/// });
+///
+/// let response = server.handle_request(br#"["seafile_destroy_repo","x"]"#);
+/// assert_eq!(response, br#"{"err_code":501,"err_msg":"Repo x not found"}"#);
+/// ```
+pub trait ToRpcError {
+    /// Code to send as `err_code`
+    fn err_code(&self) -> i32;
+
+    /// Message to send as `err_msg`
+    fn err_msg(&self) -> String;
+
+    /// The error as a [`SearpcError`] carrying this code and message
+    fn into_rpc_error(self) -> SearpcError
+    where
+        Self: Sized,
+    {
+        SearpcError::RpcError {
+            code: self.err_code(),
+            message: self.err_msg(),
+        }
+    }
+}
+
+/// Sent as [`SearpcError::err_code`] and [`SearpcError::err_msg`] report it
+impl ToRpcError for SearpcError {
+    fn err_code(&self) -> i32 {
+        SearpcError::err_code(self)
+    }
+
+    fn err_msg(&self) -> String {
+        SearpcError::err_msg(self)
+    }
+
+    fn into_rpc_error(self) -> SearpcError {
+        self
+    }
+}
+
+/// Registry of RPC functions
+///
+/// Handlers are `Send + Sync`, so one server can be shared between
+/// connection threads behind an `Arc`. Functions can still be added and
+/// removed while it is shared, with [`insert_function`](Self::insert_function)
+/// and [`remove_function`](Self::remove_function).
+#[derive(Default)]
+pub struct SearpcServer {
+    registry: RwLock<Registry>,
+    middleware: Vec<Middleware>,
+    introspection: bool,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    /// Threshold for compressing responses, if enabled
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+}
+
+/// The functions of a [`SearpcServer`]
+#[derive(Default)]
+struct Registry {
+    /// Shared so a call can run without holding the lock
+    handlers: HashMap<String, Arc<Handler>>,
+    signatures: HashMap<String, Signature>,
+}
+
+impl Registry {
+    fn insert(&mut self, function_name: String, handler: Handler, signature: Option<Signature>) {
+        match signature {
+            Some(signature) => self.signatures.insert(function_name.clone(), signature),
+            None => self.signatures.remove(&function_name),
+        };
+        self.handlers.insert(function_name, Arc::new(handler));
+    }
+}
+
+/// Name of the function added by [`SearpcServer::enable_introspection`]
+pub const LIST_FUNCTIONS: &str = "__searpc_list_functions";
+
+impl SearpcServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` as `function_name`, replacing any previous one
+    pub fn register<F>(&mut self, function_name: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> Result<Value> + Send + Sync + 'static,
+    {
+        self.registry_mut()
+            .insert(function_name.into(), Box::new(handler), None);
+        self
+    }
+
+    /// Register `handler` as `function_name` on a server that may be serving
+    ///
+    /// Like [`register`](Self::register), but through a shared reference,
+    /// so plugins can add functions to a server already behind an `Arc`.
+    /// Calls already running finish with the handler they started with.
+    ///
+    /// ```rust
+    /// use searpc::SearpcServer;
+    /// use serde_json::json;
+    /// use std::sync::Arc;
+    ///
+    /// let server = Arc::new(SearpcServer::new());
+    /// // ... serving on other threads ...
+    /// server.insert_function("plugin_version", |_| Ok(json!("1.0")));
+    /// assert!(server.has_function("plugin_version"));
+    /// assert!(server.remove_function("plugin_version"));
+    /// ```
+    pub fn insert_function<F>(&self, function_name: impl Into<String>, handler: F)
+    where
+        F: Fn(&[Value]) -> Result<Value> + Send + Sync + 'static,
+    {
+        self.registry_write()
+            .insert(function_name.into(), Box::new(handler), None);
+    }
+
+    /// Remove `function_name`; `false` if it was not registered
+    ///
+    /// Later calls get `cannot find function`; calls already running finish.
+    pub fn remove_function(&self, function_name: &str) -> bool {
+        let mut registry = self.registry_write();
+        registry.signatures.remove(function_name);
+        registry.handlers.remove(function_name).is_some()
+    }
+
+    /// Register `handler` as `function_name` with a libsearpc signature
+    ///
+    /// `signature` is spelled as in libsearpc, e.g. `"objlist__int_int_string"`
+    /// (see [`signature`](crate::signature)). Calls with the wrong number or
+    /// types of arguments get `SEAF_ERR_BAD_ARGS` (503) without reaching
+    /// the handler, and a return value not matching the signature is
+    /// reported as an error instead of being sent.
+    ///
+    /// ```rust
+    /// use searpc::server::{arg, SearpcServer};
+    /// use serde_json::json;
+    ///
+    /// let mut server = SearpcServer::new();
+    /// server.register_signed("searpc_strlen", "int__string", |args| {
+    ///     let s: Option<String> = arg(args, 0)?;
+    ///     Ok(json!(s.map_or(0, |s| s.len())))
+    /// })?;
+    ///
+    /// let response = server.dispatch(br#"["searpc_strlen",1]"#);
+    /// assert_eq!(response.err_code, Some(503));
+    /// # Ok::<(), searpc::SearpcError>(())
+    /// ```
+    pub fn register_signed<F>(
+        &mut self,
+        function_name: impl Into<String>,
+        signature: &str,
+        handler: F,
+    ) -> Result<&mut Self>
+    where
+        F: Fn(&[Value]) -> Result<Value> + Send + Sync + 'static,
+    {
+        let signature: Signature = signature.parse()?;
+        let checked = signature.clone();
+        let handler: Handler = Box::new(move |args| {
+            checked.check_args(args)?;
+            let ret = handler(args)?;
+            checked.check_return(&ret)?;
+            Ok(ret)
+        });
+        self.registry_mut()
+            .insert(function_name.into(), handler, Some(signature));
+        Ok(self)
+    }
+
+    /// Signature `function_name` was registered with, if any
+    pub fn signature(&self, function_name: &str) -> Option<Signature> {
+        self.registry().signatures.get(function_name).cloned()
+    }
+
+    /// Wrap every call in `middleware`
+    ///
+    /// Middleware runs in the order it was added: the first one sees the
+    /// request first and the response last. It may answer by itself,
+    /// change the request before calling [`Next::run`], or inspect and
+    /// change the result. Requests that cannot be parsed are answered
+    /// before any middleware runs.
+    pub fn layer<F>(&mut self, middleware: F) -> &mut Self
+    where
+        F: Fn(Request, Next<'_>) -> Result<Value> + Send + Sync + 'static,
+    {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Register functions that share `state`, such as a database handle
+    ///
+    /// Each handler registered through the returned [`WithState`] gets the
+    /// state along with the call's arguments:
+    ///
+    /// ```rust
+    /// use searpc::server::{arg, SearpcServer};
+    /// use serde_json::json;
+    /// use std::collections::HashMap;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let config = Arc::new(Mutex::new(HashMap::new()));
+    /// let mut server = SearpcServer::new();
+    /// server
+    ///     .with_state(config)
+    ///     .register("seafile_set_config", |config, args| {
+    ///         let key: String = arg(args, 0)?;
+    ///         let value: String = arg(args, 1)?;
+    ///         config.lock().unwrap().insert(key, value);
+    ///         Ok(json!(0))
+    ///     })
+    ///     .register("seafile_get_config", |config, args| {
+    ///         let key: String = arg(args, 0)?;
+    ///         Ok(json!(config.lock().unwrap().get(&key)))
+    ///     });
+    /// ```
+    pub fn with_state<S>(&mut self, state: Arc<S>) -> WithState<'_, S>
+    where
+        S: Send + Sync + 'static,
+    {
+        WithState {
+            server: self,
+            state,
+        }
+    }
+
+    /// Register every function of `service`
+    pub fn register_service<S: RpcService>(&mut self, service: Arc<S>) -> &mut Self {
+        for &function_name in S::functions() {
+            let service = Arc::clone(&service);
+            self.register(function_name, move |args| {
+                service
+                    .call(function_name, args)
+                    .unwrap_or_else(|| Err(function_not_found(function_name)))
+            });
+        }
+        self
+    }
+
+    /// Whether `function_name` is registered
+    pub fn has_function(&self, function_name: &str) -> bool {
+        self.registry().handlers.contains_key(function_name)
+    }
+
+    /// Names of the registered functions, sorted
+    pub fn functions(&self) -> Vec<String> {
+        let mut functions: Vec<_> = self.registry().handlers.keys().cloned().collect();
+        functions.sort_unstable();
+        functions
+    }
+
+    /// Answer [`LIST_FUNCTIONS`] with the functions this server has
+    ///
+    /// The call takes no arguments and returns an objlist of
+    /// `{"name": ..., "signature": ...}`, sorted by name, with the
+    /// signature from [`register_signed`](Self::register_signed) or `null`.
+    /// Meant for debugging and generic tools; it goes through middleware
+    /// like any other call, so access to it can be restricted there.
+    pub fn enable_introspection(&mut self) -> &mut Self {
+        self.introspection = true;
+        self
+    }
+
+    /// Accept requests from [`Compressed`](crate::compression::Compressed)
+    /// transports, compressing responses of at least `threshold` bytes
+    ///
+    /// Adds the [`NEGOTIATE`](crate::compression::NEGOTIATE) function
+    /// clients call first; plain requests are served as before.
+    #[cfg(feature = "compression")]
+    pub fn enable_compression(&mut self, threshold: usize) -> &mut Self {
+        self.compression = Some(threshold);
+        self
+    }
+
+    /// Report every call to `sink`, replacing any previous sink
+    ///
+    /// Calls are recorded by [`dispatch`](Self::dispatch) and the methods
+    /// built on it, with their duration including middleware. Requests
+    /// that cannot be parsed name no function and are not recorded.
+    pub fn set_metrics(&mut self, sink: Arc<dyn MetricsSink>) -> &mut Self {
+        self.metrics = Some(sink);
+        self
+    }
+
+    /// Run `function_name` with `args`, bypassing middleware
+    pub fn call(&self, function_name: &str, args: &[Value]) -> Result<Value> {
+        // Not holding the lock while the handler runs: it may register functions
+        let handler = self.registry().handlers.get(function_name).cloned();
+        match handler {
+            Some(handler) => handler(args),
+            None if self.introspection && function_name == LIST_FUNCTIONS => {
+                Ok(self.list_functions())
+            }
+            #[cfg(feature = "compression")]
+            None if self.compression.is_some() && function_name == compression::NEGOTIATE => {
+                Ok(compression::negotiate(args))
+            }
+            None => Err(function_not_found(function_name)),
+        }
+    }
+
+    fn list_functions(&self) -> Value {
+        let registry = self.registry();
+        let mut functions: Vec<(&str, Option<String>)> = registry
+            .handlers
+            .keys()
+            .map(|name| {
+                let signature = registry.signatures.get(name).map(ToString::to_string);
+                (name.as_str(), signature)
+            })
+            .collect();
+        if !registry.handlers.contains_key(LIST_FUNCTIONS) {
+            functions.push((LIST_FUNCTIONS, Some("objlist__void".to_string())));
+        }
+        functions.sort_unstable();
+        functions
+            .into_iter()
+            .map(|(name, signature)| serde_json::json!({"name": name, "signature": signature}))
+            .collect()
+    }
+
+    /// Run a serialized request and return the serialized response
+    pub fn handle_request(&self, request: &[u8]) -> Vec<u8> {
+        #[cfg(feature = "compression")]
+        if let Some(threshold) = self.compression {
+            let dispatch = |request: &[u8]| encode_response(&self.dispatch(request));
+            if let Some(response) = compression::handle_frame(request, threshold, dispatch) {
+                return response;
+            }
+        }
+        encode_response(&self.dispatch(request))
+    }
+
+    /// Run a call through the middleware and its function
+    pub fn handle(&self, request: Request) -> Result<Value> {
+        Next {
+            server: self,
+            layers: &self.middleware,
+        }
+        .run(request)
+    }
+
+    /// Run a serialized request, returning the response to send
+    pub fn dispatch(&self, request: &[u8]) -> RpcResponse {
+        let (function_name, args) = match parse_request(request) {
+            Ok(call) => call,
+            Err(e) => {
+                trace_bad_request(&e);
+                return RpcResponse::from(e);
+            }
+        };
+        let started = Instant::now();
+        let result = self.handle(Request {
+            function_name: function_name.clone(),
+            args,
+        });
+        report_call(self.metrics.as_deref(), &function_name, started, &result);
+        RpcResponse::from_result(result)
+    }
+
+    fn registry(&self) -> RwLockReadGuard<'_, Registry> {
+        self.registry.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn registry_write(&self) -> RwLockWriteGuard<'_, Registry> {
+        self.registry
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn registry_mut(&mut self) -> &mut Registry {
+        self.registry
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Registers handlers sharing one state, see [`SearpcServer::with_state`]
+pub struct WithState<'a, S> {
+    server: &'a mut SearpcServer,
+    state: Arc<S>,
+}
+
+impl<S: Send + Sync + 'static> WithState<'_, S> {
+    /// Register `handler` as `function_name`, replacing any previous one
+    pub fn register<F>(&mut self, function_name: impl Into<String>, handler: F) -> &mut Self
+    where
+        F: Fn(&Arc<S>, &[Value]) -> Result<Value> + Send + Sync + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        self.server
+            .register(function_name, move |args| handler(&state, args));
+        self
+    }
+}
+
+impl fmt::Debug for SearpcServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SearpcServer")
+            .field("functions", &self.functions())
+            .field("middleware", &self.middleware.len())
+            .field("introspection", &self.introspection)
+            .field("metrics", &self.metrics.is_some())
+            .finish()
+    }
+}
+
+/// Split `["function_name", args...]` into its parts
+pub(crate) fn parse_request(request: &[u8]) -> Result<(String, Vec<Value>)> {
+    let mut call: Vec<Value> = serde_json::from_slice(request).map_err(bad_request)?;
+    if call.is_empty() {
+        return Err(bad_request("empty request"));
+    }
+    match call.remove(0) {
+        Value::String(function_name) => Ok((function_name, call)),
+        other => Err(bad_request(format!(
+            "function name should be a string, got {}",
+            other
+        ))),
+    }
+}
+
+/// Report a finished call to tracing and `metrics`, see the [module docs](self)
+pub(crate) fn report_call(
+    metrics: Option<&dyn MetricsSink>,
+    function_name: &str,
+    started: Instant,
+    result: &Result<Value>,
+) {
+    let duration = started.elapsed();
+    if let Some(metrics) = metrics {
+        metrics.record(
+            function_name,
+            duration,
+            result.as_ref().err().map(SearpcError::err_code),
+        );
+    }
+    let duration_us = duration.as_micros() as u64;
+    match result {
+        Ok(_) => info!(
+            function = function_name,
+            duration_us,
+            outcome = "ok",
+            "RPC call"
+        ),
+        Err(e) => info!(
+            function = function_name,
+            duration_us,
+            outcome = "error",
+            err_code = e.err_code(),
+            err_msg = %e.err_msg(),
+            "RPC call failed"
+        ),
+    }
+}
+
+/// Report a request that could not be parsed into a call
+pub(crate) fn trace_bad_request(error: &SearpcError) {
+    info!(outcome = "bad_request", err_msg = %error.err_msg(), "RPC request rejected");
+}
+
+/// libsearpc's error for a request it cannot parse
+pub(crate) fn bad_request(message: impl fmt::Display) -> SearpcError {
+    SearpcError::RpcError {
+        code: KnownErrorCode::BadRequest.code(),
+        message: format!("failed to load RPC call: {}", message),
+    }
+}
+
+/// libsearpc's error for a call to an unregistered function
+pub(crate) fn function_not_found(function_name: &str) -> SearpcError {
+    SearpcError::RpcError {
+        code: KnownErrorCode::FunctionNotFound.code(),
+        message: format!("cannot find function {}.", function_name),
+    }
+}
+
+/// Error for a request turned away by [`Limits::max_queued_requests`]
+pub(crate) fn busy() -> SearpcError {
+    SearpcError::RpcError {
+        code: KnownErrorCode::Busy.code(),
+        message: "server busy, try again later".to_string(),
+    }
+}
+
+/// Serialize a response body
+pub(crate) fn encode_response(response: &RpcResponse) -> Vec<u8> {
+    serde_json::to_vec(response).unwrap_or_else(|e| {
+        // Only a handler result that fails to serialize gets here
+        let response = RpcResponse::from(SearpcError::from(e));
+        serde_json::to_vec(&response).expect("error response serializes")
+    })
+}
+
+/// Deserialize argument `index` of a call
+///
+/// Missing trailing arguments read as `null`, as in libsearpc, so optional
+/// parameters can be taken as `Option<T>`. A mismatch is reported as
+/// `SEAF_ERR_BAD_ARGS` (503).
+pub fn arg<T: DeserializeOwned>(args: &[Value], index: usize) -> Result<T> {
+    let value = args.get(index).unwrap_or(&Value::Null);
+    T::deserialize(value).map_err(|e| SearpcError::RpcError {
+        code: KnownErrorCode::BadArgs.code(),
+        message: format!("argument {}: {}", index, e),
+    })
+}
+
+/// Bounds on how much work a socket server takes on at once
+///
+/// By default only the request size is limited, to
+/// [`DEFAULT_MAX_PACKET_SIZE`]. With `max_connections`, clients beyond it
+/// wait in the listen backlog until a connection closes. With
+/// `max_concurrent_requests`, requests beyond it wait for a running one to
+/// finish; once `max_queued_requests` are waiting, further requests are
+/// answered at once with [`KnownErrorCode::Busy`] (429) instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Connections served at once, one thread or task each
+    pub max_connections: Option<usize>,
+    /// Requests handled at once, across all connections
+    pub max_concurrent_requests: Option<usize>,
+    /// Requests allowed to wait for a free slot
+    pub max_queued_requests: usize,
+    /// Largest request, in bytes, a length header may announce
+    ///
+    /// Larger requests are not read: the client gets a 511 error and the
+    /// connection is closed, since the rest of the stream cannot be trusted.
+    pub max_packet_size: usize,
+}
+
+/// Default for [`Limits::max_packet_size`]: 16 MiB
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 16 << 20;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: None,
+            max_concurrent_requests: None,
+            max_queued_requests: 0,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+}
+
+/// Error for a request over [`Limits::max_packet_size`]
+pub(crate) fn packet_too_large(len: usize, limit: usize) -> SearpcError {
+    bad_request(format!(
+        "request of {} bytes exceeds the {} byte limit",
+        len, limit
+    ))
+}
+
+/// Counting semaphore for the blocking servers
+#[derive(Default)]
+pub(crate) struct Gate {
+    limit: Option<usize>,
+    max_waiting: usize,
+    state: Mutex<GateState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct GateState {
+    active: usize,
+    waiting: usize,
+}
+
+impl Gate {
+    /// Gate letting `limit` holders in at once, or any number with `None`
+    pub(crate) fn new(limit: Option<usize>, max_waiting: usize) -> Self {
+        Gate {
+            limit,
+            max_waiting,
+            ..Gate::default()
+        }
+    }
+
+    /// Wait for a slot, however many others are waiting
+    pub(crate) fn enter(self: &Arc<Self>) -> Permit {
+        self.wait(usize::MAX).expect("unbounded wait")
+    }
+
+    /// Wait for a slot, or `None` if the queue is already full
+    pub(crate) fn try_enter(self: &Arc<Self>) -> Option<Permit> {
+        self.wait(self.max_waiting)
+    }
+
+    fn wait(self: &Arc<Self>, max_waiting: usize) -> Option<Permit> {
+        let mut state = self.state();
+        if let Some(limit) = self.limit {
+            if state.active >= limit {
+                if state.waiting >= max_waiting {
+                    return None;
+                }
+                state.waiting += 1;
+                while state.active >= limit {
+                    state = self
+                        .released
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                state.waiting -= 1;
+            }
+        }
+        state.active += 1;
+        Some(Permit(Arc::clone(self)))
+    }
+
+    fn state(&self) -> MutexGuard<'_, GateState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A slot in a [`Gate`], given back on drop
+pub(crate) struct Permit(Arc<Gate>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.state().active -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn server() -> SearpcServer {
+        let mut server = SearpcServer::new();
+        server
+            .register("searpc_strlen", |args| {
+                let s: String = arg(args, 0)?;
+                Ok(json!(s.len()))
+            })
+            .register("get_repo", |args| {
+                let id: Option<String> = arg(args, 0)?;
+                match id {
+                    Some(id) => Ok(json!({ "id": id })),
+                    None => Err(SearpcError::RpcError {
+                        code: 501,
+                        message: "Repo not exists".to_string(),
+                    }),
+                }
+            });
+        server
+    }
+
+    fn handle(server: &SearpcServer, request: &str) -> Value {
+        serde_json::from_slice(&server.handle_request(request.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let server = server();
+        assert_eq!(
+            handle(&server, r#"["searpc_strlen","hello"]"#),
+            json!({"ret": 5})
+        );
+        assert_eq!(
+            handle(&server, r#"["get_repo","abc"]"#),
+            json!({"ret": {"id": "abc"}})
+        );
+        // Missing argument reads as null
+        assert_eq!(
+            handle(&server, r#"["get_repo"]"#),
+            json!({"err_code": 501, "err_msg": "Repo not exists"})
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let server = server();
+        assert_eq!(
+            handle(&server, r#"["no_such_function"]"#),
+            json!({"err_code": 500, "err_msg": "cannot find function no_such_function."})
+        );
+
+        let response = handle(&server, r#"["searpc_strlen",1]"#);
+        assert_eq!(response["err_code"], 503);
+        assert!(response["err_msg"]
+            .as_str()
+            .unwrap()
+            .starts_with("argument 0: invalid type"));
+
+        for bad in ["not json", "[]", "[1]", r#"{"f": 1}"#] {
+            let response = handle(&server, bad);
+            assert_eq!(response["err_code"], 511, "{}", bad);
+            assert!(response["err_msg"]
+                .as_str()
+                .unwrap()
+                .starts_with("failed to load RPC call: "));
+        }
+    }
+
+    #[test]
+    fn test_gate() {
+        use std::thread;
+        use std::time::Duration;
+
+        let gate = Arc::new(Gate::new(Some(1), 1));
+        let first = gate.try_enter().unwrap();
+        let waiter = {
+            let gate = Arc::clone(&gate);
+            thread::spawn(move || gate.try_enter().is_some())
+        };
+        while gate.state().waiting == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // One running, one queued: the next is turned away
+        assert!(gate.try_enter().is_none());
+        drop(first);
+        assert!(waiter.join().unwrap());
+        assert_eq!(gate.state().active, 0);
+
+        let unlimited = Arc::new(Gate::default());
+        let permits: Vec<_> = (0..10).map(|_| unlimited.try_enter().unwrap()).collect();
+        assert_eq!(unlimited.state().active, permits.len());
+    }
+
+    #[test]
+    fn test_middleware() {
+        use std::sync::Mutex;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut server = server();
+        {
+            let log = Arc::clone(&log);
+            // Outermost: sees every call, including rejected ones
+            server.layer(move |request, next| {
+                let function_name = request.function_name.clone();
+                let result = next.run(request);
+                log.lock()
+                    .unwrap()
+                    .push(format!("{} ok={}", function_name, result.is_ok()));
+                result
+            });
+        }
+        server
+            .layer(|request, next| {
+                if request.function_name == "get_repo" && request.args.is_empty() {
+                    return Err(SearpcError::RpcError {
+                        code: 403,
+                        message: "denied".to_string(),
+                    });
+                }
+                next.run(request)
+            })
+            // Rewrite an old function name
+            .layer(|mut request, next| {
+                if request.function_name == "strlen" {
+                    request.function_name = "searpc_strlen".to_string();
+                }
+                next.run(request)
+            });
+
+        assert_eq!(handle(&server, r#"["strlen","abc"]"#), json!({"ret": 3}));
+        assert_eq!(
+            handle(&server, r#"["get_repo"]"#),
+            json!({"err_code": 403, "err_msg": "denied"})
+        );
+        assert_eq!(handle(&server, r#"["missing"]"#)["err_code"], 500);
+        // Unparsable requests never reach middleware
+        assert_eq!(handle(&server, "[")["err_code"], 511);
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["strlen ok=true", "get_repo ok=false", "missing ok=false"]
+        );
+
+        // call() goes straight to the function
+        assert_eq!(server.call("get_repo", &[]).unwrap_err().err_code(), 501);
+    }
+
+    #[test]
+    fn test_register_signed() {
+        let mut server = SearpcServer::new();
+        server
+            .register_signed("searpc_objlisttest", "objlist__int_int_string", |args| {
+                let count: i32 = arg(args, 0)?;
+                Ok(json!(vec![json!({"str": args[2]}); count as usize]))
+            })
+            .unwrap()
+            .register_signed("bad_return", "int__void", |_| Ok(json!("x")))
+            .unwrap();
+        assert_eq!(
+            server.signature("searpc_objlisttest").unwrap().to_string(),
+            "objlist__int_int_string"
+        );
+
+        assert_eq!(
+            handle(&server, r#"["searpc_objlisttest",2,1,"a"]"#),
+            json!({"ret": [{"str": "a"}, {"str": "a"}]})
+        );
+        let response = handle(&server, r#"["searpc_objlisttest",2,1]"#);
+        assert_eq!(response["err_code"], 503);
+        let response = handle(&server, r#"["searpc_objlisttest","2",1,"a"]"#);
+        assert_eq!(response["err_code"], 503);
+        let response = handle(&server, r#"["bad_return"]"#);
+        assert_eq!(response["err_code"], 500);
+
+        assert!(server
+            .register_signed("f", "int__bool", |_| Ok(json!(0)))
+            .is_err());
+        assert!(!server.has_function("f"));
+        // Plain registration drops the old signature
+        server.register("bad_return", |_| Ok(json!("x")));
+        assert!(server.signature("bad_return").is_none());
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut server = server();
+        assert_eq!(
+            handle(&server, r#"["__searpc_list_functions"]"#)["err_code"],
+            500
+        );
+
+        server
+            .register_signed("searpc_objlisttest", "objlist__int_int_string", |_| {
+                Ok(json!([]))
+            })
+            .unwrap()
+            .enable_introspection();
+        assert_eq!(
+            handle(&server, r#"["__searpc_list_functions"]"#),
+            json!({"ret": [
+                {"name": "__searpc_list_functions", "signature": "objlist__void"},
+                {"name": "get_repo", "signature": null},
+                {"name": "searpc_objlisttest", "signature": "objlist__int_int_string"},
+                {"name": "searpc_strlen", "signature": null},
+            ]})
+        );
+
+        // Middleware sees it like any other call
+        server.layer(|request, next| {
+            if request.function_name == LIST_FUNCTIONS {
+                return Err(SearpcError::RpcError {
+                    code: 403,
+                    message: "Permission denied".to_string(),
+                });
+            }
+            next.run(request)
+        });
+        assert_eq!(
+            handle(&server, r#"["__searpc_list_functions"]"#)["err_code"],
+            403
+        );
+    }
+
+    #[test]
+    fn test_runtime_registration() {
+        let server = Arc::new(server());
+        let plugin = {
+            let server = Arc::clone(&server);
+            move |args: &[Value]| {
+                // Handlers may change the registry they run from
+                let name: String = arg(args, 0)?;
+                server.insert_function(name, |_| Ok(json!("loaded")));
+                Ok(Value::Null)
+            }
+        };
+        server.insert_function("load_plugin", plugin);
+
+        assert_eq!(handle(&server, r#"["plugin_hello"]"#)["err_code"], 500);
+        handle(&server, r#"["load_plugin","plugin_hello"]"#);
+        assert_eq!(
+            handle(&server, r#"["plugin_hello"]"#),
+            json!({"ret": "loaded"})
+        );
+        assert_eq!(
+            server.functions(),
+            ["get_repo", "load_plugin", "plugin_hello", "searpc_strlen"]
+        );
+
+        assert!(server.remove_function("plugin_hello"));
+        assert!(!server.remove_function("plugin_hello"));
+        assert_eq!(handle(&server, r#"["plugin_hello"]"#)["err_code"], 500);
+        // The server holds a handle on itself through `load_plugin`
+        assert!(server.remove_function("load_plugin"));
+    }
+
+    /// Fields of the events emitted while running `f`
+    fn recorded_events(f: impl FnOnce()) -> Vec<HashMap<String, String>> {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        #[derive(Clone, Default)]
+        struct Recorder(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+        struct Fields<'a>(&'a mut HashMap<String, String>);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{:?}", value));
+            }
+
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.insert(field.name().to_string(), value.to_string());
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = HashMap::new();
+                event.record(&mut Fields(&mut fields));
+                self.0.lock().unwrap().push(fields);
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), f);
+        let events = std::mem::take(&mut *recorder.0.lock().unwrap());
+        events
+    }
+
+    #[test]
+    fn test_tracing() {
+        let server = server();
+        let events = recorded_events(|| {
+            handle(&server, r#"["searpc_strlen","abc"]"#);
+            handle(&server, r#"["get_repo"]"#);
+            handle(&server, "[]");
+        });
+        let events: Vec<_> = events
+            .into_iter()
+            .filter(|event| event.contains_key("outcome"))
+            .collect();
+        assert_eq!(events.len(), 3);
+
+        assert_eq!(events[0]["function"], "searpc_strlen");
+        assert_eq!(events[0]["outcome"], "ok");
+        assert!(events[0]["duration_us"].parse::<u64>().is_ok());
+        assert_eq!(events[1]["function"], "get_repo");
+        assert_eq!(events[1]["outcome"], "error");
+        assert_eq!(events[1]["err_code"], "501");
+        assert_eq!(events[1]["err_msg"], "Repo not exists");
+        assert_eq!(events[2]["outcome"], "bad_request");
+    }
+
+    #[test]
+    fn test_metrics() {
+        use crate::metrics::CallMetrics;
+
+        let metrics = Arc::new(CallMetrics::new());
+        let mut server = server();
+        server.set_metrics(metrics.clone());
+        handle(&server, r#"["searpc_strlen","abc"]"#);
+        handle(&server, r#"["searpc_strlen","abcd"]"#);
+        handle(&server, r#"["get_repo"]"#);
+        handle(&server, "[]");
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot["searpc_strlen"].calls, 2);
+        assert_eq!(snapshot["searpc_strlen"].errors, 0);
+        assert_eq!(snapshot["get_repo"].errors, 1);
+
+        // Any closure is a sink
+        let codes = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&codes);
+        server.set_metrics(Arc::new(
+            move |name: &str, _: Duration, err_code: Option<i32>| {
+                sink.lock().unwrap().push((name.to_string(), err_code));
+            },
+        ));
+        handle(&server, r#"["get_repo"]"#);
+        handle(&server, r#"["missing"]"#);
+        assert_eq!(
+            *codes.lock().unwrap(),
+            [
+                ("get_repo".to_string(), Some(501)),
+                ("missing".to_string(), Some(500))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_state() {
+        use std::sync::atomic::{AtomicI64, Ordering};
+
+        let counter = Arc::new(AtomicI64::new(0));
+        let mut server = SearpcServer::new();
+        server
+            .with_state(Arc::clone(&counter))
+            .register("add", |counter, args| {
+                let n: i64 = arg(args, 0)?;
+                Ok(json!(counter.fetch_add(n, Ordering::SeqCst) + n))
+            })
+            .register("get", |counter, _| {
+                Ok(json!(counter.load(Ordering::SeqCst)))
+            });
+
+        assert_eq!(handle(&server, r#"["add",2]"#), json!({"ret": 2}));
+        assert_eq!(handle(&server, r#"["add",3]"#), json!({"ret": 5}));
+        assert_eq!(handle(&server, r#"["get"]"#), json!({"ret": 5}));
+        // The caller's handle sees the same state
+        assert_eq!(counter.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_client_roundtrip() {
+        use crate::{Arg, SearpcClient};
+        use std::sync::Arc;
+
+        let server = Arc::new(server());
+        let mut client = {
+            let server = Arc::clone(&server);
+            SearpcClient::new(move |request: &[u8]| Ok(server.handle_request(request)))
+        };
+        assert_eq!(
+            client
+                .call_int("searpc_strlen", [Arg::string("héllo")])
+                .unwrap(),
+            6
+        );
+        let err = client.call_object("get_repo", [Arg::Null]).unwrap_err();
+        assert_eq!(err.inner().err_code(), 501);
+        assert_eq!(
+            client.call_int("missing", []).unwrap_err().kind(),
+            Some(KnownErrorCode::FunctionNotFound)
+        );
+    }
+}
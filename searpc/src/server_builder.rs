@@ -0,0 +1,413 @@
+//! Binding the listening socket for a [`UnixSocketServer`]
+//!
+//! [`UnixListener::bind`] fails if the socket file is left over from a
+//! server that exited without cleaning up, and creates it with whatever
+//! the umask allows. [`ServerBuilder`] removes stale sockets, sets the
+//! file's mode and owner before the socket accepts connections, and sets
+//! the listen backlog.
+//!
+//! ```rust,no_run
+//! use searpc::{ServerBuilder, UnixSocketServer};
+//! use std::sync::Arc;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let mut server = UnixSocketServer::new();
+//! server.service_mut("seafile-rpcserver");
+//!
+//! ServerBuilder::new("/run/user/1000/seafile.sock")
+//!     .mode(0o600)
+//!     .backlog(64)
+//!     .serve(Arc::new(server))
+//! # }
+//! ```
+//!
+//! Under systemd socket activation the socket is created by systemd and
+//! passed in through `LISTEN_FDS`; [`ServerBuilder::bind_or_activate`]
+//! uses it when present and binds otherwise, so one binary runs both ways.
+
+use crate::unix_connect::sockaddr_un;
+use crate::UnixSocketServer;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Listen backlog when none is set, as used by the standard library
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// First descriptor passed by systemd (`SD_LISTEN_FDS_START`)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Options for the listening socket of a [`UnixSocketServer`]
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    path: PathBuf,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    backlog: i32,
+    remove_stale: bool,
+}
+
+impl ServerBuilder {
+    /// Listen on the socket file at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        ServerBuilder {
+            path: path.into(),
+            mode: None,
+            uid: None,
+            gid: None,
+            backlog: DEFAULT_BACKLOG,
+            remove_stale: true,
+        }
+    }
+
+    /// Permission bits of the socket file, e.g. `0o600` for the owner only
+    ///
+    /// Set before the socket starts listening, so no one connects through
+    /// looser permissions in between. Unset, the umask decides.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Owner and group of the socket file; `None` leaves one unchanged
+    ///
+    /// Changing the owner usually needs root.
+    pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// Connections the kernel queues before the server accepts them
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Whether to remove a socket file no server is listening on (default)
+    ///
+    /// Only sockets that refuse connections are removed: binding still
+    /// fails if another server is listening, or if the path is not a socket.
+    pub fn remove_stale(mut self, remove_stale: bool) -> Self {
+        self.remove_stale = remove_stale;
+        self
+    }
+
+    /// Create the socket file and start listening
+    pub fn bind(&self) -> io::Result<UnixListener> {
+        if self.remove_stale {
+            remove_stale_socket(&self.path)?;
+        }
+
+        let listener = unix_socket()?;
+        let (addr, len) = sockaddr_un(&self.path)?;
+        // SAFETY: `addr` is a valid sockaddr_un of `len` bytes
+        cvt(unsafe {
+            libc::bind(
+                listener.as_raw_fd(),
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                len,
+            )
+        })?;
+        let listening = self.set_permissions().and_then(|()| {
+            // SAFETY: plain syscall on a socket we own
+            cvt(unsafe { libc::listen(listener.as_raw_fd(), self.backlog) })
+        });
+        if let Err(e) = listening {
+            // Nobody would answer on it: leave no socket file behind
+            let _ = fs::remove_file(&self.path);
+            return Err(e);
+        }
+        Ok(listener)
+    }
+
+    /// The socket passed by systemd if there is one, else [`bind`](Self::bind)
+    ///
+    /// If several are passed, the first is used and the others are closed;
+    /// use [`systemd_listeners`] to serve them all.
+    /// With socket activation the `.socket` unit sets the path and mode,
+    /// so the builder's own options only apply when binding.
+    pub fn bind_or_activate(&self) -> io::Result<UnixListener> {
+        match systemd_listeners()?.into_iter().next() {
+            Some(listener) => Ok(listener),
+            None => self.bind(),
+        }
+    }
+
+    /// Bind, then serve `server` until it is shut down
+    ///
+    /// See [`UnixSocketServer::serve`].
+    pub fn serve(&self, server: Arc<UnixSocketServer>) -> io::Result<()> {
+        server.serve(self.bind()?)
+    }
+
+    fn set_permissions(&self) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(mode))?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            let path = CString::new(self.path.as_os_str().as_bytes())?;
+            // -1 leaves the owner or group unchanged
+            let uid = self.uid.map_or(libc::uid_t::MAX, |uid| uid as libc::uid_t);
+            let gid = self.gid.map_or(libc::gid_t::MAX, |gid| gid as libc::gid_t);
+            // SAFETY: `path` is a valid C string
+            cvt(unsafe { libc::chown(path.as_ptr(), uid, gid) })?;
+        }
+        Ok(())
+    }
+}
+
+/// Unix sockets passed by systemd socket activation, in unit file order
+///
+/// Reads `LISTEN_PID` and `LISTEN_FDS` like `sd_listen_fds(0)`: empty if
+/// they are unset or meant for another process, as they are for child
+/// processes, and on every call after the first, so that each socket has a
+/// single owner. The environment is left alone, since changing it races
+/// with other threads reading it. Fails if a passed descriptor is not a
+/// Unix socket.
+pub fn systemd_listeners() -> io::Result<Vec<UnixListener>> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    listeners_from_env(pid.as_deref(), fds.as_deref(), SD_LISTEN_FDS_START)
+}
+
+fn listeners_from_env(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    start: RawFd,
+) -> io::Result<Vec<UnixListener>> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = fds.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid LISTEN_FDS: {:?}", fds),
+        )
+    })?;
+
+    let fds: Vec<RawFd> = (start..start + count).collect();
+    for &fd in &fds {
+        if !is_unix_socket(fd) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("descriptor {} from systemd is not a Unix socket", fd),
+            ));
+        }
+    }
+    fds.into_iter()
+        .map(|fd| {
+            // SAFETY: plain syscall on a descriptor passed to us
+            cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+            // SAFETY: systemd passed `fd` to this process, and
+            // `systemd_listeners` hands it out only once
+            Ok(unsafe { UnixListener::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+/// Whether `fd` is an open Unix domain socket
+fn is_unix_socket(fd: RawFd) -> bool {
+    // SAFETY: sockaddr_un is plain data, valid when zeroed
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+    // SAFETY: `addr` and `len` are valid for writes, `len` is addr's size
+    let ret = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_un as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    ret == 0 && addr.sun_family == libc::AF_UNIX as libc::sa_family_t
+}
+
+/// Remove the socket at `path` if no server is listening on it
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another server is listening on {}", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// An unbound Unix stream socket, closed on exec
+fn unix_socket() -> io::Result<UnixListener> {
+    // SAFETY: plain syscall
+    let fd = cvt(unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) })?;
+    // SAFETY: `fd` is a new socket that nothing else owns
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    // SAFETY: plain syscall on a socket we own
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    Ok(listener)
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SearpcClient, UnixSocketTransport};
+    use serde_json::json;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Fresh directory named after `test`
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "searpc-server-builder-{}-{}",
+            test,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_mode_and_serve() {
+        let dir = temp_dir("mode");
+        let path = dir.join("seafile.sock");
+
+        let mut server = UnixSocketServer::new();
+        server
+            .service_mut("seafile-rpcserver")
+            .register("ping", |_| Ok(json!(1)));
+        let server = Arc::new(server);
+        let listener = ServerBuilder::new(&path)
+            .mode(0o600)
+            .backlog(4)
+            .bind()
+            .unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let serving = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve(listener))
+        };
+        let transport = UnixSocketTransport::connect(&path, "seafile-rpcserver").unwrap();
+        assert_eq!(
+            SearpcClient::new(transport).call_int("ping", []).unwrap(),
+            1
+        );
+
+        assert!(server.shutdown(Duration::from_secs(5)));
+        serving.join().unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_socket() {
+        let dir = temp_dir("stale");
+        let path = dir.join("seafile.sock");
+
+        // A server that exited leaves its socket file behind
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let err = ServerBuilder::new(&path)
+            .remove_stale(false)
+            .bind()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let listener = ServerBuilder::new(&path).bind().unwrap();
+
+        // A live server's socket is left alone
+        let err = ServerBuilder::new(&path).bind().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+
+        // So is anything that is not a socket
+        let file = dir.join("not-a-socket");
+        fs::write(&file, "data").unwrap();
+        let err = ServerBuilder::new(&file).bind().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&file).unwrap(), b"data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_owner_and_path() {
+        let dir = temp_dir("owner");
+        let path = dir.join("seafile.sock");
+        // SAFETY: no preconditions
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+
+        // Our own uid and gid can always be set
+        let _listener = ServerBuilder::new(&path)
+            .owner(Some(uid), Some(gid))
+            .bind()
+            .unwrap();
+        let err = ServerBuilder::new(dir.join(format!("{:0200}", 0)))
+            .bind()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_systemd_listeners() {
+        let dir = temp_dir("systemd");
+        let path = dir.join("seafile.sock");
+        let pid = std::process::id().to_string();
+
+        // Not for us, or not set
+        assert!(listeners_from_env(None, None, 3).unwrap().is_empty());
+        assert!(listeners_from_env(Some("1"), Some("1"), 3)
+            .unwrap()
+            .is_empty());
+        assert!(listeners_from_env(Some(&pid), Some("x"), 3).is_err());
+
+        // Pass a bound socket on a high descriptor, as systemd would on 3
+        let bound = ServerBuilder::new(&path).bind().unwrap();
+        // SAFETY: duplicating a descriptor we own onto an unused number
+        let fd = unsafe { libc::fcntl(bound.as_raw_fd(), libc::F_DUPFD, 900) };
+        assert!(fd >= 900);
+        drop(bound);
+        let listeners = listeners_from_env(Some(&pid), Some("1"), fd).unwrap();
+        assert_eq!(listeners.len(), 1);
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(addr.as_pathname(), Some(path.as_path()));
+        UnixStream::connect(&path).unwrap();
+
+        // Anything else is refused rather than taken over
+        let file = fs::File::create(dir.join("file")).unwrap();
+        assert!(listeners_from_env(Some(&pid), Some("1"), file.as_raw_fd()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
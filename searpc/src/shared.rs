@@ -0,0 +1,188 @@
+//! A client callable through `&self`
+//!
+//! [`SearpcClient`] calls take `&mut self`, as a connection serves one call
+//! at a time. [`SharedSearpcClient`] takes a lock around each call instead,
+//! so one client can live in an `Arc` and serve several threads:
+//!
+//! ```rust,no_run
+//! use searpc::{SharedSearpcClient, UnixSocketTransport};
+//! use std::sync::Arc;
+//!
+//! let transport = UnixSocketTransport::connect("/path/to/seafile.sock", "seafile-rpcserver")?;
+//! let client = Arc::new(SharedSearpcClient::new(transport));
+//! let workers: Vec<_> = (0..4)
+//!     .map(|_| {
+//!         let client = Arc::clone(&client);
+//!         std::thread::spawn(move || client.call_string("seafile_get_version", []))
+//!     })
+//!     .collect();
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Calls still take turns on the connection. For calls that run side by
+//! side, give each thread a connection of its own with a
+//! [`TransportPool`](crate::TransportPool), or share one with a
+//! `MultiplexedTransport` on Unix.
+
+use crate::client::SearpcClient;
+use crate::error::Result;
+use crate::protocol::{ObjlistIter, RpcRequest};
+use crate::transport::{PipelinedTransport, Transport};
+use crate::types::Arg;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// [`SearpcClient`] behind a lock, see the [module docs](self)
+pub struct SharedSearpcClient<T: Transport> {
+    client: Mutex<SearpcClient<T>>,
+}
+
+impl<T: Transport> SharedSearpcClient<T> {
+    pub fn new(transport: T) -> Self {
+        SearpcClient::new(transport).into()
+    }
+
+    /// The client, locked for as long as the guard lives
+    ///
+    /// For its settings, such as timeouts and hooks, or to make several
+    /// calls with no other thread's in between.
+    pub fn lock(&self) -> MutexGuard<'_, SearpcClient<T>> {
+        self.client.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> SearpcClient<T> {
+        self.client
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// See [`SearpcClient::call`]
+    pub fn call(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.lock().call(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_int`]
+    pub fn call_int(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<i32> {
+        self.lock().call_int(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_int64`]
+    pub fn call_int64(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<i64> {
+        self.lock().call_int64(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_string`]
+    pub fn call_string(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<String> {
+        self.lock().call_string(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_object`]
+    pub fn call_object(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.lock().call_object(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_objlist`]
+    pub fn call_objlist(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Vec<Value>> {
+        self.lock().call_objlist(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_objlist_typed`]
+    pub fn call_objlist_typed<R: DeserializeOwned>(
+        &self,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<ObjlistIter<R>> {
+        self.lock().call_objlist_typed(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_as`]
+    pub fn call_as<R: DeserializeOwned>(
+        &self,
+        function_name: &str,
+        args: impl AsRef<[Arg]>,
+    ) -> Result<R> {
+        self.lock().call_as(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_void`]
+    pub fn call_void(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<()> {
+        self.lock().call_void(function_name, args)
+    }
+
+    /// See [`SearpcClient::call_json`]
+    pub fn call_json(&self, function_name: &str, args: impl AsRef<[Arg]>) -> Result<Value> {
+        self.lock().call_json(function_name, args)
+    }
+}
+
+impl<T: PipelinedTransport> SharedSearpcClient<T> {
+    /// See [`SearpcClient::call_batch`]; the lock is held for the whole
+    /// batch
+    pub fn call_batch(
+        &self,
+        requests: impl IntoIterator<Item = RpcRequest>,
+    ) -> Result<Vec<Result<Value>>> {
+        self.lock().call_batch(requests)
+    }
+}
+
+impl<T: Transport> From<SearpcClient<T>> for SharedSearpcClient<T> {
+    fn from(client: SearpcClient<T>) -> Self {
+        SharedSearpcClient {
+            client: Mutex::new(client),
+        }
+    }
+}
+
+impl<T: Transport> fmt::Debug for SharedSearpcClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedSearpcClient").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SearpcError;
+    use std::sync::Arc;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_shared_across_threads() {
+        assert_send_sync::<SharedSearpcClient<Box<dyn Transport + Send>>>();
+
+        let mut calls = 0;
+        let transport = move |request: &[u8]| -> Result<Vec<u8>> {
+            calls += 1;
+            match request {
+                br#"["calls"]"# => Ok(format!(r#"{{"ret": {}}}"#, calls).into_bytes()),
+                _ => Err(SearpcError::transport("unexpected request")),
+            }
+        };
+        let client = Arc::new(SharedSearpcClient::new(transport));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let client = Arc::clone(&client);
+                std::thread::spawn(move || {
+                    (0..25)
+                        .map(|_| client.call_int("calls", []).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut seen: Vec<_> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (1..=100).collect::<Vec<_>>());
+
+        // Several calls with no other thread's in between
+        let mut client = client.lock();
+        assert_eq!(client.call_int("calls", []).unwrap(), 101);
+        assert_eq!(client.call_int("calls", []).unwrap(), 102);
+    }
+}
@@ -0,0 +1,302 @@
+//! libsearpc function signatures
+//!
+//! libsearpc registers each server function with a signature naming its
+//! return and parameter types, `RET__PARAM1_PARAM2...` (`__void` for none):
+//!
+//! ```c
+//! searpc_server_register_function ("seafile-rpcserver", seafile_get_repo,
+//!     "seafile_get_repo", searpc_signature_object__string());
+//! ```
+//!
+//! and picks the marshal function that unpacks the arguments by that
+//! signature. [`SearpcServer::register_signed`](crate::SearpcServer::register_signed)
+//! takes the same strings, so such definitions port line by line, and
+//! checks every call against the signature before the handler runs.
+
+use crate::error::{KnownErrorCode, Result, SearpcError};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// Type of a parameter in a signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParamType {
+    /// `int`: 32-bit integer
+    Int,
+    /// `int64`: 64-bit integer
+    Int64,
+    /// `string`: string or `null`
+    String,
+    /// `json`: any JSON value
+    Json,
+}
+
+/// Type of the return value in a signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReturnType {
+    /// `int`: 32-bit integer
+    Int,
+    /// `int64`: 64-bit integer
+    Int64,
+    /// `string`: string or `null`
+    String,
+    /// `object`: object or `null`
+    Object,
+    /// `objlist`: array of objects, or `null`
+    Objlist,
+    /// `json`: any JSON value
+    Json,
+}
+
+/// A function signature such as `objlist__int_int_string`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Signature {
+    ret: ReturnType,
+    params: Vec<ParamType>,
+}
+
+impl Signature {
+    /// Signature returning `ret` and taking `params`
+    pub fn new(ret: ReturnType, params: impl Into<Vec<ParamType>>) -> Self {
+        Signature {
+            ret,
+            params: params.into(),
+        }
+    }
+
+    /// Type of the return value
+    pub fn return_type(&self) -> ReturnType {
+        self.ret
+    }
+
+    /// Types of the parameters, in order
+    pub fn params(&self) -> &[ParamType] {
+        &self.params
+    }
+
+    /// Check a call's arguments: their number, then each one's type
+    ///
+    /// Mismatches are `SEAF_ERR_BAD_ARGS` (503), like errors from
+    /// [`server::arg`](crate::server::arg).
+    pub fn check_args(&self, args: &[Value]) -> Result<()> {
+        if args.len() != self.params.len() {
+            return Err(bad_args(format!(
+                "expected {} arguments ({}), got {}",
+                self.params.len(),
+                self,
+                args.len()
+            )));
+        }
+        for (index, (param, value)) in self.params.iter().zip(args).enumerate() {
+            if !param.accepts(value) {
+                return Err(bad_args(format!(
+                    "argument {}: expected {}, got {}",
+                    index, param, value
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a handler's return value
+    pub fn check_return(&self, ret: &Value) -> Result<()> {
+        if self.ret.accepts(ret) {
+            Ok(())
+        } else {
+            Err(SearpcError::TypeError(format!(
+                "expected {} return value, got {}",
+                self.ret, ret
+            )))
+        }
+    }
+}
+
+impl ParamType {
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            ParamType::Int => is_i32(value),
+            ParamType::Int64 => value.is_i64(),
+            ParamType::String => value.is_string() || value.is_null(),
+            ParamType::Json => true,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ParamType::Int => "int",
+            ParamType::Int64 => "int64",
+            ParamType::String => "string",
+            ParamType::Json => "json",
+        }
+    }
+}
+
+impl ReturnType {
+    fn accepts(self, value: &Value) -> bool {
+        match self {
+            ReturnType::Int => is_i32(value),
+            ReturnType::Int64 => value.is_i64(),
+            ReturnType::String => value.is_string() || value.is_null(),
+            ReturnType::Object => value.is_object() || value.is_null(),
+            ReturnType::Objlist => match value {
+                Value::Array(items) => items.iter().all(Value::is_object),
+                Value::Null => true,
+                _ => false,
+            },
+            ReturnType::Json => true,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ReturnType::Int => "int",
+            ReturnType::Int64 => "int64",
+            ReturnType::String => "string",
+            ReturnType::Object => "object",
+            ReturnType::Objlist => "objlist",
+            ReturnType::Json => "json",
+        }
+    }
+}
+
+fn is_i32(value: &Value) -> bool {
+    matches!(value.as_i64(), Some(n) if i32::try_from(n).is_ok())
+}
+
+fn bad_args(message: String) -> SearpcError {
+    SearpcError::RpcError {
+        code: KnownErrorCode::BadArgs.code(),
+        message,
+    }
+}
+
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for ReturnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The libsearpc spelling, e.g. `int__string` or `json__void`
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}__", self.ret)?;
+        if self.params.is_empty() {
+            return f.write_str("void");
+        }
+        for (i, param) in self.params.iter().enumerate() {
+            if i > 0 {
+                f.write_str("_")?;
+            }
+            write!(f, "{}", param)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Signature {
+    type Err = SearpcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid =
+            |why: &str| SearpcError::TypeError(format!("Invalid signature {:?}: {}", s, why));
+
+        let (ret, params) = s
+            .split_once("__")
+            .ok_or_else(|| invalid("expected RET__PARAMS"))?;
+        let ret = match ret {
+            "int" => ReturnType::Int,
+            "int64" => ReturnType::Int64,
+            "string" => ReturnType::String,
+            "object" => ReturnType::Object,
+            "objlist" => ReturnType::Objlist,
+            "json" => ReturnType::Json,
+            _ => return Err(invalid("unknown return type")),
+        };
+        let params = match params {
+            "void" => Vec::new(),
+            params => params
+                .split('_')
+                .map(|param| match param {
+                    "int" => Ok(ParamType::Int),
+                    "int64" => Ok(ParamType::Int64),
+                    "string" => Ok(ParamType::String),
+                    "json" => Ok(ParamType::Json),
+                    _ => Err(invalid("unknown parameter type")),
+                })
+                .collect::<Result<_>>()?,
+        };
+        Ok(Signature { ret, params })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let sig: Signature = "objlist__int_int_string".parse().unwrap();
+        assert_eq!(sig.return_type(), ReturnType::Objlist);
+        assert_eq!(
+            sig.params(),
+            [ParamType::Int, ParamType::Int, ParamType::String]
+        );
+
+        for s in [
+            "int__void",
+            "string__string_int64",
+            "json__json",
+            "object__string",
+        ] {
+            assert_eq!(s.parse::<Signature>().unwrap().to_string(), s);
+        }
+        assert_eq!(Signature::new(ReturnType::Int, []).to_string(), "int__void");
+        for bad in ["int", "bool__int", "int__float", "int__", "int__int__int"] {
+            assert!(bad.parse::<Signature>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_check_args() {
+        let sig: Signature = "int__string_int_int64".parse().unwrap();
+        assert!(sig
+            .check_args(&[json!("a"), json!(1), json!(1i64 << 40)])
+            .is_ok());
+        assert!(sig.check_args(&[Value::Null, json!(-1), json!(0)]).is_ok());
+
+        let err = sig.check_args(&[json!("a"), json!(1)]).unwrap_err();
+        assert_eq!(err.kind(), Some(KnownErrorCode::BadArgs));
+        assert_eq!(
+            err.err_msg(),
+            "expected 3 arguments (int__string_int_int64), got 2"
+        );
+        let err = sig
+            .check_args(&[json!("a"), json!(1i64 << 40), json!(0)])
+            .unwrap_err();
+        assert_eq!(err.err_msg(), "argument 1: expected int, got 1099511627776");
+        assert!(sig.check_args(&[json!(1), json!(1), json!(1)]).is_err());
+        assert!(sig.check_args(&[json!("a"), json!("1"), json!(1)]).is_err());
+    }
+
+    #[test]
+    fn test_check_return() {
+        let objlist: Signature = "objlist__void".parse().unwrap();
+        assert!(objlist.check_return(&json!([{"id": 1}])).is_ok());
+        assert!(objlist.check_return(&Value::Null).is_ok());
+        assert!(objlist.check_return(&json!([1])).is_err());
+        assert!(objlist.check_return(&json!({})).is_err());
+
+        let int: Signature = "int__void".parse().unwrap();
+        assert!(int.check_return(&json!(0)).is_ok());
+        assert!(int.check_return(&json!("0")).is_err());
+        let json: Signature = "json__void".parse().unwrap();
+        assert!(json.check_return(&json!("anything")).is_ok());
+    }
+}
@@ -9,92 +9,356 @@
 //! ```
 //! Length is in network byte order (big-endian)
 
+use crate::deadline::DeadlineStream;
 use crate::error::{Result, SearpcError};
-use crate::transport::Transport;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-
-const MAX_PACKET_SIZE: usize = 65535; // uint16 max
+use crate::proxy::Proxy;
+use crate::retry::RetryPolicy;
+use crate::transport::{
+    self, ConnectionAddr, Framing, PipelinedTransport, Transport, MAX_FRAME_SIZE,
+};
+use std::io::{self, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
 /// TCP transport using the packet protocol
 pub struct TcpTransport {
-    stream: TcpStream,
+    /// Buffered, so a response's header and body usually take one read
+    stream: BufReader<DeadlineStream<TcpStream>>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+    connected: Instant,
+    chunked: bool,
+    max_response_size: usize,
 }
 
 impl TcpTransport {
     pub fn new(stream: TcpStream) -> Self {
-        TcpTransport { stream }
+        Self::with_capacity(stream, transport::DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Use buffers of `capacity` bytes from the start, for high call rates
+    ///
+    /// Responses up to that size are read in one go, and requests up to
+    /// that size are assembled without reallocating.
+    pub fn with_capacity(stream: TcpStream, capacity: usize) -> Self {
+        TcpTransport {
+            stream: BufReader::with_capacity(capacity, DeadlineStream::new(stream)),
+            buf: Vec::with_capacity(capacity),
+            connected: Instant::now(),
+            chunked: false,
+            max_response_size: transport::DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    /// Split messages over 64KB into continuation frames
+    ///
+    /// A frame of exactly 65535 bytes is followed by another frame of the
+    /// same message, which ends with a shorter, possibly empty, frame. The
+    /// server has to opt in too, like
+    /// [`AsyncSearpcServer::set_chunking`](crate::AsyncSearpcServer::set_chunking).
+    /// Messages under 65535 bytes are framed as before.
+    pub fn with_chunking(mut self, chunked: bool) -> Self {
+        self.chunked = chunked;
+        self
+    }
+
+    /// Longest response to accept, [`DEFAULT_MAX_RESPONSE_SIZE`](crate::transport::DEFAULT_MAX_RESPONSE_SIZE)
+    /// by default
+    ///
+    /// A longer one fails the call with
+    /// [`SearpcError::ProtocolDesync`]
+    /// before its body is read, and the connection is unusable after it.
+    pub fn with_max_response_size(mut self, max: usize) -> Self {
+        self.max_response_size = max;
+        self
     }
 
-    pub fn connect(addr: impl std::net::ToSocketAddrs) -> std::io::Result<Self> {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
         let stream = TcpStream::connect(addr)?;
-        Ok(TcpTransport { stream })
+        Ok(Self::new(stream))
+    }
+
+    /// Connect, waiting for a server that is not listening yet according
+    /// to `policy`
+    pub fn connect_with_retry(addr: impl ToSocketAddrs, policy: RetryPolicy) -> io::Result<Self> {
+        let stream = policy.connect(|| TcpStream::connect(&addr))?;
+        Ok(Self::new(stream))
+    }
+
+    /// Connect, giving up on each address of `addr` after `timeout`
+    pub fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> io::Result<Self> {
+        let mut last_error = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(Self::new(stream)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+        }))
+    }
+
+    /// Connect to `target` (`host:port`) through `proxy`
+    pub fn connect_via(proxy: &Proxy, target: &str) -> Result<Self> {
+        Ok(Self::new(proxy.connect(target)?))
+    }
+
+    /// Connect to `target` (`host:port`), through the proxy configured in
+    /// the environment if there is one (see [`Proxy::from_env`])
+    pub fn connect_from_env(target: &str) -> Result<Self> {
+        match Proxy::from_env(target)? {
+            Some(proxy) => Self::connect_via(&proxy, target),
+            None => Self::connect(target).map_err(|e| SearpcError::transport_io("connect", e)),
+        }
+    }
+
+    /// Fail calls whose response takes longer than `timeout` to arrive
+    ///
+    /// `None` (the default) waits forever. A timed-out call reports
+    /// [`SearpcError::is_timeout`]; drop the transport afterwards, as the
+    /// late response would be read as the next call's.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket().set_read_timeout(timeout)
+    }
+
+    /// Fail calls whose request cannot be written within `timeout`
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket().set_write_timeout(timeout)
+    }
+
+    fn socket(&self) -> &TcpStream {
+        self.stream.get_ref().get_ref()
     }
 
     /// Read exactly n bytes
-    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
-        self.stream
-            .read_exact(buf)
-            .map_err(|e| SearpcError::TransportError(format!("Read failed: {}", e)))
+    fn read_exact(&mut self, buf: &mut [u8], frame_start: bool) -> Result<()> {
+        transport::read_response(&mut self.stream, buf, frame_start)
     }
 
     /// Write all bytes
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
-        self.stream
-            .write_all(buf)
-            .map_err(|e| SearpcError::TransportError(format!("Write failed: {}", e)))
+        transport::write_request(self.stream.get_mut(), buf)
     }
 
     /// Send a packet
     fn send_packet(&mut self, data: &[u8]) -> Result<()> {
-        if data.len() > MAX_PACKET_SIZE {
-            return Err(SearpcError::TransportError(format!(
+        if data.len() > MAX_FRAME_SIZE && !self.chunked {
+            return Err(SearpcError::transport(format!(
                 "Packet too large: {} > {}",
                 data.len(),
-                MAX_PACKET_SIZE
+                MAX_FRAME_SIZE
             )));
         }
 
-        // Write length (2 bytes, big-endian)
-        let len = data.len() as u16;
-        self.write_all(&len.to_be_bytes())?;
-
-        // Write data
-        self.write_all(data)?;
-
-        Ok(())
+        // Length (2 bytes, big-endian) and data in one write: two small
+        // writes hit Nagle's algorithm and the peer's delayed ACK (~40ms)
+        let mut packet = std::mem::take(&mut self.buf);
+        packet.clear();
+        if self.chunked {
+            transport::encode_chunked(data, &mut packet);
+        } else {
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+        }
+        let result = self.write_all(&packet);
+        self.buf = packet;
+        result
     }
 
-    /// Receive a packet
+    /// Receive a packet, joining continuation frames if chunking
     fn recv_packet(&mut self) -> Result<Vec<u8>> {
-        // Read length (2 bytes, big-endian)
-        let mut len_buf = [0u8; 2];
-        self.read_exact(&mut len_buf)?;
-        let len = u16::from_be_bytes(len_buf) as usize;
+        let mut data = Vec::new();
+        let mut frame_start = true;
+        loop {
+            // Read length (2 bytes, big-endian)
+            let mut len_buf = [0u8; 2];
+            self.read_exact(&mut len_buf, frame_start)?;
+            frame_start = false;
+            let len = u16::from_be_bytes(len_buf) as usize;
 
-        if len == 0 {
-            return Err(SearpcError::TransportError(
+            // Read data
+            let start = data.len();
+            transport::check_response_len(start + len, self.max_response_size)?;
+            data.resize(start + len, 0);
+            if start == 0 {
+                let (head, rest) = data.split_at_mut(Framing::U16.peek_len(&len_buf, len));
+                self.read_exact(head, false)?;
+                Framing::U16.check_start(&len_buf, head)?;
+                self.read_exact(rest, false)?;
+            } else {
+                // Continuation frames carry the middle of the message
+                self.read_exact(&mut data[start..], false)?;
+            }
+            if !self.chunked || len < MAX_FRAME_SIZE {
+                break;
+            }
+        }
+
+        if data.is_empty() {
+            return Err(SearpcError::transport(
                 "Received packet with zero length".to_string(),
             ));
         }
-
-        // Read data
-        let mut data = vec![0u8; len];
-        self.read_exact(&mut data)?;
-
         Ok(data)
     }
 }
 
 impl Transport for TcpTransport {
     fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.stream.get_mut().start();
         self.send_packet(request)?;
         self.recv_packet()
     }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.socket().peer_addr().ok().map(ConnectionAddr::Tcp)
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.socket().local_addr().ok().map(ConnectionAddr::Tcp)
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        Some(self.connected.elapsed())
+    }
+
+    /// Bounds each call as a whole: the socket's timeouts are set to the
+    /// time left before each read and write
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.stream
+            .get_mut()
+            .set_timeout(timeout)
+            .map_err(|e| SearpcError::transport_io("Setting timeout", e))
+    }
+}
+
+/// With a timeout, each request and each response gets all of it
+impl PipelinedTransport for TcpTransport {
+    fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.stream.get_mut().start();
+        self.send_packet(request)
+    }
+
+    fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.stream.get_mut().start();
+        self.recv_packet()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut transport = TcpTransport::connect_timeout(addr, Duration::from_secs(5)).unwrap();
+        let _peer = listener.accept().unwrap();
+
+        // The peer never answers
+        transport
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        transport
+            .set_write_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let err = transport.send(br#"["get_version"]"#).unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+        assert!(!err.is_connection_closed());
+
+        drop(listener);
+        assert!(TcpTransport::connect_timeout(addr, Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn test_connection_metadata() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let transport = TcpTransport::connect(addr).unwrap();
+        let (_peer, client_addr) = listener.accept().unwrap();
+
+        assert_eq!(transport.peer_addr(), Some(ConnectionAddr::Tcp(addr)));
+        assert_eq!(
+            transport.local_addr(),
+            Some(ConnectionAddr::Tcp(client_addr))
+        );
+        let age = transport.connection_age().unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(transport.connection_age().unwrap() > age);
+    }
+
+    /// Responses straddling the read buffer, two of them in one write
+    #[test]
+    fn test_buffered_reads() {
+        use std::io::{Read, Write};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut transport = TcpTransport::with_capacity(stream, 16);
+        let (mut peer, _) = listener.accept().unwrap();
+
+        let responses = [
+            format!(r#"{{"ret":"{}"}}"#, "x".repeat(100)),
+            r#"{"ret":1}"#.to_string(),
+        ];
+        let mut frames = Vec::new();
+        for response in &responses {
+            frames.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            frames.extend_from_slice(response.as_bytes());
+        }
+        peer.write_all(&frames).unwrap();
+        for response in &responses {
+            assert_eq!(transport.send(br#"["f"]"#).unwrap(), response.as_bytes());
+        }
+        let mut requests = [0u8; 2 * 7];
+        peer.read_exact(&mut requests).unwrap();
+        assert_eq!(&requests[..7], b"\x00\x05[\"f\"]");
+    }
+
+    /// The limit applies to the joined message, not to each frame
+    #[test]
+    fn test_max_response_size() {
+        use std::io::Write;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut transport = TcpTransport::new(stream)
+            .with_chunking(true)
+            .with_max_response_size(MAX_FRAME_SIZE + 10);
+        let (mut peer, _) = listener.accept().unwrap();
+
+        peer.write_all(&(MAX_FRAME_SIZE as u16).to_be_bytes())
+            .unwrap();
+        peer.write_all(&vec![b' '; MAX_FRAME_SIZE]).unwrap();
+        peer.write_all(&11u16.to_be_bytes()).unwrap();
+        let err = transport.send(br#"["f"]"#).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+    }
+
+    /// Only the first frame starts like JSON
+    #[test]
+    fn test_continuation_frame() {
+        use std::io::Write;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut transport = TcpTransport::new(stream).with_chunking(true);
+        let (mut peer, _) = listener.accept().unwrap();
+
+        // A 256-byte continuation frame starting `},{"`, which with its
+        // header would look like a 32-bit frame
+        let first = format!(r#"{{"ret":[{{"k":"{}""#, "x".repeat(MAX_FRAME_SIZE - 15));
+        let response = format!(r#"{}}},{{"k":"{}"}}]}}"#, first, "y".repeat(244));
+        assert_eq!(response.len(), MAX_FRAME_SIZE + 256);
+        let mut frames = Vec::new();
+        transport::encode_chunked(response.as_bytes(), &mut frames);
+        peer.write_all(&frames).unwrap();
+        assert_eq!(transport.send(br#"["f"]"#).unwrap(), response.as_bytes());
+    }
+
     #[test]
     fn test_packet_encoding() {
         // Test that packet length is encoded as big-endian
@@ -0,0 +1,394 @@
+//! Conformance checks for transport implementations
+//!
+//! The harness drives a transport against a scripted peer. For each check
+//! it calls the factory with a [`Peer`]; the factory must return a
+//! transport whose other end decodes each request frame, passes the request
+//! bytes to the peer and carries out its [`PeerReply`]. For the built-in
+//! transports that is a loopback server thread speaking the same framing.
+
+use crate::error::SearpcError;
+use crate::transport::Transport;
+use std::fmt;
+use std::sync::Arc;
+
+/// What the far end does with one request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerReply {
+    /// Send these bytes back as one response frame
+    Respond(Vec<u8>),
+    /// Hang up without answering
+    Close,
+}
+
+/// Scripted far end of a transport under test
+pub type Peer = Arc<dyn Fn(&[u8]) -> PeerReply + Send + Sync>;
+
+/// Limits of the transport under test
+#[derive(Debug, Clone)]
+pub struct ConformanceOptions {
+    /// Largest request and response payload the transport must carry
+    pub max_payload: usize,
+}
+
+impl Default for ConformanceOptions {
+    fn default() -> Self {
+        ConformanceOptions {
+            max_payload: 1 << 20,
+        }
+    }
+}
+
+/// Outcome a check expects for its requests
+#[derive(Debug)]
+enum Expect {
+    /// One response per request, in order
+    Responses(Vec<Vec<u8>>),
+    /// The last request fails with [`SearpcError::ConnectionClosed`]
+    ConnectionClosed,
+    /// The last request fails with any error
+    Error,
+}
+
+/// One scenario: a peer, the requests to send and the expected outcome
+struct Check {
+    name: &'static str,
+    peer: Peer,
+    requests: Vec<Vec<u8>>,
+    expect: Expect,
+}
+
+impl fmt::Debug for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Check").field("name", &self.name).finish()
+    }
+}
+
+fn echo() -> Peer {
+    Arc::new(|request: &[u8]| PeerReply::Respond(request.to_vec()))
+}
+
+/// A `["echo", "xxx..."]` request of exactly `len` bytes (at least 12)
+fn padded_request(len: usize) -> Vec<u8> {
+    let mut request = b"[\"echo\",\"".to_vec();
+    request.resize(len.saturating_sub(2), b'x');
+    request.extend_from_slice(b"\"]");
+    request
+}
+
+fn checks(options: &ConformanceOptions) -> Vec<Check> {
+    let large = padded_request(options.max_payload);
+    vec![
+        Check {
+            name: "round trip",
+            peer: echo(),
+            requests: vec![br#"["searpc_strlen","hello"]"#.to_vec()],
+            expect: Expect::Responses(vec![br#"["searpc_strlen","hello"]"#.to_vec()]),
+        },
+        Check {
+            name: "several requests on one connection",
+            peer: Arc::new(|request: &[u8]| {
+                PeerReply::Respond(format!(r#"{{"ret":{}}}"#, request.len()).into_bytes())
+            }),
+            requests: vec![
+                b"[\"a\"]".to_vec(),
+                b"[\"bb\"]".to_vec(),
+                b"[\"ccc\"]".to_vec(),
+            ],
+            expect: Expect::Responses(vec![
+                br#"{"ret":5}"#.to_vec(),
+                br#"{"ret":6}"#.to_vec(),
+                br#"{"ret":7}"#.to_vec(),
+            ]),
+        },
+        Check {
+            name: "non-ASCII payload",
+            peer: echo(),
+            requests: vec!["[\"echo\",\"héllo wörld ✓ 文件\"]".as_bytes().to_vec()],
+            expect: Expect::Responses(vec!["[\"echo\",\"héllo wörld ✓ 文件\"]"
+                .as_bytes()
+                .to_vec()]),
+        },
+        Check {
+            name: "large payload",
+            peer: echo(),
+            requests: vec![large.clone()],
+            expect: Expect::Responses(vec![large]),
+        },
+        Check {
+            name: "response larger than request",
+            peer: {
+                let len = options.max_payload;
+                Arc::new(move |_: &[u8]| PeerReply::Respond(padded_request(len)))
+            },
+            requests: vec![b"[\"big\"]".to_vec()],
+            expect: Expect::Responses(vec![padded_request(options.max_payload)]),
+        },
+        Check {
+            name: "zero-length response",
+            peer: Arc::new(|_: &[u8]| PeerReply::Respond(Vec::new())),
+            requests: vec![b"[\"empty\"]".to_vec()],
+            expect: Expect::Error,
+        },
+        Check {
+            name: "peer hangs up before answering",
+            peer: Arc::new(|_: &[u8]| PeerReply::Close),
+            requests: vec![b"[\"hangup\"]".to_vec()],
+            expect: Expect::ConnectionClosed,
+        },
+        Check {
+            name: "peer hangs up after answering",
+            peer: {
+                let answered = std::sync::atomic::AtomicBool::new(false);
+                Arc::new(move |request: &[u8]| {
+                    if answered.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                        PeerReply::Close
+                    } else {
+                        PeerReply::Respond(request.to_vec())
+                    }
+                })
+            },
+            requests: vec![b"[\"first\"]".to_vec(), b"[\"second\"]".to_vec()],
+            expect: Expect::ConnectionClosed,
+        },
+    ]
+}
+
+impl Check {
+    /// Panic unless `results` (one per request sent) meet the expectation
+    fn assert(&self, results: Vec<crate::Result<Vec<u8>>>) {
+        let name = self.name;
+        match &self.expect {
+            Expect::Responses(expected) => {
+                assert_eq!(
+                    results.len(),
+                    expected.len(),
+                    "transport conformance ({name}): wrong number of results"
+                );
+                for (i, (result, expected)) in results.into_iter().zip(expected).enumerate() {
+                    match result {
+                        Ok(response) => assert!(
+                            &response == expected,
+                            "transport conformance ({name}): response {i} differs: \
+                             got {} bytes, expected {} bytes",
+                            response.len(),
+                            expected.len()
+                        ),
+                        Err(e) => panic!("transport conformance ({name}): request {i} failed: {e}"),
+                    }
+                }
+            }
+            Expect::ConnectionClosed | Expect::Error => {
+                let (last, earlier) = results.split_last().expect("at least one request");
+                for (i, result) in earlier.iter().enumerate() {
+                    if let Err(e) = result {
+                        panic!("transport conformance ({name}): request {i} failed: {e}");
+                    }
+                }
+                match last {
+                    Ok(response) => panic!(
+                        "transport conformance ({name}): expected an error, got {} bytes",
+                        response.len()
+                    ),
+                    Err(e) if matches!(self.expect, Expect::ConnectionClosed) => assert!(
+                        matches!(e, SearpcError::ConnectionClosed { .. }),
+                        "transport conformance ({name}): expected ConnectionClosed, got {e:?}"
+                    ),
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Run the conformance checks against a transport, with default options
+///
+/// `make_transport` is called once per check with the peer the transport
+/// must be connected to. Panics with the name of the first failing check.
+///
+/// ```rust,no_run
+/// use searpc::test_util::{assert_transport_conformance, Peer};
+/// # fn spawn_loopback_server(peer: Peer) -> searpc::TcpTransport { unimplemented!() }
+///
+/// assert_transport_conformance(|peer: Peer| spawn_loopback_server(peer));
+/// ```
+pub fn assert_transport_conformance<T, F>(make_transport: F)
+where
+    T: Transport,
+    F: FnMut(Peer) -> T,
+{
+    assert_transport_conformance_with(&ConformanceOptions::default(), make_transport)
+}
+
+/// Run the conformance checks against a transport
+pub fn assert_transport_conformance_with<T, F>(options: &ConformanceOptions, mut make_transport: F)
+where
+    T: Transport,
+    F: FnMut(Peer) -> T,
+{
+    for check in checks(options) {
+        let mut transport = make_transport(check.peer.clone());
+        let results = send_all(&check.requests, |request| transport.send(request));
+        check.assert(results);
+    }
+}
+
+/// Send requests in order, stopping after the first error
+fn send_all(
+    requests: &[Vec<u8>],
+    mut send: impl FnMut(&[u8]) -> crate::Result<Vec<u8>>,
+) -> Vec<crate::Result<Vec<u8>>> {
+    let mut results = Vec::new();
+    for request in requests {
+        let result = send(request);
+        let failed = result.is_err();
+        results.push(result);
+        if failed {
+            break;
+        }
+    }
+    results
+}
+
+/// Async counterpart of [`assert_transport_conformance`]
+#[cfg(feature = "async-core")]
+pub async fn assert_async_transport_conformance<T, F, Fut>(make_transport: F)
+where
+    T: crate::AsyncTransport,
+    F: FnMut(Peer) -> Fut,
+    Fut: std::future::Future<Output = T>,
+{
+    assert_async_transport_conformance_with(&ConformanceOptions::default(), make_transport).await
+}
+
+/// Async counterpart of [`assert_transport_conformance_with`]
+#[cfg(feature = "async-core")]
+pub async fn assert_async_transport_conformance_with<T, F, Fut>(
+    options: &ConformanceOptions,
+    mut make_transport: F,
+) where
+    T: crate::AsyncTransport,
+    F: FnMut(Peer) -> Fut,
+    Fut: std::future::Future<Output = T>,
+{
+    for check in checks(options) {
+        let mut transport = make_transport(check.peer.clone()).await;
+        let mut results = Vec::new();
+        for request in &check.requests {
+            let result = transport.send(request).await;
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                break;
+            }
+        }
+        check.assert(results);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    /// Serve `peer` on one connection using the 16-bit TCP framing
+    fn serve_tcp(mut stream: TcpStream, peer: Peer) {
+        loop {
+            let mut len = [0u8; 2];
+            if stream.read_exact(&mut len).is_err() {
+                return;
+            }
+            let mut request = vec![0u8; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut request).unwrap();
+            match peer(&request) {
+                PeerReply::Respond(response) => {
+                    stream
+                        .write_all(&(response.len() as u16).to_be_bytes())
+                        .unwrap();
+                    stream.write_all(&response).unwrap();
+                }
+                PeerReply::Close => return,
+            }
+        }
+    }
+
+    fn spawn_tcp_peer(peer: Peer) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_tcp(stream, peer);
+        });
+        addr
+    }
+
+    fn tcp_options() -> ConformanceOptions {
+        ConformanceOptions {
+            max_payload: u16::MAX as usize,
+        }
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        assert_transport_conformance_with(&tcp_options(), |peer| {
+            crate::TcpTransport::connect(spawn_tcp_peer(peer)).unwrap()
+        });
+    }
+
+    #[cfg(feature = "rt-tokio")]
+    #[tokio::test]
+    async fn test_async_tcp_transport() {
+        assert_async_transport_conformance_with(&tcp_options(), |peer| async move {
+            crate::AsyncTcpTransport::connect(spawn_tcp_peer(peer))
+                .await
+                .unwrap()
+        })
+        .await;
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_transport() {
+        use std::os::unix::net::UnixStream;
+
+        assert_transport_conformance(|peer| {
+            let (client, mut stream) = UnixStream::pair().unwrap();
+            thread::spawn(move || loop {
+                let mut len = [0u8; 4];
+                if stream.read_exact(&mut len).is_err() {
+                    return;
+                }
+                let mut packet = vec![0u8; u32::from_ne_bytes(len) as usize];
+                stream.read_exact(&mut packet).unwrap();
+                let envelope: serde_json::Value = serde_json::from_slice(&packet).unwrap();
+                let request = envelope["request"].as_str().unwrap();
+                match peer(request.as_bytes()) {
+                    PeerReply::Respond(response) => {
+                        stream
+                            .write_all(&(response.len() as u32).to_ne_bytes())
+                            .unwrap();
+                        stream.write_all(&response).unwrap();
+                    }
+                    PeerReply::Close => return,
+                }
+            });
+            crate::UnixSocketTransport::new(client, "test")
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "transport conformance (zero-length response)")]
+    fn test_detects_bad_transport() {
+        // Calls the peer directly, so an empty response comes back as Ok
+        assert_transport_conformance(|peer| {
+            move |request: &[u8]| match peer(request) {
+                PeerReply::Respond(response) => Ok(response),
+                PeerReply::Close => Err(SearpcError::ConnectionClosed {
+                    request_sent: true,
+                    mid_frame: false,
+                }),
+            }
+        });
+    }
+}
@@ -0,0 +1,106 @@
+//! In-process transport to a [`SearpcServer`]
+
+use crate::error::Result;
+use crate::server::SearpcServer;
+use crate::transport::Transport;
+use std::sync::Arc;
+
+/// Transport handing each request straight to an in-process server
+///
+/// Requests and responses still go through their serialized form, so a
+/// client and its server are tested end to end, but with no sockets,
+/// threads or framing: handlers run on the calling thread, in order.
+///
+/// ```rust
+/// use searpc::server::{arg, SearpcServer};
+/// use searpc::test_util::LoopbackTransport;
+/// use searpc::{Arg, SearpcClient};
+/// use serde_json::json;
+///
+/// let mut server = SearpcServer::new();
+/// server.register("searpc_strlen", |args| {
+///     let s: String = arg(args, 0)?;
+///     Ok(json!(s.len()))
+/// });
+///
+/// let mut client = SearpcClient::new(LoopbackTransport::new(server));
+/// assert_eq!(client.call_int("searpc_strlen", vec![Arg::string("hello")]).unwrap(), 5);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoopbackTransport {
+    server: Arc<SearpcServer>,
+}
+
+impl LoopbackTransport {
+    /// Send requests to `server`, which may be shared with other transports
+    pub fn new(server: impl Into<Arc<SearpcServer>>) -> Self {
+        LoopbackTransport {
+            server: server.into(),
+        }
+    }
+
+    /// The server requests go to
+    pub fn server(&self) -> &Arc<SearpcServer> {
+        &self.server
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.server.handle_request(request))
+    }
+}
+
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl crate::async_transport::AsyncTransport for LoopbackTransport {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.server.handle_request(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{Arg, SearpcClient};
+    use serde_json::json;
+
+    #[test]
+    fn test_loopback() {
+        let mut server = SearpcServer::new();
+        server.register("searpc_strlen", |args| {
+            let s: String = arg(args, 0)?;
+            Ok(json!(s.len()))
+        });
+        let transport = LoopbackTransport::new(server);
+        let server = Arc::clone(transport.server());
+
+        let mut client = SearpcClient::new(transport);
+        assert_eq!(
+            client
+                .call_int("searpc_strlen", vec![Arg::string("hello")])
+                .unwrap(),
+            5
+        );
+        let err = client.call_int("missing", vec![]).unwrap_err();
+        assert_eq!(err.err_code(), 500);
+
+        // Functions added later are visible to the client
+        server.insert_function("searpc_ping", |_| Ok(json!("pong")));
+        assert_eq!(client.call_string("searpc_ping", vec![]).unwrap(), "pong");
+    }
+
+    #[cfg(feature = "async-core")]
+    #[tokio::test]
+    async fn test_async_loopback() {
+        let mut server = SearpcServer::new();
+        server.register("searpc_ping", |_| Ok(json!("pong")));
+        let mut client = crate::AsyncSearpcClient::new(LoopbackTransport::new(server));
+        assert_eq!(
+            client.call_string("searpc_ping", vec![]).await.unwrap(),
+            "pong"
+        );
+    }
+}
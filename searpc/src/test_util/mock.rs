@@ -0,0 +1,263 @@
+//! Scripted transport with per-call expectations
+
+use crate::error::{Result, SearpcError};
+use crate::protocol::RpcResponse;
+use crate::transport::Transport;
+use crate::types::Arg;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// One expected call and the response to give
+#[derive(Debug)]
+struct Expectation {
+    function: String,
+    /// Expected arguments; `None` accepts any
+    args: Option<Vec<Value>>,
+    /// Response bytes, or the transport error to fail with
+    response: Result<Vec<u8>>,
+}
+
+/// Scripted transport: serves canned responses to expected calls, in order
+///
+/// Each request is checked against the next expectation and the test panics
+/// on a mismatch or an unexpected call. Clones share the same script, so a
+/// test can hand one clone to the client and keep another to call
+/// [`verify`](MockTransport::verify). Dropping the last clone with
+/// expectations left also panics.
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    expectations: Arc<Mutex<VecDeque<Expectation>>>,
+}
+
+/// Builder for an expectation, finished by one of the `returns*` methods
+#[must_use = "an expectation is only registered once a response is set"]
+pub struct ExpectCall<'a> {
+    mock: &'a MockTransport,
+    function: String,
+    args: Option<Vec<Value>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expect a call to `function` next (after previously expected calls)
+    pub fn expect(&self, function: impl Into<String>) -> ExpectCall<'_> {
+        ExpectCall {
+            mock: self,
+            function: function.into(),
+            args: None,
+        }
+    }
+
+    /// Number of expected calls not made yet
+    pub fn remaining(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Panic if any expected call has not been made
+    pub fn verify(&self) {
+        let expectations = self.lock();
+        if !expectations.is_empty() {
+            let pending: Vec<&str> = expectations.iter().map(|e| e.function.as_str()).collect();
+            panic!("MockTransport: expected calls not made: {:?}", pending);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<Expectation>> {
+        // A failed assertion in another clone must not hide the original panic
+        self.expectations
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn push(&self, expectation: Expectation) {
+        self.lock().push_back(expectation);
+    }
+
+    /// Check `request` against the next expectation and return its response
+    fn respond(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let call: Vec<Value> = match serde_json::from_slice(request) {
+            Ok(Value::Array(call)) if matches!(call.first(), Some(Value::String(_))) => call,
+            _ => panic!(
+                "MockTransport: malformed request: {}",
+                String::from_utf8_lossy(request)
+            ),
+        };
+        let function = call[0].as_str().unwrap_or_default();
+        let args = &call[1..];
+
+        let Some(expected) = self.lock().pop_front() else {
+            panic!("MockTransport: unexpected call {}({:?})", function, args);
+        };
+        if expected.function != function {
+            panic!(
+                "MockTransport: expected call to {}, got {}({:?})",
+                expected.function, function, args
+            );
+        }
+        if let Some(expected_args) = &expected.args {
+            if expected_args.as_slice() != args {
+                panic!(
+                    "MockTransport: {} called with {:?}, expected {:?}",
+                    function, args, expected_args
+                );
+            }
+        }
+        expected.response
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.respond(request)
+    }
+}
+
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl crate::async_transport::AsyncTransport for MockTransport {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.respond(request)
+    }
+}
+
+impl Drop for MockTransport {
+    fn drop(&mut self) {
+        // Only the last clone checks, and never while already unwinding
+        if Arc::strong_count(&self.expectations) == 1 && !std::thread::panicking() {
+            self.verify();
+        }
+    }
+}
+
+impl ExpectCall<'_> {
+    /// Require exactly these arguments (default: any arguments)
+    pub fn with_args(mut self, args: Vec<Arg>) -> Self {
+        let args = args
+            .iter()
+            .map(|arg| serde_json::to_value(arg).expect("Arg always serializes"))
+            .collect();
+        self.args = Some(args);
+        self
+    }
+
+    /// Answer with `{"ret": value}`
+    pub fn returns(self, value: Value) {
+        self.returns_response(&RpcResponse::ok(value));
+    }
+
+    /// Answer with an RPC error
+    pub fn returns_error(self, code: i32, message: impl Into<String>) {
+        self.returns_response(&RpcResponse::error(code, message));
+    }
+
+    /// Answer with an arbitrary response
+    pub fn returns_response(self, response: &RpcResponse) {
+        let bytes = serde_json::to_vec(response).expect("RpcResponse always serializes");
+        self.returns_raw(bytes);
+    }
+
+    /// Answer with raw bytes, e.g. to test malformed responses
+    pub fn returns_raw(self, response: impl Into<Vec<u8>>) {
+        self.mock.push(Expectation {
+            function: self.function,
+            args: self.args,
+            response: Ok(response.into()),
+        });
+    }
+
+    /// Fail the call with `error` instead of answering, to simulate a
+    /// broken connection or other transport failure
+    pub fn fails_with(self, error: SearpcError) {
+        self.mock.push(Expectation {
+            function: self.function,
+            args: self.args,
+            response: Err(error),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SearpcClient, SearpcError};
+    use serde_json::json;
+
+    #[test]
+    fn test_scripted_calls() {
+        let mock = MockTransport::new();
+        mock.expect("get_version").returns(json!("1.0"));
+        mock.expect("strlen")
+            .with_args(vec![Arg::string("hello")])
+            .returns(json!(5));
+
+        let mut client = SearpcClient::new(mock.clone());
+        assert_eq!(client.call_string("get_version", vec![]).unwrap(), "1.0");
+        assert_eq!(client.call_int("strlen", vec!["hello".into()]).unwrap(), 5);
+        mock.verify();
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mock = MockTransport::new();
+        mock.expect("remove_repo")
+            .returns_error(501, "Repo not exists");
+
+        let mut client = SearpcClient::new(mock.clone());
+        let err = client.call_int("remove_repo", vec![]).unwrap_err();
+        assert!(matches!(
+            err.inner(),
+            SearpcError::RpcError { code: 501, .. }
+        ));
+    }
+
+    #[test]
+    fn test_transport_failure() {
+        let mock = MockTransport::new();
+        mock.expect("get_version")
+            .fails_with(SearpcError::ConnectionClosed {
+                request_sent: true,
+                mid_frame: false,
+            });
+        mock.expect("get_version").returns(json!("1.0"));
+
+        let mut client = SearpcClient::new(mock.clone());
+        let err = client.call_string("get_version", vec![]).unwrap_err();
+        assert!(err.is_connection_closed());
+        assert_eq!(client.call_string("get_version", vec![]).unwrap(), "1.0");
+        mock.verify();
+    }
+
+    #[test]
+    #[should_panic(expected = "expected call to get_version, got strlen")]
+    fn test_wrong_function() {
+        let mock = MockTransport::new();
+        mock.expect("get_version").returns(json!("1.0"));
+
+        let mut client = SearpcClient::new(mock.clone());
+        let _ = client.call_int("strlen", vec![]);
+    }
+
+    #[test]
+    #[should_panic(expected = "strlen called with")]
+    fn test_wrong_args() {
+        let mock = MockTransport::new();
+        mock.expect("strlen")
+            .with_args(vec![Arg::string("hello")])
+            .returns(json!(5));
+
+        let mut client = SearpcClient::new(mock.clone());
+        let _ = client.call_int("strlen", vec!["bye".into()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected calls not made: [\"get_version\"]")]
+    fn test_unconsumed_on_drop() {
+        let mock = MockTransport::new();
+        mock.expect("get_version").returns(json!("1.0"));
+    }
+}
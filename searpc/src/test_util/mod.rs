@@ -0,0 +1,55 @@
+//! Test helpers for code built on searpc
+//!
+//! Enabled with the `test-util` feature. [`MockTransport`] replaces the
+//! hand-written closure transports downstream tests tend to grow:
+//!
+//! ```rust
+//! use searpc::test_util::MockTransport;
+//! use searpc::{Arg, SearpcClient};
+//! use serde_json::json;
+//!
+//! let mock = MockTransport::new();
+//! mock.expect("seafile_get_repo")
+//!     .with_args(vec![Arg::string("repo-id")])
+//!     .returns(json!({"id": "repo-id", "name": "Docs"}));
+//! mock.expect("seafile_remove_repo")
+//!     .returns_error(501, "Repo not exists");
+//!
+//! let mut client = SearpcClient::new(mock.clone());
+//! let repo = client.call_object("seafile_get_repo", vec![Arg::string("repo-id")]).unwrap();
+//! assert_eq!(repo["name"], "Docs");
+//! assert!(client.call_int("seafile_remove_repo", vec![Arg::string("x")]).is_err());
+//!
+//! mock.verify();
+//! ```
+//!
+//! [`ExpectCall::fails_with`] makes a call fail with a transport error
+//! instead, to test how code copes with a daemon going away.
+//!
+//! [`ReplayTransport`] serves responses from a recorded session instead, for
+//! deterministic integration tests against captured daemon traffic.
+//!
+//! [`LoopbackTransport`] connects a client to an in-process
+//! [`SearpcServer`](crate::SearpcServer), for fast integration tests of
+//! both sides without sockets.
+//!
+//! [`assert_transport_conformance`] checks a transport implementation:
+//! framing round trips, large payloads, zero-length frames and hang-ups.
+
+mod conformance;
+mod loopback;
+mod mock;
+mod replay;
+
+#[cfg(feature = "async-core")]
+pub use conformance::{
+    assert_async_transport_conformance, assert_async_transport_conformance_with,
+};
+pub use conformance::{
+    assert_transport_conformance, assert_transport_conformance_with, ConformanceOptions, Peer,
+    PeerReply,
+};
+
+pub use loopback::LoopbackTransport;
+pub use mock::{ExpectCall, MockTransport};
+pub use replay::{load_fixtures, Exchange, ReplayTransport};
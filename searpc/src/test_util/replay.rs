@@ -0,0 +1,325 @@
+//! Replay of recorded sessions (golden files)
+//!
+//! A fixture file is JSON Lines, one exchange per line:
+//!
+//! ```text
+//! {"request":"[\"seafile_get_repo\",\"repo-id\"]","response":"{\"ret\":{\"id\":\"repo-id\"}}"}
+//! ```
+//!
+//! Frames are stored as the exact strings sent over the wire, after the
+//! client serialized them and before any transport framing.
+//! [`Recording`](crate::Recording) captures sessions in this format.
+
+pub use crate::recording::Exchange;
+
+use crate::error::{Result, SearpcError};
+use crate::transport::Transport;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Load the exchanges of a fixture file
+pub fn load_fixtures(path: impl AsRef<Path>) -> Result<Vec<Exchange>> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)?;
+    parse_fixtures(&content)
+        .map_err(|e| SearpcError::InvalidResponse(format!("{}: {}", path.display(), e)))
+}
+
+fn parse_fixtures(content: &str) -> std::result::Result<Vec<Exchange>, String> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| serde_json::from_str(line).map_err(|e| format!("line {}: {}", n + 1, e)))
+        .collect()
+}
+
+/// How one argument of a function is compared against the recording
+#[derive(Debug)]
+enum ArgRule {
+    Ignore,
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    function: String,
+    index: usize,
+    rule: ArgRule,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    exchanges: VecDeque<Exchange>,
+    rules: Vec<Rule>,
+}
+
+/// Transport answering from a recorded session, in order
+///
+/// Each request must match the next recorded one: same function, same
+/// number of arguments and equal arguments, except where relaxed by
+/// [`ignore_arg`](ReplayTransport::ignore_arg) or
+/// [`match_arg`](ReplayTransport::match_arg). The test panics on a mismatch
+/// or once the recording is exhausted.
+///
+/// Clones share the same recording, so a test can keep one to call
+/// [`verify`](ReplayTransport::verify) after handing another to the client.
+/// Unlike [`MockTransport`](super::MockTransport), leftover exchanges are
+/// only reported by `verify`, as tests often replay a prefix of a session.
+///
+/// ```rust,no_run
+/// use searpc::test_util::ReplayTransport;
+/// use searpc::SearpcClient;
+///
+/// let replay = ReplayTransport::load("tests/fixtures/clone.jsonl")?
+///     .ignore_arg("seafile_clone", 5)
+///     .match_arg("seafile_clone", 0, r"^[0-9a-f-]{36}$");
+/// let mut client = SearpcClient::new(replay.clone());
+/// // ... run the code under test ...
+/// replay.verify();
+/// # Ok::<(), searpc::SearpcError>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ReplayTransport {
+    state: Arc<Mutex<State>>,
+}
+
+impl ReplayTransport {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        ReplayTransport {
+            state: Arc::new(Mutex::new(State {
+                exchanges: exchanges.into(),
+                rules: Vec::new(),
+            })),
+        }
+    }
+
+    /// Replay a fixture file, see [`load_fixtures`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(load_fixtures(path)?))
+    }
+
+    /// Replay fixtures given inline in the JSON Lines format
+    pub fn from_jsonl(content: &str) -> Result<Self> {
+        let exchanges = parse_fixtures(content).map_err(SearpcError::InvalidResponse)?;
+        Ok(Self::new(exchanges))
+    }
+
+    /// Accept any value for argument `index` (0-based) of `function`
+    pub fn ignore_arg(self, function: impl Into<String>, index: usize) -> Self {
+        self.add_rule(function.into(), index, ArgRule::Ignore)
+    }
+
+    /// Accept any string matching `pattern` for argument `index` of `function`
+    ///
+    /// Panics if `pattern` is not a valid regex.
+    pub fn match_arg(self, function: impl Into<String>, index: usize, pattern: &str) -> Self {
+        let regex = Regex::new(pattern)
+            .unwrap_or_else(|e| panic!("ReplayTransport: bad pattern {:?}: {}", pattern, e));
+        self.add_rule(function.into(), index, ArgRule::Regex(regex))
+    }
+
+    /// Number of recorded exchanges not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.lock().exchanges.len()
+    }
+
+    /// Panic if part of the recording has not been replayed
+    pub fn verify(&self) {
+        let state = self.lock();
+        if let Some(next) = state.exchanges.front() {
+            panic!(
+                "ReplayTransport: {} recorded exchanges not replayed, next: {}",
+                state.exchanges.len(),
+                next.request
+            );
+        }
+    }
+
+    fn add_rule(self, function: String, index: usize, rule: ArgRule) -> Self {
+        self.lock().rules.push(Rule {
+            function,
+            index,
+            rule,
+        });
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn respond(&self, request: &[u8]) -> Vec<u8> {
+        let mut state = self.lock();
+        let request = String::from_utf8_lossy(request);
+        let Some(recorded) = state.exchanges.pop_front() else {
+            panic!("ReplayTransport: recording exhausted, got {}", request);
+        };
+        if let Err(reason) = state.matches(&recorded.request, &request) {
+            panic!(
+                "ReplayTransport: request does not match the recording ({})\n  recorded: {}\n  actual:   {}",
+                reason, recorded.request, request
+            );
+        }
+        recorded.response.into_bytes()
+    }
+}
+
+impl State {
+    fn rule(&self, function: &str, index: usize) -> Option<&ArgRule> {
+        self.rules
+            .iter()
+            .find(|r| r.function == function && r.index == index)
+            .map(|r| &r.rule)
+    }
+
+    /// Compare a request against the recorded one, honouring the rules
+    fn matches(&self, recorded: &str, actual: &str) -> std::result::Result<(), String> {
+        if recorded == actual {
+            return Ok(());
+        }
+        let recorded = parse_call(recorded).ok_or("recorded request is not a call")?;
+        let actual = parse_call(actual).ok_or("malformed request")?;
+        let function = recorded[0].as_str().unwrap_or_default();
+        if actual[0] != recorded[0] {
+            return Err("different function".to_string());
+        }
+        if actual.len() != recorded.len() {
+            return Err("different number of arguments".to_string());
+        }
+
+        for (index, (want, got)) in recorded[1..].iter().zip(&actual[1..]).enumerate() {
+            let ok = match self.rule(function, index) {
+                Some(ArgRule::Ignore) => true,
+                Some(ArgRule::Regex(regex)) => got.as_str().is_some_and(|s| regex.is_match(s)),
+                None => want == got,
+            };
+            if !ok {
+                return Err(format!("argument {} differs", index));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse `["function", args...]`
+fn parse_call(request: &str) -> Option<Vec<Value>> {
+    match serde_json::from_str(request) {
+        Ok(Value::Array(call)) if matches!(call.first(), Some(Value::String(_))) => Some(call),
+        _ => None,
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.respond(request))
+    }
+}
+
+#[cfg(feature = "async-core")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl crate::async_transport::AsyncTransport for ReplayTransport {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.respond(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arg, SearpcClient};
+
+    const SESSION: &str = r#"
+{"request":"[\"seafile_get_version\"]","response":"{\"ret\":\"9.0.0\"}"}
+{"request":"[\"seafile_clone\",\"2c0c3a1e-0d1f-4e57-9c5b-1f0f8d1d7a6e\",\"/tmp/a\",\"tok-1\"]","response":"{\"ret\":\"task-1\"}"}
+"#;
+
+    fn clone_args(repo_id: &str, token: &str) -> Vec<Arg> {
+        vec![
+            Arg::string(repo_id),
+            Arg::string("/tmp/a"),
+            Arg::string(token),
+        ]
+    }
+
+    #[test]
+    fn test_exact_replay() {
+        let replay = ReplayTransport::from_jsonl(SESSION).unwrap();
+        let mut client = SearpcClient::new(replay.clone());
+
+        assert_eq!(
+            client.call_string("seafile_get_version", vec![]).unwrap(),
+            "9.0.0"
+        );
+        let task = client
+            .call_string(
+                "seafile_clone",
+                clone_args("2c0c3a1e-0d1f-4e57-9c5b-1f0f8d1d7a6e", "tok-1"),
+            )
+            .unwrap();
+        assert_eq!(task, "task-1");
+        replay.verify();
+    }
+
+    #[test]
+    fn test_fuzzy_args() {
+        let replay = ReplayTransport::from_jsonl(SESSION)
+            .unwrap()
+            .match_arg("seafile_clone", 0, r"^[0-9a-f-]{36}$")
+            .ignore_arg("seafile_clone", 2);
+        let mut client = SearpcClient::new(replay.clone());
+
+        client.call_string("seafile_get_version", vec![]).unwrap();
+        let task = client
+            .call_string(
+                "seafile_clone",
+                clone_args("99999999-0000-4000-8000-000000000000", "tok-2"),
+            )
+            .unwrap();
+        assert_eq!(task, "task-1");
+    }
+
+    #[test]
+    #[should_panic(expected = "argument 2 differs")]
+    fn test_mismatch() {
+        let replay = ReplayTransport::from_jsonl(SESSION).unwrap();
+        let mut client = SearpcClient::new(replay);
+
+        client.call_string("seafile_get_version", vec![]).unwrap();
+        let _ = client.call_string(
+            "seafile_clone",
+            clone_args("2c0c3a1e-0d1f-4e57-9c5b-1f0f8d1d7a6e", "tok-2"),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "1 recorded exchanges not replayed")]
+    fn test_verify_leftovers() {
+        let replay = ReplayTransport::from_jsonl(SESSION).unwrap();
+        let mut client = SearpcClient::new(replay.clone());
+
+        client.call_string("seafile_get_version", vec![]).unwrap();
+        replay.verify();
+    }
+
+    #[test]
+    fn test_load_fixtures() {
+        let path =
+            std::env::temp_dir().join(format!("searpc-fixture-{}.jsonl", std::process::id()));
+        std::fs::write(&path, SESSION).unwrap();
+        let exchanges = load_fixtures(&path).unwrap();
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[0].response, r#"{"ret":"9.0.0"}"#);
+
+        std::fs::write(&path, "{\"request\":\"[]\"}\n").unwrap();
+        let err = load_fixtures(&path).unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,581 @@
+//! TLS-secured TCP transport (rustls)
+//!
+//! Carries searpc over TLS so it can cross untrusted networks without an
+//! external tunnel. By default packets use the TCP demo framing (16-bit
+//! big-endian header, as [`TcpTransport`](crate::TcpTransport));
+//! [`TlsTcpTransport::with_service`] switches to the Seafile framing
+//! (32-bit header and service envelope, as
+//! [`UnixSocketTransport`](crate::UnixSocketTransport)).
+//!
+//! ```rust,no_run
+//! use searpc::tls_transport::rustls::RootCertStore;
+//! use searpc::tls_transport::{client_config, TlsTcpTransport};
+//! use searpc::SearpcClient;
+//!
+//! let mut roots = RootCertStore::empty();
+//! # let ca_der = rustls::pki_types::CertificateDer::from(Vec::new());
+//! roots.add(ca_der)?;
+//! let config = client_config(roots)?;
+//! let transport = TlsTcpTransport::connect("rpc.example.com:12345", "rpc.example.com", config)?;
+//! let mut client = SearpcClient::new(transport);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! For mutual authentication, [`ClientConfigBuilder`] adds a client
+//! certificate, and can pin the server's certificate by its SHA-256
+//! fingerprint:
+//!
+//! ```rust,no_run
+//! use searpc::tls_transport::rustls::RootCertStore;
+//! use searpc::tls_transport::{ClientConfigBuilder, TlsTcpTransport};
+//! # let (cert_chain, key) = (Vec::new(), rustls::pki_types::PrivateKeyDer::Pkcs8(Vec::new().into()));
+//! # let fingerprint = [0u8; 32];
+//!
+//! // No roots: the pins alone decide, which suits self-signed servers
+//! let config = ClientConfigBuilder::new(RootCertStore::empty())
+//!     .with_client_cert(cert_chain, key)
+//!     .pin_certificate(fingerprint)
+//!     .build()?;
+//! let transport = TlsTcpTransport::connect("10.0.0.2:12345", "seafile", config)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::deadline::DeadlineStream;
+use crate::error::{Result, SearpcError};
+use crate::transport::{self, ConnectionAddr, Endianness, Framing, PipelinedTransport, Transport};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme, StreamOwned,
+};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use rustls;
+
+/// TLS client stream the transport runs over
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// TLS transport using the packet protocol
+pub struct TlsTcpTransport {
+    stream: StreamOwned<ClientConnection, DeadlineStream<TcpStream>>,
+    /// Seafile framing for this service, or the 16-bit demo framing if `None`
+    service: Option<String>,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+    connected: Instant,
+}
+
+/// Client configuration trusting `roots`, with rustls' `ring` provider
+///
+/// Certificates are verified as usual; see [`ClientConfigBuilder`] for
+/// client certificates and pinning.
+pub fn client_config(roots: RootCertStore) -> Result<Arc<ClientConfig>> {
+    ClientConfigBuilder::new(roots).build()
+}
+
+/// Client configuration with a client certificate or pinned server
+/// certificates, with rustls' `ring` provider
+pub struct ClientConfigBuilder {
+    roots: RootCertStore,
+    pins: Vec<[u8; 32]>,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl ClientConfigBuilder {
+    /// Verify server certificates against `roots`
+    pub fn new(roots: RootCertStore) -> Self {
+        ClientConfigBuilder {
+            roots,
+            pins: Vec::new(),
+            identity: None,
+        }
+    }
+
+    /// Present `cert_chain`, end-entity certificate first, to servers
+    /// asking for a client certificate
+    pub fn with_client_cert(
+        mut self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Self {
+        self.identity = Some((cert_chain, key));
+        self
+    }
+
+    /// Only accept a server certificate with this SHA-256 fingerprint of
+    /// its DER encoding, or one of the others pinned
+    ///
+    /// The certificate still has to verify against the roots, unless there
+    /// are none: then the pins alone decide, whatever the server name.
+    pub fn pin_certificate(mut self, sha256: [u8; 32]) -> Self {
+        self.pins.push(sha256);
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<ClientConfig>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?;
+        let builder = if self.pins.is_empty() {
+            builder.with_root_certificates(self.roots)
+        } else {
+            let webpki = if self.roots.is_empty() {
+                None
+            } else {
+                let verifier = WebPkiServerVerifier::builder_with_provider(
+                    Arc::new(self.roots),
+                    Arc::clone(&provider),
+                )
+                .build()
+                .map_err(|e| SearpcError::transport(format!("TLS: {}", e)))?;
+                Some(verifier)
+            };
+            let verifier = PinnedVerifier {
+                webpki,
+                pins: self.pins,
+                provider,
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+        };
+        let config = match self.identity {
+            Some((cert_chain, key)) => builder
+                .with_client_auth_cert(cert_chain, key)
+                .map_err(tls_error)?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// SHA-256 fingerprint of a DER certificate, as [`ClientConfigBuilder::pin_certificate`] takes
+pub fn fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(digest.as_ref());
+    fingerprint
+}
+
+/// Accepts pinned certificates, after the usual verification if there are
+/// roots
+#[derive(Debug)]
+struct PinnedVerifier {
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+        }
+        if !self.pins.contains(&fingerprint(end_entity)) {
+            return Err(CertificateError::ApplicationVerificationFailure.into());
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl TlsTcpTransport {
+    pub fn new(stream: TlsStream) -> Self {
+        let StreamOwned { conn, sock } = stream;
+        TlsTcpTransport {
+            stream: StreamOwned::new(conn, DeadlineStream::new(sock)),
+            service: None,
+            buf: Vec::new(),
+            connected: Instant::now(),
+        }
+    }
+
+    /// Connect to `addr` and start TLS, verifying the certificate against
+    /// `server_name` (also sent as SNI)
+    ///
+    /// The handshake completes with the first request.
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+    ) -> Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+            SearpcError::transport(format!("Invalid server name {:?}: {}", server_name, e))
+        })?;
+        let tcp = TcpStream::connect(addr).map_err(|e| SearpcError::transport_io("connect", e))?;
+        let conn = ClientConnection::new(config, server_name).map_err(tls_error)?;
+        Ok(Self::new(StreamOwned::new(conn, tcp)))
+    }
+
+    /// Use the Seafile framing, sending requests to `service`
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    /// The TLS session, e.g. for the certificates the server presented
+    pub fn connection(&self) -> &ClientConnection {
+        &self.stream.conn
+    }
+
+    /// The TCP connection the session runs over
+    pub fn tcp_stream(&self) -> &TcpStream {
+        self.stream.sock.get_ref()
+    }
+
+    /// Send a packet, header and body in a single write
+    fn send_packet(&mut self, data: &[u8]) -> Result<()> {
+        let mut packet = std::mem::take(&mut self.buf);
+        let service = self.service.as_deref();
+        let result = framing(service)
+            .encode(service, data, &mut packet)
+            .and_then(|()| transport::write_request(&mut self.stream, &packet));
+        self.buf = packet;
+        result
+    }
+
+    /// Receive a packet
+    fn recv_packet(&mut self) -> Result<Vec<u8>> {
+        let framing = framing(self.service.as_deref());
+        let mut header = [0u8; 4];
+        let header = &mut header[..framing.header_len()];
+        transport::read_response(&mut self.stream, header, true)?;
+        let len = framing.body_len(header)?;
+        let mut data = vec![0u8; len];
+        let (start, rest) = data.split_at_mut(framing.peek_len(header, len));
+        transport::read_response(&mut self.stream, start, false)?;
+        framing.check_start(header, start)?;
+        transport::read_response(&mut self.stream, rest, false)?;
+        Ok(data)
+    }
+}
+
+/// The Seafile framing if there is a `service`, the 16-bit demo framing
+/// otherwise
+pub(crate) fn framing(service: Option<&str>) -> Framing {
+    match service {
+        Some(_) => Framing::U32(Endianness::Native),
+        None => Framing::U16,
+    }
+}
+
+impl Transport for TlsTcpTransport {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.stream.sock.start();
+        self.send_packet(request)?;
+        self.recv_packet()
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.tcp_stream().peer_addr().ok().map(ConnectionAddr::Tcp)
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.tcp_stream().local_addr().ok().map(ConnectionAddr::Tcp)
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        Some(self.connected.elapsed())
+    }
+
+    /// Bounds each call as a whole, handshake included: the socket's
+    /// timeouts are set to the time left before each read and write
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.stream
+            .sock
+            .set_timeout(timeout)
+            .map_err(|e| SearpcError::transport_io("Setting timeout", e))
+    }
+}
+
+pub(crate) fn tls_error(e: rustls::Error) -> SearpcError {
+    SearpcError::transport(format!("TLS: {}", e))
+}
+
+/// With a timeout, each request and each response gets all of it
+impl PipelinedTransport for TlsTcpTransport {
+    fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.stream.sock.start();
+        self.send_packet(request)
+    }
+
+    fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.stream.sock.start();
+        self.recv_packet()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::server::{arg, SearpcServer};
+    use crate::SearpcClient;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{ServerConfig, ServerConnection};
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Self-signed certificate for `localhost` and a server config using it
+    pub(crate) fn test_certs() -> (CertificateDer<'static>, Arc<ServerConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key))
+            .unwrap();
+        (cert, Arc::new(config))
+    }
+
+    fn strlen_server() -> SearpcServer {
+        let mut server = SearpcServer::new();
+        server.register("searpc_strlen", |args| {
+            let s: String = arg(args, 0)?;
+            Ok(json!(s.len()))
+        });
+        server
+    }
+
+    /// Serve one connection over TLS, with the 32-bit framing if `seafile`
+    pub(crate) fn serve_one(listener: TcpListener, config: Arc<ServerConfig>, seafile: bool) {
+        let server = strlen_server();
+        let (tcp, _) = listener.accept().unwrap();
+        let mut stream = StreamOwned::new(ServerConnection::new(config).unwrap(), tcp);
+        loop {
+            let len = if seafile {
+                let mut len = [0u8; 4];
+                if stream.read_exact(&mut len).is_err() {
+                    return;
+                }
+                u32::from_ne_bytes(len) as usize
+            } else {
+                let mut len = [0u8; 2];
+                if stream.read_exact(&mut len).is_err() {
+                    return;
+                }
+                u16::from_be_bytes(len) as usize
+            };
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body).unwrap();
+            let request = if seafile {
+                let envelope: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(envelope["service"], "test-service");
+                envelope["request"].as_str().unwrap().as_bytes().to_vec()
+            } else {
+                body
+            };
+            let response = server.handle_request(&request);
+            if seafile {
+                stream
+                    .write_all(&(response.len() as u32).to_ne_bytes())
+                    .unwrap();
+            } else {
+                stream
+                    .write_all(&(response.len() as u16).to_be_bytes())
+                    .unwrap();
+            }
+            stream.write_all(&response).unwrap();
+            stream.flush().unwrap();
+        }
+    }
+
+    pub(crate) fn roots(cert: CertificateDer<'static>) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        roots
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for seafile in [false, true] {
+            let (cert, server_config) = test_certs();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = std::thread::spawn(move || serve_one(listener, server_config, seafile));
+
+            let config = client_config(roots(cert)).unwrap();
+            let mut transport = TlsTcpTransport::connect(addr, "localhost", config).unwrap();
+            if seafile {
+                transport = transport.with_service("test-service");
+            }
+            let mut client = SearpcClient::new(transport);
+            for s in ["hello", "searpc over tls"] {
+                let len = client
+                    .call_int("searpc_strlen", [crate::Arg::string(s)])
+                    .unwrap();
+                assert_eq!(len as usize, s.len());
+            }
+            drop(client);
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_untrusted_certificate() {
+        let (_, server_config) = test_certs();
+        let (other_cert, _) = test_certs();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let mut stream = StreamOwned::new(ServerConnection::new(server_config).unwrap(), tcp);
+            let _ = stream.read(&mut [0u8; 1]);
+        });
+
+        let config = client_config(roots(other_cert)).unwrap();
+        let mut transport = TlsTcpTransport::connect(addr, "localhost", config).unwrap();
+        let err = transport.send(br#"["searpc_strlen","a"]"#).unwrap_err();
+        assert!(err.to_string().contains("certificate"), "{}", err);
+
+        let config = client_config(RootCertStore::empty()).unwrap();
+        assert!(TlsTcpTransport::connect(addr, "not a name!", config).is_err());
+    }
+
+    /// Self-signed client certificate and its key
+    fn client_identity() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        (certified.cert.der().clone(), PrivateKeyDer::Pkcs8(key))
+    }
+
+    /// Server config requiring a client certificate issued by `client_ca`
+    fn mutual_auth_certs(
+        client_ca: CertificateDer<'static>,
+    ) -> (CertificateDer<'static>, Arc<ServerConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots(client_ca)),
+            Arc::clone(&provider),
+        )
+        .build()
+        .unwrap();
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key))
+            .unwrap();
+        (cert, Arc::new(config))
+    }
+
+    /// Send one request with `config` to a server using `server_config`
+    fn call_once(
+        config: Arc<ClientConfig>,
+        server_name: &str,
+        server_config: Arc<ServerConfig>,
+    ) -> Result<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || serve_one(listener, server_config, false));
+        let mut transport = TlsTcpTransport::connect(addr, server_name, config)?;
+        let result = transport.send(br#"["searpc_strlen","abc"]"#);
+        drop(transport);
+        server.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        let (client_cert, client_key) = client_identity();
+        let (server_cert, server_config) = mutual_auth_certs(client_cert.clone());
+        let config = ClientConfigBuilder::new(roots(server_cert.clone()))
+            .with_client_cert(vec![client_cert], client_key)
+            .build()
+            .unwrap();
+        assert_eq!(
+            call_once(config, "localhost", Arc::clone(&server_config)).unwrap(),
+            br#"{"ret":3}"#
+        );
+
+        let config = client_config(roots(server_cert)).unwrap();
+        assert!(call_once(config, "localhost", server_config).is_err());
+    }
+
+    #[test]
+    fn test_pinned_certificate() {
+        let (server_cert, server_config) = test_certs();
+        let (other_cert, _) = test_certs();
+        let pinned = |roots: RootCertStore, cert: &CertificateDer<'_>| {
+            ClientConfigBuilder::new(roots)
+                .pin_certificate(fingerprint(cert))
+                .build()
+                .unwrap()
+        };
+        let check =
+            |config, server_name| call_once(config, server_name, Arc::clone(&server_config));
+
+        // Without roots, the pin alone decides
+        let config = pinned(RootCertStore::empty(), &server_cert);
+        assert!(check(config, "seafile").is_ok());
+        let config = pinned(RootCertStore::empty(), &other_cert);
+        let err = check(config, "localhost").unwrap_err();
+        assert!(err.to_string().contains("certificate"), "{}", err);
+
+        // With roots, the certificate has to verify too
+        let config = pinned(roots(server_cert.clone()), &server_cert);
+        assert!(check(config, "localhost").is_ok());
+        let config = pinned(roots(server_cert.clone()), &server_cert);
+        assert!(check(config, "seafile").is_err());
+        let config = pinned(roots(server_cert), &other_cert);
+        assert!(check(config, "localhost").is_err());
+    }
+}
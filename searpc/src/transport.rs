@@ -1,4 +1,11 @@
-use crate::error::Result;
+use crate::error::{Result, SearpcError};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// Transport callback trait
 ///
@@ -16,6 +23,77 @@ pub trait Transport {
     /// * `Ok(Vec<u8>)` - Response bytes
     /// * `Err(SearpcError)` - Transport error
     fn send(&mut self, request: &[u8]) -> Result<Vec<u8>>;
+
+    /// Address of the server end of the connection, if known
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        None
+    }
+
+    /// Address of this end of the connection, if known
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        None
+    }
+
+    /// Time since the connection was established, if known
+    fn connection_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Fail calls not answered within `timeout`, with an error reporting
+    /// [`SearpcError::is_timeout`]; `None` waits forever
+    ///
+    /// The socket transports bound the whole exchange, setting the socket's
+    /// timeouts to the time left before each read and write. Transports
+    /// that cannot bound calls fail, which is the default.
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let _ = timeout;
+        Err(SearpcError::transport(
+            "Transport does not support timeouts",
+        ))
+    }
+}
+
+/// Transport whose requests and responses can be handled apart, so several
+/// requests can be in flight on one connection
+///
+/// The server answers in request order, so each response belongs to the
+/// oldest request still unanswered. See
+/// [`SearpcClient::call_batch`](crate::SearpcClient::call_batch).
+pub trait PipelinedTransport: Transport {
+    /// Send a request without waiting for its response
+    fn send_request(&mut self, request: &[u8]) -> Result<()>;
+
+    /// Receive the response to the oldest request still unanswered
+    fn recv_response(&mut self) -> Result<Vec<u8>>;
+}
+
+/// One end of a transport's connection, for logging where a call went
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectionAddr {
+    Tcp(SocketAddr),
+    /// Socket path, `None` for an unnamed socket such as a client's end
+    Unix(Option<PathBuf>),
+    /// Endpoint of a connectionless transport, such as HTTP
+    Url(String),
+}
+
+impl fmt::Display for ConnectionAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionAddr::Tcp(addr) => addr.fmt(f),
+            ConnectionAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            ConnectionAddr::Unix(None) => f.write_str("unix:(unnamed)"),
+            ConnectionAddr::Url(url) => f.write_str(url),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<std::os::unix::net::SocketAddr> for ConnectionAddr {
+    fn from(addr: std::os::unix::net::SocketAddr) -> Self {
+        ConnectionAddr::Unix(addr.as_pathname().map(PathBuf::from))
+    }
 }
 
 /// Function-based transport (for simple callbacks)
@@ -28,10 +106,645 @@ where
     }
 }
 
+/// Boxed transport, to pick TCP or Unix socket at runtime:
+/// `SearpcClient<Box<dyn Transport>>`
+impl Transport for Box<dyn Transport> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        (**self).send(request)
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        (**self).peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        (**self).local_addr()
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        (**self).connection_age()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        (**self).set_timeout(timeout)
+    }
+}
+
+/// Boxed transport that can move to another thread
+impl Transport for Box<dyn Transport + Send> {
+    fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        (**self).send(request)
+    }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        (**self).peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        (**self).local_addr()
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        (**self).connection_age()
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        (**self).set_timeout(timeout)
+    }
+}
+
+/// Read buffer and initial packet buffer size of the socket transports
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Default for the longest response the socket transports accept: 16 MiB,
+/// like [`DEFAULT_MAX_PACKET_SIZE`](crate::server::DEFAULT_MAX_PACKET_SIZE)
+/// for requests
+///
+/// A longer one is not read, so a broken server cannot make the client
+/// allocate up to 4 GiB. Any four bytes of JSON text read as a length, as
+/// after a corrupted header, come to more as well: at least `0x20202020`
+/// (512 MiB).
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 << 20;
+
+/// [`SearpcError::ProtocolDesync`] if a response of `len` bytes is over
+/// `max`
+///
+/// The body is left unread, so the connection is out of step either way.
+pub(crate) fn check_response_len(len: usize, max: usize) -> Result<()> {
+    if len > max {
+        return Err(SearpcError::ProtocolDesync {
+            reason: format!("response length {} over limit of {} bytes", len, max),
+        });
+    }
+    Ok(())
+}
+
+/// [`SearpcError::ProtocolDesync`] unless `body` starts like the JSON
+/// object every response is
+pub(crate) fn check_response_body(body: &[u8]) -> Result<()> {
+    match body.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') => Ok(()),
+        _ => Err(SearpcError::ProtocolDesync {
+            reason: format!(
+                "response is not a JSON object: {:?}",
+                String::from_utf8_lossy(&body[..body.len().min(16)])
+            ),
+        }),
+    }
+}
+
+/// Largest frame of the 16-bit TCP framing
+///
+/// With chunking enabled, a frame of exactly this size is continued by the
+/// next one, and a shorter (possibly empty) frame ends the message.
+pub(crate) const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+
+/// Append `data` to `packet` as chunked 16-bit frames
+pub(crate) fn encode_chunked(data: &[u8], packet: &mut Vec<u8>) {
+    let mut rest = data;
+    loop {
+        let (frame, next) = rest.split_at(rest.len().min(MAX_FRAME_SIZE));
+        packet.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+        packet.extend_from_slice(frame);
+        if frame.len() < MAX_FRAME_SIZE {
+            return;
+        }
+        rest = next;
+    }
+}
+
+/// Byte order of the 32-bit length header of the Unix socket protocol
+///
+/// libsearpc writes the header in the machine's byte order, so client and
+/// server agree as long as they run on the same machine. When a socket is
+/// forwarded between machines of different byte order, both ends have to
+/// be set to the same explicit order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    /// This machine's byte order, as libsearpc uses
+    #[default]
+    Native,
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// Header announcing `len` bytes
+    pub fn encode(self, len: u32) -> [u8; 4] {
+        match self {
+            Endianness::Native => len.to_ne_bytes(),
+            Endianness::Little => len.to_le_bytes(),
+            Endianness::Big => len.to_be_bytes(),
+        }
+    }
+
+    /// Length announced by `header`
+    pub fn decode(self, header: [u8; 4]) -> u32 {
+        match self {
+            Endianness::Native => u32::from_ne_bytes(header),
+            Endianness::Little => u32::from_le_bytes(header),
+            Endianness::Big => u32::from_be_bytes(header),
+        }
+    }
+
+    /// `Little` or `Big`, resolving `Native`
+    fn resolve(self) -> Endianness {
+        match self {
+            Endianness::Native if cfg!(target_endian = "big") => Endianness::Big,
+            Endianness::Native => Endianness::Little,
+            endianness => endianness,
+        }
+    }
+}
+
+/// Length header in front of each packet on a byte stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// 16-bit big-endian length, as in the libsearpc demo protocol
+    #[default]
+    U16,
+    /// 32-bit length in the given byte order, as in the Seafile protocol
+    U32(Endianness),
+}
+
+impl Framing {
+    /// Size of the length header
+    pub fn header_len(self) -> usize {
+        match self {
+            Framing::U16 => 2,
+            Framing::U32(_) => 4,
+        }
+    }
+
+    /// Write the packet for `data` to `packet`, wrapped in the service
+    /// envelope if there is a `service`
+    pub(crate) fn encode(
+        self,
+        service: Option<&str>,
+        data: &[u8],
+        packet: &mut Vec<u8>,
+    ) -> Result<()> {
+        packet.clear();
+        packet.resize(self.header_len(), 0);
+        match service {
+            Some(service) => wrap_request(service, data, packet)?,
+            None => packet.extend_from_slice(data),
+        }
+        let len = packet.len() - self.header_len();
+        match self {
+            Framing::U16 => {
+                let len = u16::try_from(len).map_err(|_| {
+                    SearpcError::transport(format!("Packet too large: {} > {}", len, u16::MAX))
+                })?;
+                packet[..2].copy_from_slice(&len.to_be_bytes());
+            }
+            Framing::U32(endianness) => {
+                let len = u32::try_from(len)
+                    .map_err(|_| SearpcError::transport("Request too large for 32-bit header"))?;
+                packet[..4].copy_from_slice(&endianness.encode(len));
+            }
+        }
+        Ok(())
+    }
+
+    /// Body length announced by `header`, of [`header_len`](Self::header_len) bytes
+    pub(crate) fn body_len(self, header: &[u8]) -> Result<usize> {
+        let len = match (self, header) {
+            (Framing::U16, &[a, b]) => u16::from_be_bytes([a, b]) as usize,
+            (Framing::U32(endianness), &[a, b, c, d]) => endianness.decode([a, b, c, d]) as usize,
+            _ => unreachable!("header of the wrong length"),
+        };
+        if len == 0 {
+            return Err(SearpcError::transport(
+                "Received packet with zero length".to_string(),
+            ));
+        }
+        // A 16-bit packet read with a 32-bit header has its `{"` in the
+        // header, which tells it apart before the body. A 16-bit header
+        // cannot announce this much; its body start is checked after
+        // `peek_len` instead.
+        if matches!(self, Framing::U32(_)) && len > DEFAULT_MAX_RESPONSE_SIZE {
+            self.check_start(header, &[])?;
+        }
+        Ok(len)
+    }
+
+    /// Framing of the packet starting with `prefix`, if that shows
+    ///
+    /// Every request and response is a JSON array or object starting with
+    /// a string, so the header is followed by `[` or `{` and then, if
+    /// `prefix` goes on, by `"`. With the 16-bit framing that is the third
+    /// byte, with the 32-bit one the fifth; the byte order of a 32-bit
+    /// header is the one giving the smaller length.
+    ///
+    /// ```
+    /// use searpc::transport::{Endianness, Framing};
+    ///
+    /// assert_eq!(Framing::detect(b"\x00\x0b{\"ret\":1}"), Some(Framing::U16));
+    /// assert_eq!(
+    ///     Framing::detect(b"\x00\x00\x00\x0b{\"ret\":1}"),
+    ///     Some(Framing::U32(Endianness::Big))
+    /// );
+    /// assert_eq!(Framing::detect(b"\x00\x00\x00"), None);
+    /// ```
+    pub fn detect(prefix: &[u8]) -> Option<Framing> {
+        let is_json = |offset: usize| {
+            matches!(prefix.get(offset), Some(b'[' | b'{'))
+                && matches!(prefix.get(offset + 1), None | Some(b'"' | b'}' | b']'))
+        };
+        if is_json(2) && u16::from_be_bytes([prefix[0], prefix[1]]) >= 2 {
+            return Some(Framing::U16);
+        }
+        if !is_json(4) {
+            return None;
+        }
+        let header = [prefix[0], prefix[1], prefix[2], prefix[3]];
+        let endianness = match u32::from_le_bytes(header).cmp(&u32::from_be_bytes(header)) {
+            std::cmp::Ordering::Less => Endianness::Little,
+            std::cmp::Ordering::Equal => Endianness::Native,
+            std::cmp::Ordering::Greater => Endianness::Big,
+        };
+        if endianness.resolve() == Endianness::Native.resolve() {
+            return Some(Framing::U32(Endianness::Native));
+        }
+        Some(Framing::U32(endianness))
+    }
+
+    /// Bytes of a body of `len` to read and pass to
+    /// [`check_start`](Self::check_start) before the rest
+    ///
+    /// A 32-bit little-endian header read as a 16-bit one announces a
+    /// multiple of 256 bytes, of which the response has far fewer. Reading
+    /// the start first tells that apart before waiting for the rest forever.
+    pub(crate) fn peek_len(self, header: &[u8], len: usize) -> usize {
+        match (self, header) {
+            (Framing::U16, &[_, 0]) => len.min(3),
+            _ => 0,
+        }
+    }
+
+    /// [`SearpcError::ProtocolDesync`] if the packet starting with `header`
+    /// and then `body` is in another framing
+    pub(crate) fn check_start(self, header: &[u8], body: &[u8]) -> Result<()> {
+        let prefix = [header, body].concat();
+        match Framing::detect(&prefix) {
+            Some(framing) if !framing.same_as(self) => Err(SearpcError::ProtocolDesync {
+                reason: format!(
+                    "response is in the {}, but the transport expects the {}",
+                    framing, self
+                ),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the framings match, `Native` being this machine's order
+    fn same_as(self, other: Framing) -> bool {
+        match (self, other) {
+            (Framing::U32(a), Framing::U32(b)) => a.resolve() == b.resolve(),
+            _ => self == other,
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Framing::U16 => write!(f, "16-bit demo framing"),
+            Framing::U32(Endianness::Native) => write!(f, "32-bit Seafile framing"),
+            Framing::U32(Endianness::Little) => write!(f, "32-bit little-endian Seafile framing"),
+            Framing::U32(Endianness::Big) => write!(f, "32-bit big-endian Seafile framing"),
+        }
+    }
+}
+
+/// Framing of the server `connect` reaches, found by calling it
+///
+/// Tries the 32-bit Seafile framing, calling a function no server has in
+/// `service`, and then the 16-bit demo framing, each on a new connection
+/// from `connect`. Any response tells the framing, even an error, and even
+/// one in the other framing. A server reading a packet in the wrong
+/// framing may wait for more than was sent, so `connect` should set a read
+/// timeout, as with [`std::net::TcpStream::set_read_timeout`].
+///
+/// ```rust,no_run
+/// use searpc::transport::{self, Framing};
+/// use searpc::FramedTransport;
+/// use std::net::TcpStream;
+/// use std::time::Duration;
+///
+/// let connect = || {
+///     let stream = TcpStream::connect("127.0.0.1:12345")?;
+///     stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+///     Ok(stream)
+/// };
+/// let framing = transport::probe_framing("seafile-rpcserver", connect)?;
+/// let mut transport = FramedTransport::new(connect()?, framing);
+/// if let Framing::U32(_) = framing {
+///     transport = transport.with_service("seafile-rpcserver");
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn probe_framing<S: Read + Write>(
+    service: &str,
+    mut connect: impl FnMut() -> std::io::Result<S>,
+) -> Result<Framing> {
+    let request = br#"["searpc_probe_framing"]"#;
+    let mut errors = Vec::new();
+    for (framing, service) in [
+        (Framing::U32(Endianness::Native), Some(service)),
+        (Framing::U16, None),
+    ] {
+        let mut packet = Vec::new();
+        framing.encode(service, request, &mut packet)?;
+        match probe(connect()?, &packet) {
+            Ok(detected) => return Ok(detected),
+            Err(e) => errors.push(format!("{}: {}", framing, e)),
+        }
+    }
+    Err(SearpcError::ProtocolDesync {
+        reason: format!("no response in either framing ({})", errors.join("; ")),
+    })
+}
+
+/// Send `packet` and detect the framing of the response
+fn probe<S: Read + Write>(mut stream: S, packet: &[u8]) -> Result<Framing> {
+    write_request(&mut stream, packet)?;
+    flush_request(&mut stream)?;
+    let mut prefix = [0u8; 5];
+    let mut filled = 0;
+    loop {
+        if let Some(framing) = Framing::detect(&prefix[..filled]) {
+            return Ok(framing);
+        }
+        if filled == prefix.len() {
+            return Err(SearpcError::ProtocolDesync {
+                reason: format!("unknown framing: {:?}", prefix),
+            });
+        }
+        let n = match stream.read(&mut prefix[filled..]) {
+            Ok(0) => {
+                return Err(SearpcError::ConnectionClosed {
+                    request_sent: true,
+                    mid_frame: filled > 0,
+                })
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => 0,
+            Err(e) => return Err(SearpcError::transport_io("Read failed", e)),
+        };
+        filled += n;
+    }
+}
+
+/// Service envelope around a request, see [`wrap_request`]
+///
+/// Fields borrow from the packet when they contain no escapes.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Envelope<'a> {
+    #[serde(borrow)]
+    pub service: Cow<'a, str>,
+    #[serde(borrow)]
+    pub request: Cow<'a, str>,
+    /// ID of a multiplexed call, echoed in its [`TaggedResponse`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    /// Asks for the service's [`Notification`]s instead of calling a
+    /// function; `request` is empty then
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub subscribe: bool,
+}
+
+impl Envelope<'_> {
+    /// Copy borrowed fields, to outlive the packet
+    pub(crate) fn into_owned(self) -> Envelope<'static> {
+        Envelope {
+            service: Cow::Owned(self.service.into_owned()),
+            request: Cow::Owned(self.request.into_owned()),
+            id: self.id,
+            subscribe: self.subscribe,
+        }
+    }
+}
+
+/// Response to a request sent with an ID
+///
+/// `{"id": 7, "response": "{\"ret\":...}"}`: like the request in its
+/// [`Envelope`], the response goes in as a JSON string.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TaggedResponse<'a> {
+    pub id: u64,
+    #[serde(borrow)]
+    pub response: Cow<'a, str>,
+}
+
+/// Frame pushed by the server to subscribed clients
+///
+/// `{"notification": "{\"event\":...}"}`, the payload as a JSON string.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Notification<'a> {
+    #[serde(borrow)]
+    pub notification: Cow<'a, str>,
+}
+
+/// Append the Seafile service envelope for `rpc_request` to `buf`
+///
+/// `{"service": "xxx", "request": "[\"function_name\",arg1,...]"}`: the
+/// request goes in as a JSON *string*, as pysearpc sends it.
+pub(crate) fn wrap_request(service: &str, rpc_request: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+    wrap_tagged_request(service, None, rpc_request, buf)
+}
+
+/// [`wrap_request`], tagging the request with `id` if there is one
+pub(crate) fn wrap_tagged_request(
+    service: &str,
+    id: Option<u64>,
+    rpc_request: &[u8],
+    buf: &mut Vec<u8>,
+) -> Result<()> {
+    let request = std::str::from_utf8(rpc_request)
+        .map_err(|e| SearpcError::InvalidResponse(format!("Request is not valid UTF-8: {}", e)))?;
+
+    // CRITICAL: Keep request as a string, don't parse it as JSON!
+    // The server expects: {"service":"...", "request":"[...]"}
+    // NOT: {"service":"...", "request":[...]}
+    let envelope = Envelope {
+        service: Cow::Borrowed(service),
+        request: Cow::Borrowed(request),
+        id,
+        subscribe: false,
+    };
+    // Appends to `buf`: the request is escaped once, with no copy in between
+    serde_json::to_writer(buf, &envelope)?;
+    Ok(())
+}
+
+/// Append the envelope subscribing to `service`'s notifications to `buf`
+pub(crate) fn wrap_subscription(service: &str, id: u64, buf: &mut Vec<u8>) -> Result<()> {
+    let envelope = Envelope {
+        service: Cow::Borrowed(service),
+        request: Cow::Borrowed(""),
+        id: Some(id),
+        subscribe: true,
+    };
+    serde_json::to_writer(buf, &envelope)?;
+    Ok(())
+}
+
+/// Whether an I/O error means the peer went away
+pub(crate) fn is_disconnect(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::UnexpectedEof
+            | ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+    )
+}
+
+/// Read exactly `buf.len()` bytes of a response
+///
+/// `frame_start` marks the first read of a frame: EOF before any byte of it
+/// is a clean close, anything later is a close in the middle of a frame.
+pub(crate) fn read_response<R: Read>(
+    reader: &mut R,
+    buf: &mut [u8],
+    frame_start: bool,
+) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => {
+                return Err(SearpcError::ConnectionClosed {
+                    request_sent: true,
+                    mid_frame: !frame_start || filled > 0,
+                })
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if is_disconnect(e.kind()) => {
+                return Err(SearpcError::ConnectionClosed {
+                    request_sent: true,
+                    mid_frame: !frame_start || filled > 0,
+                })
+            }
+            Err(e) => return Err(SearpcError::transport_io("Read failed", e)),
+        }
+    }
+    Ok(())
+}
+
+/// Flush a request written with [`write_request`], for buffering writers
+pub(crate) fn flush_request<W: Write>(writer: &mut W) -> Result<()> {
+    writer.flush().map_err(write_error)
+}
+
+/// Write part of a request, reporting a vanished peer as [`SearpcError::ConnectionClosed`]
+pub(crate) fn write_request<W: Write>(writer: &mut W, buf: &[u8]) -> Result<()> {
+    writer.write_all(buf).map_err(write_error)
+}
+
+pub(crate) fn write_error(e: std::io::Error) -> SearpcError {
+    if is_disconnect(e.kind()) || e.kind() == ErrorKind::WriteZero {
+        SearpcError::ConnectionClosed {
+            request_sent: false,
+            mid_frame: false,
+        }
+    } else {
+        SearpcError::transport_io("Write failed", e)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_detect_framing() {
+        let native = Framing::U32(Endianness::Native);
+        let foreign = if cfg!(target_endian = "big") {
+            Framing::U32(Endianness::Little)
+        } else {
+            Framing::U32(Endianness::Big)
+        };
+        let response = br#"{"ret":1}"#;
+        for framing in [Framing::U16, native, foreign] {
+            let mut packet = Vec::new();
+            framing.encode(None, response, &mut packet).unwrap();
+            assert_eq!(Framing::detect(&packet), Some(framing));
+            assert_eq!(
+                Framing::detect(&packet[..framing.header_len() + 1]),
+                Some(framing)
+            );
+            assert_eq!(Framing::detect(&packet[..framing.header_len()]), None);
+            assert!(framing.check_start(&packet[..2], &packet[2..5]).is_ok());
+        }
+        assert_eq!(Framing::detect(b"\x00\x00\x00\x00\x00"), None);
+        assert_eq!(Framing::detect(b"\x00\x09{x"), None);
+
+        // A little-endian header read as a 16-bit one
+        let err = Framing::U16
+            .check_start(b"\x0b\x00", b"\x00\x00{")
+            .unwrap_err();
+        assert!(err.is_protocol_desync());
+        assert_eq!(Framing::U16.peek_len(b"\x0b\x00", 2816), 3);
+        assert_eq!(Framing::U16.peek_len(b"\x00\x0b", 11), 0);
+
+        // A 16-bit packet read as a 32-bit one shows in the header alone
+        let err = Framing::U32(Endianness::Little)
+            .body_len(b"\x00\x0b{\"")
+            .unwrap_err();
+        assert!(err.is_protocol_desync());
+    }
+
+    #[test]
+    fn test_read_response_clean_eof() {
+        let mut reader: &[u8] = b"";
+        let mut buf = [0u8; 4];
+        let err = read_response(&mut reader, &mut buf, true).unwrap_err();
+        assert!(matches!(
+            err,
+            SearpcError::ConnectionClosed {
+                request_sent: true,
+                mid_frame: false
+            }
+        ));
+        assert!(!err.may_replay());
+    }
+
+    #[test]
+    fn test_read_response_mid_frame() {
+        let mut reader: &[u8] = b"\x00\x00";
+        let mut buf = [0u8; 4];
+        let err = read_response(&mut reader, &mut buf, true).unwrap_err();
+        assert!(matches!(
+            err,
+            SearpcError::ConnectionClosed {
+                mid_frame: true,
+                ..
+            }
+        ));
+
+        // EOF before the body of a frame whose header was read
+        let mut reader: &[u8] = b"";
+        let err = read_response(&mut reader, &mut buf, false).unwrap_err();
+        assert!(matches!(
+            err,
+            SearpcError::ConnectionClosed {
+                mid_frame: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_write_request_closed() {
+        let mut writer: &mut [u8] = &mut [];
+        let err = write_request(&mut writer, b"data").unwrap_err();
+        assert!(err.is_connection_closed());
+        assert!(err.may_replay());
+    }
+
     #[test]
     fn test_function_transport() {
         let mut transport = |req: &[u8]| -> Result<Vec<u8>> {
@@ -42,4 +755,57 @@ mod tests {
         let result = transport.send(b"test").unwrap();
         assert_eq!(result, b"test");
     }
+
+    #[test]
+    fn test_boxed_transport() {
+        fn pick(remote: bool) -> Box<dyn Transport + Send> {
+            if remote {
+                Box::new(|_: &[u8]| Ok(br#"{"ret":"remote"}"#.to_vec()))
+            } else {
+                Box::new(|_: &[u8]| Ok(br#"{"ret":"local"}"#.to_vec()))
+            }
+        }
+
+        for (remote, expected) in [(true, "remote"), (false, "local")] {
+            let mut client = crate::SearpcClient::new(pick(remote));
+            assert_eq!(client.call_string("where", vec![]).unwrap(), expected);
+        }
+        let transport: Box<dyn Transport> = Box::new(|req: &[u8]| Ok(req.to_vec()));
+        let mut transport = transport;
+        assert_eq!(transport.send(b"test").unwrap(), b"test");
+        assert_eq!(transport.peer_addr(), None);
+        assert_eq!(transport.connection_age(), None);
+    }
+
+    #[test]
+    fn test_encode_chunked() {
+        let frames = |data: &[u8]| {
+            let mut packet = Vec::new();
+            encode_chunked(data, &mut packet);
+            let mut lens = Vec::new();
+            let mut rest = &packet[..];
+            while let [a, b, tail @ ..] = rest {
+                let len = u16::from_be_bytes([*a, *b]) as usize;
+                lens.push(len);
+                rest = &tail[len..];
+            }
+            lens
+        };
+        assert_eq!(frames(b"short"), [5]);
+        assert_eq!(
+            frames(&[0; 70_000]),
+            [MAX_FRAME_SIZE, 70_000 - MAX_FRAME_SIZE]
+        );
+        // A full last frame needs an empty one to end the message
+        assert_eq!(frames(&[0; MAX_FRAME_SIZE]), [MAX_FRAME_SIZE, 0]);
+    }
+
+    #[test]
+    fn test_connection_addr_display() {
+        let tcp = ConnectionAddr::Tcp("127.0.0.1:8080".parse().unwrap());
+        assert_eq!(tcp.to_string(), "127.0.0.1:8080");
+        let unix = ConnectionAddr::Unix(Some("/run/seafile.sock".into()));
+        assert_eq!(unix.to_string(), "unix:/run/seafile.sock");
+        assert_eq!(ConnectionAddr::Unix(None).to_string(), "unix:(unnamed)");
+    }
 }
@@ -0,0 +1,176 @@
+//! Unix socket connect with a deadline
+//!
+//! A blocking connect to a socket whose listen backlog is full waits until
+//! the server accepts, which a wedged daemon never does. Here the socket is
+//! non-blocking while connecting. Linux refuses such a connect with `EAGAIN`
+//! while the backlog is full, so it is retried until the deadline. Other
+//! systems may answer `EINPROGRESS` instead, and the socket is then polled
+//! for the outcome.
+
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Wait between connects refused for a full backlog
+pub(crate) const BACKLOG_RETRY: Duration = Duration::from_millis(10);
+
+/// Connect to the socket at `path`, failing with `TimedOut` after `timeout`
+///
+/// The stream returned is blocking.
+pub(crate) fn connect_timeout(path: &Path, timeout: Duration) -> io::Result<UnixStream> {
+    let deadline = Instant::now() + timeout;
+    let (addr, addr_len) = sockaddr_un(path)?;
+    // SAFETY: socket has no memory preconditions
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a fresh socket owned by nothing else
+    let stream = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    // SAFETY: `fd` is open; FD_CLOEXEC is a valid descriptor flag
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    stream.set_nonblocking(true)?;
+
+    loop {
+        // SAFETY: `addr` is a valid sockaddr_un of `addr_len` bytes
+        let ret =
+            unsafe { libc::connect(fd, (&addr as *const libc::sockaddr_un).cast(), addr_len) };
+        if ret == 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => {}
+            Some(libc::EAGAIN) => {
+                let left = remaining(deadline)?;
+                std::thread::sleep(left.min(BACKLOG_RETRY));
+            }
+            Some(libc::EINPROGRESS) => {
+                wait_writable(&stream, deadline)?;
+                if let Some(e) = stream.take_error()? {
+                    return Err(e);
+                }
+                break;
+            }
+            _ => return Err(e),
+        }
+    }
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+/// Address of the socket at `path`, and its length
+///
+/// Empty paths are refused along with ones too long for `sun_path`: Linux
+/// would take an empty one for an unnamed socket.
+pub(crate) fn sockaddr_un(path: &Path) -> io::Result<(libc::sockaddr_un, libc::socklen_t)> {
+    // SAFETY: all-zero is a valid sockaddr_un
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let bytes = path.as_os_str().as_bytes();
+    // Room for the terminating NUL
+    if bytes.is_empty() || bytes.len() >= addr.sun_path.len() || bytes.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid Unix socket path {:?}", path),
+        ));
+    }
+    for (dst, &src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = src as libc::c_char;
+    }
+    let path_offset = addr.sun_path.as_ptr() as usize - &addr as *const _ as usize;
+    Ok((addr, (path_offset + bytes.len() + 1) as libc::socklen_t))
+}
+
+/// Time left until `deadline`, or `TimedOut` if there is none
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Unix socket connect timed out",
+        ));
+    }
+    Ok(left)
+}
+
+/// Wait until a connect in progress on `stream` finished
+fn wait_writable(stream: &UnixStream, deadline: Instant) -> io::Result<()> {
+    loop {
+        let left = remaining(deadline)?;
+        let mut pollfd = libc::pollfd {
+            fd: stream.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        // Rounded up, so a deadline under 1ms is not a busy loop
+        let ms = left
+            .as_millis()
+            .saturating_add(1)
+            .min(libc::c_int::MAX as u128) as libc::c_int;
+        // SAFETY: `pollfd` is valid for the call
+        let ret = unsafe { libc::poll(&mut pollfd, 1, ms) };
+        if ret > 0 {
+            return Ok(());
+        }
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_connect() {
+        let dir = std::env::temp_dir().join(format!("searpc-unix-connect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+
+        let err = connect_timeout(&path, Duration::from_secs(5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let stream = connect_timeout(&path, Duration::from_secs(5)).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        assert_eq!(
+            stream.peer_addr().unwrap().as_pathname(),
+            Some(path.as_path())
+        );
+        drop(accepted);
+
+        // Fill the backlog: the next connect would wait for an accept
+        #[cfg(target_os = "linux")]
+        {
+            let mut connected = Vec::new();
+            let err = loop {
+                match connect_timeout(&path, Duration::from_millis(30)) {
+                    Ok(stream) => connected.push(stream),
+                    Err(e) => break e,
+                }
+                assert!(connected.len() < 10_000, "backlog never filled");
+            };
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        for invalid in [dir.join("x".repeat(200)), PathBuf::new()] {
+            let err = connect_timeout(&invalid, Duration::from_secs(5)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
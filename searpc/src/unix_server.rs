@@ -0,0 +1,1304 @@
+//! Unix Domain Socket server, the counterpart of
+//! [`UnixSocketTransport`](crate::UnixSocketTransport)
+//!
+//! Reads the production framing used by Seafile: a 32-bit native-endian
+//! length, then `{"service": "name", "request": "<request JSON as string>"}`.
+//! The request is routed to the [`SearpcServer`] registered under that
+//! service name, and its response is written back with the same header.
+//! [`UnixSocketServer::set_endianness`] picks a fixed byte order instead,
+//! for sockets forwarded between machines.
+//!
+//! ```rust,no_run
+//! use searpc::server::arg;
+//! use searpc::{SearpcServer, UnixSocketServer};
+//! use serde_json::json;
+//! use std::os::unix::net::UnixListener;
+//! use std::sync::Arc;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let mut rpc = SearpcServer::new();
+//! rpc.register("seafile_get_config", |args| {
+//!     let key: String = arg(args, 0)?;
+//!     Ok(json!(format!("value of {}", key)))
+//! });
+//!
+//! let mut server = UnixSocketServer::new();
+//! server.add_service("seafile-rpcserver", rpc);
+//! Arc::new(server).serve(UnixListener::bind("/tmp/seafile.sock")?)
+//! # }
+//! ```
+//!
+//! Requests whose envelope carries an `"id"` (see
+//! [`MultiplexedTransport`](crate::MultiplexedTransport)) are answered on
+//! their own threads, as `{"id": N, "response": "<response JSON as string>"}`
+//! in whatever order they finish; requests without one are answered in
+//! order, as by libsearpc. A connection runs at most
+//! [`MAX_TAGGED_REQUESTS`] such threads at once; further requests are not
+//! read until one of them finishes.
+//!
+//! Such clients can also subscribe to a service, with
+//! [`MultiplexedTransport::subscribe`](crate::MultiplexedTransport::subscribe).
+//! [`UnixSocketServer::notify`] then pushes
+//! `{"notification": "<JSON as string>"}` frames to them between responses.
+//!
+//! Like the Seafile daemon, a server can refuse other users' processes:
+//! [`UnixSocketServer::authorize`] sees each connection's
+//! [`PeerCredentials`] before any request is read. On Linux, they can come
+//! from `SCM_CREDENTIALS` sent with the first request instead, see
+//! [`UnixSocketServer::receive_credentials`].
+
+use crate::error::{KnownErrorCode, Result, SearpcError};
+use crate::server::{
+    bad_request, busy, encode_response, packet_too_large, Gate, Limits, SearpcServer,
+};
+use crate::transport;
+use crate::transport::{Endianness, Envelope, Notification, TaggedResponse};
+use crate::RpcResponse;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, info_span, warn};
+
+/// Tagged requests one connection may have running on their own threads
+///
+/// Applies whatever the [`Limits`], so that a single multiplexing client
+/// cannot start threads without bound.
+pub const MAX_TAGGED_REQUESTS: usize = 16;
+
+/// Decides whether a connecting process may use the server
+pub type Authorizer = Box<dyn Fn(&PeerCredentials) -> bool + Send + Sync>;
+
+/// Identity of the process at the other end of a Unix socket
+///
+/// Read from the kernel (`SO_PEERCRED` on Linux, `getpeereid` elsewhere),
+/// so it cannot be forged by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerCredentials {
+    /// Effective user ID
+    pub uid: u32,
+    /// Effective group ID
+    pub gid: u32,
+    /// Process ID, where the platform reports it
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    /// Credentials of the peer connected to `stream`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn of(stream: &UnixStream) -> io::Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `cred` and `len` are valid for writes and sized for SO_PEERCRED
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials {
+            uid: cred.uid,
+            gid: cred.gid,
+            pid: Some(cred.pid),
+        })
+    }
+
+    /// Credentials of the peer connected to `stream`
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn of(stream: &UnixStream) -> io::Result<Self> {
+        let mut uid = 0;
+        let mut gid = 0;
+        // SAFETY: `uid` and `gid` are valid for writes
+        if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials {
+            uid,
+            gid,
+            pid: None,
+        })
+    }
+
+    /// Whether the peer runs as this process's effective user
+    pub fn is_same_user(&self) -> bool {
+        // SAFETY: geteuid has no preconditions and cannot fail
+        self.uid == unsafe { libc::geteuid() }
+    }
+}
+
+/// Serves named services over the Seafile Unix socket protocol
+#[derive(Default)]
+pub struct UnixSocketServer {
+    services: HashMap<String, Arc<SearpcServer>>,
+    authorizer: Option<Authorizer>,
+    limits: Limits,
+    endianness: Endianness,
+    /// Take credentials from `SCM_CREDENTIALS` rather than `SO_PEERCRED`
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    credentials: bool,
+    connection_slots: Arc<Gate>,
+    request_slots: Arc<Gate>,
+    lifecycle: Mutex<Lifecycle>,
+    /// Signalled whenever a connection ends
+    drained: Condvar,
+}
+
+/// What [`UnixSocketServer::shutdown`] needs to reach
+#[derive(Default)]
+struct Lifecycle {
+    stopping: bool,
+    /// Path of the listening socket, to wake up `accept`
+    listener: Option<PathBuf>,
+    /// Handles on the open connections, by connection number
+    connections: HashMap<u64, UnixStream>,
+    next_id: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+/// Connection subscribed to a service's notifications
+struct Subscriber {
+    connection: u64,
+    service: String,
+    /// The connection's writer, shared with its responses
+    writer: Arc<Mutex<UnixStream>>,
+}
+
+impl UnixSocketServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `server`'s functions as `service`, replacing any previous one
+    pub fn add_service(
+        &mut self,
+        service: impl Into<String>,
+        server: impl Into<Arc<SearpcServer>>,
+    ) -> &mut Self {
+        self.services.insert(service.into(), server.into());
+        self
+    }
+
+    /// The server registered as `service`
+    pub fn service(&self, service: &str) -> Option<&SearpcServer> {
+        self.services.get(service).map(Arc::as_ref)
+    }
+
+    /// The server registered as `service`, created empty if there is none
+    ///
+    /// Like libsearpc's `searpc_create_service` followed by function
+    /// registrations, so functions can be added service by service:
+    ///
+    /// ```rust
+    /// # use searpc::UnixSocketServer;
+    /// # use serde_json::json;
+    /// let mut server = UnixSocketServer::new();
+    /// server
+    ///     .service_mut("seafile-rpcserver")
+    ///     .register("seafile_get_version", |_| Ok(json!("9.0.0")));
+    /// server
+    ///     .service_mut("seafile-threaded-rpcserver")
+    ///     .register("seafile_get_repo_list", |_| Ok(json!([])));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the server for `service` was passed to
+    /// [`add_service`](Self::add_service) as an `Arc` that is still shared.
+    pub fn service_mut(&mut self, service: &str) -> &mut SearpcServer {
+        let server = self
+            .services
+            .entry(service.to_string())
+            .or_insert_with(|| Arc::new(SearpcServer::new()));
+        Arc::get_mut(server).unwrap_or_else(|| {
+            panic!(
+                "UnixSocketServer: service {} is shared and cannot be modified",
+                service
+            )
+        })
+    }
+
+    /// Names of the registered services, in no particular order
+    pub fn services(&self) -> impl Iterator<Item = &str> {
+        self.services.keys().map(String::as_str)
+    }
+
+    /// Bound the connections and requests served at once
+    ///
+    /// Requests over the limit queue, then get a 429 busy error; see
+    /// [`Limits`]. Set before serving: connections already open keep the
+    /// old limits.
+    pub fn set_limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self.connection_slots = Arc::new(Gate::new(limits.max_connections, usize::MAX));
+        self.request_slots = Arc::new(Gate::new(
+            limits.max_concurrent_requests,
+            limits.max_queued_requests,
+        ));
+        self
+    }
+
+    /// The limits set with [`set_limits`](Self::set_limits)
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Byte order of the length headers, native by default, as for
+    /// [`UnixSocketTransport::with_endianness`](crate::UnixSocketTransport::with_endianness)
+    pub fn set_endianness(&mut self, endianness: Endianness) -> &mut Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Identify clients by the `SCM_CREDENTIALS` sent with their first
+    /// request, rather than by the credentials of the connecting process
+    /// (Linux only)
+    ///
+    /// These name the process actually sending requests, even if the
+    /// connection was opened by another one and handed over. Clients send
+    /// them with
+    /// [`UnixSocketTransport::with_credentials`](crate::UnixSocketTransport::with_credentials);
+    /// with an [`authorize`](Self::authorize) callback, connections whose
+    /// first request comes without them are closed.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn receive_credentials(&mut self, enabled: bool) -> &mut Self {
+        self.credentials = enabled;
+        self
+    }
+
+    /// Only serve connections for which `authorizer` returns `true`
+    ///
+    /// Other connections are closed without reading a request, as are
+    /// those whose credentials cannot be read. To accept only processes of
+    /// the user running the server:
+    ///
+    /// ```rust
+    /// # use searpc::UnixSocketServer;
+    /// let mut server = UnixSocketServer::new();
+    /// server.authorize(|peer| peer.is_same_user());
+    /// ```
+    pub fn authorize<F>(&mut self, authorizer: F) -> &mut Self
+    where
+        F: Fn(&PeerCredentials) -> bool + Send + Sync + 'static,
+    {
+        self.authorizer = Some(Box::new(authorizer));
+        self
+    }
+
+    /// Run one wrapped request and return the serialized response
+    ///
+    /// Unknown services get libsearpc's 501 `cannot find service NAME.`
+    pub fn handle_packet(&self, packet: &[u8]) -> Vec<u8> {
+        match serde_json::from_slice(packet) {
+            Ok(envelope) => self.handle_envelope(&envelope),
+            Err(e) => encode_response(&RpcResponse::from(bad_request(e))),
+        }
+    }
+
+    fn handle_envelope(&self, envelope: &Envelope<'_>) -> Vec<u8> {
+        let response = match self.services.get(envelope.service.as_ref()) {
+            Some(server) => server.handle_request(envelope.request.as_bytes()),
+            None => service_not_found(&envelope.service),
+        };
+        tag_response(envelope.id, response)
+    }
+
+    /// Push `notification` to the clients subscribed to `service`, and
+    /// return how many it was written to
+    ///
+    /// Blocks while a subscriber's socket buffer is full, i.e. while it
+    /// does not read.
+    pub fn notify(&self, service: &str, notification: &Value) -> usize {
+        let writers: Vec<_> = self
+            .lifecycle()
+            .subscribers
+            .iter()
+            .filter(|subscriber| subscriber.service == service)
+            .map(|subscriber| Arc::clone(&subscriber.writer))
+            .collect();
+        let frame = serde_json::to_vec(&Notification {
+            notification: notification.to_string().into(),
+        })
+        .expect("notification serializes");
+        writers
+            .iter()
+            .filter(|writer| {
+                let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+                match write_frame(&mut writer, &frame, self.endianness) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        debug!("searpc unix socket server: writing notification: {}", e);
+                        false
+                    }
+                }
+            })
+            .count()
+    }
+
+    /// Add `connection` to `service`'s subscribers, and return the response
+    fn subscribe(
+        &self,
+        connection: u64,
+        service: &str,
+        writer: &Arc<Mutex<UnixStream>>,
+    ) -> Vec<u8> {
+        if !self.services.contains_key(service) {
+            return service_not_found(service);
+        }
+        let mut lifecycle = self.lifecycle();
+        let subscribed = lifecycle
+            .subscribers
+            .iter()
+            .any(|subscriber| subscriber.connection == connection && subscriber.service == service);
+        if !subscribed {
+            lifecycle.subscribers.push(Subscriber {
+                connection,
+                service: service.to_string(),
+                writer: Arc::clone(writer),
+            });
+        }
+        encode_response(&RpcResponse::ok(Value::Bool(true)))
+    }
+
+    /// Accept connections on `listener`, serving each on its own thread
+    ///
+    /// With [`Limits::max_connections`], at most that many threads run:
+    /// further clients are accepted as connections close. Returns once
+    /// [`shutdown`](Self::shutdown) is called, or if accepting fails;
+    /// errors on single connections are logged.
+    pub fn serve(self: Arc<Self>, listener: UnixListener) -> std::io::Result<()> {
+        if !self.start_listening(&listener)? {
+            return Ok(());
+        }
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let slot = self.connection_slots.enter();
+            if self.lifecycle().stopping {
+                break;
+            }
+            let server = Arc::clone(&self);
+            thread::spawn(move || {
+                let _slot = slot;
+                if let Err(e) = server.serve_connection(stream) {
+                    warn!("searpc unix socket connection: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Accept connections on `listener` and serve them one at a time, on
+    /// the calling thread
+    ///
+    /// For constrained systems that should not start threads (and, built
+    /// without the `async` feature, do not link tokio). A client is only
+    /// accepted once the previous one hangs up, so this suits clients that
+    /// connect for a few calls, like `seaf-cli`, rather than ones keeping a
+    /// connection open. Returns like [`serve`](Self::serve).
+    pub fn serve_sequential(&self, listener: UnixListener) -> std::io::Result<()> {
+        if !self.start_listening(&listener)? {
+            return Ok(());
+        }
+        for stream in listener.incoming() {
+            let stream = stream?;
+            if self.lifecycle().stopping {
+                break;
+            }
+            if let Err(e) = self.serve_client(stream, false) {
+                warn!("searpc unix socket connection: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Note the listener for [`shutdown`](Self::shutdown); `false` if
+    /// already stopping
+    fn start_listening(&self, listener: &UnixListener) -> std::io::Result<bool> {
+        let mut lifecycle = self.lifecycle();
+        if lifecycle.stopping {
+            return Ok(false);
+        }
+        lifecycle.listener = listener.local_addr()?.as_pathname().map(PathBuf::from);
+        Ok(true)
+    }
+
+    /// Answer requests on one connection until the client hangs up
+    ///
+    /// A zero length header also ends the connection, as in libsearpc.
+    /// Connections refused by the [`authorize`](Self::authorize) callback
+    /// are closed with an error.
+    pub fn serve_connection(&self, stream: UnixStream) -> Result<()> {
+        self.serve_client(stream, true)
+    }
+
+    /// [`serve_connection`](Self::serve_connection), answering requests
+    /// with an ID on threads of their own if `concurrent`
+    fn serve_client(&self, stream: UnixStream, concurrent: bool) -> Result<()> {
+        let peer = match (self.peer_credentials(&stream), &self.authorizer) {
+            // Hung up before the first request
+            (Err(e), _) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            (Ok(peer), Some(authorizer)) if !authorizer(&peer) => {
+                return Err(SearpcError::transport(format!(
+                    "Connection refused for uid {} (pid {:?})",
+                    peer.uid, peer.pid
+                )));
+            }
+            (Ok(peer), _) => Some(peer),
+            (Err(e), Some(_)) => {
+                return Err(SearpcError::transport_io(
+                    "Reading peer credentials failed",
+                    e,
+                ))
+            }
+            // Only needed for logging then
+            (Err(_), None) => None,
+        };
+        let span = info_span!(
+            "searpc_connection",
+            uid = peer.map(|peer| peer.uid),
+            pid = peer.and_then(|peer| peer.pid)
+        );
+        let _entered = span.enter();
+        debug!("searpc unix socket connection from {:?}", peer);
+        let handle = stream
+            .try_clone()
+            .map_err(|e| SearpcError::transport_io("Clone failed", e))?;
+        let id = {
+            let mut lifecycle = self.lifecycle();
+            if lifecycle.stopping {
+                return Ok(());
+            }
+            let id = lifecycle.next_id;
+            lifecycle.next_id += 1;
+            lifecycle.connections.insert(id, handle);
+            id
+        };
+
+        let result = self.serve_requests(stream, id, concurrent);
+
+        {
+            let mut lifecycle = self.lifecycle();
+            lifecycle.connections.remove(&id);
+            lifecycle
+                .subscribers
+                .retain(|subscriber| subscriber.connection != id);
+        }
+        self.drained.notify_all();
+        result
+    }
+
+    /// Credentials to authorize and log the client on `stream` with
+    fn peer_credentials(&self, stream: &UnixStream) -> io::Result<PeerCredentials> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.credentials {
+            crate::scm_credentials::pass_credentials(stream)?;
+            return crate::scm_credentials::peek_credentials(stream);
+        }
+        PeerCredentials::of(stream)
+    }
+
+    /// Stop serving: no new connections or requests, in-flight ones finish
+    ///
+    /// [`serve`](Self::serve) stops accepting and returns. Open connections
+    /// stop reading: requests already sent are answered, then each
+    /// connection closes. Waits up to `grace` for that, then closes what is
+    /// left and returns `false`; `true` means every connection finished.
+    ///
+    /// The server cannot be restarted afterwards.
+    pub fn shutdown(&self, grace: Duration) -> bool {
+        let deadline = Instant::now() + grace;
+        let listener = {
+            let mut lifecycle = self.lifecycle();
+            lifecycle.stopping = true;
+            for stream in lifecycle.connections.values() {
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+            lifecycle.listener.take()
+        };
+        // `accept` only notices the flag once it returns: give it a connection
+        if let Some(path) = listener {
+            let _ = UnixStream::connect(path);
+        }
+
+        let mut lifecycle = self.lifecycle();
+        while !lifecycle.connections.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                warn!(
+                    "searpc unix socket server: closing {} busy connections",
+                    lifecycle.connections.len()
+                );
+                for stream in lifecycle.connections.values() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                return false;
+            }
+            lifecycle = self
+                .drained
+                .wait_timeout(lifecycle, timeout)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+
+    fn lifecycle(&self) -> MutexGuard<'_, Lifecycle> {
+        self.lifecycle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn serve_requests(
+        &self,
+        mut stream: UnixStream,
+        connection: u64,
+        concurrent: bool,
+    ) -> Result<()> {
+        // Responses from request threads and notifications interleave with ours
+        let writer = Arc::new(Mutex::new(
+            stream
+                .try_clone()
+                .map_err(|e| SearpcError::transport_io("Clone failed", e))?,
+        ));
+        let respond = |body: &[u8]| {
+            let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+            write_frame(&mut writer, body, self.endianness)
+        };
+        let tagged_slots = Arc::new(Gate::new(Some(MAX_TAGGED_REQUESTS), usize::MAX));
+        // Returns once requests still running on their threads are answered
+        thread::scope(|scope| loop {
+            let mut len_bytes = [0u8; 4];
+            match transport::read_response(&mut stream, &mut len_bytes, true) {
+                Ok(()) => {}
+                // Clean close between requests
+                Err(SearpcError::ConnectionClosed {
+                    mid_frame: false, ..
+                }) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let len = self.endianness.decode(len_bytes) as usize;
+            if len == 0 {
+                return Ok(());
+            }
+            if len > self.limits.max_packet_size {
+                let err = packet_too_large(len, self.limits.max_packet_size);
+                let _ = respond(&encode_response(&RpcResponse::from(err)));
+                return Err(SearpcError::transport(format!(
+                    "Request of {} bytes over the size limit, closing",
+                    len
+                )));
+            }
+            let mut packet = vec![0u8; len];
+            transport::read_response(&mut stream, &mut packet, false)?;
+            debug!("RPC request: {}", String::from_utf8_lossy(&packet));
+
+            let envelope: Envelope<'_> = match serde_json::from_slice(&packet) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    respond(&encode_response(&RpcResponse::from(bad_request(e))))?;
+                    continue;
+                }
+            };
+            if envelope.subscribe {
+                let body = self.subscribe(connection, &envelope.service, &writer);
+                respond(&tag_response(envelope.id, body))?;
+                continue;
+            }
+            // Waiting here stops reading, which holds the client back
+            let concurrent = concurrent && envelope.id.is_some();
+            let tagged_slot = concurrent.then(|| tagged_slots.enter());
+            let Some(slot) = self.request_slots.try_enter() else {
+                warn!("searpc unix socket server: busy, request turned away");
+                let body = encode_response(&RpcResponse::from(busy()));
+                respond(&tag_response(envelope.id, body))?;
+                continue;
+            };
+            if concurrent {
+                let envelope = envelope.into_owned();
+                let respond = &respond;
+                scope.spawn(move || {
+                    let body = self.handle_envelope(&envelope);
+                    drop(slot);
+                    if let Err(e) = respond(&body) {
+                        debug!("searpc unix socket server: writing response: {}", e);
+                    }
+                    drop(tagged_slot);
+                });
+            } else {
+                let body = self.handle_envelope(&envelope);
+                drop(slot);
+                respond(&body)?;
+            }
+        })
+    }
+}
+
+/// libsearpc's 501 `cannot find service NAME.`
+fn service_not_found(service: &str) -> Vec<u8> {
+    encode_response(&RpcResponse::error(
+        KnownErrorCode::ServiceNotFound.code(),
+        format!("cannot find service {}.", service),
+    ))
+}
+
+/// Wrap `response` in a [`TaggedResponse`] if its request had an `id`
+fn tag_response(id: Option<u64>, response: Vec<u8>) -> Vec<u8> {
+    match id {
+        Some(id) => serde_json::to_vec(&TaggedResponse {
+            id,
+            response: String::from_utf8_lossy(&response),
+        })
+        .expect("tagged response serializes"),
+        None => response,
+    }
+}
+
+/// Write `body` with its 32-bit length header, in one write
+fn write_frame(stream: &mut UnixStream, body: &[u8], endianness: Endianness) -> Result<()> {
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&endianness.encode(body.len() as u32));
+    frame.extend_from_slice(body);
+    stream
+        .write_all(&frame)
+        .map_err(|e| SearpcError::transport_io("Write failed", e))
+}
+
+impl fmt::Debug for UnixSocketServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut services: Vec<_> = self.services.keys().collect();
+        services.sort_unstable();
+        f.debug_struct("UnixSocketServer")
+            .field("services", &services)
+            .field("limits", &self.limits)
+            .field("endianness", &self.endianness)
+            .field("authorizer", &self.authorizer.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::arg;
+    use crate::{Arg, SearpcClient, UnixSocketTransport};
+    use serde_json::json;
+    use std::sync::mpsc;
+
+    fn server() -> Arc<UnixSocketServer> {
+        let mut seafile = SearpcServer::new();
+        seafile.register("seafile_get_config", |args| {
+            let key: String = arg(args, 0)?;
+            Ok(json!(format!("seafile:{}", key)))
+        });
+        let mut ccnet = SearpcServer::new();
+        ccnet.register("get_session_info", |_| Ok(json!({"id": "ccnet"})));
+
+        let mut server = UnixSocketServer::new();
+        server
+            .add_service("seafile-rpcserver", seafile)
+            .add_service("ccnet-rpcserver", ccnet);
+        Arc::new(server)
+    }
+
+    /// Client for `service`, served by `server` on a socket pair
+    fn connect(server: &Arc<UnixSocketServer>, service: &str) -> SearpcClient<UnixSocketTransport> {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let server = Arc::clone(server);
+        thread::spawn(move || server.serve_connection(theirs));
+        SearpcClient::new(UnixSocketTransport::new(ours, service))
+    }
+
+    #[test]
+    fn test_routes_by_service() {
+        let server = server();
+
+        let mut seafile = connect(&server, "seafile-rpcserver");
+        for _ in 0..2 {
+            let value = seafile
+                .call_string("seafile_get_config", [Arg::string("a\"b")])
+                .unwrap();
+            assert_eq!(value, "seafile:a\"b");
+        }
+        let err = seafile.call_object("get_session_info", []).unwrap_err();
+        assert_eq!(err.kind(), Some(KnownErrorCode::FunctionNotFound));
+
+        let mut ccnet = connect(&server, "ccnet-rpcserver");
+        let info = ccnet.call_object("get_session_info", []).unwrap();
+        assert_eq!(info["id"], "ccnet");
+    }
+
+    /// One long-lived connection: requests for any service, written back to
+    /// back before reading, are answered in order
+    #[test]
+    fn test_keep_alive_pipelined() {
+        use std::io::Read;
+
+        let server = server();
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let serving = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_connection(theirs))
+        };
+
+        let requests = [
+            ("seafile-rpcserver", r#"["seafile_get_config","a"]"#),
+            ("ccnet-rpcserver", r#"["get_session_info"]"#),
+            ("seafile-rpcserver", r#"["seafile_get_config","b"]"#),
+        ];
+        let mut frames = Vec::new();
+        for (service, request) in requests {
+            let packet =
+                serde_json::to_vec(&json!({"service": service, "request": request})).unwrap();
+            frames.extend_from_slice(&(packet.len() as u32).to_ne_bytes());
+            frames.extend_from_slice(&packet);
+        }
+        ours.write_all(&frames).unwrap();
+
+        let mut responses = Vec::new();
+        for _ in requests {
+            let mut len = [0u8; 4];
+            ours.read_exact(&mut len).unwrap();
+            let mut body = vec![0u8; u32::from_ne_bytes(len) as usize];
+            ours.read_exact(&mut body).unwrap();
+            responses.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap());
+        }
+        assert_eq!(
+            responses,
+            [
+                json!({"ret": "seafile:a"}),
+                json!({"ret": {"id": "ccnet"}}),
+                json!({"ret": "seafile:b"}),
+            ]
+        );
+
+        // The connection ends when the client hangs up
+        drop(ours);
+        serving.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_max_packet_size() {
+        use std::io::Read;
+
+        let mut server = UnixSocketServer::new();
+        server.set_limits(Limits {
+            max_packet_size: 64,
+            ..Limits::default()
+        });
+        server
+            .service_mut("seafile-rpcserver")
+            .register("echo", |args| Ok(args[0].clone()));
+        let server = Arc::new(server);
+
+        let mut client = connect(&server, "seafile-rpcserver");
+        let small = client.call_string("echo", [Arg::string("x")]).unwrap();
+        assert_eq!(small, "x");
+        let err = client
+            .call_string("echo", [Arg::string("x".repeat(100))])
+            .unwrap_err();
+        assert_eq!(err.kind(), Some(KnownErrorCode::BadRequest));
+        assert!(err.inner().err_msg().contains("exceeds the 64 byte limit"));
+
+        // A huge header is refused without waiting for, or allocating, the body
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let serving = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_connection(theirs))
+        };
+        ours.write_all(&u32::MAX.to_ne_bytes()).unwrap();
+        assert!(serving.join().unwrap().is_err());
+        let mut response = Vec::new();
+        ours.read_to_end(&mut response).unwrap();
+        assert!(String::from_utf8_lossy(&response[4..]).contains("\"err_code\":511"));
+    }
+
+    #[test]
+    fn test_unknown_service() {
+        let mut client = connect(&server(), "no-such-service");
+        let err = client
+            .call_string("seafile_get_config", [Arg::string("x")])
+            .unwrap_err();
+        assert_eq!(err.kind(), Some(KnownErrorCode::ServiceNotFound));
+        assert_eq!(
+            err.inner().err_msg(),
+            "cannot find service no-such-service."
+        );
+    }
+
+    #[test]
+    fn test_bad_envelope() {
+        let server = server();
+        let response: serde_json::Value =
+            serde_json::from_slice(&server.handle_packet(br#"["seafile_get_config"]"#)).unwrap();
+        assert_eq!(response["err_code"], 511);
+    }
+
+    #[test]
+    fn test_endianness() {
+        use std::io::Read;
+
+        let mut server = UnixSocketServer::new();
+        server
+            .service_mut("seafile-rpcserver")
+            .register("ping", |_| Ok(json!(1)));
+        server.set_endianness(Endianness::Big);
+        let server = Arc::new(server);
+
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let serving = Arc::clone(&server);
+        thread::spawn(move || serving.serve_connection(theirs));
+        let transport =
+            UnixSocketTransport::new(ours, "seafile-rpcserver").with_endianness(Endianness::Big);
+        let mut client = SearpcClient::new(transport);
+        assert_eq!(client.call_int("ping", []).unwrap(), 1);
+
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        thread::spawn(move || server.serve_connection(theirs));
+        let packet = br#"{"service":"seafile-rpcserver","request":"[\"ping\"]"}"#;
+        ours.write_all(&(packet.len() as u32).to_be_bytes())
+            .unwrap();
+        ours.write_all(packet).unwrap();
+        let mut len = [0u8; 4];
+        ours.read_exact(&mut len).unwrap();
+        assert_eq!(u32::from_be_bytes(len) as usize, br#"{"ret":1}"#.len());
+    }
+
+    #[test]
+    fn test_tagged_request() {
+        let server = server();
+        let packet = br#"{"service":"ccnet-rpcserver","request":"[\"get_session_info\"]","id":7}"#;
+        let tagged: serde_json::Value =
+            serde_json::from_slice(&server.handle_packet(packet)).unwrap();
+        assert_eq!(tagged["id"], 7);
+        let response: serde_json::Value =
+            serde_json::from_str(tagged["response"].as_str().unwrap()).unwrap();
+        assert_eq!(response["ret"]["id"], "ccnet");
+    }
+
+    #[test]
+    fn test_notify() {
+        use crate::MultiplexedTransport;
+
+        let server = server();
+        let transport = |service| {
+            let (ours, theirs) = UnixStream::pair().unwrap();
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_connection(theirs));
+            MultiplexedTransport::new(ours, service).unwrap()
+        };
+        let subscribed = transport("seafile-rpcserver");
+        let events = subscribed.subscribe().unwrap();
+        // Subscribing twice adds a receiver, not a connection
+        let more_events = subscribed.subscribe().unwrap();
+        let other = transport("ccnet-rpcserver");
+        let _other_events = other.subscribe().unwrap();
+        let _unsubscribed = transport("seafile-rpcserver");
+        let err = transport("no-such-service").subscribe().unwrap_err();
+        assert_eq!(err.kind(), Some(KnownErrorCode::ServiceNotFound));
+
+        for i in 0..3 {
+            assert_eq!(server.notify("seafile-rpcserver", &json!({"seq": i})), 1);
+        }
+        for i in 0..3 {
+            assert_eq!(events.recv().unwrap(), json!({"seq": i}));
+            assert_eq!(more_events.recv().unwrap(), json!({"seq": i}));
+        }
+        // Calls still work alongside
+        let mut client = SearpcClient::new(subscribed);
+        let value = client
+            .call_string("seafile_get_config", [Arg::string("x")])
+            .unwrap();
+        assert_eq!(value, "seafile:x");
+
+        // The channel ends with the connection
+        drop(client);
+        assert!(events.recv().is_err());
+        assert_eq!(server.notify("seafile-rpcserver", &json!({})), 0);
+    }
+
+    #[test]
+    fn test_service_mut() {
+        let mut server = UnixSocketServer::new();
+        server
+            .service_mut("seafile-rpcserver")
+            .register("a", |_| Ok(json!(1)));
+        server
+            .service_mut("seafile-rpcserver")
+            .register("b", |_| Ok(json!(2)));
+        server
+            .service_mut("seafile-threaded-rpcserver")
+            .register("a", |_| Ok(json!(3)));
+
+        let mut services: Vec<_> = server.services().collect();
+        services.sort_unstable();
+        assert_eq!(
+            services,
+            ["seafile-rpcserver", "seafile-threaded-rpcserver"]
+        );
+        let seafile = server.service("seafile-rpcserver").unwrap();
+        assert!(seafile.has_function("a") && seafile.has_function("b"));
+        assert!(!server
+            .service("seafile-threaded-rpcserver")
+            .unwrap()
+            .has_function("b"));
+    }
+
+    #[test]
+    #[should_panic(expected = "service shared is shared")]
+    fn test_service_mut_shared() {
+        let shared = Arc::new(SearpcServer::new());
+        let mut server = UnixSocketServer::new();
+        server.add_service("shared", Arc::clone(&shared));
+        server.service_mut("shared");
+    }
+
+    #[test]
+    fn test_serve_listener() {
+        let (dir, listener) = listen("listener");
+        let path = dir.join("seafile.sock");
+        let server = server();
+        thread::spawn(move || server.serve(listener));
+
+        // Clients of different services share the socket
+        let transport = UnixSocketTransport::connect(&path, "seafile-rpcserver").unwrap();
+        let mut seafile = SearpcClient::new(transport);
+        let transport = UnixSocketTransport::connect(&path, "ccnet-rpcserver").unwrap();
+        let mut ccnet = SearpcClient::new(transport);
+        for _ in 0..2 {
+            let value = seafile
+                .call_string("seafile_get_config", [Arg::string("k")])
+                .unwrap();
+            assert_eq!(value, "seafile:k");
+            let info = ccnet.call_object("get_session_info", []).unwrap();
+            assert_eq!(info["id"], "ccnet");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_serve_sequential() {
+        let (dir, listener) = listen("sequential");
+        let path = dir.join("seafile.sock");
+        let server = server();
+        let serving = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_sequential(listener))
+        };
+
+        for key in ["a", "b"] {
+            let transport = UnixSocketTransport::connect(&path, "seafile-rpcserver").unwrap();
+            let mut client = SearpcClient::new(transport);
+            let value = client
+                .call_string("seafile_get_config", [Arg::string(key)])
+                .unwrap();
+            assert_eq!(value, format!("seafile:{}", key));
+        }
+
+        assert!(server.shutdown(Duration::from_secs(5)));
+        serving.join().unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Listening socket in a fresh directory named after `test`
+    fn listen(test: &str) -> (std::path::PathBuf, UnixListener) {
+        let dir = std::env::temp_dir().join(format!(
+            "searpc-unix-server-{}-{}",
+            test,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        (dir, listener)
+    }
+
+    /// Server whose `block` function signals `started`, then waits for `release`
+    fn blocking_server(
+        limits: Limits,
+    ) -> (Arc<UnixSocketServer>, mpsc::Receiver<()>, mpsc::Sender<()>) {
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let started_tx = Mutex::new(started_tx);
+        let release_rx = Mutex::new(release_rx);
+        let mut server = UnixSocketServer::new();
+        server.set_limits(limits);
+        server
+            .service_mut("seafile-rpcserver")
+            .register("block", move |_| {
+                started_tx.lock().unwrap().send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+                Ok(json!("done"))
+            })
+            .register("ping", |_| Ok(json!(1)));
+        (Arc::new(server), started, release)
+    }
+
+    #[test]
+    fn test_shutdown_idle() {
+        let (dir, listener) = listen("shutdown-idle");
+        let (server, _started, _release) = blocking_server(Limits::default());
+        let serving = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve(listener))
+        };
+
+        let transport =
+            UnixSocketTransport::connect(dir.join("seafile.sock"), "seafile-rpcserver").unwrap();
+        let mut client = SearpcClient::new(transport);
+        assert_eq!(client.call_int("ping", []).unwrap(), 1);
+
+        assert!(server.shutdown(Duration::from_secs(5)));
+        serving.join().unwrap().unwrap();
+        assert!(client.call_int("ping", []).is_err());
+        // Stopped for good
+        assert!(server
+            .serve_connection(UnixStream::pair().unwrap().0)
+            .is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_drains_in_flight() {
+        let (server, started, release) = blocking_server(Limits::default());
+        let mut client = connect(&server, "seafile-rpcserver");
+        let calling = thread::spawn(move || client.call_string("block", []));
+        started.recv().unwrap();
+
+        let shutting_down = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.shutdown(Duration::from_secs(10)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!shutting_down.is_finished());
+
+        release.send(()).unwrap();
+        assert_eq!(calling.join().unwrap().unwrap(), "done");
+        assert!(shutting_down.join().unwrap());
+    }
+
+    #[test]
+    fn test_shutdown_deadline() {
+        let (server, started, release) = blocking_server(Limits::default());
+        let mut client = connect(&server, "seafile-rpcserver");
+        let calling = thread::spawn(move || client.call_string("block", []));
+        started.recv().unwrap();
+
+        assert!(!server.shutdown(Duration::from_millis(50)));
+        release.send(()).unwrap();
+        assert!(calling.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_request_limit() {
+        let (server, started, release) = blocking_server(Limits {
+            max_concurrent_requests: Some(1),
+            ..Limits::default()
+        });
+        let mut first = connect(&server, "seafile-rpcserver");
+        let mut second = connect(&server, "seafile-rpcserver");
+        let calling = thread::spawn(move || first.call_string("block", []));
+        started.recv().unwrap();
+
+        // No queue: turned away while `block` runs
+        let err = second.call_int("ping", []).unwrap_err();
+        assert_eq!(err.kind(), Some(KnownErrorCode::Busy));
+        assert_eq!(err.inner().err_msg(), "server busy, try again later");
+
+        release.send(()).unwrap();
+        assert_eq!(calling.join().unwrap().unwrap(), "done");
+        assert_eq!(second.call_int("ping", []).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_tagged_request_limit() {
+        let (server, started, release) = blocking_server(Limits::default());
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_connection(theirs));
+        }
+        let send = |stream: &mut UnixStream, id: usize| {
+            let packet = format!(
+                r#"{{"service":"seafile-rpcserver","request":"[\"block\"]","id":{}}}"#,
+                id
+            );
+            let mut frame = Endianness::default().encode(packet.len() as u32).to_vec();
+            frame.extend_from_slice(packet.as_bytes());
+            stream.write_all(&frame).unwrap();
+        };
+        for id in 0..=MAX_TAGGED_REQUESTS {
+            send(&mut ours, id);
+        }
+        for _ in 0..MAX_TAGGED_REQUESTS {
+            started.recv().unwrap();
+        }
+        // The last one waits for a thread of its own
+        assert!(started.recv_timeout(Duration::from_millis(50)).is_err());
+
+        release.send(()).unwrap();
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+        for _ in 0..MAX_TAGGED_REQUESTS {
+            release.send(()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_request_queue() {
+        let (server, started, release) = blocking_server(Limits {
+            max_concurrent_requests: Some(1),
+            max_queued_requests: 1,
+            ..Limits::default()
+        });
+        let mut first = connect(&server, "seafile-rpcserver");
+        let mut second = connect(&server, "seafile-rpcserver");
+        let calling = thread::spawn(move || first.call_string("block", []));
+        started.recv().unwrap();
+
+        // Queued behind `block` rather than turned away
+        let queued = thread::spawn(move || second.call_int("ping", []));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!queued.is_finished());
+
+        release.send(()).unwrap();
+        assert_eq!(calling.join().unwrap().unwrap(), "done");
+        assert_eq!(queued.join().unwrap().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let (dir, listener) = listen("connection-limit");
+        let path = dir.join("seafile.sock");
+        let (server, _started, _release) = blocking_server(Limits {
+            max_connections: Some(1),
+            ..Limits::default()
+        });
+        let serving = {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve(listener))
+        };
+
+        let transport = UnixSocketTransport::connect(&path, "seafile-rpcserver").unwrap();
+        let mut first = SearpcClient::new(transport);
+        assert_eq!(first.call_int("ping", []).unwrap(), 1);
+
+        // Connects, but is only served once the first client hangs up
+        let transport = UnixSocketTransport::connect(&path, "seafile-rpcserver").unwrap();
+        let mut second = SearpcClient::new(transport);
+        let waiting = thread::spawn(move || second.call_int("ping", []));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        assert_eq!(waiting.join().unwrap().unwrap(), 1);
+
+        assert!(server.shutdown(Duration::from_secs(5)));
+        serving.join().unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_peer_credentials() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let peer = PeerCredentials::of(&theirs).unwrap();
+        // SAFETY: no preconditions
+        assert_eq!(peer.uid, unsafe { libc::geteuid() });
+        assert_eq!(peer.gid, unsafe { libc::getegid() });
+        if let Some(pid) = peer.pid {
+            assert_eq!(pid as u32, std::process::id());
+        }
+        assert!(peer.is_same_user());
+        drop(ours);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut server = UnixSocketServer::new();
+        {
+            let seen = Arc::clone(&seen);
+            server.authorize(move |peer| {
+                seen.lock().unwrap().push(*peer);
+                peer.is_same_user()
+            });
+        }
+        server
+            .service_mut("seafile-rpcserver")
+            .register("ping", |_| Ok(json!(1)));
+        let server = Arc::new(server);
+
+        let mut client = connect(&server, "seafile-rpcserver");
+        assert_eq!(client.call_int("ping", []).unwrap(), 1);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        // Refused: closed before any request is answered
+        let mut server = UnixSocketServer::new();
+        server.authorize(|_| false);
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let err = server.serve_connection(theirs).unwrap_err();
+        assert!(err.to_string().contains("Connection refused for uid"));
+        let mut client = SearpcClient::new(UnixSocketTransport::new(ours, "seafile-rpcserver"));
+        assert!(client.call_int("ping", []).is_err());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_receive_credentials() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut server = UnixSocketServer::new();
+        {
+            let seen = Arc::clone(&seen);
+            server.receive_credentials(true).authorize(move |peer| {
+                seen.lock().unwrap().push(*peer);
+                true
+            });
+        }
+        server
+            .service_mut("seafile-rpcserver")
+            .register("ping", |_| Ok(json!(1)));
+        let server = Arc::new(server);
+
+        let serve = |theirs| {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.serve_connection(theirs))
+        };
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let serving = serve(theirs);
+        let transport = UnixSocketTransport::new(ours, "seafile-rpcserver").with_credentials(true);
+        let mut client = SearpcClient::new(transport);
+        for _ in 0..2 {
+            assert_eq!(client.call_int("ping", []).unwrap(), 1);
+        }
+        drop(client);
+        serving.join().unwrap().unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [PeerCredentials::of(&UnixStream::pair().unwrap().0).unwrap()]
+        );
+
+        // Without credentials, the authorizer is never asked
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let serving = serve(theirs);
+        let mut client = SearpcClient::new(UnixSocketTransport::new(ours, "seafile-rpcserver"));
+        assert!(client.call_int("ping", []).is_err());
+        let err = serving.join().unwrap().unwrap_err();
+        assert!(err.to_string().contains("peer credentials"), "{}", err);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+}
@@ -21,82 +21,192 @@
 //! {"ret": value, "err_code": code, "err_msg": msg}
 //! ```
 
+use crate::deadline::DeadlineStream;
 use crate::error::{Result, SearpcError};
-use crate::transport::Transport;
-use std::io::{Read, Write};
+use crate::retry::RetryPolicy;
+use crate::transport::{
+    self, wrap_request, ConnectionAddr, Endianness, Framing, PipelinedTransport, Transport,
+};
+use crate::unix_connect;
+use std::io::{self, BufReader};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 /// Unix Domain Socket transport
 ///
 /// Uses 32-bit length header (matching Seafile's named pipe transport)
 pub struct UnixSocketTransport {
-    stream: UnixStream,
+    /// Buffered, so a response's header and body usually take one read
+    stream: BufReader<DeadlineStream<UnixStream>>,
     service: String,
+    endianness: Endianness,
+    /// Packet buffer, reused across requests
+    buf: Vec<u8>,
+    connected: Instant,
+    max_response_size: usize,
+    /// Send `SCM_CREDENTIALS` with each request
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    credentials: bool,
 }
 
 impl UnixSocketTransport {
     pub fn new(stream: UnixStream, service: impl Into<String>) -> Self {
+        Self::with_capacity(stream, service, transport::DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Use buffers of `capacity` bytes from the start, for high call rates
+    ///
+    /// Responses up to that size are read in one go, and requests up to
+    /// that size are assembled without reallocating.
+    pub fn with_capacity(stream: UnixStream, service: impl Into<String>, capacity: usize) -> Self {
         UnixSocketTransport {
-            stream,
+            stream: BufReader::with_capacity(capacity, DeadlineStream::new(stream)),
             service: service.into(),
+            endianness: Endianness::Native,
+            buf: Vec::with_capacity(capacity),
+            connected: Instant::now(),
+            max_response_size: transport::DEFAULT_MAX_RESPONSE_SIZE,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            credentials: false,
         }
     }
 
+    /// Byte order of the length header, native by default
+    pub fn with_endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Longest response to accept, [`DEFAULT_MAX_RESPONSE_SIZE`](crate::transport::DEFAULT_MAX_RESPONSE_SIZE)
+    /// by default
+    ///
+    /// A longer one fails the call with
+    /// [`SearpcError::ProtocolDesync`]
+    /// before its body is read, and the connection is unusable after it.
+    pub fn with_max_response_size(mut self, max: usize) -> Self {
+        self.max_response_size = max;
+        self
+    }
+
+    /// Attach this process's credentials to each request as
+    /// `SCM_CREDENTIALS` ancillary data (Linux only)
+    ///
+    /// For servers checking them with
+    /// [`UnixSocketServer::receive_credentials`](crate::UnixSocketServer::receive_credentials).
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn with_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
     pub fn connect(path: impl AsRef<Path>, service: impl Into<String>) -> std::io::Result<Self> {
         let stream = UnixStream::connect(path)?;
-        Ok(UnixSocketTransport {
-            stream,
-            service: service.into(),
-        })
+        Ok(Self::new(stream, service))
+    }
+
+    /// Connect, waiting for a daemon that is not listening yet according
+    /// to `policy`
+    pub fn connect_with_retry(
+        path: impl AsRef<Path>,
+        service: impl Into<String>,
+        policy: RetryPolicy,
+    ) -> io::Result<Self> {
+        let stream = policy.connect(|| UnixStream::connect(path.as_ref()))?;
+        Ok(Self::new(stream, service))
+    }
+
+    /// Connect, giving up after `timeout`
+    ///
+    /// A plain Unix socket connect blocks while the server's listen backlog
+    /// is full, i.e. for as long as a wedged daemon does not accept. This
+    /// one connects without blocking and fails with `TimedOut` once
+    /// `timeout` passed.
+    pub fn connect_timeout(
+        path: impl AsRef<Path>,
+        service: impl Into<String>,
+        timeout: Duration,
+    ) -> io::Result<Self> {
+        let stream = unix_connect::connect_timeout(path.as_ref(), timeout)?;
+        Ok(Self::new(stream, service))
+    }
+
+    /// Fail calls whose response takes longer than `timeout` to arrive
+    ///
+    /// `None` (the default) waits forever. A timed-out call reports
+    /// [`SearpcError::is_timeout`]; drop the transport afterwards, as the
+    /// late response would be read as the next call's.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket().set_read_timeout(timeout)
+    }
+
+    /// Fail calls whose request cannot be written within `timeout`
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket().set_write_timeout(timeout)
+    }
+
+    fn socket(&self) -> &UnixStream {
+        self.stream.get_ref().get_ref()
     }
 
     /// Read exactly n bytes
-    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
-        self.stream
-            .read_exact(buf)
-            .map_err(|e| SearpcError::TransportError(format!("Read failed: {}", e)))
+    fn read_exact(&mut self, buf: &mut [u8], frame_start: bool) -> Result<()> {
+        transport::read_response(&mut self.stream, buf, frame_start)
     }
 
     /// Write all bytes
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
-        self.stream
-            .write_all(buf)
-            .map_err(|e| SearpcError::TransportError(format!("Write failed: {}", e)))
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.credentials {
+            let stream = self.stream.get_ref();
+            let mut writer =
+                crate::scm_credentials::CredentialsWriter::new(stream.get_ref(), stream.deadline());
+            return transport::write_request(&mut writer, buf);
+        }
+        transport::write_request(self.stream.get_mut(), buf)
     }
 
     /// Send a packet with service wrapper
+    ///
+    /// The envelope is serialized straight after a placeholder header, which
+    /// is filled in afterwards, so the packet goes out in a single write.
     fn send_packet(&mut self, rpc_request: &[u8]) -> Result<()> {
-        // Wrap the RPC request with service info
-        let wrapped = self.wrap_request(rpc_request)?;
-
-        let len = wrapped.len() as u32;
-
-        // Write length (4 bytes, native endian - matches C code using guint32)
-        self.write_all(&len.to_ne_bytes())?;
-
-        // Write data
-        self.write_all(&wrapped)?;
-
-        Ok(())
+        let mut packet = std::mem::take(&mut self.buf);
+        packet.clear();
+        packet.extend_from_slice(&[0; 4]);
+        let result = self.wrap_request(rpc_request, &mut packet).and_then(|()| {
+            let len = u32::try_from(packet.len() - 4)
+                .map_err(|_| SearpcError::transport("Request too large for 32-bit header"))?;
+            // Native endian by default - matches C code using guint32
+            packet[..4].copy_from_slice(&self.endianness.encode(len));
+            self.write_all(&packet)
+        });
+        self.buf = packet;
+        result
     }
 
     /// Receive a packet
     fn recv_packet(&mut self) -> Result<Vec<u8>> {
-        // Read length (4 bytes, native endian)
+        // Read length (4 bytes)
         let mut len_buf = [0u8; 4];
-        self.read_exact(&mut len_buf)?;
-        let len = u32::from_ne_bytes(len_buf) as usize;
+        self.read_exact(&mut len_buf, true)?;
+        let len = self.endianness.decode(len_buf) as usize;
 
         if len == 0 {
-            return Err(SearpcError::TransportError(
+            return Err(SearpcError::transport(
                 "Received packet with zero length".to_string(),
             ));
         }
+        // A 16-bit response has its `{"` in our header: say so, rather
+        // than only that the length is too large
+        if len > self.max_response_size {
+            Framing::U32(self.endianness).check_start(&len_buf, &[])?;
+        }
+        transport::check_response_len(len, self.max_response_size)?;
 
         // Read data
         let mut data = vec![0u8; len];
-        self.read_exact(&mut data)?;
+        self.read_exact(&mut data, false)?;
 
         Ok(data)
     }
@@ -110,30 +220,51 @@ impl UnixSocketTransport {
     /// This matches Python's pysearpc implementation:
     ///   json.dumps({'service': service, 'request': fcall_str})
     /// where fcall_str is already a JSON string like '["func",arg1,arg2]'
-    fn wrap_request(&self, rpc_request: &[u8]) -> Result<Vec<u8>> {
-        use serde_json::json;
-
-        let request_str = std::str::from_utf8(rpc_request).map_err(|e| {
-            SearpcError::InvalidResponse(format!("Request is not valid UTF-8: {}", e))
-        })?;
-
-        // CRITICAL: Keep request as a string, don't parse it as JSON!
-        // The server expects: {"service":"...", "request":"[...]"}
-        // NOT: {"service":"...", "request":[...]}
-        let wrapped = json!({
-            "service": &self.service,
-            "request": request_str  // Pass as string, not parsed JSON
-        });
-
-        Ok(serde_json::to_vec(&wrapped)?)
+    fn wrap_request(&self, rpc_request: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+        wrap_request(&self.service, rpc_request, buf)
     }
 }
 
 impl Transport for UnixSocketTransport {
     fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.stream.get_mut().start();
         self.send_packet(request)?;
         self.recv_packet()
     }
+
+    fn peer_addr(&self) -> Option<ConnectionAddr> {
+        self.socket().peer_addr().ok().map(ConnectionAddr::from)
+    }
+
+    fn local_addr(&self) -> Option<ConnectionAddr> {
+        self.socket().local_addr().ok().map(ConnectionAddr::from)
+    }
+
+    fn connection_age(&self) -> Option<Duration> {
+        Some(self.connected.elapsed())
+    }
+
+    /// Bounds each call as a whole: the socket's timeouts are set to the
+    /// time left before each read and write
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.stream
+            .get_mut()
+            .set_timeout(timeout)
+            .map_err(|e| SearpcError::transport_io("Setting timeout", e))
+    }
+}
+
+/// With a timeout, each request and each response gets all of it
+impl PipelinedTransport for UnixSocketTransport {
+    fn send_request(&mut self, request: &[u8]) -> Result<()> {
+        self.stream.get_mut().start();
+        self.send_packet(request)
+    }
+
+    fn recv_response(&mut self) -> Result<Vec<u8>> {
+        self.stream.get_mut().start();
+        self.recv_packet()
+    }
 }
 
 #[cfg(test)]
@@ -158,17 +289,205 @@ mod tests {
 
     #[test]
     fn test_wrap_request() {
-        let transport = UnixSocketTransport {
-            stream: UnixStream::pair().unwrap().0,
-            service: "test-service".to_string(),
-        };
+        let transport = UnixSocketTransport::new(UnixStream::pair().unwrap().0, "test-service");
 
         let rpc_request = r#"["get_version"]"#.as_bytes();
-        let wrapped = transport.wrap_request(rpc_request).unwrap();
+        let mut wrapped = Vec::new();
+        transport.wrap_request(rpc_request, &mut wrapped).unwrap();
         let wrapped_str = std::str::from_utf8(&wrapped).unwrap();
 
         assert!(wrapped_str.contains("\"service\":\"test-service\""));
         // request 字段是 JSON 字符串，不是直接的数组
         assert!(wrapped_str.contains("\"request\":\"[\\\"get_version\\\"]\""));
     }
+
+    #[test]
+    fn test_send_packet() {
+        use std::io::Read;
+
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let mut transport = UnixSocketTransport::new(ours, "test-service");
+        for request in [&br#"["get_version"]"#[..], br#"["f","a\"b"]"#] {
+            transport.send_packet(request).unwrap();
+
+            let mut len = [0u8; 4];
+            theirs.read_exact(&mut len).unwrap();
+            let mut body = vec![0u8; u32::from_ne_bytes(len) as usize];
+            theirs.read_exact(&mut body).unwrap();
+            let envelope: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(envelope["service"], "test-service");
+            assert_eq!(envelope["request"].as_str().unwrap().as_bytes(), request);
+        }
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let mut transport = UnixSocketTransport::new(ours, "test-service");
+        transport
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let err = transport.send(br#"["get_version"]"#).unwrap_err();
+        assert!(err.is_timeout(), "{}", err);
+
+        let dir =
+            std::env::temp_dir().join(format!("searpc-unix-transport-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        UnixSocketTransport::connect_timeout(&path, "test-service", Duration::from_secs(5))
+            .unwrap();
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        let err =
+            UnixSocketTransport::connect_timeout(&path, "test-service", Duration::from_secs(5))
+                .err()
+                .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_connect_with_retry() {
+        let dir = std::env::temp_dir().join(format!("searpc-unix-retry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seafile.sock");
+        let _ = std::fs::remove_file(&path);
+        let policy = RetryPolicy {
+            max_attempts: 50,
+            initial_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(20),
+            ..RetryPolicy::default()
+        };
+
+        // The daemon binds its socket a bit after we start connecting
+        let daemon = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                let listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+                listener.accept().unwrap();
+            })
+        };
+        UnixSocketTransport::connect_with_retry(&path, "test-service", policy).unwrap();
+        daemon.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            ..policy
+        };
+        let err = UnixSocketTransport::connect_with_retry(&path, "test-service", policy)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    /// More calls than fit in the pipeline, some of them failing
+    #[test]
+    fn test_call_batch() {
+        use crate::server::arg;
+        use crate::{Arg, RpcRequest, SearpcClient, SearpcServer, UnixSocketServer};
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let mut rpc = SearpcServer::new();
+        rpc.register("double", |args| {
+            let n: i64 = arg(args, 0)?;
+            Ok(json!(n * 2))
+        });
+        let mut server = UnixSocketServer::new();
+        server.add_service("test-service", rpc);
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || Arc::new(server).serve_connection(theirs));
+
+        let mut client = SearpcClient::new(UnixSocketTransport::new(ours, "test-service"));
+        client.intercept_request(|request| match request.args.first() {
+            Some(Arg::Int(13)) => Err(SearpcError::transport("unlucky")),
+            _ => Ok(()),
+        });
+        let requests = (0..100)
+            .map(|n| RpcRequest::with_args("double", vec![Arg::Int(n)]))
+            .chain([RpcRequest::new("missing")]);
+        let results = client.call_batch(requests).unwrap();
+        assert_eq!(results.len(), 101);
+        for (n, result) in results[..100].iter().enumerate() {
+            match n {
+                13 => assert_eq!(result.as_ref().unwrap_err().function(), Some("double")),
+                _ => assert_eq!(*result.as_ref().unwrap(), json!(n * 2)),
+            }
+        }
+        assert_eq!(results[100].as_ref().unwrap_err().err_code(), 500);
+
+        // Still in step for single calls
+        assert_eq!(client.call_int("double", [Arg::Int(21)]).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_connection_metadata() {
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let transport = UnixSocketTransport::new(ours, "test-service");
+        assert_eq!(transport.peer_addr(), Some(ConnectionAddr::Unix(None)));
+        assert_eq!(transport.local_addr(), Some(ConnectionAddr::Unix(None)));
+        assert!(transport.connection_age().is_some());
+    }
+
+    #[test]
+    fn test_connection_closed() {
+        // Peer gone before the request: safe to replay
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        drop(theirs);
+        let mut transport = UnixSocketTransport::new(ours, "test-service");
+        let err = transport.send(br#"["get_version"]"#).unwrap_err();
+        assert!(err.is_connection_closed());
+        assert!(err.may_replay());
+
+        // Peer reads the request, then hangs up without answering
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let peer = std::thread::spawn(move || {
+            use std::io::Read;
+            let mut len = [0u8; 4];
+            theirs.read_exact(&mut len).unwrap();
+            let mut body = vec![0u8; u32::from_ne_bytes(len) as usize];
+            theirs.read_exact(&mut body).unwrap();
+        });
+        let mut transport = UnixSocketTransport::new(ours, "test-service");
+        let err = transport.send(br#"["get_version"]"#).unwrap_err();
+        peer.join().unwrap();
+        assert!(matches!(
+            err,
+            SearpcError::ConnectionClosed {
+                request_sent: true,
+                mid_frame: false
+            }
+        ));
+        assert!(!err.may_replay());
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        use std::io::Write;
+
+        // A header announcing 4 GiB is refused before anything is allocated
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        theirs.write_all(&u32::MAX.to_ne_bytes()).unwrap();
+        let mut transport = UnixSocketTransport::new(ours, "test-service");
+        let err = transport.send(br#"["get_version"]"#).unwrap_err();
+        assert!(err.is_protocol_desync(), "{}", err);
+
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let response = br#"{"ret":"1.0"}"#;
+        for _ in 0..2 {
+            theirs
+                .write_all(&(response.len() as u32).to_ne_bytes())
+                .unwrap();
+            theirs.write_all(response).unwrap();
+        }
+        let mut transport =
+            UnixSocketTransport::new(ours, "test-service").with_max_response_size(response.len());
+        assert_eq!(transport.send(br#"["get_version"]"#).unwrap(), response);
+        transport = transport.with_max_response_size(response.len() - 1);
+        let err = transport.send(br#"["get_version"]"#).unwrap_err();
+        assert!(err.to_string().contains("limit of 12 bytes"), "{}", err);
+    }
 }
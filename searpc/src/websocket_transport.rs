@@ -0,0 +1,183 @@
+//! WebSocket transport (tokio-tungstenite)
+//!
+//! One RPC per message: each request is sent as a text message holding
+//! `["function_name", args...]`, and the next text or binary message is its
+//! response. WebSocket does its own framing, so there is no length header.
+//!
+//! [`WebSocketTransport::connect`] opens plain `ws://` connections; for
+//! `wss://`, do the handshake over a TLS stream with
+//! [`tokio_tungstenite::client_async`] and pass the result to
+//! [`WebSocketTransport::new`].
+
+use crate::{async_transport::AsyncTransport, error::SearpcError, Result};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub use tokio_tungstenite;
+
+/// Async transport over a WebSocket connection
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use searpc::{Arg, AsyncSearpcClient, WebSocketTransport};
+///
+/// let transport = WebSocketTransport::connect("ws://127.0.0.1:8080/rpc").await?;
+/// let mut client = AsyncSearpcClient::new(transport);
+///
+/// let len = client.call_int("searpc_strlen", vec![Arg::string("hello")]).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct WebSocketTransport<S> {
+    stream: WebSocketStream<S>,
+}
+
+impl WebSocketTransport<MaybeTlsStream<TcpStream>> {
+    /// Connect to a `ws://` URL
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (stream, _response) = tokio_tungstenite::connect_async(url)
+            .await
+            .map_err(|e| ws_error("WebSocket connect", e))?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> WebSocketTransport<S> {
+    /// Use a connection that has completed the WebSocket handshake
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        WebSocketTransport { stream }
+    }
+
+    /// The underlying WebSocket stream
+    pub fn get_ref(&self) -> &WebSocketStream<S> {
+        &self.stream
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncTransport for WebSocketTransport<S> {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let request = std::str::from_utf8(request).map_err(|e| {
+            SearpcError::InvalidResponse(format!("Request is not valid UTF-8: {}", e))
+        })?;
+        self.stream
+            .send(Message::Text(request.to_string()))
+            .await
+            .map_err(|e| match e {
+                tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                    SearpcError::ConnectionClosed {
+                        request_sent: false,
+                        mid_frame: false,
+                    }
+                }
+                e => ws_error("WebSocket send", e),
+            })?;
+
+        // Pings are answered by tungstenite while we wait
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(text.into_bytes()),
+                Some(Ok(Message::Binary(data))) => return Ok(data),
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(SearpcError::ConnectionClosed {
+                        request_sent: true,
+                        mid_frame: false,
+                    })
+                }
+                Some(Err(e)) => return Err(ws_error("WebSocket receive", e)),
+            }
+        }
+    }
+}
+
+fn ws_error(context: &str, e: tungstenite::Error) -> SearpcError {
+    match e {
+        tungstenite::Error::Io(e) => SearpcError::transport_io(context, e),
+        e => SearpcError::transport(format!("{}: {}", context, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{arg, SearpcServer};
+    use crate::{Arg, AsyncSearpcClient};
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    /// Serve one WebSocket connection, pinging before each response
+    async fn serve_one(listener: TcpListener, server: Arc<SearpcServer>) {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+        // The client drops the connection without a closing handshake
+        while let Some(Ok(message)) = ws.next().await {
+            let request = match message {
+                Message::Text(text) => text.into_bytes(),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let response = server.handle_request(&request);
+            ws.send(Message::Ping(b"ping".to_vec())).await.unwrap();
+            ws.send(Message::Text(String::from_utf8(response).unwrap()))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let mut server = SearpcServer::new();
+        server.register("searpc_strlen", |args| {
+            let s: String = arg(args, 0)?;
+            Ok(json!(s.len()))
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/rpc", listener.local_addr().unwrap());
+        let served = tokio::spawn(serve_one(listener, Arc::new(server)));
+
+        let transport = WebSocketTransport::connect(&url).await.unwrap();
+        let mut client = AsyncSearpcClient::new(transport);
+        for s in ["hello", "searpc over websocket"] {
+            let len = client
+                .call_int("searpc_strlen", vec![Arg::string(s)])
+                .await
+                .unwrap();
+            assert_eq!(len as usize, s.len());
+        }
+        let err = client
+            .call_int("missing", Vec::<Arg>::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.err_code(), 500);
+        drop(client);
+        served.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/rpc", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(tcp).await.unwrap();
+            ws.next().await;
+            ws.close(None).await.unwrap();
+        });
+
+        let mut transport = WebSocketTransport::connect(&url).await.unwrap();
+        let err = transport
+            .send(br#"["searpc_strlen","a"]"#)
+            .await
+            .unwrap_err();
+        assert!(err.is_connection_closed());
+        assert!(!err.may_replay());
+    }
+}
@@ -0,0 +1,213 @@
+//! `#[searpc_service]` served to a `#[rpc]` client
+#![cfg(feature = "macro")]
+
+use searpc::server::ToRpcError;
+use searpc::{
+    rpc, searpc_service, Arg, KnownErrorCode, Result, SearpcClient, SearpcError, SearpcServer,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Repo {
+    id: String,
+    name: String,
+}
+
+trait Daemon {
+    fn get_repo_list(&self, start: i32, limit: i32) -> Result<Vec<Repo>>;
+    fn get_repo(&self, id: &str) -> Result<Option<Repo>>;
+    fn set_config(&self, key: &str, value: Option<String>) -> Result<i32>;
+    fn is_auto_sync_enabled(&self) -> Result<bool>;
+}
+
+#[derive(Default)]
+struct FakeDaemon {
+    repos: Vec<Repo>,
+    config: Mutex<Vec<(String, Option<String>)>>,
+}
+
+#[searpc_service(prefix = "seafile")]
+impl Daemon for FakeDaemon {
+    fn get_repo_list(&self, start: i32, limit: i32) -> Result<Vec<Repo>> {
+        let limit = if limit < 0 {
+            usize::MAX
+        } else {
+            limit as usize
+        };
+        Ok(self
+            .repos
+            .iter()
+            .skip(start.max(0) as usize)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn get_repo(&self, id: &str) -> Result<Option<Repo>> {
+        Ok(self.repos.iter().find(|repo| repo.id == id).cloned())
+    }
+
+    fn set_config(&self, key: &str, value: Option<String>) -> Result<i32> {
+        if key.is_empty() {
+            return Err(SearpcError::RpcError {
+                code: 503,
+                message: "Empty key".to_string(),
+            });
+        }
+        self.config.lock().unwrap().push((key.to_string(), value));
+        Ok(0)
+    }
+
+    #[rpc(name = "seafile_is_auto_sync_enabled")]
+    fn is_auto_sync_enabled(&self) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+#[rpc(prefix = "seafile")]
+trait DaemonClient {
+    fn get_repo_list(&mut self, start: i32, limit: i32) -> Result<Vec<Repo>>;
+    fn get_repo(&mut self, id: &str) -> Result<Option<Repo>>;
+    fn set_config(&mut self, key: &str, value: Option<String>) -> Result<i32>;
+    /// The same call, without its meaningless result
+    #[rpc(name = "seafile_set_config")]
+    fn store_config(&mut self, key: &str, value: Option<String>) -> Result<()>;
+    fn is_auto_sync_enabled(&mut self) -> Result<bool>;
+}
+
+fn daemon() -> Arc<FakeDaemon> {
+    Arc::new(FakeDaemon {
+        repos: vec![
+            Repo {
+                id: "a".to_string(),
+                name: "Docs".to_string(),
+            },
+            Repo {
+                id: "b".to_string(),
+                name: "Photos".to_string(),
+            },
+        ],
+        ..FakeDaemon::default()
+    })
+}
+
+fn client(daemon: Arc<FakeDaemon>) -> SearpcClient<impl searpc::Transport> {
+    let mut server = SearpcServer::new();
+    server.register_service(daemon);
+    SearpcClient::new(move |request: &[u8]| Ok(server.handle_request(request)))
+}
+
+#[test]
+fn test_generated_dispatch() {
+    let daemon = daemon();
+    let mut client = client(Arc::clone(&daemon));
+
+    assert_eq!(client.get_repo_list(1, -1).unwrap(), daemon.repos[1..]);
+    assert_eq!(client.get_repo("a").unwrap().unwrap().name, "Docs");
+    assert_eq!(client.get_repo("missing").unwrap(), None);
+    assert!(client.is_auto_sync_enabled().unwrap());
+
+    assert_eq!(client.set_config("key", None).unwrap(), 0);
+    let err = client.set_config("", Some("x".to_string())).unwrap_err();
+    assert_eq!(err.inner().err_code(), 503);
+    client.store_config("other", Some("x".to_string())).unwrap();
+    let err = client.store_config("", None).unwrap_err();
+    assert_eq!(err.inner().err_code(), 503);
+    assert_eq!(
+        *daemon.config.lock().unwrap(),
+        [
+            ("key".to_string(), None),
+            ("other".to_string(), Some("x".to_string()))
+        ]
+    );
+}
+
+#[test]
+fn test_names_and_arguments() {
+    use searpc::server::RpcService;
+
+    let mut names = FakeDaemon::functions().to_vec();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        [
+            "seafile_get_repo",
+            "seafile_get_repo_list",
+            "seafile_is_auto_sync_enabled",
+            "seafile_set_config",
+        ]
+    );
+
+    let mut client = client(daemon());
+    // Wrong argument type: the generated code reports bad arguments
+    let err = client
+        .call_objlist("seafile_get_repo_list", [Arg::string("0"), Arg::int(1)])
+        .unwrap_err();
+    assert_eq!(err.inner().err_code(), 503);
+    // Missing trailing arguments read as null
+    assert_eq!(
+        client
+            .call_int("seafile_set_config", [Arg::string("key")])
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        client.call_int("seafile_is_auto_sync_enabled", []).unwrap(),
+        1
+    );
+}
+
+/// Domain error sent with its own codes
+enum QuotaError {
+    Full { used: u64 },
+    TooManyFiles,
+}
+
+impl ToRpcError for QuotaError {
+    fn err_code(&self) -> i32 {
+        match self {
+            QuotaError::Full { .. } => KnownErrorCode::QuotaFull.code(),
+            QuotaError::TooManyFiles => KnownErrorCode::TooManyFiles.code(),
+        }
+    }
+
+    fn err_msg(&self) -> String {
+        match self {
+            QuotaError::Full { used } => format!("Quota full: {} bytes used", used),
+            QuotaError::TooManyFiles => "Too many files in library".to_string(),
+        }
+    }
+}
+
+struct Quota;
+
+#[searpc_service]
+impl Quota {
+    fn check_quota(&self, files: i32) -> std::result::Result<i32, QuotaError> {
+        match files {
+            0..=9 => Ok(0),
+            10..=99 => Err(QuotaError::Full { used: 1024 }),
+            _ => Err(QuotaError::TooManyFiles),
+        }
+    }
+}
+
+#[test]
+fn test_domain_errors() {
+    let mut server = SearpcServer::new();
+    server.register_service(Arc::new(Quota));
+    let mut client = SearpcClient::new(move |request: &[u8]| Ok(server.handle_request(request)));
+
+    assert_eq!(client.call_int("check_quota", [Arg::int(1)]).unwrap(), 0);
+    let err = client.call_int("check_quota", [Arg::int(10)]).unwrap_err();
+    assert_eq!(err.kind(), Some(KnownErrorCode::QuotaFull));
+    assert_eq!(err.inner().err_msg(), "Quota full: 1024 bytes used");
+    let err = client.call_int("check_quota", [Arg::int(100)]).unwrap_err();
+    assert_eq!(err.kind(), Some(KnownErrorCode::TooManyFiles));
+    // Argument errors still come from searpc
+    let err = client
+        .call_int("check_quota", [Arg::string("x")])
+        .unwrap_err();
+    assert_eq!(err.kind(), Some(KnownErrorCode::BadArgs));
+}